@@ -25,7 +25,7 @@ show_usage() {
 get_binary_names() {
     # Parse binary names from Cargo.toml
     cargo metadata --no-deps --format-version 1 | \
-        jq -r '.packages[0].targets[] | select(.kind[] | contains("bin")) | .name'
+        jq -r '.packages[].targets[] | select(.kind[] | contains("bin")) | .name'
 }
 
 # Compile for specific target
@@ -0,0 +1,129 @@
+/* Generated with cbindgen from src/capi.rs, do not edit */
+
+#ifndef RVM_H
+#define RVM_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Outcome of a call into the library
+typedef enum RvmStatus {
+  RVM_STATUS_OK = 0,
+  // A pointer was null or a string was not valid UTF-8
+  RVM_STATUS_INVALID_ARGUMENT = 1,
+  // The source failed to parse or compile
+  RVM_STATUS_COMPILE_ERROR = 2,
+  // The program failed while running
+  RVM_STATUS_RUNTIME_ERROR = 3,
+} RvmStatus;
+
+// Type of the value held by an `RvmValue`
+typedef enum RvmValueType {
+  RVM_VALUE_TYPE_INT = 0,
+  RVM_VALUE_TYPE_FLOAT = 1,
+  RVM_VALUE_TYPE_STRING = 2,
+  RVM_VALUE_TYPE_BOOL = 3,
+} RvmValueType;
+
+// What is off about a result, see `rvm_value_warning`
+typedef enum RvmWarning {
+  RVM_WARNING_NONE = 0,
+  // The result is NaN
+  RVM_WARNING_NOT_A_NUMBER = 1,
+  // The result is an infinity
+  RVM_WARNING_INFINITE = 2,
+  // The result is a float too large for its integer part to be exact
+  RVM_WARNING_IMPRECISE = 3,
+} RvmWarning;
+
+// A compiled program
+typedef struct RvmProgram RvmProgram;
+
+// An argument or result of a run
+typedef struct RvmValue RvmValue;
+
+// A VM loaded with a program, reusable for many runs
+typedef struct RvmVm RvmVm;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Describe a status, the returned string is static
+const char *rvm_status_message(enum RvmStatus status);
+
+// The message of the last failure on this thread, or null when nothing failed yet. It
+// stays valid until the next failing call on the thread.
+const char *rvm_last_error(void);
+
+// Compile `source`, whose free variables are an error, into `*program`
+enum RvmStatus rvm_compile(const char *source, struct RvmProgram **program);
+
+// Compile `source` with the `count` names in `params` as its parameters, which runs are
+// given in the same order
+enum RvmStatus rvm_compile_with_params(const char *source,
+                                       const char *const *params,
+                                       uintptr_t count,
+                                       struct RvmProgram **program);
+
+void rvm_program_free(struct RvmProgram *program);
+
+// Create a VM running `program` with room for `stack_size` values, or null when
+// `program` is null. Integer arithmetic that overflows fails the run rather than wrapping
+// around. The program can be freed once the VM is created.
+struct RvmVm *rvm_vm_new(const struct RvmProgram *program, uintptr_t stack_size);
+
+void rvm_vm_free(struct RvmVm *vm);
+
+// Run the program of `vm` with the `count` values in `args` as its parameters, storing
+// the value it returns in `*result`. The arguments stay owned by the caller.
+enum RvmStatus rvm_run(struct RvmVm *vm,
+                       const struct RvmValue *const *args,
+                       uintptr_t count,
+                       struct RvmValue **result);
+
+struct RvmValue *rvm_value_int(int64_t value);
+
+struct RvmValue *rvm_value_float(double value);
+
+struct RvmValue *rvm_value_bool(bool value);
+
+// A string value holding a copy of `value`, or null when it is null or not UTF-8
+struct RvmValue *rvm_value_string(const char *value);
+
+void rvm_value_free(struct RvmValue *value);
+
+enum RvmValueType rvm_value_type(const struct RvmValue *value);
+
+// The integer held by `value`, 0 for other types
+int64_t rvm_value_as_int(const struct RvmValue *value);
+
+// The number held by `value` as a float, integers are converted and other types are 0
+double rvm_value_as_float(const struct RvmValue *value);
+
+// The boolean held by `value`, false for other types
+bool rvm_value_as_bool(const struct RvmValue *value);
+
+// Whether `value` is known exactly, true for every type but floats, which may have been
+// rounded
+bool rvm_value_is_exact(const struct RvmValue *value);
+
+// What is off about `value` as a result of a run, `RvmWarning::None` when nothing is
+enum RvmWarning rvm_value_warning(const struct RvmValue *value);
+
+// Describe a warning, the returned string is static and empty for `RvmWarning::None`
+const char *rvm_warning_message(enum RvmWarning warning);
+
+// `value` written as the REPL prints it, to be freed with `rvm_string_free`. Null when
+// the text holds a NUL byte.
+char *rvm_value_to_string(const struct RvmValue *value);
+
+void rvm_string_free(char *string);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* RVM_H */
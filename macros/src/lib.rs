@@ -0,0 +1,109 @@
+use proc_macro::{Delimiter, TokenStream, TokenTree};
+
+use librvm::compiler::compile_with_params;
+
+// Evaluate an rvm expression, splicing host variables marked with `#`:
+//
+//     let area = rvm_expr!{ #width * #height };
+//
+// The expression is compiled while building the host crate, so syntax errors surface as
+// Rust compile errors. Each `#name` becomes a parameter bound to the variable `name`,
+// which must convert into a `Value`. The macro evaluates to `Result<Value, RuntimeError>`.
+#[proc_macro]
+pub fn rvm_expr(input: TokenStream) -> TokenStream {
+    let mut source = String::new();
+    let mut params = Vec::new();
+    if let Err(message) = render(input, &mut source, &mut params) {
+        return compile_error(message);
+    }
+
+    let names: Vec<&str> = params.iter().map(String::as_str).collect();
+    let bytecode = match compile_with_params(&source, &names) {
+        Ok(bytecode) => bytecode,
+        Err(message) => {
+            return compile_error(&format!("invalid rvm expression `{source}`: {message}"));
+        }
+    };
+
+    let bytes: Vec<String> = bytecode
+        .code()
+        .iter()
+        .map(|byte| byte.to_string())
+        .collect();
+    let args: Vec<String> = params
+        .iter()
+        .map(|param| format!("::librvm::value::Value::from({param})"))
+        .collect();
+    format!(
+        "{{
+            const BYTECODE: &[u8] = &[{}];
+            ::librvm::vm::Vm::new(BYTECODE, 32).run_with_args(&[{}])
+        }}",
+        bytes.join(", "),
+        args.join(", ")
+    )
+    .parse()
+    .unwrap()
+}
+
+// Turn the macro tokens back into rvm source, collecting `#name` placeholders as parameters
+fn render(
+    input: TokenStream,
+    source: &mut String,
+    params: &mut Vec<String>,
+) -> Result<(), &'static str> {
+    let mut tokens = input.into_iter();
+    // Adjacent identifiers and literals need a space to stay separate tokens
+    let mut after_word = false;
+    while let Some(token) = tokens.next() {
+        match token {
+            TokenTree::Punct(punct) if punct.as_char() == '#' => {
+                let Some(TokenTree::Ident(ident)) = tokens.next() else {
+                    return Err("expected a variable name after `#`");
+                };
+                let name = ident.to_string();
+                if after_word {
+                    source.push(' ');
+                }
+                source.push_str(&name);
+                if !params.contains(&name) {
+                    params.push(name);
+                }
+                after_word = true;
+            }
+            TokenTree::Punct(punct) => {
+                source.push(punct.as_char());
+                after_word = false;
+            }
+            TokenTree::Ident(_) | TokenTree::Literal(_) => {
+                if after_word {
+                    source.push(' ');
+                }
+                source.push_str(&token.to_string());
+                after_word = true;
+            }
+            TokenTree::Group(group) => {
+                let (open, close) = match group.delimiter() {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Brace => ("{", "}"),
+                    Delimiter::None => ("", ""),
+                    Delimiter::Bracket => return Err("brackets are not valid in rvm expressions"),
+                };
+                if after_word && group.delimiter() == Delimiter::Brace {
+                    source.push(' ');
+                }
+                source.push_str(open);
+                render(group.stream(), source, params)?;
+                source.push_str(close);
+                after_word = false;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn compile_error(message: &str) -> TokenStream {
+    format!("::core::compile_error!({message:?})")
+        .parse()
+        .unwrap()
+}
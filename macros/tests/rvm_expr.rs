@@ -0,0 +1,34 @@
+use librvm::value::Value;
+use rvm_macros::rvm_expr;
+
+#[test]
+fn test_literal_expression() {
+    assert_eq!(rvm_expr! { 1 + 2 }, Ok(Value::Int(3)));
+    assert_eq!(rvm_expr! { (2.5 + 1.5) * 2 }, Ok(Value::Float(8.0)));
+    assert_eq!(rvm_expr! { 5! }, Ok(Value::Int(120)));
+}
+
+#[test]
+fn test_spliced_variables() {
+    let a = 20;
+    let b = 2;
+    assert_eq!(rvm_expr! { #a * 2 + #b }, Ok(Value::Int(42)));
+
+    let radius = 2.0;
+    assert_eq!(rvm_expr! { #radius * #radius }, Ok(Value::Float(4.0)));
+}
+
+#[test]
+fn test_negative_literals() {
+    let x = 5;
+    assert_eq!(rvm_expr! { #x * -2 }, Ok(Value::Int(-10)));
+}
+
+#[test]
+fn test_functions() {
+    let n = 4;
+    assert_eq!(
+        rvm_expr! { fn sq(x) { x * x } sq(#n) + #n },
+        Ok(Value::Int(20))
+    );
+}
@@ -0,0 +1,134 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use librvm::{
+    compiler::compile_unit,
+    program::{Program, ENTRY},
+    value::Value,
+    verify::verify,
+    vm::Vm as Machine,
+};
+use pyo3::{
+    create_exception,
+    exceptions::{PyException, PyTypeError},
+    prelude::*,
+    types::{PyBool, PyBytes, PyDict, PyFloat, PyInt, PyString},
+};
+
+const STACK_SIZE: usize = 256;
+
+create_exception!(
+    rvm,
+    CompileError,
+    PyException,
+    "The source failed to compile"
+);
+create_exception!(
+    rvm,
+    EvalError,
+    PyException,
+    "The program failed while running"
+);
+create_exception!(
+    rvm,
+    InvalidProgram,
+    PyException,
+    "The bytes are not a program or their code failed to verify"
+);
+
+// Compile an expression into the bytecode container format, whose free variables become
+// the parameters `Vm.run` binds by name
+#[pyfunction]
+fn compile<'py>(py: Python<'py>, source: &str) -> PyResult<Bound<'py, PyBytes>> {
+    let program =
+        compile_unit(&[(ENTRY, source)]).map_err(|e| CompileError::new_err(e.render(source)))?;
+    Ok(PyBytes::new(py, &program.to_bytes()))
+}
+
+// A VM loaded with a program from `compile`, reusable for many runs. Python objects may be
+// shared between threads, which take turns with the VM.
+#[pyclass]
+struct Vm {
+    vm: Mutex<Machine>,
+    params: Vec<String>,
+}
+
+#[pymethods]
+impl Vm {
+    // The bytes are verified before they are run, since they may come from anywhere
+    #[new]
+    #[pyo3(signature = (program, stack_size = STACK_SIZE))]
+    fn new(program: &[u8], stack_size: usize) -> PyResult<Vm> {
+        let program =
+            Program::from_bytes(program).map_err(|e| InvalidProgram::new_err(e.to_string()))?;
+        verify(&program).map_err(|e| InvalidProgram::new_err(e.to_string()))?;
+        let params = program
+            .entry(ENTRY)
+            .ok_or_else(|| InvalidProgram::new_err("program has no main entry point"))?
+            .params()
+            .to_vec();
+        Ok(Vm {
+            vm: Mutex::new(Machine::new(program, stack_size)),
+            params,
+        })
+    }
+
+    #[getter]
+    fn params(&self) -> Vec<String> {
+        self.params.clone()
+    }
+
+    // Run with the parameters given as keyword arguments, `vm.run(x=1, y=2.5)`
+    #[pyo3(signature = (**variables))]
+    fn run(&self, py: Python<'_>, variables: Option<&Bound<'_, PyDict>>) -> PyResult<PyObject> {
+        let mut env = HashMap::new();
+        for (name, value) in variables.into_iter().flatten() {
+            env.insert(name.extract::<String>()?, from_py(&value)?);
+        }
+        let mut vm = self.vm.lock().unwrap_or_else(|e| e.into_inner());
+        let value = vm
+            .run_entry(ENTRY, &env)
+            .map_err(|e| EvalError::new_err(e.to_string()))?;
+        to_py(py, value)
+    }
+}
+
+fn to_py(py: Python<'_>, value: Value) -> PyResult<PyObject> {
+    Ok(match value {
+        Value::Int(value) => value.into_pyobject(py)?.into_any().unbind(),
+        Value::Float(value) => value.into_pyobject(py)?.into_any().unbind(),
+        Value::Str(value) => value.into_pyobject(py)?.into_any().unbind(),
+        Value::Bool(value) => value.into_pyobject(py)?.to_owned().into_any().unbind(),
+    })
+}
+
+// Python's bool is a subclass of int, so it is checked first. Ints outside of 64 bits
+// raise OverflowError.
+fn from_py(value: &Bound<'_, PyAny>) -> PyResult<Value> {
+    if value.is_instance_of::<PyBool>() {
+        return Ok(Value::Bool(value.extract()?));
+    }
+    if value.is_instance_of::<PyInt>() {
+        return Ok(Value::Int(value.extract()?));
+    }
+    if value.is_instance_of::<PyFloat>() {
+        return Ok(Value::Float(value.extract()?));
+    }
+    if value.is_instance_of::<PyString>() {
+        return Ok(Value::from(value.extract::<String>()?));
+    }
+    Err(PyTypeError::new_err(format!(
+        "cannot pass {} to rvm, only int, float, str and bool",
+        value.get_type().name()?
+    )))
+}
+
+#[pymodule]
+fn rvm(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = module.py();
+    module.add_function(wrap_pyfunction!(compile, module)?)?;
+    module.add_class::<Vm>()?;
+    module.add("CompileError", py.get_type::<CompileError>())?;
+    module.add("EvalError", py.get_type::<EvalError>())?;
+    module.add("InvalidProgram", py.get_type::<InvalidProgram>())?;
+    Ok(())
+}
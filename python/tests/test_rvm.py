@@ -0,0 +1,35 @@
+import pytest
+
+import rvm
+
+
+def test_run():
+    vm = rvm.Vm(rvm.compile("x * 2 + y"))
+    assert vm.params == ["x", "y"]
+    assert vm.run(x=3, y=1) == 7
+    assert vm.run(x=1.5, y=0) == 3.0
+
+
+@pytest.mark.parametrize(
+    "source, expected",
+    [("1 < 2", True), ('"a" + "b"', "ab"), ("7 / 2", 3), ("1.5 * 2", 3.0)],
+)
+def test_values(source, expected):
+    result = rvm.Vm(rvm.compile(source)).run()
+    assert result == expected
+    assert type(result) is type(expected)
+
+
+def test_errors():
+    with pytest.raises(rvm.CompileError, match="Unexpected trailing input"):
+        rvm.compile("1 +")
+    with pytest.raises(rvm.EvalError, match="Division by zero"):
+        rvm.Vm(rvm.compile("1 / 0")).run()
+    with pytest.raises(rvm.EvalError, match="Missing argument x"):
+        rvm.Vm(rvm.compile("x")).run()
+    with pytest.raises(rvm.InvalidProgram):
+        rvm.Vm(b"not a program")
+    with pytest.raises(TypeError):
+        rvm.Vm(rvm.compile("x")).run(x=[1])
+    with pytest.raises(OverflowError):
+        rvm.Vm(rvm.compile("x")).run(x=2**64)
@@ -0,0 +1,169 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    compiler::{compile_unit, CompileError},
+    program::{Program, ENTRY, FORMAT_VERSION},
+    verify::verify,
+};
+
+// Programs compiled by another version of the compiler are never reused
+const COMPILER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// On-disk cache of compiled programs keyed by a hash of their source, the compiler version
+// and the bytecode format version, so repeatedly invoked scripts skip parsing and code
+// generation. Every entry holds the source it was compiled from, which has to match for the
+// entry to be used, so colliding hashes are a miss rather than the wrong program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramCache {
+    dir: PathBuf,
+}
+
+impl ProgramCache {
+    pub fn new<P>(dir: P) -> ProgramCache
+    where
+        P: Into<PathBuf>,
+    {
+        ProgramCache { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // Location of the cached program for `source`, whether or not it exists yet
+    pub fn path(&self, source: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}.rvmb", key(source)))
+    }
+
+    // The cached program for `source`. Unreadable entries, those of another source and
+    // programs failing verification count as a miss.
+    pub fn load(&self, source: &str) -> Option<Program> {
+        let bytes = fs::read(self.path(source)).ok()?;
+        let (len, rest) = bytes.split_first_chunk::<8>()?;
+        let len = usize::try_from(u64::from_le_bytes(*len)).ok()?;
+        if rest.get(..len)? != source.as_bytes() {
+            return None;
+        }
+        let program = Program::from_bytes(&rest[len..]).ok()?;
+        verify(&program).ok()?;
+        Some(program)
+    }
+
+    // Store `program` as the entry of `source`, which is written ahead of it as its length
+    // and bytes
+    pub fn store(&self, source: &str, program: &Program) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        // Write to a temporary file first so concurrent readers never see a partial entry
+        let path = self.path(source);
+        let partial = path.with_extension(format!("{}.tmp", std::process::id()));
+        let mut file = fs::File::create(&partial)?;
+        file.write_all(&(source.len() as u64).to_le_bytes())?;
+        file.write_all(source.as_bytes())?;
+        program.write_to(file)?;
+        fs::rename(partial, path)
+    }
+
+    // Load `source` from the cache or compile it with a single `main` entry point and store
+    // the result. Failing to write the cache never fails the compilation.
+    pub fn compile(&self, source: &str) -> Result<Program, CompileError> {
+        if let Some(program) = self.load(source) {
+            return Ok(program);
+        }
+        let program = compile_unit(&[(ENTRY, source)])?;
+        let _ = self.store(source, &program);
+        Ok(program)
+    }
+}
+
+// 64 bit FNV-1a, unlike the std hashers its output is stable across Rust releases
+fn key(source: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let bytes = COMPILER_VERSION
+        .bytes()
+        .chain([0, FORMAT_VERSION, 0])
+        .chain(source.bytes());
+    for byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{opcode::Opcode, value::Value, vm::Vm};
+    use std::collections::HashMap;
+
+    fn temp_cache(name: &str) -> ProgramCache {
+        let dir = std::env::temp_dir().join(format!("rvm-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        ProgramCache::new(dir)
+    }
+
+    #[test]
+    fn test_compile_stores_and_reuses() {
+        let cache = temp_cache("reuse");
+        assert_eq!(cache.load("1 + 2"), None);
+
+        let program = cache.compile("1 + 2").unwrap();
+        assert_eq!(cache.load("1 + 2"), Some(program.clone()));
+        assert_eq!(cache.compile("1 + 2"), Ok(program.clone()));
+        assert_ne!(cache.path("1 + 2"), cache.path("1 + 3"));
+
+        let mut vm = Vm::new(program, 8);
+        assert_eq!(vm.run_entry(ENTRY, &HashMap::new()), Ok(Value::Int(3)));
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_entry_is_recompiled() {
+        let cache = temp_cache("corrupt");
+        fs::create_dir_all(cache.dir()).unwrap();
+        fs::write(cache.path("2 * 3"), b"garbage").unwrap();
+        assert_eq!(cache.load("2 * 3"), None);
+
+        let program = cache.compile("2 * 3").unwrap();
+        assert_eq!(cache.load("2 * 3"), Some(program));
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn test_entry_of_another_source_is_a_miss() {
+        let cache = temp_cache("collision");
+        cache.compile("1 + 2").unwrap();
+        // As if both sources hashed to the same key
+        fs::copy(cache.path("1 + 2"), cache.path("2 + 2")).unwrap();
+        assert_eq!(cache.load("2 + 2"), None);
+        let program = cache.compile("2 + 2").unwrap();
+        let mut vm = Vm::new(program, 8);
+        assert_eq!(vm.run_entry(ENTRY, &HashMap::new()), Ok(Value::Int(4)));
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn test_unverified_entry_is_a_miss() {
+        let cache = temp_cache("unverified");
+        let program = Program::from(vec![Opcode::Addition as u8, Opcode::Return as u8]);
+        cache.store("1 + 2", &program).unwrap();
+        assert_eq!(cache.load("1 + 2"), None);
+        assert_eq!(
+            cache
+                .compile("1 + 2")
+                .map(|program| program.bytecode().len() > 2),
+            Ok(true)
+        );
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn test_compile_errors_are_not_cached() {
+        let cache = temp_cache("errors");
+        assert!(cache.compile("1 +").is_err());
+        assert!(!cache.path("1 +").exists());
+    }
+}
@@ -0,0 +1,460 @@
+// C interface for embedding the evaluator, see `include/rvm.h`, which is generated from
+// this module with cbindgen. Pointers handed in must come from the matching constructor of
+// this module and not have been freed, and strings must be NUL terminated. Only the
+// functions returning a status or a pointer and the `_free` functions accept null. Those
+// returning a status leave the details of a failure for `rvm_last_error`.
+#![allow(clippy::missing_safety_doc)]
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, CStr, CString},
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr, slice,
+};
+
+use crate::{
+    compiler::{compile_program, CompileOptions},
+    program::Program,
+    value::{Value, Warning},
+    vm::Vm,
+};
+
+/// Outcome of a call into the library
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RvmStatus {
+    Ok = 0,
+    /// A pointer was null or a string was not valid UTF-8
+    InvalidArgument = 1,
+    /// The source failed to parse or compile
+    CompileError = 2,
+    /// The program failed while running
+    RuntimeError = 3,
+}
+
+/// Type of the value held by an `RvmValue`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RvmValueType {
+    Int = 0,
+    Float = 1,
+    String = 2,
+    Bool = 3,
+}
+
+/// What is off about a result, see `rvm_value_warning`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RvmWarning {
+    None = 0,
+    /// The result is NaN
+    NotANumber = 1,
+    /// The result is an infinity
+    Infinite = 2,
+    /// The result is a float too large for its integer part to be exact
+    Imprecise = 3,
+}
+
+/// A compiled program
+pub struct RvmProgram(Program);
+
+/// A VM loaded with a program, reusable for many runs
+pub struct RvmVm(Vm);
+
+/// An argument or result of a run
+pub struct RvmValue(Value);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn fail(status: RvmStatus, message: impl ToString) -> RvmStatus {
+    // Messages with a NUL byte in them, which C cannot hold, are cut at it
+    let mut message = message.to_string();
+    message.truncate(message.find('\0').unwrap_or(message.len()));
+    let message = CString::new(message).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    status
+}
+
+unsafe fn string<'a>(input: *const c_char) -> Option<&'a str> {
+    match input.is_null() {
+        true => None,
+        false => CStr::from_ptr(input).to_str().ok(),
+    }
+}
+
+/// Describe a status, the returned string is static
+#[no_mangle]
+pub extern "C" fn rvm_status_message(status: RvmStatus) -> *const c_char {
+    let message: &'static CStr = match status {
+        RvmStatus::Ok => c"ok",
+        RvmStatus::InvalidArgument => c"invalid argument",
+        RvmStatus::CompileError => c"compile error",
+        RvmStatus::RuntimeError => c"runtime error",
+    };
+    message.as_ptr()
+}
+
+/// The message of the last failure on this thread, or null when nothing failed yet. It
+/// stays valid until the next failing call on the thread.
+#[no_mangle]
+pub extern "C" fn rvm_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Compile `source`, whose free variables are an error, into `*program`
+#[no_mangle]
+pub unsafe extern "C" fn rvm_compile(
+    source: *const c_char,
+    program: *mut *mut RvmProgram,
+) -> RvmStatus {
+    rvm_compile_with_params(source, ptr::null(), 0, program)
+}
+
+/// Compile `source` with the `count` names in `params` as its parameters, which runs are
+/// given in the same order
+#[no_mangle]
+pub unsafe extern "C" fn rvm_compile_with_params(
+    source: *const c_char,
+    params: *const *const c_char,
+    count: usize,
+    program: *mut *mut RvmProgram,
+) -> RvmStatus {
+    let Some(source) = string(source) else {
+        return fail(RvmStatus::InvalidArgument, "source is null or not UTF-8");
+    };
+    if program.is_null() || (params.is_null() && count > 0) {
+        return fail(RvmStatus::InvalidArgument, "null pointer");
+    }
+    let params = match count {
+        0 => &[],
+        count => slice::from_raw_parts(params, count),
+    };
+    let Some(params) = params
+        .iter()
+        .map(|&p| string(p))
+        .collect::<Option<Vec<&str>>>()
+    else {
+        return fail(RvmStatus::InvalidArgument, "parameter is null or not UTF-8");
+    };
+    match compile_program(source, &CompileOptions::new().params(&params)) {
+        Ok(compiled) => {
+            *program = Box::into_raw(Box::new(RvmProgram(compiled)));
+            RvmStatus::Ok
+        }
+        Err(error) => fail(RvmStatus::CompileError, error.render(source)),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rvm_program_free(program: *mut RvmProgram) {
+    if !program.is_null() {
+        drop(Box::from_raw(program));
+    }
+}
+
+/// Create a VM running `program` with room for `stack_size` values, or null when
+/// `program` is null. Integer arithmetic that overflows fails the run rather than wrapping
+/// around. The program can be freed once the VM is created.
+#[no_mangle]
+pub unsafe extern "C" fn rvm_vm_new(program: *const RvmProgram, stack_size: usize) -> *mut RvmVm {
+    match program.as_ref() {
+        Some(RvmProgram(program)) => {
+            let vm = Vm::new(program.clone(), stack_size).checked_arithmetic(true);
+            Box::into_raw(Box::new(RvmVm(vm)))
+        }
+        None => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rvm_vm_free(vm: *mut RvmVm) {
+    if !vm.is_null() {
+        drop(Box::from_raw(vm));
+    }
+}
+
+/// Run the program of `vm` with the `count` values in `args` as its parameters, storing
+/// the value it returns in `*result`. The arguments stay owned by the caller.
+#[no_mangle]
+pub unsafe extern "C" fn rvm_run(
+    vm: *mut RvmVm,
+    args: *const *const RvmValue,
+    count: usize,
+    result: *mut *mut RvmValue,
+) -> RvmStatus {
+    let Some(RvmVm(vm)) = vm.as_mut() else {
+        return fail(RvmStatus::InvalidArgument, "vm is null");
+    };
+    if result.is_null() || (args.is_null() && count > 0) {
+        return fail(RvmStatus::InvalidArgument, "null pointer");
+    }
+    let args = match count {
+        0 => &[],
+        count => slice::from_raw_parts(args, count),
+    };
+    let Some(args) = args
+        .iter()
+        .map(|&arg| arg.as_ref().map(|RvmValue(value)| value.clone()))
+        .collect::<Option<Vec<Value>>>()
+    else {
+        return fail(RvmStatus::InvalidArgument, "argument is null");
+    };
+    // Unwinding into C is undefined, a panic left in the VM must not reach the caller
+    match catch_unwind(AssertUnwindSafe(|| vm.run_with_args(&args))) {
+        Ok(Ok(value)) => {
+            *result = Box::into_raw(Box::new(RvmValue(value)));
+            RvmStatus::Ok
+        }
+        Ok(Err(error)) => fail(RvmStatus::RuntimeError, error),
+        Err(_) => fail(RvmStatus::RuntimeError, "evaluation panicked"),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn rvm_value_int(value: i64) -> *mut RvmValue {
+    Box::into_raw(Box::new(RvmValue(Value::Int(value))))
+}
+
+#[no_mangle]
+pub extern "C" fn rvm_value_float(value: f64) -> *mut RvmValue {
+    Box::into_raw(Box::new(RvmValue(Value::Float(value))))
+}
+
+#[no_mangle]
+pub extern "C" fn rvm_value_bool(value: bool) -> *mut RvmValue {
+    Box::into_raw(Box::new(RvmValue(Value::Bool(value))))
+}
+
+/// A string value holding a copy of `value`, or null when it is null or not UTF-8
+#[no_mangle]
+pub unsafe extern "C" fn rvm_value_string(value: *const c_char) -> *mut RvmValue {
+    match string(value) {
+        Some(value) => Box::into_raw(Box::new(RvmValue(Value::from(value)))),
+        None => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rvm_value_free(value: *mut RvmValue) {
+    if !value.is_null() {
+        drop(Box::from_raw(value));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rvm_value_type(value: *const RvmValue) -> RvmValueType {
+    match &(*value).0 {
+        Value::Int(_) => RvmValueType::Int,
+        Value::Float(_) => RvmValueType::Float,
+        Value::Str(_) => RvmValueType::String,
+        Value::Bool(_) => RvmValueType::Bool,
+    }
+}
+
+/// The integer held by `value`, 0 for other types
+#[no_mangle]
+pub unsafe extern "C" fn rvm_value_as_int(value: *const RvmValue) -> i64 {
+    match (*value).0 {
+        Value::Int(value) => value,
+        _ => 0,
+    }
+}
+
+/// The number held by `value` as a float, integers are converted and other types are 0
+#[no_mangle]
+pub unsafe extern "C" fn rvm_value_as_float(value: *const RvmValue) -> f64 {
+    match (*value).0 {
+        Value::Float(value) => value,
+        Value::Int(value) => value as f64,
+        _ => 0.0,
+    }
+}
+
+/// The boolean held by `value`, false for other types
+#[no_mangle]
+pub unsafe extern "C" fn rvm_value_as_bool(value: *const RvmValue) -> bool {
+    matches!((*value).0, Value::Bool(true))
+}
+
+/// Whether `value` is known exactly, true for every type but floats, which may have been
+/// rounded
+#[no_mangle]
+pub unsafe extern "C" fn rvm_value_is_exact(value: *const RvmValue) -> bool {
+    (*value).0.is_exact()
+}
+
+/// What is off about `value` as a result of a run, `RvmWarning::None` when nothing is
+#[no_mangle]
+pub unsafe extern "C" fn rvm_value_warning(value: *const RvmValue) -> RvmWarning {
+    match (*value).0.warning() {
+        None => RvmWarning::None,
+        Some(Warning::NotANumber) => RvmWarning::NotANumber,
+        Some(Warning::Infinite) => RvmWarning::Infinite,
+        Some(Warning::Imprecise) => RvmWarning::Imprecise,
+    }
+}
+
+/// Describe a warning, the returned string is static and empty for `RvmWarning::None`
+#[no_mangle]
+pub extern "C" fn rvm_warning_message(warning: RvmWarning) -> *const c_char {
+    let message: &'static CStr = match warning {
+        RvmWarning::None => c"",
+        RvmWarning::NotANumber => c"result is not a number",
+        RvmWarning::Infinite => c"result is infinite",
+        RvmWarning::Imprecise => c"result is too large to be exact",
+    };
+    message.as_ptr()
+}
+
+/// `value` written as the REPL prints it, to be freed with `rvm_string_free`. Null when
+/// the text holds a NUL byte.
+#[no_mangle]
+pub unsafe extern "C" fn rvm_value_to_string(value: *const RvmValue) -> *mut c_char {
+    CString::new((*value).0.to_string()).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn rvm_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    unsafe fn text(string: *const c_char) -> String {
+        CStr::from_ptr(string).to_str().unwrap().to_string()
+    }
+
+    unsafe fn compile(source: &CStr, params: &[&CStr]) -> Result<*mut RvmProgram, RvmStatus> {
+        let params: Vec<*const c_char> = params.iter().map(|p| p.as_ptr()).collect();
+        let mut program = ptr::null_mut();
+        match rvm_compile_with_params(source.as_ptr(), params.as_ptr(), params.len(), &mut program)
+        {
+            RvmStatus::Ok => Ok(program),
+            status => Err(status),
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        unsafe {
+            let program = compile(c"x * 2 + y", &[c"x", c"y"]).unwrap();
+            let vm = rvm_vm_new(program, 64);
+            rvm_program_free(program);
+            for (x, expected) in [(1, 5), (10, 23)] {
+                let args = [rvm_value_int(x), rvm_value_int(3)];
+                let mut result = ptr::null_mut();
+                let status = rvm_run(vm, args.as_ptr().cast(), args.len(), &mut result);
+                assert_eq!(status, RvmStatus::Ok);
+                assert_eq!(rvm_value_type(result), RvmValueType::Int);
+                assert_eq!(rvm_value_as_int(result), expected);
+                args.into_iter().for_each(|arg| rvm_value_free(arg));
+                rvm_value_free(result);
+            }
+            rvm_vm_free(vm);
+        }
+    }
+
+    #[rstest]
+    #[case(c"1.5 * 2", RvmValueType::Float, "3", false, RvmWarning::None)]
+    #[case(c"1 < 2", RvmValueType::Bool, "true", true, RvmWarning::None)]
+    #[case(c"\"ab\" + \"c\"", RvmValueType::String, "abc", true, RvmWarning::None)]
+    #[case(c"3", RvmValueType::Int, "3", true, RvmWarning::None)]
+    #[case(c"1 / 0.0", RvmValueType::Float, "inf", false, RvmWarning::Infinite)]
+    #[case(
+        c"pow(2.0, 60) + 1",
+        RvmValueType::Float,
+        "1152921504606847000",
+        false,
+        RvmWarning::Imprecise
+    )]
+    fn test_values(
+        #[case] source: &CStr,
+        #[case] kind: RvmValueType,
+        #[case] expected: &str,
+        #[case] exact: bool,
+        #[case] warning: RvmWarning,
+    ) {
+        unsafe {
+            let program = compile(source, &[]).unwrap();
+            let vm = rvm_vm_new(program, 64);
+            let mut result = ptr::null_mut();
+            assert_eq!(rvm_run(vm, ptr::null(), 0, &mut result), RvmStatus::Ok);
+            assert_eq!(rvm_value_type(result), kind);
+            assert_eq!(rvm_value_is_exact(result), exact);
+            assert_eq!(rvm_value_warning(result), warning);
+            let string = rvm_value_to_string(result);
+            assert_eq!(text(string), expected);
+            rvm_string_free(string);
+            rvm_value_free(result);
+            rvm_vm_free(vm);
+            rvm_program_free(program);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        unsafe {
+            assert_eq!(compile(c"1 +", &[]), Err(RvmStatus::CompileError));
+            assert!(text(rvm_last_error()).starts_with("Unexpected trailing input"));
+            assert_eq!(
+                text(rvm_status_message(RvmStatus::CompileError)),
+                "compile error"
+            );
+
+            let mut program = ptr::null_mut();
+            let status = rvm_compile(ptr::null(), &mut program);
+            assert_eq!(status, RvmStatus::InvalidArgument);
+
+            let program = compile(c"1 / 0", &[]).unwrap();
+            let vm = rvm_vm_new(program, 64);
+            let mut result = ptr::null_mut();
+            assert_eq!(
+                rvm_run(vm, ptr::null(), 0, &mut result),
+                RvmStatus::RuntimeError
+            );
+            assert_eq!(text(rvm_last_error()), "Division by zero");
+            rvm_vm_free(vm);
+            rvm_program_free(program);
+
+            let program = compile(c"-\"a\"", &[]).unwrap();
+            let vm = rvm_vm_new(program, 64);
+            assert_eq!(
+                rvm_run(vm, ptr::null(), 0, &mut result),
+                RvmStatus::RuntimeError
+            );
+            assert_eq!(text(rvm_last_error()), "Type mismatch in Negate of string");
+            assert!(result.is_null());
+            rvm_vm_free(vm);
+            rvm_program_free(program);
+        }
+    }
+
+    #[rstest]
+    #[case(c"x / y", "Divide")]
+    #[case(c"x % y", "Modulo")]
+    #[case(c"x * 2 + y", "Multiply")]
+    #[case(c"-x + y", "Negate")]
+    fn test_overflow(#[case] source: &CStr, #[case] op: &str) {
+        unsafe {
+            let program = compile(source, &[c"x", c"y"]).unwrap();
+            let vm = rvm_vm_new(program, 64);
+            rvm_program_free(program);
+            let args = [rvm_value_int(i64::MIN), rvm_value_int(-1)];
+            let mut result = ptr::null_mut();
+            let status = rvm_run(vm, args.as_ptr().cast(), args.len(), &mut result);
+            assert_eq!(status, RvmStatus::RuntimeError);
+            let message = format!("Integer overflow in {} of {}", op, i64::MIN);
+            assert!(text(rvm_last_error()).starts_with(&message));
+            assert!(result.is_null());
+            args.into_iter().for_each(|arg| rvm_value_free(arg));
+            rvm_vm_free(vm);
+        }
+    }
+}
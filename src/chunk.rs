@@ -0,0 +1,215 @@
+use crate::{error::DecodeError, instruction::Instruction, lexer::Span, value::Value};
+
+// Side table mapping the address of an instruction to the source it was compiled from.
+// Only instructions that can fail at run time are recorded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    locations: Vec<(usize, Span)>,
+}
+
+impl DebugInfo {
+    pub fn new(mut locations: Vec<(usize, Span)>) -> DebugInfo {
+        locations.sort_by_key(|&(address, _)| address);
+        DebugInfo { locations }
+    }
+
+    // Source span of the instruction at `address`
+    pub fn span_at(&self, address: usize) -> Option<Span> {
+        let index = self
+            .locations
+            .binary_search_by_key(&address, |&(address, _)| address)
+            .ok()?;
+        Some(self.locations[index].1)
+    }
+
+    pub fn locations(&self) -> &[(usize, Span)] {
+        &self.locations
+    }
+}
+
+// A unit of compiled code: the bytecode along with the values and debug info that travel
+// with it from the compiler to the VM and into serialized programs
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Chunk {
+    code: Vec<u8>,
+    // Values shared by the code. The compiler encodes literals inline, so only chunks
+    // assembled by hand have any so far.
+    constants: Vec<Value>,
+    // Names of the host functions the code calls, `CallHost` refers to them by index
+    host_functions: Vec<String>,
+    debug: Option<DebugInfo>,
+}
+
+impl Chunk {
+    pub fn new(code: Vec<u8>) -> Chunk {
+        Chunk {
+            code,
+            ..Chunk::default()
+        }
+    }
+
+    pub fn with_constants(self, constants: Vec<Value>) -> Chunk {
+        Chunk { constants, ..self }
+    }
+
+    pub fn with_host_functions(self, host_functions: Vec<String>) -> Chunk {
+        Chunk {
+            host_functions,
+            ..self
+        }
+    }
+
+    pub fn with_debug_info(self, debug: DebugInfo) -> Chunk {
+        Chunk {
+            debug: Some(debug),
+            ..self
+        }
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    pub fn host_functions(&self) -> &[String] {
+        &self.host_functions
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug.as_ref()
+    }
+
+    // Length of the code in bytes
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    // The instructions of the code in order, each with its address. Code that does not decode
+    // ends with the error, the rest of it is skipped.
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions {
+            code: &self.code,
+            position: 0,
+        }
+    }
+
+    pub fn into_code(self) -> Vec<u8> {
+        self.code
+    }
+}
+
+impl From<Vec<u8>> for Chunk {
+    fn from(code: Vec<u8>) -> Self {
+        Chunk::new(code)
+    }
+}
+
+impl From<Chunk> for Vec<u8> {
+    fn from(chunk: Chunk) -> Self {
+        chunk.code
+    }
+}
+
+impl<'a> IntoIterator for &'a Chunk {
+    type Item = Result<(usize, Instruction), DecodeError>;
+    type IntoIter = Instructions<'a>;
+
+    fn into_iter(self) -> Instructions<'a> {
+        self.instructions()
+    }
+}
+
+// Iterator over the instructions of a chunk, see `Chunk::instructions`
+pub struct Instructions<'a> {
+    code: &'a [u8],
+    position: usize,
+}
+
+impl Iterator for Instructions<'_> {
+    type Item = Result<(usize, Instruction), DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.code.len() {
+            return None;
+        }
+        let position = self.position;
+        match Instruction::try_decode(self.code, position) {
+            Ok((instruction, size)) => {
+                self.position += size;
+                Some(Ok((position, instruction)))
+            }
+            Err(e) => {
+                self.position = self.code.len();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::compile, vm::Vm};
+
+    #[test]
+    fn test_instructions() {
+        let chunk = compile("2 * 3").unwrap();
+        let instructions: Result<Vec<(usize, Instruction)>, DecodeError> =
+            chunk.instructions().collect();
+        assert_eq!(
+            instructions,
+            Ok(vec![
+                (0, Instruction::Literal(Value::Int(2))),
+                (10, Instruction::Literal(Value::Int(3))),
+                (20, Instruction::Multiply),
+                (21, Instruction::Return),
+            ])
+        );
+        assert_eq!((&chunk).into_iter().count(), 4);
+        assert_eq!(chunk.len(), 22);
+    }
+
+    #[test]
+    fn test_instructions_truncated() {
+        // The literal `3` cut short after its type tag
+        let mut code = compile("2 * 3").unwrap().into_code();
+        code.truncate(12);
+        let chunk = Chunk::new(code);
+        let instructions: Vec<_> = chunk.instructions().collect();
+        assert_eq!(
+            instructions,
+            vec![
+                Ok((0, Instruction::Literal(Value::Int(2)))),
+                Err(DecodeError::Truncated),
+            ]
+        );
+
+        let chunk = Chunk::new(vec![0xFF, 0x06]);
+        let instructions: Vec<_> = chunk.instructions().collect();
+        assert_eq!(instructions, vec![Err(DecodeError::InvalidOpcode(0xFF))]);
+    }
+
+    #[test]
+    fn test_chunk_runs() {
+        let chunk = compile("2 * 3").unwrap();
+        assert_eq!(chunk.constants(), &[]);
+        assert_eq!(chunk.debug_info(), None);
+        assert_eq!(Vm::new(chunk, 8).run(), Ok(Value::Int(6)));
+    }
+
+    #[test]
+    fn test_span_at() {
+        let span = |start, end| Span { start, end };
+        let debug = DebugInfo::new(vec![(9, span(4, 5)), (3, span(0, 1))]);
+        assert_eq!(debug.locations()[0], (3, span(0, 1)));
+        assert_eq!(debug.span_at(9), Some(span(4, 5)));
+        assert_eq!(debug.span_at(4), None);
+    }
+}
@@ -0,0 +1,133 @@
+// Shims keeping the panicking interfaces from before `RuntimeError` and `DecodeError`, so
+// existing embedders can move to the fallible API one call site at a time
+use std::collections::HashMap;
+
+use crate::{error::RuntimeError, opcode::Opcode, program::Program, value::Value, vm};
+
+// Panics on bytes that are not an opcode, `Opcode::decode` reports them instead
+impl From<u8> for Opcode {
+    fn from(value: u8) -> Self {
+        Opcode::decode(value).unwrap_or_else(|_| panic!("invalid opcode"))
+    }
+}
+
+// A `vm::Vm` whose runs return `None` in the cases they used to, that is when the program
+// ends without a value, the entry point does not exist or an argument is missing. Every
+// other failure panics.
+pub struct Vm {
+    vm: vm::Vm,
+}
+
+impl Vm {
+    pub fn new<P>(program: P, stack_size: usize) -> Vm
+    where
+        P: Into<Program>,
+    {
+        Vm {
+            vm: vm::Vm::new(program, stack_size),
+        }
+    }
+
+    pub fn run(&mut self) -> Option<Value> {
+        legacy(self.vm.run())
+    }
+
+    pub fn run_with_args(&mut self, args: &[Value]) -> Option<Value> {
+        legacy(self.vm.run_with_args(args))
+    }
+
+    pub fn run_entry(&mut self, name: &str, env: &HashMap<String, Value>) -> Option<Value> {
+        legacy(self.vm.run_entry(name, env))
+    }
+
+    pub fn swap_program(&mut self, program: &Program) -> Result<(), &'static str> {
+        self.vm
+            .swap_program(program)
+            .map_err(|_| "Incompatible program")
+    }
+
+    // The underlying VM, for call sites that have moved to the fallible API
+    pub fn into_inner(self) -> vm::Vm {
+        self.vm
+    }
+}
+
+impl From<vm::Vm> for Vm {
+    fn from(vm: vm::Vm) -> Self {
+        Vm { vm }
+    }
+}
+
+fn legacy(result: Result<Value, RuntimeError>) -> Option<Value> {
+    match result {
+        Ok(value) => Some(value),
+        Err(
+            RuntimeError::NoResult
+            | RuntimeError::UnknownEntry(_)
+            | RuntimeError::MissingArgument(_),
+        ) => None,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile, compile_unit};
+    use rstest::rstest;
+
+    #[test]
+    fn test_run() {
+        let mut vm = Vm::new(compile("2 * 21").unwrap(), 8);
+        assert_eq!(vm.run(), Some(Value::Int(42)));
+        assert_eq!(vm.into_inner().run(), Ok(Value::Int(42)));
+    }
+
+    #[test]
+    fn test_no_result() {
+        // The program ends without returning its value
+        let bytecode = compile("1 + 2").unwrap().into_code();
+        let mut vm = Vm::new(&bytecode[..bytecode.len() - 1], 8);
+        assert_eq!(vm.run(), None);
+    }
+
+    #[rstest]
+    #[case("f", &[("x", 1)], Some(Value::Int(2)))]
+    #[case("g", &[("x", 1)], None)]
+    #[case("f", &[], None)]
+    fn test_run_entry(
+        #[case] name: &str,
+        #[case] env: &[(&str, i64)],
+        #[case] expected: Option<Value>,
+    ) {
+        let program = compile_unit(&[("f", "x + 1")]).unwrap();
+        let env = env
+            .iter()
+            .map(|&(name, n)| (name.to_string(), Value::Int(n)))
+            .collect();
+        let mut vm = Vm::from(vm::Vm::new(program, 8));
+        assert_eq!(vm.run_entry(name, &env), expected);
+    }
+
+    #[test]
+    fn test_swap_program() {
+        let mut vm = Vm::new(compile_unit(&[("f", "x")]).unwrap(), 8);
+        let other = compile_unit(&[("g", "x")]).unwrap();
+        assert_eq!(vm.swap_program(&other), Err("Incompatible program"));
+    }
+
+    #[rstest]
+    #[case(0x1C, Opcode::ShiftLeft)]
+    #[case(0x00, Opcode::Literal)]
+    fn test_opcode_from(#[case] input: u8, #[case] expected: Opcode) {
+        assert_eq!(Opcode::from(input), expected);
+    }
+
+    #[rstest]
+    #[case(0xF0)]
+    #[case(0xFF)]
+    #[should_panic(expected = "invalid opcode")]
+    fn test_invalid_opcode_from(#[case] invalid_opcode: u8) {
+        let _ = Opcode::from(invalid_opcode);
+    }
+}
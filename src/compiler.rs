@@ -1,130 +1,946 @@
-use nom::{
-    branch::alt,
-    character::complete::{char, digit1, multispace0, one_of},
-    combinator::{map_res, opt, recognize},
-    multi::fold_many0,
-    sequence::{delimited, pair, tuple},
-    IResult,
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
 };
 
-use crate::{opcode::Opcode, value::Value};
+use crate::{
+    chunk::{Chunk, DebugInfo},
+    ir::{self, lower, lower_with_locations, Ir, Label},
+    json,
+    lexer::Span,
+    opcode::Opcode,
+    operator::binary_operator,
+    optimize::{
+        eliminate_dead_code, eliminate_dead_code_with_locations, eliminate_dead_program_code,
+        schedule_script_spanned,
+    },
+    parser::parse_script_with,
+    program::{Entry, Program, ENTRY},
+    typecheck::check_script,
+    value::Value,
+};
+pub use crate::{
+    error::CompileError,
+    lexer::NumberParser,
+    parser::{parse, parse_script, ParseError},
+};
+
+// Kind of value an expression produces, as far as it is known before running it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Number,
+    Str,
+    Bool,
+    Any,
+}
+
+impl Kind {
+    fn accepts(self, other: Kind) -> bool {
+        self == Kind::Any || other == Kind::Any || self == other
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Kind::Number => "a number",
+            Kind::Str => "a string",
+            Kind::Bool => "a boolean",
+            Kind::Any => "any value",
+        }
+    }
+}
+
+const NUMBER: &[Kind] = &[Kind::Number];
+const NUMBERS: &[Kind] = &[Kind::Number, Kind::Number];
+
+// Builtin functions callable as `name(args...)` with their parameter kinds and implementing
+// opcode, calls are checked against the signature at compile time
+const BUILTINS: [(&str, &[Kind], Opcode); 8] = [
+    ("sqrt", NUMBER, Opcode::Sqrt),
+    ("factorial", NUMBER, Opcode::Factorial),
+    ("abs", NUMBER, Opcode::Abs),
+    ("pow", NUMBERS, Opcode::Pow),
+    ("min", NUMBERS, Opcode::Min),
+    ("max", NUMBERS, Opcode::Max),
+    ("mod", NUMBERS, Opcode::Modulo),
+    ("len", &[Kind::Str], Opcode::Len),
+];
+
+fn builtin(name: &str) -> Option<(&'static str, &'static [Kind], Opcode)> {
+    BUILTINS
+        .into_iter()
+        .find(|&(builtin, _, _)| builtin == name)
+}
+
+// Well-known constants available by name in every expression
+const CONSTANTS: [(&str, f64); 5] = [
+    ("pi", std::f64::consts::PI),
+    ("e", std::f64::consts::E),
+    ("tau", std::f64::consts::TAU),
+    ("inf", f64::INFINITY),
+    ("nan", f64::NAN),
+];
+
+// Names a source can use without defining them, offered as suggestions in diagnostics
+pub fn builtin_names() -> impl Iterator<Item = &'static str> {
+    BUILTINS.into_iter().map(|(name, _, _)| name)
+}
 
+pub fn constant_names() -> impl Iterator<Item = &'static str> {
+    CONSTANTS.into_iter().map(|(name, _)| name)
+}
+
+// How an operator stored as a single char is spelled in the source
+fn spelling(op: char) -> String {
+    match binary_operator(op) {
+        Some(operator) => operator.spelling.to_string(),
+        None if op == '¬' => "!".to_string(),
+        None => op.to_string(),
+    }
+}
+
+// Expression tree produced by the parser. Operators are stored as a single char, multi
+// character ones as `=` (==), `≠` (!=), `≤` (<=), `≥` (>=), `&` (&&) and `|` (||). Unary
+// `!` is factorial, `¬` logical not, `√` square root and `-` negation.
 #[derive(Debug, PartialEq, Clone)]
-enum Expr {
+pub enum Expr {
     Number(Value),
+    Str(String),
+    Var(String),
+    Call(String, Vec<Expr>),
     BinOp(Box<Expr>, char, Box<Expr>),
     UnaryOp(char, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
 }
 
-// Parse integers or floats
-fn number(input: &str) -> IResult<&str, Expr> {
-    alt((
-        // Parse floats (must have decimal point)
-        map_res(
-            recognize(tuple((
-                opt(char('-')),
-                digit1,
-                char('.'),
-                digit1
-            ))),
-            |s: &str| s.parse::<f64>().map(|n| Expr::Number(Value::Float(n)))
-        ),
-        // Parse integers (with optional negative sign)
-        map_res(
-            recognize(pair(
-                opt(char('-')),
-                digit1,
-            )),
-            |s: &str| s.parse::<i64>().map(|n| Expr::Number(Value::Int(n)))
-        ),
-    ))(input)
-}
-
-// Parse expressions in parentheses
-fn parens(input: &str) -> IResult<&str, Expr> {
-    delimited(
-        char('('),
-        delimited(multispace0, expr, multispace0),
-        char(')'),
-    )(input)
-}
-
-// Parse a term (number or parenthesized expression)
-fn term(input: &str) -> IResult<&str, Expr> {
-    let (input, num) = delimited(multispace0, alt((number, parens)), multispace0)(input)?;
-    
-    // Look for optional unary operators
-    let (input, op) = opt(alt((char('!'), char('√'))))(input)?;
-    
-    match op {
-        Some('!') => Ok((input, Expr::UnaryOp('!', Box::new(num)))),
-        Some('√') => Ok((input, Expr::UnaryOp('√', Box::new(num)))),
-        _ => Ok((input, num)),
+#[derive(Debug, PartialEq, Clone)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Expr,
+}
+
+// A full source unit: function definitions followed by the expression to evaluate
+#[derive(Debug, PartialEq, Clone)]
+pub struct Script {
+    pub functions: Vec<Function>,
+    pub body: Expr,
+}
+
+impl Expr {
+    // Encode as a JSON object whose `type` names the kind of expression. Binary operators
+    // are given as spelled in the source, unary ones by name since `!` is both factorial
+    // and logical not.
+    pub fn to_json(&self) -> String {
+        match self {
+            Expr::Number(value) => format!(
+                r#"{{"type":"{}","value":{}}}"#,
+                value.type_name(),
+                value.to_json()
+            ),
+            Expr::Str(value) => format!(r#"{{"type":"string","value":{}}}"#, json::string(value)),
+            Expr::Var(name) => format!(r#"{{"type":"variable","name":{}}}"#, json::string(name)),
+            Expr::Call(name, args) => format!(
+                r#"{{"type":"call","name":{},"args":[{}]}}"#,
+                json::string(name),
+                args.iter().map(Expr::to_json).collect::<Vec<_>>().join(",")
+            ),
+            Expr::BinOp(left, op, right) => format!(
+                r#"{{"type":"binary","op":{},"left":{},"right":{}}}"#,
+                json::string(&spelling(*op)),
+                left.to_json(),
+                right.to_json()
+            ),
+            Expr::UnaryOp(op, operand) => {
+                let name = match op {
+                    '!' => "factorial",
+                    '¬' => "not",
+                    '√' => "sqrt",
+                    '-' => "negate",
+                    _ => "unknown",
+                };
+                format!(
+                    r#"{{"type":"unary","op":"{}","operand":{}}}"#,
+                    name,
+                    operand.to_json()
+                )
+            }
+            Expr::Conditional(condition, then, otherwise) => format!(
+                r#"{{"type":"conditional","condition":{},"then":{},"otherwise":{}}}"#,
+                condition.to_json(),
+                then.to_json(),
+                otherwise.to_json()
+            ),
+        }
+    }
+}
+
+impl Script {
+    // Encode as a JSON object with the function definitions and the main expression
+    pub fn to_json(&self) -> String {
+        let functions: Vec<String> = self
+            .functions
+            .iter()
+            .map(|function| {
+                let params: Vec<String> = function.params.iter().map(|p| json::string(p)).collect();
+                format!(
+                    r#"{{"name":{},"params":[{}],"body":{}}}"#,
+                    json::string(&function.name),
+                    params.join(","),
+                    function.body.to_json()
+                )
+            })
+            .collect();
+        format!(
+            r#"{{"functions":[{}],"body":{}}}"#,
+            functions.join(","),
+            self.body.to_json()
+        )
+    }
+}
+
+// Parse `input` and encode its syntax tree as JSON, for tools that visualize or further
+// process expressions. See `Expr::to_json` for the encoding.
+pub fn compile_to_ast_json(input: &str) -> Result<String, CompileError> {
+    Ok(parse_script(input)?.to_json())
+}
+
+pub fn compile(input: &str) -> Result<Chunk, CompileError> {
+    let (ast, _) = parse_script_with(input, None)?;
+    codegen_script(&ast, &[]).map_err(|e| e.locate(input))
+}
+
+// Compile an expression whose free variables are supplied by the host at run time, the
+// arguments are passed to `Vm::run_with_args` in the same order as `params`
+pub fn compile_with_params(input: &str, params: &[&str]) -> Result<Chunk, CompileError> {
+    compile_with_options(input, &CompileOptions::new().params(params))
+}
+
+// Syntax features that can be individually enabled for restricted compilation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Feature {
+    Addition,
+    Subtraction,
+    Multiplication,
+    Division,
+    Modulo,
+    Factorial,
+    Sqrt,
+    Functions,
+    Builtins,
+    Not,
+    Negation,
+    Strings,
+    Comparisons,
+    Logic,
+    Conditionals,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 15] = [
+        Feature::Addition,
+        Feature::Subtraction,
+        Feature::Multiplication,
+        Feature::Division,
+        Feature::Modulo,
+        Feature::Factorial,
+        Feature::Sqrt,
+        Feature::Functions,
+        Feature::Builtins,
+        Feature::Not,
+        Feature::Negation,
+        Feature::Strings,
+        Feature::Comparisons,
+        Feature::Logic,
+        Feature::Conditionals,
+    ];
+
+    fn rejection(&self) -> &'static str {
+        match self {
+            Feature::Addition => "Addition is not allowed",
+            Feature::Subtraction => "Subtraction is not allowed",
+            Feature::Multiplication => "Multiplication is not allowed",
+            Feature::Division => "Division is not allowed",
+            Feature::Modulo => "Modulo is not allowed",
+            Feature::Factorial => "Factorial is not allowed",
+            Feature::Sqrt => "Square root is not allowed",
+            Feature::Functions => "Functions are not allowed",
+            Feature::Builtins => "Builtin functions are not allowed",
+            Feature::Not => "Logical not is not allowed",
+            Feature::Negation => "Negation is not allowed",
+            Feature::Strings => "Strings are not allowed",
+            Feature::Comparisons => "Comparisons are not allowed",
+            Feature::Logic => "Logical operators are not allowed",
+            Feature::Conditionals => "Conditionals are not allowed",
+        }
+    }
+}
+
+// The set of features accepted by `compile_restricted`, numeric literals are always allowed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Allowlist {
+    features: HashSet<Feature>,
+}
+
+impl Allowlist {
+    pub fn new() -> Allowlist {
+        Allowlist::default()
+    }
+
+    pub fn all() -> Allowlist {
+        Allowlist {
+            features: Feature::ALL.into_iter().collect(),
+        }
+    }
+
+    pub fn allow(mut self, feature: Feature) -> Allowlist {
+        self.features.insert(feature);
+        self
+    }
+
+    pub fn deny(mut self, feature: Feature) -> Allowlist {
+        self.features.remove(&feature);
+        self
+    }
+
+    pub fn allows(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+
+    // Host functions count as functions of the script, builtins shadow them
+    fn check_script(&self, script: &Script, hosts: &[(String, usize)]) -> Result<(), CompileError> {
+        if !script.functions.is_empty() && !self.allows(Feature::Functions) {
+            return Err(CompileError::new(Feature::Functions.rejection()).with_token("fn"));
+        }
+        let hosts = hosts.iter().map(|(name, _)| name.as_str());
+        let functions: HashSet<&str> = script
+            .functions
+            .iter()
+            .map(|f| f.name.as_str())
+            .chain(hosts.filter(|&name| builtin(name).is_none()))
+            .collect();
+        for function in &script.functions {
+            self.check(&function.body, &functions)?;
+        }
+        self.check(&script.body, &functions)
+    }
+
+    fn check(&self, expr: &Expr, functions: &HashSet<&str>) -> Result<(), CompileError> {
+        let feature = match expr {
+            Expr::Number(_) | Expr::Var(_) => return Ok(()),
+            Expr::Str(_) => Feature::Strings,
+            Expr::Call(name, _) if functions.contains(name.as_str()) => Feature::Functions,
+            Expr::Call(name, _) => match builtin(name) {
+                Some((_, _, Opcode::Sqrt)) => Feature::Sqrt,
+                Some((_, _, Opcode::Factorial)) => Feature::Factorial,
+                Some((_, _, Opcode::Len)) => Feature::Strings,
+                Some(_) => Feature::Builtins,
+                None => return Err(CompileError::new("Unknown function").with_token(name)),
+            },
+            Expr::UnaryOp('!', _) => Feature::Factorial,
+            Expr::UnaryOp('√', _) => Feature::Sqrt,
+            Expr::UnaryOp('¬', _) => Feature::Not,
+            Expr::UnaryOp('-', _) => Feature::Negation,
+            Expr::BinOp(_, op, _) => match binary_operator(*op) {
+                Some(operator) => operator.feature,
+                None => return Err("Unsupported operator".into()),
+            },
+            Expr::Conditional(_, _, _) => Feature::Conditionals,
+            Expr::UnaryOp(_, _) => return Err("Unsupported unary operator".into()),
+        };
+        if !self.allows(feature) {
+            let token = match expr {
+                Expr::Str(_) => "\"".to_string(),
+                Expr::Call(name, _) => name.clone(),
+                Expr::UnaryOp(op, _) | Expr::BinOp(_, op, _) => spelling(*op),
+                Expr::Conditional(_, _, _) => "?".to_string(),
+                Expr::Number(_) | Expr::Var(_) => String::new(),
+            };
+            return Err(CompileError::new(feature.rejection()).with_token(token));
+        }
+
+        match expr {
+            Expr::UnaryOp(_, operand) => self.check(operand, functions),
+            Expr::BinOp(left, _, right) => {
+                self.check(left, functions)?;
+                self.check(right, functions)
+            }
+            Expr::Call(_, args) => args.iter().try_for_each(|arg| self.check(arg, functions)),
+            Expr::Conditional(condition, then, otherwise) => {
+                self.check(condition, functions)?;
+                self.check(then, functions)?;
+                self.check(otherwise, functions)
+            }
+            Expr::Number(_) | Expr::Str(_) | Expr::Var(_) => Ok(()),
+        }
+    }
+}
+
+// Compile only the subset of syntax enabled by the allowlist, rejecting anything else
+pub fn compile_restricted(input: &str, allowlist: &Allowlist) -> Result<Chunk, CompileError> {
+    compile_with_options(input, &CompileOptions::new().allowlist(allowlist.clone()))
+}
+
+// Instruction set a compilation produces. Only the stack ISA has a backend, `Vm` runs it and
+// `RegisterVm` lowers it to register code as it runs, so register code is never compiled
+// ahead. The others are reserved so callers can select and query them in one place.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Target {
+    #[default]
+    Stack,
+    Register,
+    Wasm,
+}
+
+impl Target {
+    pub const ALL: [Target; 3] = [Target::Stack, Target::Register, Target::Wasm];
+
+    // Whether this build can generate code for the target
+    pub fn is_supported(&self) -> bool {
+        matches!(self, Target::Stack)
+    }
+}
+
+// How much work the optimizer does, each level includes the ones before it. Dead code is
+// always removed, `Size` reorders operands to need fewer stack slots and folds arithmetic
+// on literals, `Speed` also rewrites arithmetic into cheaper instructions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OptLevel {
+    #[default]
+    Basic,
+    Size,
+    Speed,
+}
+
+// Everything that selects how a source is compiled, see `compile_with_options`
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    target: Target,
+    opt_level: OptLevel,
+    params: Vec<String>,
+    allowlist: Allowlist,
+    number_parser: Option<Arc<dyn NumberParser>>,
+    type_check: bool,
+    debug_info: bool,
+    free_variables: bool,
+    host_functions: Vec<(String, usize)>,
+}
+
+impl Default for CompileOptions {
+    fn default() -> CompileOptions {
+        CompileOptions {
+            target: Target::default(),
+            opt_level: OptLevel::default(),
+            params: Vec::new(),
+            allowlist: Allowlist::all(),
+            number_parser: None,
+            type_check: false,
+            debug_info: false,
+            free_variables: false,
+            host_functions: Vec::new(),
+        }
+    }
+}
+
+impl CompileOptions {
+    pub fn new() -> CompileOptions {
+        CompileOptions::default()
+    }
+
+    pub fn target(mut self, target: Target) -> CompileOptions {
+        self.target = target;
+        self
+    }
+
+    pub fn opt_level(mut self, opt_level: OptLevel) -> CompileOptions {
+        self.opt_level = opt_level;
+        self
+    }
+
+    pub fn params(mut self, params: &[&str]) -> CompileOptions {
+        self.params = params.iter().map(|param| param.to_string()).collect();
+        self
+    }
+
+    pub fn allowlist(mut self, allowlist: Allowlist) -> CompileOptions {
+        self.allowlist = allowlist;
+        self
+    }
+
+    pub fn number_parser(mut self, parser: impl NumberParser + 'static) -> CompileOptions {
+        self.number_parser = Some(Arc::new(parser));
+        self
+    }
+
+    // Reject type errors evident from the source before generating code, see `type_check`
+    pub fn type_check(mut self, enabled: bool) -> CompileOptions {
+        self.type_check = enabled;
+        self
     }
+
+    // Attach the source span of every instruction that can fail to programs compiled by
+    // `compile_program`
+    pub fn debug_info(mut self, enabled: bool) -> CompileOptions {
+        self.debug_info = enabled;
+        self
+    }
+
+    // Turn identifiers of the main expression that are neither parameters nor constants
+    // into parameters instead of failing, following the declared ones in order of first
+    // use, so they can be bound at run time
+    pub fn free_variables(mut self, enabled: bool) -> CompileOptions {
+        self.free_variables = enabled;
+        self
+    }
+
+    // Compile calls to `name` into calls to the host function the VM registers under it
+    // with `Vm::register_fn`, taking `arity` arguments. Functions of the script and the
+    // builtins take precedence over it.
+    pub fn host_function(mut self, name: &str, arity: usize) -> CompileOptions {
+        self.host_functions.retain(|(host, _)| host != name);
+        self.host_functions.push((name.to_string(), arity));
+        self
+    }
+}
+
+// Compile `input` for the target and with the parameters and syntax selected by `options`.
+// The chunk carries the source location of its instructions when debug info is enabled.
+pub fn compile_with_options(input: &str, options: &CompileOptions) -> Result<Chunk, CompileError> {
+    compile_parts(input, options).map(|(chunk, _)| chunk)
+}
+
+// Compile like `compile_with_options`, also returning the parameters of the main expression
+fn compile_parts(
+    input: &str,
+    options: &CompileOptions,
+) -> Result<(Chunk, Vec<String>), CompileError> {
+    if !options.target.is_supported() {
+        return Err(CompileError::new("Unsupported target"));
+    }
+    if options.host_functions.len() > u8::MAX as usize + 1 {
+        return Err(CompileError::new("Too many host functions"));
+    }
+    if let Some((name, _)) = options
+        .host_functions
+        .iter()
+        .find(|&&(_, arity)| arity > u8::MAX as usize)
+    {
+        return Err(CompileError::new("Too many function parameters").with_token(name));
+    }
+    let (mut ast, mut spans) = parse_script_with(input, options.number_parser.as_deref())?;
+    let mut params = options.params.clone();
+    if options.free_variables {
+        free_variables(&ast.body, &mut params);
+    }
+    if options.type_check {
+        check_script(input, &ast, &spans, &params)?;
+    }
+    options
+        .allowlist
+        .check_script(&ast, &options.host_functions)
+        .map_err(|e| e.locate(input))?;
+    if !options.debug_info {
+        spans.clear();
+    }
+    if options.opt_level >= OptLevel::Size {
+        (ast, spans) = schedule_script_spanned(&ast, &spans);
+    }
+    let mut code =
+        emit(&ast, &params, &options.host_functions, &spans).map_err(|e| e.locate(input))?;
+    if options.opt_level >= OptLevel::Size {
+        code = ir::fold_constants(&code);
+    }
+    if options.opt_level >= OptLevel::Speed {
+        code = ir::reduce_strength(&code);
+    }
+    let (bytecode, locations) = lower_with_locations(&code, 0);
+    let (bytecode, locations) = eliminate_dead_code_with_locations(&bytecode, &locations);
+    let hosts = options.host_functions.iter().map(|(name, _)| name.clone());
+    let chunk = Chunk::new(bytecode).with_host_functions(hosts.collect());
+    if !options.debug_info {
+        return Ok((chunk, params));
+    }
+    Ok((chunk.with_debug_info(DebugInfo::new(locations)), params))
 }
 
-// Parse operators by precedence level
-fn op(input: &str) -> IResult<&str, char> {
-    delimited(multispace0, one_of("+-*/%"), multispace0)(input)
+// Compile like `compile_with_options` into a program ready to be serialized. With free
+// variables its main expression is the entry point `main`, whose parameters `Vm::run_with`
+// binds by name, otherwise the program has no named entry points.
+pub fn compile_program(input: &str, options: &CompileOptions) -> Result<Program, CompileError> {
+    let (chunk, params) = compile_parts(input, options)?;
+    if !options.free_variables {
+        return Ok(Program::from(chunk));
+    }
+    Ok(Program::with_entries(
+        chunk,
+        vec![Entry::new(ENTRY, 0, params)],
+    ))
+}
+
+// Generate the code of a parsed expression. Without the source at hand errors carry the
+// offending token but no location.
+pub fn codegen(expr: &Expr) -> Result<Chunk, CompileError> {
+    let mut codegen = Codegen::default();
+    codegen.compile_expr(expr)?;
+    codegen.code.push(Ir::Op(Opcode::Return));
+    Ok(Chunk::new(lower(&codegen.code, 0)))
 }
 
-// Main expression parser
-fn expr(input: &str) -> IResult<&str, Expr> {
-    let (input, initial) = term(input)?;
+fn codegen_script(script: &Script, params: &[String]) -> Result<Chunk, CompileError> {
+    let bytecode = lower(&emit_ir(script, params)?, 0);
+    Ok(Chunk::new(eliminate_dead_code(&bytecode, &[0]).0))
+}
+
+// Append the code for `script` to `bytecode`, its main expression starts at the current end
+fn codegen_into(
+    bytecode: &mut Vec<u8>,
+    script: &Script,
+    params: &[String],
+) -> Result<(), CompileError> {
+    let code = emit_ir(script, params)?;
+    bytecode.extend(lower(&code, bytecode.len()));
+    Ok(())
+}
 
-    fold_many0(
-        pair(op, term),
-        move || initial.clone(),
-        |acc, (op, val)| Expr::BinOp(Box::new(acc), op, Box::new(val)),
-    )(input)
+// Generate the unoptimized IR of a parsed script whose main expression reads `params`. The
+// main expression comes first, each function body follows under its own label.
+pub fn emit_ir(script: &Script, params: &[String]) -> Result<Vec<Ir>, CompileError> {
+    emit(script, params, &[], &[])
 }
 
-pub fn compile(input: &str) -> Result<Vec<u8>, &'static str> {
-    let (_, ast) = expr(input).map_err(|_| "Failed to parse expression")?;
+// Generate the IR of a script calling `hosts` with the spans from `parse_script_with`, or
+// none to leave out the locations
+fn emit(
+    script: &Script,
+    params: &[String],
+    hosts: &[(String, usize)],
+    spans: &[Span],
+) -> Result<Vec<Ir>, CompileError> {
+    check_params(params)?;
+    let mut codegen = Codegen {
+        params,
+        hosts,
+        spans,
+        ..Codegen::default()
+    };
+    let mut entries = Vec::with_capacity(script.functions.len());
+    for function in &script.functions {
+        check_params(&function.params)?;
+        let entry = codegen.label();
+        entries.push(entry);
+        let arity = function.params.len();
+        if codegen
+            .functions
+            .insert(&function.name, (entry, arity))
+            .is_some()
+        {
+            return Err(
+                CompileError::new("Duplicate function definition").with_token(&function.name)
+            );
+        }
+    }
+
+    // Function bodies were parsed before the main expression, so their spans come first
+    let mut starts = Vec::with_capacity(script.functions.len());
+    let mut next = 0;
+    for function in &script.functions {
+        starts.push(next);
+        next += node_count(&function.body);
+    }
+
+    codegen.next = next;
+    codegen.compile_expr(&script.body)?;
+    codegen.code.push(Ir::Op(Opcode::Return));
+
+    for ((function, entry), start) in script.functions.iter().zip(entries).zip(starts) {
+        codegen.code.push(Ir::Label(entry));
+        let body = codegen.code.len();
+        codegen.params = &function.params;
+        codegen.next = start;
+        codegen.compile_expr(&function.body)?;
+        codegen.code.push(Ir::Op(Opcode::Return));
+        ir::mark_tail_calls(&mut codegen.code[body..]);
+    }
+    Ok(codegen.code)
+}
+
+// Compile several named formulas into one program, each becoming an entry point whose
+// parameters are the free variables of the formula in order of first use
+pub fn compile_unit(formulas: &[(&str, &str)]) -> Result<Program, CompileError> {
     let mut bytecode = Vec::new();
-    compile_expr(&ast, &mut bytecode);
-    bytecode.push(Opcode::Return as u8);
-    Ok(bytecode)
+    let mut entries: Vec<Entry> = Vec::with_capacity(formulas.len());
+    for &(name, input) in formulas {
+        if entries.iter().any(|entry| entry.name() == name) {
+            return Err(CompileError::new("Duplicate entry point").with_token(name));
+        }
+        let ast = parse_script(input)?;
+
+        let mut params = Vec::new();
+        free_variables(&ast.body, &mut params);
+        let address = bytecode.len();
+        codegen_into(&mut bytecode, &ast, &params).map_err(|e| e.locate(input))?;
+        entries.push(Entry::new(name, address, params));
+    }
+    let program = Program::with_entries(bytecode, entries);
+    Ok(eliminate_dead_program_code(program))
+}
+
+// Number of expressions in `expr`, the parser records a span for each of them
+fn node_count(expr: &Expr) -> usize {
+    1 + match expr {
+        Expr::Number(_) | Expr::Str(_) | Expr::Var(_) => 0,
+        Expr::Call(_, args) => args.iter().map(node_count).sum(),
+        Expr::BinOp(left, _, right) => node_count(left) + node_count(right),
+        Expr::UnaryOp(_, operand) => node_count(operand),
+        Expr::Conditional(condition, then, otherwise) => {
+            node_count(condition) + node_count(then) + node_count(otherwise)
+        }
+    }
+}
+
+fn free_variables(expr: &Expr, names: &mut Vec<String>) {
+    match expr {
+        Expr::Var(name) => {
+            let constant = CONSTANTS.iter().any(|(constant, _)| constant == name);
+            if !constant && !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        Expr::Call(_, args) => args.iter().for_each(|arg| free_variables(arg, names)),
+        Expr::BinOp(left, _, right) => {
+            free_variables(left, names);
+            free_variables(right, names);
+        }
+        Expr::UnaryOp(_, operand) => free_variables(operand, names),
+        Expr::Conditional(condition, then, otherwise) => {
+            free_variables(condition, names);
+            free_variables(then, names);
+            free_variables(otherwise, names);
+        }
+        Expr::Number(_) | Expr::Str(_) => {}
+    }
+}
+
+// Whether `expr` is known to evaluate to a number before running it
+pub(crate) fn is_numeric(expr: &Expr) -> bool {
+    kind_of(expr) == Kind::Number
 }
 
-fn compile_expr(expr: &Expr, bytecode: &mut Vec<u8>) {
+// Kind of value `expr` evaluates to where it is evident from the expression alone,
+// parameters and user function calls are `Any`
+fn kind_of(expr: &Expr) -> Kind {
     match expr {
-        Expr::Number(value) => {
-            bytecode.push(Opcode::Literal as u8);
-            bytecode.extend(value.to_vec());
-        }
-        Expr::UnaryOp('!', expr) => {
-            compile_expr(expr, bytecode);
-            bytecode.push(Opcode::Factorial as u8);
-        }
-        Expr::UnaryOp('√', expr) => {
-            compile_expr(expr, bytecode);
-            bytecode.push(Opcode::Sqrt as u8);
-        }
-        Expr::UnaryOp(_, _) => {
-            panic!("Unsupported unary operator");
-        }
-        Expr::BinOp(left, op, right) => {
-            compile_expr(left, bytecode);
-            compile_expr(right, bytecode);
-
-            let opcode = match op {
-                '+' => Opcode::Addition,
-                '-' => Opcode::Subtract,
-                '*' => Opcode::Multiply,
-                '/' => Opcode::Divide,
-                '%' => Opcode::Modulo,
-                _ => panic!("Unsupported operator"),
+        Expr::Number(Value::Bool(_)) => Kind::Bool,
+        Expr::Number(Value::Str(_)) | Expr::Str(_) => Kind::Str,
+        Expr::Number(_) => Kind::Number,
+        Expr::Var(_) => Kind::Any,
+        Expr::Call(name, _) => match builtin(name) {
+            Some(_) => Kind::Number,
+            None => Kind::Any,
+        },
+        Expr::BinOp(left, '+', right) => match (kind_of(left), kind_of(right)) {
+            (Kind::Str, _) | (_, Kind::Str) => Kind::Str,
+            (Kind::Number, Kind::Number) => Kind::Number,
+            _ => Kind::Any,
+        },
+        Expr::BinOp(_, '-' | '*' | '/' | '%', _) => Kind::Number,
+        Expr::BinOp(_, _, _) => Kind::Bool,
+        Expr::UnaryOp('¬', operand) => match kind_of(operand) {
+            Kind::Bool => Kind::Bool,
+            Kind::Number => Kind::Number,
+            _ => Kind::Any,
+        },
+        Expr::UnaryOp(_, _) => Kind::Number,
+        Expr::Conditional(_, then, otherwise) => match (kind_of(then), kind_of(otherwise)) {
+            (a, b) if a == b => a,
+            _ => Kind::Any,
+        },
+    }
+}
+
+fn check_params(params: &[String]) -> Result<(), CompileError> {
+    if params.len() > u8::MAX as usize {
+        return Err("Too many function parameters".into());
+    }
+    let mut unique = HashSet::new();
+    match params.iter().find(|param| !unique.insert(*param)) {
+        Some(param) => Err(CompileError::new("Duplicate function parameter").with_token(param)),
+        None => Ok(()),
+    }
+}
+
+// Check the arguments of a builtin call against its number and kinds of parameters
+fn check_signature(name: &str, signature: &[Kind], args: &[Expr]) -> Result<(), CompileError> {
+    if args.len() != signature.len() {
+        return Err(CompileError::wrong_arity(name, signature.len(), args.len()));
+    }
+    for (position, (arg, &expected)) in args.iter().zip(signature).enumerate() {
+        let got = kind_of(arg);
+        if !expected.accepts(got) {
+            let detail = format!(
+                "{name} expects {} as argument {}, got {}",
+                expected.describe(),
+                position + 1,
+                got.describe()
+            );
+            return Err(CompileError::new("Mismatched argument type")
+                .with_token(name)
+                .with_detail(detail));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Codegen<'a> {
+    code: Vec<Ir>,
+    // Function name to the label of its body and its arity
+    functions: HashMap<&'a str, (Label, usize)>,
+    // Parameters of the function currently being compiled
+    params: &'a [String],
+    // Host functions and their arity, indexed by their id
+    hosts: &'a [(String, usize)],
+    labels: usize,
+    // Spans of the expressions in the order they complete, `next` is the one of the
+    // expression being compiled once its operands are done. Empty without debug info.
+    spans: &'a [Span],
+    next: usize,
+}
+
+impl Codegen<'_> {
+    fn label(&mut self) -> Label {
+        self.labels += 1;
+        Label(self.labels - 1)
+    }
+
+    // Record the source of the instruction about to be emitted
+    fn locate(&mut self) {
+        if let Some(&span) = self.spans.get(self.next) {
+            self.code.push(Ir::Location(span));
+        }
+    }
+
+    // Calls live outside `compile_expr` to keep its frame small, every operator in a long
+    // chain adds one frame of recursion
+    fn compile_call(&mut self, name: &str, args: &[Expr]) -> Result<(), CompileError> {
+        // User defined functions shadow the builtins, which shadow the host functions
+        let Some(&(target, arity)) = self.functions.get(name) else {
+            let Some((_, signature, opcode)) = builtin(name) else {
+                return self.compile_host_call(name, args);
             };
-            bytecode.push(opcode as u8);
+            check_signature(name, signature, args)?;
+            for arg in args {
+                self.compile_expr(arg)?;
+            }
+            self.locate();
+            self.code.push(Ir::Op(opcode));
+            return Ok(());
+        };
+        if args.len() != arity {
+            return Err(CompileError::wrong_arity(name, arity, args.len()));
+        }
+        for arg in args {
+            self.compile_expr(arg)?;
+        }
+        self.locate();
+        self.code.push(Ir::Call {
+            target,
+            argc: arity as u8,
+        });
+        Ok(())
+    }
+
+    fn compile_host_call(&mut self, name: &str, args: &[Expr]) -> Result<(), CompileError> {
+        let id = self
+            .hosts
+            .iter()
+            .position(|(host, _)| host == name)
+            .ok_or_else(|| CompileError::new("Unknown function").with_token(name))?;
+        let arity = self.hosts[id].1;
+        if args.len() != arity {
+            return Err(CompileError::wrong_arity(name, arity, args.len()));
+        }
+        for arg in args {
+            self.compile_expr(arg)?;
+        }
+        self.locate();
+        self.code.push(Ir::CallHost {
+            id: id as u8,
+            argc: arity as u8,
+        });
+        Ok(())
+    }
+
+    fn compile_expr(&mut self, expr: &Expr) -> Result<(), CompileError> {
+        match expr {
+            Expr::Number(value) => self.code.push(Ir::Literal(value.clone())),
+            Expr::Str(value) => self.code.push(Ir::Literal(Value::from(value.as_str()))),
+            Expr::Var(name) => {
+                // Parameters shadow the builtin constants
+                if let Some(index) = self.params.iter().position(|param| param == name) {
+                    self.code.push(Ir::LoadArg(index as u8));
+                } else {
+                    let &(_, value) = CONSTANTS
+                        .iter()
+                        .find(|(constant, _)| constant == name)
+                        .ok_or_else(|| CompileError::new("Unknown variable").with_token(name))?;
+                    self.code.push(Ir::Literal(Value::Float(value)));
+                }
+            }
+            Expr::Call(name, args) => self.compile_call(name, args)?,
+            Expr::UnaryOp(op, expr) => {
+                let opcode = unary_opcode(*op)?;
+                self.compile_expr(expr)?;
+                self.locate();
+                self.code.push(Ir::Op(opcode));
+            }
+            Expr::Conditional(condition, then, otherwise) => {
+                let (skip_then, skip_otherwise) = (self.label(), self.label());
+                self.compile_expr(condition)?;
+                self.code.push(Ir::JumpIfFalse(skip_then));
+                self.compile_expr(then)?;
+                self.code.push(Ir::Jump(skip_otherwise));
+                self.code.push(Ir::Label(skip_then));
+                self.compile_expr(otherwise)?;
+                self.code.push(Ir::Label(skip_otherwise));
+            }
+            Expr::BinOp(left, op, right) => {
+                let opcode = binary_opcode(*op)?;
+                self.compile_expr(left)?;
+                self.compile_expr(right)?;
+                self.locate();
+                self.code.push(Ir::Op(opcode));
+            }
         }
+        self.next += 1;
+        Ok(())
+    }
+}
+
+fn unary_opcode(op: char) -> Result<Opcode, CompileError> {
+    match op {
+        '!' => Ok(Opcode::Factorial),
+        '√' => Ok(Opcode::Sqrt),
+        '¬' => Ok(Opcode::Not),
+        '-' => Ok(Opcode::Negate),
+        _ => Err(CompileError::new("Unsupported unary operator").with_token(op.to_string())),
+    }
+}
+
+fn binary_opcode(op: char) -> Result<Opcode, CompileError> {
+    match binary_operator(op) {
+        Some(operator) => Ok(operator.opcode),
+        None => Err(CompileError::new("Unsupported binary operator").with_token(op.to_string())),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::vm::Vm;
+    use crate::{
+        instruction::{decode_all, Instruction},
+        optimize::max_stack_depth,
+        vm::Vm,
+    };
     use rstest::rstest;
 
     fn eval(input: &str) -> Value {
@@ -187,29 +1003,29 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "Unsupported unary operator")]
     fn test_invalid_unary_operator() {
         let ast = Expr::UnaryOp('~', Box::new(Expr::Number(Value::Int(5))));
-        let mut bytecode = Vec::new();
-        compile_expr(&ast, &mut bytecode);
+        let error = Codegen::default().compile_expr(&ast).unwrap_err();
+        assert_eq!(error.message(), "Unsupported unary operator");
+        assert_eq!(error.token(), "~");
     }
 
     #[test]
-    #[should_panic(expected = "Unsupported operator")]
     fn test_invalid_binary_operator() {
         let ast = Expr::BinOp(
             Box::new(Expr::Number(Value::Int(5))),
             '^',  // Invalid operator
             Box::new(Expr::Number(Value::Int(2)))
         );
-        let mut bytecode = Vec::new();
-        compile_expr(&ast, &mut bytecode);
+        let error = Codegen::default().compile_expr(&ast).unwrap_err();
+        assert_eq!(error.message(), "Unsupported binary operator");
+        assert_eq!(error.token(), "^");
     }
 
     #[rstest]
     #[case("4√", Value::Float(2.0))]
     #[case("16√", Value::Float(4.0))]
-    #[case("2√", Value::Float(1.4142135623730951))]
+    #[case("2√", Value::Float(std::f64::consts::SQRT_2))]
     #[case("(2 + 2)√", Value::Float(2.0))]
     fn test_sqrt_operations(#[case] input: &str, #[case] expected: Value) {
         assert_eq!(eval(input), expected);
@@ -222,4 +1038,761 @@ mod tests {
     fn test_sqrt_with_expressions(#[case] input: &str, #[case] expected: Value) {
         assert_eq!(eval(input), expected);
     }
+
+    #[rstest]
+    #[case("1 + 2 * 3", Allowlist::new().allow(Feature::Addition).allow(Feature::Multiplication), Value::Int(9))]
+    #[case("42", Allowlist::new(), Value::Int(42))]
+    #[case("(2 + 2)√", Allowlist::all(), Value::Float(2.0))]
+    fn test_restricted_allowed(
+        #[case] input: &str,
+        #[case] allowlist: Allowlist,
+        #[case] expected: Value,
+    ) {
+        let bytecode = compile_restricted(input, &allowlist).unwrap();
+        let mut vm = Vm::new(bytecode, 32);
+        assert_eq!(vm.run().unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case("1 + 2", Allowlist::new(), "Addition is not allowed")]
+    #[case("5!", Allowlist::all().deny(Feature::Factorial), "Factorial is not allowed")]
+    #[case("1 + 16√", Allowlist::new().allow(Feature::Addition), "Square root is not allowed")]
+    #[case("(1 + 2) % 2", Allowlist::new().allow(Feature::Addition), "Modulo is not allowed")]
+    #[case("1 + 2 foo", Allowlist::all(), "Unexpected trailing input")]
+    fn test_restricted_rejected(
+        #[case] input: &str,
+        #[case] allowlist: Allowlist,
+        #[case] expected: &str,
+    ) {
+        assert_eq!(
+            compile_restricted(input, &allowlist).map_err(|e| e.message()),
+            Err(expected)
+        );
+    }
+
+    #[rstest]
+    #[case("fn double(x) { x * 2 } double(21)", Value::Int(42))]
+    #[case("fn add(a, b) { a + b } add(1, 2.5)", Value::Float(3.5))]
+    #[case("fn answer() { 42 } answer() + 1", Value::Int(43))]
+    #[case(
+        "fn sq(x) { x * x } fn hyp(a, b) { (sq(a) + sq(b))√ } hyp(3, 4)",
+        Value::Float(5.0)
+    )]
+    #[case(
+        "fn twice(x) { quad(x) / 2 } fn quad(x) { x * 4 } twice(5)",
+        Value::Int(10)
+    )]
+    #[case("fn f(x) { x! } f(2 + 1) * f(3)", Value::Int(36))]
+    fn test_functions(#[case] input: &str, #[case] expected: Value) {
+        assert_eq!(eval(input), expected);
+    }
+
+    #[rstest]
+    #[case("fn f(x) { y } f(1)", "Unknown variable")]
+    #[case("x + 1", "Unknown variable")]
+    #[case("g(1)", "Unknown function")]
+    #[case("fn f(x) { x } f(1, 2)", "Wrong number of arguments")]
+    #[case("fn f(x) { x } fn f(y) { y } f(1)", "Duplicate function definition")]
+    #[case("fn f(x, x) { x } f(1, 2)", "Duplicate function parameter")]
+    fn test_function_errors(#[case] input: &str, #[case] expected: &str) {
+        assert_eq!(compile(input).map_err(|e| e.message()), Err(expected));
+    }
+
+    #[rstest]
+    #[case("1 +")]
+    #[case("1 2")]
+    #[case("1.5e3")]
+    #[case("0x10")]
+    #[case("pi = 3")]
+    #[case("fn f(x) { x } f(1) )")]
+    fn test_trailing_input(#[case] input: &str) {
+        assert_eq!(
+            compile(input).map_err(|e| e.message()),
+            Err("Unexpected trailing input")
+        );
+    }
+
+    #[test]
+    fn test_restricted_functions() {
+        let input = "fn double(x) { x * 2 } double(2)";
+        let allowlist = Allowlist::new().allow(Feature::Multiplication);
+        assert_eq!(
+            compile_restricted(input, &allowlist).map_err(|e| e.message()),
+            Err("Functions are not allowed")
+        );
+        assert_eq!(
+            compile_restricted("double(2)", &allowlist).map_err(|e| e.message()),
+            Err("Unknown function")
+        );
+
+        let allowlist = allowlist.allow(Feature::Functions);
+        assert!(compile_restricted(input, &allowlist).is_ok());
+    }
+
+    #[rstest]
+    #[case("1 + 2 # trailing comment", Value::Int(3))]
+    #[case("// leading comment\n1 + 2", Value::Int(3))]
+    #[case("1 /* inline */ + /* comments */ 2", Value::Int(3))]
+    #[case("/* multi\nline */ 2 * # halfway\n 3", Value::Int(6))]
+    #[case(
+        "# double it\nfn/* name */double(x) { x * 2 // body\n} double(4)",
+        Value::Int(8)
+    )]
+    fn test_comments(#[case] input: &str, #[case] expected: Value) {
+        assert_eq!(eval(input), expected);
+    }
+
+    #[rstest]
+    #[case("1 + /* unterminated 2")]
+    #[case("fndouble(x) { x } fndouble(1)")]
+    fn test_invalid_comments(#[case] input: &str) {
+        assert!(compile_restricted(input, &Allowlist::all()).is_err());
+    }
+
+    #[rstest]
+    #[case("a * 2 + b", &["a", "b"], &[Value::Int(20), Value::Int(2)], Value::Int(42))]
+    #[case("b / a", &["a", "b"], &[Value::Int(4), Value::Float(2.0)], Value::Float(0.5))]
+    #[case("fn sq(x) { x * x } sq(n) + 1", &["n"], &[Value::Int(3)], Value::Int(10))]
+    fn test_compile_with_params(
+        #[case] input: &str,
+        #[case] params: &[&str],
+        #[case] args: &[Value],
+        #[case] expected: Value,
+    ) {
+        let bytecode = compile_with_params(input, params).unwrap();
+        let mut vm = Vm::new(bytecode, 32);
+        assert_eq!(vm.run_with_args(args).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case("a + c", &["a", "b"], "Unknown variable")]
+    #[case("a + a", &["a", "a"], "Duplicate function parameter")]
+    #[case("a + 1 )", &["a"], "Unexpected trailing input")]
+    fn test_compile_with_params_errors(
+        #[case] input: &str,
+        #[case] params: &[&str],
+        #[case] expected: &str,
+    ) {
+        assert_eq!(
+            compile_with_params(input, params).map_err(|e| e.message()),
+            Err(expected)
+        );
+    }
+
+    #[rstest]
+    #[case("pi", Value::Float(std::f64::consts::PI))]
+    #[case("e", Value::Float(std::f64::consts::E))]
+    #[case("tau / 2", Value::Float(std::f64::consts::PI))]
+    #[case("2 * pi * 3", Value::Float(6.0 * std::f64::consts::PI))]
+    #[case("inf", Value::Float(f64::INFINITY))]
+    #[case("0 - inf", Value::Float(f64::NEG_INFINITY))]
+    #[case("fn f(e) { e * 2 } f(4)", Value::Int(8))]
+    fn test_constants(#[case] input: &str, #[case] expected: Value) {
+        assert_eq!(eval(input), expected);
+    }
+
+    #[test]
+    fn test_nan_constant() {
+        assert!(matches!(eval("nan + 1"), Value::Float(value) if value.is_nan()));
+    }
+
+    #[test]
+    fn test_constants_with_params() {
+        let bytecode = compile_with_params("2 * pi * r", &["r"]).unwrap();
+        let mut vm = Vm::new(bytecode, 32);
+        let ret = vm.run_with_args(&[Value::Float(0.5)]).unwrap();
+        assert_eq!(ret, Value::Float(std::f64::consts::PI));
+    }
+
+    #[rstest]
+    #[case("sqrt(16)", Value::Float(4.0))]
+    #[case("sqrt(9) + 16√", Value::Float(7.0))]
+    #[case("factorial(2 + 3)", Value::Int(120))]
+    #[case("pow(2, 10)", Value::Int(1024))]
+    #[case("pow(2, -1)", Value::Float(0.5))]
+    #[case("pow(4, 0.5)", Value::Float(2.0))]
+    #[case("abs(-3) + abs(-1.5)", Value::Float(4.5))]
+    #[case("min(3, 2.5)", Value::Float(2.5))]
+    #[case("max(3, 7)", Value::Int(7))]
+    #[case("mod(7, 3)", Value::Int(1))]
+    #[case("max(pow(2, 3), sqrt(100))", Value::Float(10.0))]
+    #[case("fn abs(x) { x * 2 } abs(-3)", Value::Int(-6))]
+    fn test_builtin_calls(#[case] input: &str, #[case] expected: Value) {
+        assert_eq!(eval(input), expected);
+    }
+
+    #[rstest]
+    #[case("sqrt(1, 2)", "Wrong number of arguments")]
+    #[case("pow(2)", "Wrong number of arguments")]
+    #[case("cbrt(8)", "Unknown function")]
+    fn test_builtin_call_errors(#[case] input: &str, #[case] expected: &str) {
+        assert_eq!(compile(input).map_err(|e| e.message()), Err(expected));
+    }
+
+    #[rstest]
+    #[case("sqrt(4)", Allowlist::new(), Err("Square root is not allowed"))]
+    #[case("sqrt(4)", Allowlist::new().allow(Feature::Sqrt), Ok(()))]
+    #[case("pow(2, 3)", Allowlist::new().allow(Feature::Functions), Err("Builtin functions are not allowed"))]
+    #[case("pow(2, 3)", Allowlist::new().allow(Feature::Builtins), Ok(()))]
+    fn test_restricted_builtins(
+        #[case] input: &str,
+        #[case] allowlist: Allowlist,
+        #[case] expected: Result<(), &str>,
+    ) {
+        let result = compile_restricted(input, &allowlist);
+        assert_eq!(result.map(|_| ()).map_err(|e| e.message()), expected);
+    }
+
+    #[test]
+    fn test_compile_unit() {
+        let program = compile_unit(&[
+            ("area", "w * h"),
+            ("perimeter", "2 * (w + h)"),
+            ("circle", "fn sq(x) { x * x } pi * sq(r)"),
+        ])
+        .unwrap();
+        let names: Vec<&str> = program.entries().iter().map(|e| e.name()).collect();
+        assert_eq!(names, ["area", "perimeter", "circle"]);
+        assert_eq!(program.entries()[1].params(), ["w", "h"]);
+        assert_eq!(program.entries()[2].params(), ["r"]);
+
+        let env = HashMap::from([
+            ("w".to_string(), Value::Int(3)),
+            ("h".to_string(), Value::Int(4)),
+            ("r".to_string(), Value::Int(1)),
+        ]);
+        let mut vm = Vm::new(program, 32);
+        assert_eq!(vm.run_entry("area", &env), Ok(Value::Int(12)));
+        assert_eq!(vm.run_entry("perimeter", &env), Ok(Value::Int(14)));
+        assert_eq!(
+            vm.run_entry("circle", &env),
+            Ok(Value::Float(std::f64::consts::PI))
+        );
+        assert_eq!(vm.run_entry("area", &env), Ok(Value::Int(12)));
+    }
+
+    #[rstest]
+    #[case(&[("a", "1"), ("a", "2")], "Duplicate entry point")]
+    #[case(&[("a", "1 +")], "Unexpected trailing input")]
+    #[case(&[("a", "f(1)")], "Unknown function")]
+    fn test_compile_unit_errors(#[case] formulas: &[(&str, &str)], #[case] expected: &str) {
+        assert_eq!(
+            compile_unit(formulas).map_err(|e| e.message()),
+            Err(expected)
+        );
+    }
+
+    #[rstest]
+    #[case("√16", Value::Float(4.0))]
+    #[case("√√16", Value::Float(2.0))]
+    #[case("2 * √(3 * 3)", Value::Float(6.0))]
+    #[case("√16 + 16√", Value::Float(8.0))]
+    #[case("!0", Value::Int(1))]
+    #[case("!5", Value::Int(0))]
+    #[case("!!5", Value::Int(1))]
+    #[case("¬0.0", Value::Int(1))]
+    #[case("!3!", Value::Int(0))]
+    #[case("!(3 - 3)!", Value::Int(0))]
+    #[case("√4!", Value::Float(24f64.sqrt()))]
+    #[case("1 + !0 * 3", Value::Int(6))]
+    fn test_prefix_operators(#[case] input: &str, #[case] expected: Value) {
+        assert_eq!(eval(input), expected);
+    }
+
+    #[test]
+    fn test_prefix_operator_precedence() {
+        let ast = parse("!3!").unwrap();
+        let factorial = Expr::UnaryOp('!', Box::new(Expr::Number(Value::Int(3))));
+        assert_eq!(ast, Expr::UnaryOp('¬', Box::new(factorial)));
+    }
+
+    #[test]
+    fn test_restricted_not() {
+        let allowlist = Allowlist::all().deny(Feature::Not);
+        assert_eq!(
+            compile_restricted("!1", &allowlist).map_err(|e| e.message()),
+            Err("Logical not is not allowed")
+        );
+        assert!(compile_restricted("1!", &allowlist).is_ok());
+    }
+
+    #[rstest]
+    #[case("-(2 + 3)", Value::Int(-5))]
+    #[case("- 2", Value::Int(-2))]
+    #[case("--2", Value::Int(2))]
+    #[case("-(1.5)", Value::Float(-1.5))]
+    #[case("4 - -(1 + 1)", Value::Int(6))]
+    #[case("-(3)!", Value::Int(-6))]
+    #[case("-sqrt(16)", Value::Float(-4.0))]
+    #[case("-pi", Value::Float(-std::f64::consts::PI))]
+    #[case("fn neg(x) { -x } neg(7) * 2", Value::Int(-14))]
+    fn test_unary_minus(#[case] input: &str, #[case] expected: Value) {
+        assert_eq!(eval(input), expected);
+    }
+
+    #[test]
+    fn test_negative_literal_is_not_negated() {
+        let ast = parse("-2").unwrap();
+        assert_eq!(ast, Expr::Number(Value::Int(-2)));
+        let ast = parse("-9223372036854775808").unwrap();
+        assert_eq!(ast, Expr::Number(Value::Int(i64::MIN)));
+    }
+
+    #[test]
+    fn test_restricted_negation() {
+        let allowlist = Allowlist::all().deny(Feature::Negation);
+        assert_eq!(
+            compile_restricted("-(1)", &allowlist).map_err(|e| e.message()),
+            Err("Negation is not allowed")
+        );
+        assert!(compile_restricted("-1", &allowlist).is_ok());
+    }
+
+    #[rstest]
+    #[case(r#""hello""#, Value::from("hello"))]
+    #[case(r#""""#, Value::from(""))]
+    #[case(r#""foo" + "bar""#, Value::from("foobar"))]
+    #[case(r#""n = " + (2 * 3)"#, Value::from("n = 6"))]
+    #[case(r#"1 + 2 + "x""#, Value::from("3x"))]
+    #[case(r#""a\"b\\c\n\t""#, Value::from("a\"b\\c\n\t"))]
+    #[case(r#"len("hello")"#, Value::Int(5))]
+    #[case(r#"len("ab" + "cd") * 2"#, Value::Int(8))]
+    #[case(
+        r#"fn greet(name) { "hi " + name } greet("bob")"#,
+        Value::from("hi bob")
+    )]
+    fn test_strings(#[case] input: &str, #[case] expected: Value) {
+        assert_eq!(eval(input), expected);
+    }
+
+    #[rstest]
+    #[case(r#""unterminated"#)]
+    #[case(r#""bad \q escape""#)]
+    fn test_invalid_strings(#[case] input: &str) {
+        assert_eq!(
+            compile_with_params(input, &[]).map_err(|e| e.message()),
+            Err("Failed to parse expression")
+        );
+    }
+
+    #[test]
+    fn test_restricted_strings() {
+        let allowlist = Allowlist::all().deny(Feature::Strings);
+        assert_eq!(
+            compile_restricted(r#""a""#, &allowlist).map_err(|e| e.message()),
+            Err("Strings are not allowed")
+        );
+        assert_eq!(
+            compile_restricted("len(1)", &allowlist).map_err(|e| e.message()),
+            Err("Strings are not allowed")
+        );
+        assert!(compile_restricted("1 + 2", &allowlist).is_ok());
+    }
+
+    #[rstest]
+    #[case("true", Value::Bool(true))]
+    #[case("false", Value::Bool(false))]
+    #[case("1 < 2", Value::Bool(true))]
+    #[case("2 <= 2.0", Value::Bool(true))]
+    #[case("3 > 4", Value::Bool(false))]
+    #[case("3 >= 4", Value::Bool(false))]
+    #[case("2 == 2.0", Value::Bool(true))]
+    #[case("2 != 3", Value::Bool(true))]
+    #[case("3! != 6", Value::Bool(false))]
+    #[case("1 + 2 * 3 == 9", Value::Bool(true))]
+    #[case("1 < 2 == true", Value::Bool(true))]
+    #[case("1 < 2 && 3 > 4", Value::Bool(false))]
+    #[case("true || false && false", Value::Bool(true))]
+    #[case("(true || false) && false", Value::Bool(false))]
+    #[case("!true", Value::Bool(false))]
+    #[case("!(1 > 2)", Value::Bool(true))]
+    #[case(r#""abc" < "abd""#, Value::Bool(true))]
+    #[case("fn positive(x) { x > 0 } positive(-3)", Value::Bool(false))]
+    fn test_booleans(#[case] input: &str, #[case] expected: Value) {
+        assert_eq!(eval(input), expected);
+    }
+
+    #[test]
+    fn test_logical_precedence() {
+        let ast = parse("1 < 2 || 3 == 4 && 5").unwrap();
+        let int = |n| Box::new(Expr::Number(Value::Int(n)));
+        let less = Expr::BinOp(int(1), '<', int(2));
+        let equal = Expr::BinOp(int(3), '=', int(4));
+        let and = Expr::BinOp(Box::new(equal), '&', int(5));
+        assert_eq!(ast, Expr::BinOp(Box::new(less), '|', Box::new(and)));
+    }
+
+    #[test]
+    fn test_boolean_keyword_prefix() {
+        let bytecode = compile_with_params("trueish + 1", &["trueish"]).unwrap();
+        let mut vm = Vm::new(bytecode, 32);
+        assert_eq!(vm.run_with_args(&[Value::Int(1)]), Ok(Value::Int(2)));
+    }
+
+    #[test]
+    fn test_restricted_comparisons() {
+        let allowlist = Allowlist::all()
+            .deny(Feature::Comparisons)
+            .deny(Feature::Logic);
+        assert_eq!(
+            compile_restricted("1 < 2", &allowlist).map_err(|e| e.message()),
+            Err("Comparisons are not allowed")
+        );
+        assert_eq!(
+            compile_restricted("true && false", &allowlist).map_err(|e| e.message()),
+            Err("Logical operators are not allowed")
+        );
+        assert!(compile_restricted("true", &allowlist).is_ok());
+    }
+
+    #[rstest]
+    #[case(Target::Stack, true)]
+    #[case(Target::Register, false)]
+    #[case(Target::Wasm, false)]
+    fn test_target_support(#[case] target: Target, #[case] supported: bool) {
+        assert_eq!(target.is_supported(), supported);
+        let options = CompileOptions::new().target(target);
+        let result = compile_with_options("1 + 2", &options);
+        if supported {
+            assert_eq!(result, compile("1 + 2"));
+        } else {
+            assert_eq!(result.map_err(|e| e.message()), Err("Unsupported target"));
+        }
+    }
+
+    #[test]
+    fn test_compile_with_options() {
+        let options = CompileOptions::new()
+            .params(&["x"])
+            .allowlist(Allowlist::new().allow(Feature::Multiplication));
+        let bytecode = compile_with_options("x * 3", &options).unwrap();
+        let mut vm = Vm::new(bytecode, 32);
+        assert_eq!(vm.run_with_args(&[Value::Int(4)]), Ok(Value::Int(12)));
+        assert_eq!(
+            compile_with_options("x + 3", &options).map_err(|e| e.message()),
+            Err("Addition is not allowed")
+        );
+    }
+    #[rstest]
+    #[case("1 + 2.5", r#"{"functions":[],"body":{"type":"binary","op":"+","left":{"type":"int","value":1},"right":{"type":"float","value":2.5}}}"#)]
+    #[case("!x! <= -2.0", r#"{"functions":[],"body":{"type":"binary","op":"<=","left":{"type":"unary","op":"not","operand":{"type":"unary","op":"factorial","operand":{"type":"variable","name":"x"}}},"right":{"type":"float","value":-2.0}}}"#)]
+    #[case(r#"true ? len("a\"b") : 0"#, r#"{"functions":[],"body":{"type":"conditional","condition":{"type":"bool","value":true},"then":{"type":"call","name":"len","args":[{"type":"string","value":"a\"b"}]},"otherwise":{"type":"int","value":0}}}"#)]
+    #[case("fn f(a, b) { a } f(1, 2)", r#"{"functions":[{"name":"f","params":["a","b"],"body":{"type":"variable","name":"a"}}],"body":{"type":"call","name":"f","args":[{"type":"int","value":1},{"type":"int","value":2}]}}"#)]
+    fn test_compile_to_ast_json(#[case] input: &str, #[case] expected: &str) {
+        assert_eq!(compile_to_ast_json(input), Ok(expected.to_string()));
+    }
+
+    #[rstest]
+    #[case(OptLevel::Basic)]
+    #[case(OptLevel::Size)]
+    fn test_debug_info(#[case] opt_level: OptLevel) {
+        let input = "fn f(x) { 10 / x } 2 * (3 * √f(4))";
+        let options = CompileOptions::new().opt_level(opt_level);
+        let program = compile_program(input, &options.clone().debug_info(true)).unwrap();
+        assert_eq!(
+            program.bytecode(),
+            compile_with_options(input, &options).unwrap().code()
+        );
+
+        let debug_info = program.debug_info().unwrap();
+        let sources: Vec<(Opcode, &str)> = decode_all(program.bytecode())
+            .into_iter()
+            .filter_map(|(address, instruction)| {
+                let span = debug_info.span_at(address)?;
+                Some((instruction.opcode(), &input[span.start..span.end]))
+            })
+            .collect();
+        assert_eq!(
+            sources,
+            vec![
+                (Opcode::Call, "f(4)"),
+                (Opcode::Sqrt, "√f(4)"),
+                (Opcode::Multiply, "3 * √f(4)"),
+                (Opcode::Multiply, "2 * (3 * √f(4))"),
+                (Opcode::Divide, "10 / x"),
+            ]
+        );
+        let program = compile_program(input, &options).unwrap();
+        assert_eq!(program.debug_info(), None);
+    }
+
+    #[test]
+    fn test_opt_level() {
+        let options = CompileOptions::new().params(&["x"]);
+        let basic = compile_with_options("x * 4", &options).unwrap();
+        assert_eq!(basic, compile_with_params("x * 4", &["x"]).unwrap());
+
+        let options = options.opt_level(OptLevel::Size);
+        let size = compile_with_options("1 + (2 + (3 + x * 4))", &options).unwrap();
+        assert_eq!(max_stack_depth(size.code(), 0, 1), Some(3));
+        let mut vm = Vm::new(size, 3);
+        assert_eq!(vm.run_with_args(&[Value::Int(5)]), Ok(Value::Int(35)));
+        let folded = compile_with_options("x + (2 * 3 - 1)", &options).unwrap();
+        let basic = compile_with_params("x + (2 * 3 - 1)", &["x"]).unwrap();
+        assert!(folded.len() < basic.len());
+        let mut vm = Vm::new(folded, 8);
+        assert_eq!(vm.run_with_args(&[Value::Int(1)]), Ok(Value::Int(6)));
+
+        let options = options.opt_level(OptLevel::Speed);
+        let speed = compile_with_options("x * 4", &options).unwrap();
+        assert_eq!(speed.code()[speed.len() - 2], Opcode::ShiftLeft as u8);
+        let mut vm = Vm::new(speed, 32);
+        assert_eq!(vm.run_with_args(&[Value::Int(5)]), Ok(Value::Int(20)));
+    }
+
+
+    #[rstest]
+    #[case("true ? 1 : 2", Value::Int(1))]
+    #[case("1 > 2 ? 1 : 2", Value::Int(2))]
+    #[case("0 ? 1 : 2 + 3", Value::Int(5))]
+    #[case("false ? 1 : true ? 2 : 3", Value::Int(2))]
+    #[case("(true ? 2 : 3) * 10", Value::Int(20))]
+    #[case("fn sign(x) { x < 0 ? -1 : x > 0 ? 1 : 0 } sign(-5) * 10 + sign(3)", Value::Int(-9))]
+    #[case("fn fact(n) { n <= 1 ? 1 : n * fact(n - 1) } fact(5)", Value::Int(120))]
+    fn test_conditional(#[case] input: &str, #[case] expected: Value) {
+        assert_eq!(eval(input), expected);
+    }
+
+    #[test]
+    fn test_conditional_in_compile_unit() {
+        let program = compile_unit(&[("a", "1"), ("b", "x ? 2 : 3")]).unwrap();
+        let mut vm = Vm::new(program, 32);
+        let env = HashMap::from([("x".to_string(), Value::Bool(false))]);
+        assert_eq!(vm.run_entry("b", &env), Ok(Value::Int(3)));
+    }
+
+    #[test]
+    fn test_restricted_conditionals() {
+        let allowlist = Allowlist::all().deny(Feature::Conditionals);
+        assert_eq!(
+            compile_restricted("true ? 1 : 2", &allowlist).map_err(|e| e.message()),
+            Err("Conditionals are not allowed")
+        );
+    }
+
+    #[rstest]
+    #[case("1 + * 2", "Unexpected trailing input", "+", 2, 1, 3)]
+    #[case("(1 + 2", "Failed to parse expression", "", 6, 1, 7)]
+    #[case("1 +\n  foo(2)", "Unknown function", "foo", 6, 2, 3)]
+    #[case("fo + foo", "Unknown variable", "fo", 0, 1, 1)]
+    #[case("x + sqrt(1, 2)", "Unknown variable", "x", 0, 1, 1)]
+    #[case("√4 + sqrt(1, 2)", "Wrong number of arguments", "sqrt", 7, 1, 6)]
+    #[case(
+        "fn f(a, a) { a } f(1, 2)",
+        "Duplicate function parameter",
+        "a",
+        5,
+        1,
+        6
+    )]
+    fn test_compile_error_location(
+        #[case] input: &str,
+        #[case] message: &str,
+        #[case] token: &str,
+        #[case] offset: usize,
+        #[case] line: usize,
+        #[case] column: usize,
+    ) {
+        let error = compile_with_params(input, &[]).unwrap_err();
+        assert_eq!(error.message(), message);
+        assert_eq!(error.token(), token);
+        assert_eq!(error.offset(), offset);
+        assert_eq!((error.line(), error.column()), (line, column));
+    }
+
+    #[test]
+    fn test_restricted_error_location() {
+        let allowlist = Allowlist::all().deny(Feature::Comparisons);
+        let error = compile_restricted("1 + 2 <= 3", &allowlist).unwrap_err();
+        assert_eq!((error.token(), error.column()), ("<=", 7));
+    }
+
+    #[test]
+    fn test_compile_unit_error_location() {
+        let error = compile_unit(&[("a", "1"), ("b", "2 +\n 3 $")]).unwrap_err();
+        assert_eq!(error.message(), "Unexpected trailing input");
+        assert_eq!((error.line(), error.column()), (2, 4));
+    }
+
+    #[test]
+    fn test_render_compile_error() {
+        let input = "2 * bar(3)";
+        let error = compile(input).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown function");
+        assert_eq!(
+            error.render(input),
+            "Unknown function at 1:5\n2 * bar(3)\n    ^^^"
+        );
+    }
+
+    #[test]
+    fn test_parse_and_codegen() {
+        let ast = parse(" 1 + 2 ").unwrap();
+        let one = Box::new(Expr::Number(Value::Int(1)));
+        let two = Box::new(Expr::Number(Value::Int(2)));
+        assert_eq!(ast, Expr::BinOp(one, '+', two));
+        assert_eq!(codegen(&ast), compile("1 + 2"));
+    }
+
+    #[rstest]
+    #[case("1 +", "Unexpected trailing input")]
+    #[case("fn f(x) { x } f(1)", "Unexpected trailing input")]
+    #[case("", "Failed to parse expression")]
+    fn test_parse_errors(#[case] input: &str, #[case] expected: &str) {
+        assert_eq!(parse(input).map_err(|e| e.message()), Err(expected));
+    }
+
+    #[test]
+    fn test_codegen_error() {
+        let ast = parse("y * 2").unwrap();
+        let error = codegen(&ast).unwrap_err();
+        assert_eq!((error.message(), error.token()), ("Unknown variable", "y"));
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let script = parse_script("fn double(x) { x * 2 } double(4)").unwrap();
+        assert_eq!(script.functions.len(), 1);
+        assert_eq!(script.functions[0].name, "double");
+        assert_eq!(script.functions[0].params, vec!["x".to_string()]);
+        let four = Expr::Number(Value::Int(4));
+        assert_eq!(script.body, Expr::Call("double".to_string(), vec![four]));
+    }
+
+    // Reads `<digits>k` as thousands and every other literal as a float
+    #[derive(Debug)]
+    struct Thousands;
+
+    impl NumberParser for Thousands {
+        fn parse<'a>(&self, input: &'a str) -> Option<(Value, &'a str)> {
+            let end = input
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(input.len());
+            let n: f64 = input[..end].parse().ok()?;
+            match input[end..].strip_prefix('k') {
+                Some(rest) => Some((Value::Float(n * 1000.0), rest)),
+                None => Some((Value::Float(n), &input[end..])),
+            }
+        }
+    }
+
+    #[rstest]
+    #[case("2k + 500", Value::Float(2500.0))]
+    #[case("1.5k", Value::Float(1500.0))]
+    #[case("7 / 2", Value::Float(3.5))]
+    #[case("-(3)", Value::Float(-3.0))]
+    fn test_number_parser(#[case] input: &str, #[case] expected: Value) {
+        let options = CompileOptions::new().number_parser(Thousands);
+        let bytecode = compile_with_options(input, &options).unwrap();
+        assert_eq!(Vm::new(bytecode, 32).run(), Ok(expected));
+    }
+
+    #[test]
+    fn test_number_parser_is_scoped() {
+        let options = CompileOptions::new().number_parser(Thousands);
+        assert!(compile_with_options("1k", &options).is_ok());
+        assert_eq!(eval("7 / 2"), Value::Int(3));
+        assert_eq!(
+            compile_with_params("1k", &[]).map_err(|e| e.message()),
+            Err("Unexpected trailing input")
+        );
+    }
+
+    #[test]
+    fn test_type_check_option() {
+        assert!(compile_with_options("2.5!", &CompileOptions::new()).is_ok());
+        let options = CompileOptions::new().type_check(true);
+        let error = compile_with_options("1 + 2.5!", &options).unwrap_err();
+        assert_eq!((error.message(), error.column()), ("Type error", 5));
+        let options = options.params(&["pi"]);
+        assert!(compile_with_options("pi!", &options).is_ok());
+    }
+
+    #[rstest]
+    #[case("sqrt(1, 2)", "sqrt expects 1 argument, got 2")]
+    #[case("pow(2)", "pow expects 2 arguments, got 1")]
+    #[case("fn f(x) { x } f()", "f expects 1 argument, got 0")]
+    #[case(r#"sqrt("4")"#, "sqrt expects a number as argument 1, got a string")]
+    #[case(
+        r#"pow(2, "a" + 1)"#,
+        "pow expects a number as argument 2, got a string"
+    )]
+    #[case("len(3 * 2)", "len expects a string as argument 1, got a number")]
+    #[case("abs(1 < 2)", "abs expects a number as argument 1, got a boolean")]
+    fn test_builtin_signatures(#[case] input: &str, #[case] expected: &str) {
+        let error = compile(input).unwrap_err();
+        assert_eq!(error.to_string(), expected);
+    }
+
+    #[rstest]
+    #[case("fn s(x) { x } len(s(\"ab\"))", Value::Int(2))]
+    #[case("len(true ? \"a\" : \"bc\")", Value::Int(1))]
+    #[case("sqrt(len(\"abcd\"))", Value::Float(2.0))]
+    fn test_builtin_signatures_accept(#[case] input: &str, #[case] expected: Value) {
+        assert_eq!(eval(input), expected);
+    }
+
+    #[test]
+    fn test_builtin_signature_error_location() {
+        let input = "1 + max(2, \"x\")";
+        let error = compile(input).unwrap_err();
+        assert_eq!(error.message(), "Mismatched argument type");
+        assert_eq!(
+            error.render(input),
+            "max expects a number as argument 2, got a string at 1:5\n1 + max(2, \"x\")\n    ^^^"
+        );
+    }
+
+    #[rstest]
+    #[case("price(2) + 1", Ok(vec![Instruction::CallHost { id: 1, argc: 1 }]))]
+    #[case("fn price(x) { x } price(2)", Ok(vec![]))]
+    #[case("abs(-2)", Ok(vec![]))]
+    #[case("price(now()) - now()", Ok(vec![
+        Instruction::CallHost { id: 0, argc: 0 },
+        Instruction::CallHost { id: 1, argc: 1 },
+        Instruction::CallHost { id: 0, argc: 0 },
+    ]))]
+    #[case("price()", Err("price expects 1 argument, got 0"))]
+    #[case("cost(1)", Err("Unknown function"))]
+    fn test_host_functions(#[case] input: &str, #[case] expected: Result<Vec<Instruction>, &str>) {
+        let options = CompileOptions::new()
+            .host_function("now", 0)
+            .host_function("price", 1)
+            .host_function("abs", 1);
+        let result = compile_with_options(input, &options).map(|chunk| {
+            assert_eq!(chunk.host_functions(), ["now", "price", "abs"]);
+            chunk
+                .instructions()
+                .map(|result| result.unwrap().1)
+                .filter(|instruction| instruction.opcode() == Opcode::CallHost)
+                .collect()
+        });
+        assert_eq!(
+            result.map_err(|e| e.to_string()),
+            expected.map_err(str::to_string)
+        );
+    }
+
+    #[test]
+    fn test_free_variables() {
+        let input = "price * qty + tax + pi";
+        let error = compile_program(input, &CompileOptions::new()).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown variable");
+
+        let options = CompileOptions::new().params(&["tax"]).free_variables(true);
+        let program = compile_program(input, &options).unwrap();
+        let params = program.entry(ENTRY).unwrap().params();
+        assert_eq!(params, ["tax", "price", "qty"]);
+        assert_eq!(program.entries().len(), 1);
+
+        let error = compile_program("fn f() { x } f()", &options).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown variable");
+    }
+
+    #[test]
+    fn test_unused_functions_are_removed() {
+        let with_unused = compile("fn unused(x) { x * x } fn one() { 1 } one() + 1").unwrap();
+        let without = compile("fn one() { 1 } one() + 1").unwrap();
+        assert_eq!(with_unused, without);
+    }
 }
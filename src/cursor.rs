@@ -0,0 +1,129 @@
+use crate::{error::DecodeError, value::Value};
+
+// Sequential reader over bytecode, each read performs a single bounds check for the
+// whole operand instead of one per converted slice. Reads past the end of the bytecode
+// fail with `DecodeError::Truncated`.
+pub struct Cursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    pub fn new(bytes: &'a [u8], position: usize) -> Cursor<'a> {
+        Cursor { bytes, position }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn jump(&mut self, position: usize) {
+        self.position = position;
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    #[inline]
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let bytes = self
+            .bytes
+            .get(self.position..)
+            .and_then(|rest| rest.first_chunk::<N>())
+            .ok_or(DecodeError::Truncated)?;
+        self.position += N;
+        Ok(*bytes)
+    }
+
+    #[inline]
+    pub fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        let [byte] = self.read_array::<1>()?;
+        Ok(byte)
+    }
+
+    #[inline]
+    pub fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_be_bytes(self.read_array::<4>()?))
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let bytes = self
+            .bytes
+            .get(self.position..)
+            .and_then(|rest| rest.get(..len))
+            .ok_or(DecodeError::Truncated)?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    // Fast path of `ValueCodec::BYTECODE` for the VM, reading in place instead of slicing
+    // out the encoded value first
+    #[inline]
+    pub fn read_value(&mut self) -> Result<Value, DecodeError> {
+        let value = match self.read_u8()? {
+            0 => Value::Int(i64::from_be_bytes(self.read_array::<8>()?)),
+            1 => Value::Float(f64::from_be_bytes(self.read_array::<8>()?)),
+            2 => {
+                let len = self.read_u32()? as usize;
+                let bytes = self.read_bytes(len)?;
+                Value::from(std::str::from_utf8(bytes).map_err(|_| DecodeError::InvalidUtf8)?)
+            }
+            3 => Value::Bool(self.read_u8()? != 0),
+            tag => return Err(DecodeError::InvalidValueTag(tag)),
+        };
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[test]
+    fn test_sequential_reads() {
+        let mut bytes = vec![7];
+        bytes.extend(0x01020304u32.to_be_bytes());
+        bytes.extend(Value::Int(-42).to_vec());
+        bytes.extend(Value::Float(2.5).to_vec());
+        bytes.extend(Value::from("hi").to_vec());
+        bytes.extend(Value::Bool(true).to_vec());
+
+        let mut cursor = Cursor::new(&bytes, 0);
+        assert_eq!(cursor.read_u8(), Ok(7));
+        assert_eq!(cursor.read_u32(), Ok(0x01020304));
+        assert_eq!(cursor.read_value(), Ok(Value::Int(-42)));
+        assert_eq!(cursor.read_value(), Ok(Value::Float(2.5)));
+        assert_eq!(cursor.read_value(), Ok(Value::from("hi")));
+        assert_eq!(cursor.read_value(), Ok(Value::Bool(true)));
+        assert!(cursor.is_at_end());
+    }
+
+    #[test]
+    fn test_jump() {
+        let bytes = [1, 2, 3];
+        let mut cursor = Cursor::new(&bytes, 0);
+        cursor.jump(2);
+        assert_eq!(cursor.position(), 2);
+        assert_eq!(cursor.read_u8(), Ok(3));
+    }
+
+    #[rstest]
+    #[case(vec![0, 1, 2], DecodeError::Truncated)]
+    #[case(vec![2, 0, 0, 0, 5, b'a', b'b'], DecodeError::Truncated)]
+    #[case(vec![2, 0, 0, 0, 1, 0xff], DecodeError::InvalidUtf8)]
+    #[case(vec![9, 0, 0, 0, 0, 0, 0, 0, 0], DecodeError::InvalidValueTag(9))]
+    fn test_invalid_value(#[case] bytes: Vec<u8>, #[case] expected: DecodeError) {
+        assert_eq!(Cursor::new(&bytes, 0).read_value(), Err(expected));
+    }
+
+    #[test]
+    fn test_read_past_end() {
+        let bytes = [0];
+        assert_eq!(
+            Cursor::new(&bytes, 5).read_u8(),
+            Err(DecodeError::Truncated)
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use std::fmt::Display;
+
+use crate::{
+    compiler::{builtin_names, constant_names, CompileError},
+    json,
+};
+
+// Stable codes for each kind of compile error, matched on the error message
+const CODES: [(&str, &str); 13] = [
+    ("Failed to parse expression", "E001"),
+    ("Unexpected trailing input", "E002"),
+    ("Unknown function", "E003"),
+    ("Unknown variable", "E004"),
+    ("Wrong number of arguments", "E005"),
+    ("Mismatched argument type", "E006"),
+    ("Duplicate function definition", "E007"),
+    ("Duplicate function parameter", "E008"),
+    ("Duplicate entry point", "E009"),
+    ("Unsupported target", "E010"),
+    ("Unsupported unary operator", "E011"),
+    ("Type error", "E013"),
+    ("Unsupported binary operator", "E014"),
+];
+
+// Code of errors raised for syntax denied by an allowlist
+const NOT_ALLOWED: &str = "E012";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => f.write_str("error"),
+            Severity::Warning => f.write_str("warning"),
+        }
+    }
+}
+
+// A compiler message in a form meant for tools rather than people. The span is the byte
+// range of the offending token, line and column are one based like in `CompileError`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    pub suggestion: Option<String>,
+}
+
+impl Diagnostic {
+    // Encode as a single line JSON object
+    pub fn to_json(&self) -> String {
+        let suggestion = match &self.suggestion {
+            Some(suggestion) => json::string(suggestion),
+            None => "null".to_string(),
+        };
+        format!(
+            r#"{{"severity":"{}","code":"{}","span":{{"start":{},"end":{},"line":{},"column":{}}},"message":{},"suggestion":{}}}"#,
+            self.severity,
+            self.code,
+            self.start,
+            self.end,
+            self.line,
+            self.column,
+            json::string(&self.message),
+            suggestion
+        )
+    }
+}
+
+impl From<&CompileError> for Diagnostic {
+    fn from(error: &CompileError) -> Self {
+        let code = CODES
+            .iter()
+            .find(|(message, _)| *message == error.message())
+            .map(|&(_, code)| code);
+        let code = match code {
+            Some(code) => code,
+            None if error.message().ends_with("is not allowed") => NOT_ALLOWED,
+            None => "E000",
+        };
+        Diagnostic {
+            severity: Severity::Error,
+            code,
+            start: error.offset(),
+            end: error.offset() + error.token().len(),
+            line: error.line(),
+            column: error.column(),
+            message: error.to_string(),
+            suggestion: suggest(error),
+        }
+    }
+}
+
+fn suggest(error: &CompileError) -> Option<String> {
+    let candidates: Vec<&str> = match error.message() {
+        "Unknown function" => builtin_names().collect(),
+        "Unknown variable" => constant_names().collect(),
+        _ => return None,
+    };
+    let token = error.token();
+    candidates
+        .into_iter()
+        .map(|candidate| (distance(token, candidate), candidate))
+        .filter(|&(distance, _)| distance <= 2 && distance < token.chars().count())
+        .min()
+        .map(|(_, candidate)| format!("did you mean `{}`?", candidate))
+}
+
+// Levenshtein distance in characters
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{compile, compile_restricted, Allowlist};
+    use rstest::rstest;
+
+    fn diagnose(input: &str) -> Diagnostic {
+        Diagnostic::from(&compile(input).unwrap_err())
+    }
+
+    #[rstest]
+    #[case("sqr(4)", "E003", Some("did you mean `sqrt`?"))]
+    #[case("1 + tua", "E004", Some("did you mean `tau`?"))]
+    #[case("1 + zzz", "E004", None)]
+    #[case("sqrt(1, 2)", "E005", None)]
+    #[case("(1 + 2", "E001", None)]
+    fn test_codes(#[case] input: &str, #[case] code: &str, #[case] suggestion: Option<&str>) {
+        let diagnostic = diagnose(input);
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(diagnostic.code, code);
+        assert_eq!(diagnostic.suggestion.as_deref(), suggestion);
+    }
+
+    #[test]
+    fn test_not_allowed() {
+        let error = compile_restricted("1 + 2", &Allowlist::new()).unwrap_err();
+        assert_eq!(Diagnostic::from(&error).code, "E012");
+    }
+
+    #[test]
+    fn test_to_json() {
+        let diagnostic = diagnose("1 +\nsqr(4)");
+        assert_eq!(
+            diagnostic.to_json(),
+            r#"{"severity":"error","code":"E003","span":{"start":4,"end":7,"line":2,"column":1},"message":"Unknown function","suggestion":"did you mean `sqrt`?"}"#
+        );
+    }
+
+    #[rstest]
+    #[case("", "abc", 3)]
+    #[case("sqrt", "sqrt", 0)]
+    #[case("kitten", "sitting", 3)]
+    fn test_distance(#[case] a: &str, #[case] b: &str, #[case] expected: usize) {
+        assert_eq!(distance(a, b), expected);
+    }
+}
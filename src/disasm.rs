@@ -0,0 +1,153 @@
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{compiler::compile, error::VerifyError, instruction::Instruction};
+
+// Render bytecode one instruction per line, prefixed by its byte offset. Fails at the first
+// instruction that does not decode.
+pub fn disassemble(bytecode: &[u8]) -> Result<String, VerifyError> {
+    let mut output = String::new();
+    let mut position = 0;
+    while position < bytecode.len() {
+        let (instruction, size) = Instruction::try_decode(bytecode, position)
+            .map_err(|e| VerifyError::InvalidInstruction(position, e))?;
+        writeln!(output, "{:04x}  {}", position, instruction).unwrap();
+        position += size;
+    }
+    Ok(output)
+}
+
+// Compile `input` and render its bytecode without byte offsets, call targets are replaced
+// by labels so the output only changes when the generated instructions do. Intended for
+// snapshot tests pinning the codegen of a formula, compile errors are rendered as well.
+pub fn codegen_snapshot(input: &str) -> String {
+    let chunk = match compile(input) {
+        Ok(chunk) => chunk,
+        Err(message) => return format!("error: {}\n", message),
+    };
+    let instructions: Vec<(usize, Instruction)> = match chunk.instructions().collect() {
+        Ok(instructions) => instructions,
+        Err(e) => return format!("error: {}\n", e),
+    };
+
+    // Number the call and jump targets in the order they appear in the bytecode
+    let mut labels = BTreeMap::new();
+    for (_, instruction) in &instructions {
+        if let Instruction::Call { address, .. }
+        | Instruction::TailCall { address, .. }
+        | Instruction::Jump(address)
+        | Instruction::JumpIfFalse(address) = instruction
+        {
+            labels.insert(*address, 0);
+        }
+    }
+    for (index, label) in labels.values_mut().enumerate() {
+        *label = index;
+    }
+
+    let mut output = String::new();
+    for (position, instruction) in instructions {
+        if let Some(label) = labels.get(&position) {
+            writeln!(output, "L{}:", label).unwrap();
+        }
+        match instruction {
+            Instruction::Call { address, argc } | Instruction::TailCall { address, argc } => {
+                let mnemonic = instruction.mnemonic();
+                writeln!(output, "    {} L{} {}", mnemonic, labels[&address], argc).unwrap();
+            }
+            Instruction::Jump(address) | Instruction::JumpIfFalse(address) => {
+                let mnemonic = instruction.mnemonic();
+                writeln!(output, "    {} L{}", mnemonic, labels[&address]).unwrap();
+            }
+            _ => writeln!(output, "    {}", instruction).unwrap(),
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DecodeError;
+
+    #[test]
+    fn test_disassemble() {
+        let bytecode = compile("1 + 2.5").unwrap();
+        let expected = "\
+0000  literal int 1
+000a  literal float 2.5
+0014  add
+0015  return
+";
+        assert_eq!(disassemble(bytecode.code()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_disassemble_invalid() {
+        let mut bytecode = compile("1 + 2").unwrap().code().to_vec();
+        bytecode.insert(10, 0xFF);
+        assert_eq!(
+            disassemble(&bytecode),
+            Err(VerifyError::InvalidInstruction(
+                10,
+                DecodeError::InvalidOpcode(0xFF)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_snapshot_expression() {
+        let expected = "    literal int 2
+    literal int 3
+    literal int 4
+    add
+    mul
+    factorial
+    return
+";
+        assert_eq!(codegen_snapshot("(2 * (3 + 4))!"), expected);
+    }
+
+    #[test]
+    fn test_snapshot_functions() {
+        let input = "fn sq(x) { x * x } fn hyp(a, b) { (sq(a) + sq(b))√ } hyp(3, 4)";
+        let expected = "    literal int 3
+    literal int 4
+    call L1 2
+    return
+L0:
+    load_arg 0
+    load_arg 0
+    mul
+    return
+L1:
+    load_arg 0
+    call L0 1
+    load_arg 1
+    call L0 1
+    add
+    sqrt
+    return
+";
+        assert_eq!(codegen_snapshot(input), expected);
+    }
+
+    #[test]
+    fn test_snapshot_conditional() {
+        let expected = "    literal bool true
+    jump_if_false L0
+    literal int 1
+    jump L1
+L0:
+    literal int 2
+L1:
+    return
+";
+        assert_eq!(codegen_snapshot("true ? 1 : 2"), expected);
+    }
+
+    #[test]
+    fn test_snapshot_error() {
+        assert_eq!(codegen_snapshot("x + 1"), "error: Unknown variable\n");
+        assert!(codegen_snapshot("1 2").starts_with("error: Unexpected trailing input"));
+    }
+}
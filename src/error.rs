@@ -0,0 +1,354 @@
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::opcode::Opcode;
+
+// A compile failure with the location of the offending token in the source
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{}", self.detail.as_deref().unwrap_or(self.message))]
+pub struct CompileError {
+    message: &'static str,
+    detail: Option<String>,
+    token: String,
+    offset: Option<usize>,
+    line: usize,
+    column: usize,
+}
+
+impl CompileError {
+    pub(crate) fn new(message: &'static str) -> CompileError {
+        CompileError {
+            message,
+            detail: None,
+            token: String::new(),
+            offset: None,
+            line: 1,
+            column: 1,
+        }
+    }
+
+    pub(crate) fn with_token(mut self, token: impl Into<String>) -> CompileError {
+        self.token = token.into();
+        self
+    }
+
+    pub(crate) fn with_detail(mut self, detail: String) -> CompileError {
+        self.detail = Some(detail);
+        self
+    }
+
+    pub(crate) fn wrong_arity(name: &str, expected: usize, got: usize) -> CompileError {
+        let plural = if expected == 1 { "" } else { "s" };
+        CompileError::new("Wrong number of arguments")
+            .with_token(name)
+            .with_detail(format!(
+                "{name} expects {expected} argument{plural}, got {got}"
+            ))
+    }
+
+    // Error at byte `offset` of the source, the token is the word or symbol found there
+    pub(crate) fn at(message: &'static str, input: &str, offset: usize) -> CompileError {
+        let rest = &input[offset..];
+        let word = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(rest.len());
+        let len = match word {
+            0 => rest.chars().next().map_or(0, char::len_utf8),
+            len => len,
+        };
+        let error = CompileError {
+            offset: Some(offset),
+            ..CompileError::new(message).with_token(&rest[..len])
+        };
+        error.locate(input)
+    }
+
+    // Locate the error at bytes `start..end` of `input`, which become its token
+    pub(crate) fn spanning(mut self, input: &str, start: usize, end: usize) -> CompileError {
+        self.token = input[start..end].to_string();
+        self.offset = Some(start);
+        self.locate(input)
+    }
+
+    // Resolve the location in `input`. Errors found after parsing only know their token,
+    // so they point at its first occurrence, or at the start when there is none.
+    pub(crate) fn locate(mut self, input: &str) -> CompileError {
+        let offset = match self.offset {
+            Some(offset) => offset,
+            None => find_token(input, &self.token).unwrap_or(0),
+        };
+        let before = &input[..offset];
+        self.offset = Some(offset);
+        self.line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        self.column = before[line_start..].chars().count() + 1;
+        self
+    }
+
+    // Short description of the kind of error, stable enough to match on
+    pub fn message(&self) -> &'static str {
+        self.message
+    }
+
+    // Specific explanation such as `sqrt expects 1 argument, got 2`, when there is one
+    pub fn detail(&self) -> Option<&str> {
+        self.detail.as_deref()
+    }
+
+    // The offending source text, empty when the error is not tied to a token or the
+    // input ended unexpectedly
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    // Byte offset of the offending token in the source
+    pub fn offset(&self) -> usize {
+        self.offset.unwrap_or(0)
+    }
+
+    // One based line and column (in characters) of the offending token
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    // Render the offending line of `source` with the token underlined by carets
+    pub fn render(&self, source: &str) -> String {
+        let line = source.lines().nth(self.line - 1).unwrap_or("");
+        let width = self.token.chars().count().max(1);
+        format!(
+            "{} at {}:{}\n{}\n{}{}",
+            self,
+            self.line,
+            self.column,
+            line,
+            " ".repeat(self.column - 1),
+            "^".repeat(width)
+        )
+    }
+}
+
+impl From<&'static str> for CompileError {
+    fn from(message: &'static str) -> Self {
+        CompileError::new(message)
+    }
+}
+
+// Byte offset of the first occurrence of `token`, identifiers only match whole words
+fn find_token(input: &str, token: &str) -> Option<usize> {
+    if token.is_empty() {
+        return None;
+    }
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    if !token.chars().all(is_word) {
+        return input.find(token);
+    }
+    input.match_indices(token).map(|(i, _)| i).find(|&i| {
+        let before = input[..i].chars().next_back();
+        let after = input[i + token.len()..].chars().next();
+        !before.is_some_and(is_word) && !after.is_some_and(is_word)
+    })
+}
+
+// A failure while executing bytecode
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum RuntimeError {
+    #[error("Stack overflow")]
+    StackOverflow,
+    #[error("Stack underflow")]
+    StackUnderflow,
+    #[error("Division by zero")]
+    DivisionByZero,
+    #[error("Factorial of negative number {0}")]
+    NegativeFactorial(i64),
+    #[error("Integer overflow in {op:?} of {}", join_operands(.operands))]
+    Overflow { op: Opcode, operands: Vec<i64> },
+    #[error("Call stack overflow")]
+    CallStackOverflow,
+    #[error("Invalid opcode {0}")]
+    InvalidOpcode(u8),
+    #[error("Invalid literal: {0}")]
+    InvalidLiteral(DecodeError),
+    #[error("Unexpected end of bytecode")]
+    UnexpectedEnd,
+    #[error("Program ended without returning a value")]
+    NoResult,
+    #[error("Unknown entry point {0}")]
+    UnknownEntry(String),
+    #[error("Missing argument {0}")]
+    MissingArgument(String),
+    #[error("Incompatible program")]
+    IncompatibleProgram,
+    #[error("Evaluation timed out after {} ms", .0.as_millis())]
+    Timeout(Duration),
+    #[error("Evaluation failed")]
+    Aborted,
+    #[error("Out of fuel")]
+    OutOfFuel,
+    #[error("Out of memory")]
+    OutOfMemory,
+    #[error("No suspended run to resume")]
+    NotSuspended,
+    #[error("Inconsistent stack depth at {0:#06x}")]
+    UnbalancedStack(usize),
+    #[error("Unknown host function {0}")]
+    UnknownHostFunction(String),
+    #[error("Host function {name} takes {expected} arguments, called with {got}")]
+    HostArity {
+        name: String,
+        expected: usize,
+        got: usize,
+    },
+    #[error("Host function {name} failed: {message}")]
+    Host { name: String, message: String },
+    #[error("Type mismatch in {op:?} of {}", join_types(.lhs, .rhs))]
+    TypeMismatch {
+        op: Opcode,
+        lhs: &'static str,
+        rhs: Option<&'static str>,
+    },
+}
+
+// A failure of an operation on `Stack`, each matching the runtime error of the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum StackError {
+    #[error("Stack overflow")]
+    Overflow,
+    #[error("Stack underflow")]
+    Underflow,
+    #[error("Out of memory")]
+    OutOfMemory,
+}
+
+// Malformed bytecode met while executing
+impl From<StackError> for RuntimeError {
+    fn from(error: StackError) -> Self {
+        match error {
+            StackError::Overflow => RuntimeError::StackOverflow,
+            StackError::Underflow => RuntimeError::StackUnderflow,
+            StackError::OutOfMemory => RuntimeError::OutOfMemory,
+        }
+    }
+}
+
+impl From<DecodeError> for RuntimeError {
+    fn from(error: DecodeError) -> Self {
+        match error {
+            DecodeError::Truncated => RuntimeError::UnexpectedEnd,
+            DecodeError::InvalidOpcode(opcode) => RuntimeError::InvalidOpcode(opcode),
+            error => RuntimeError::InvalidLiteral(error),
+        }
+    }
+}
+
+fn join_operands(operands: &[i64]) -> String {
+    let operands: Vec<String> = operands.iter().map(i64::to_string).collect();
+    operands.join(" and ")
+}
+
+fn join_types(lhs: &str, rhs: &Option<&str>) -> String {
+    match rhs {
+        Some(rhs) => format!("{} and {}", lhs, rhs),
+        None => lhs.to_string(),
+    }
+}
+
+// A failure while reading serialized values or programs
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum DecodeError {
+    #[error("Invalid program magic")]
+    InvalidMagic,
+    #[error("Unsupported program format version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Unexpected end of input")]
+    Truncated,
+    #[error("Unexpected trailing bytes")]
+    TrailingBytes,
+    #[error("Missing program section {0}")]
+    MissingSection(u8),
+    #[error("Duplicate program section {0}")]
+    DuplicateSection(u8),
+    #[error("Invalid UTF-8")]
+    InvalidUtf8,
+    #[error("Invalid value tag {0}")]
+    InvalidValueTag(u8),
+    #[error("Invalid opcode {0}")]
+    InvalidOpcode(u8),
+    #[error("Invalid compressed program")]
+    InvalidCompressed,
+    #[error("Compressed programs require the {0} feature")]
+    CompressionUnsupported(&'static str),
+    #[error("Unsupported program flags {0:#04b}")]
+    UnsupportedFlags(u8),
+}
+
+// A reason `verify` rejects a program, addresses are those of the offending instruction
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum VerifyError {
+    #[error("Invalid instruction at {0:#06x}: {1}")]
+    InvalidInstruction(usize, DecodeError),
+    #[error("Jump at {0:#06x} to {1:#06x} does not land on an instruction")]
+    InvalidTarget(usize, usize),
+    #[error("Entry point {0} does not start on an instruction")]
+    InvalidEntry(String),
+    #[error("Stack underflow at {0:#06x}")]
+    StackUnderflow(usize),
+    #[error("Inconsistent stack depth at {0:#06x}")]
+    UnbalancedStack(usize),
+    #[error("Argument {1} out of range at {0:#06x}")]
+    InvalidArgument(usize, usize),
+    #[error("Host function {1} out of range at {0:#06x}")]
+    InvalidHostFunction(usize, usize),
+    #[error("Code at {0:#06x} runs past the end of the program")]
+    MissingReturn(usize),
+}
+
+// Any failure of the library, for callers that compile, load and run in one go
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Compile(#[from] CompileError),
+    #[error(transparent)]
+    Runtime(#[from] RuntimeError),
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+    #[error(transparent)]
+    Verify(#[from] VerifyError),
+}
+
+// A rejected or failed evaluation of `sandbox::eval_untrusted`
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum SandboxError {
+    #[error("Source too long")]
+    SourceTooLong,
+    #[error("Expression nested too deeply")]
+    NestedTooDeeply,
+    #[error(transparent)]
+    Compile(#[from] CompileError),
+    #[error(transparent)]
+    Runtime(#[from] RuntimeError),
+}
+
+// A line of the config of `rvmd` that `repl::parse_config` could not read
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ConfigError {
+    #[error("Expected key = value")]
+    ExpectedKeyValue,
+    #[error("Unterminated string {0}")]
+    UnterminatedString(String),
+    #[error("Invalid escape in {0}")]
+    InvalidEscape(String),
+}
+
+// A formula or range that `Compiled::tabulate` cannot tabulate
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum TabulateError {
+    #[error("Expression must use exactly one variable")]
+    NotUnary,
+    #[error("Range needs more than {0} rows")]
+    TooManyRows(usize),
+}
@@ -0,0 +1,150 @@
+use crate::{
+    chunk::Chunk,
+    compiler::{compile_unit, CompileError},
+    error::TabulateError,
+    program::Program,
+    value::Value,
+    vm::Vm,
+};
+
+const STACK_SIZE: usize = 32;
+
+// Rows `Compiled::tabulate` computes at most, a range that needs more is refused
+pub const MAX_ROWS: usize = 1 << 20;
+
+// A single formula compiled with its free variables as parameters, in order of first use
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compiled {
+    chunk: Chunk,
+    params: Vec<String>,
+}
+
+pub fn compile_formula(input: &str) -> Result<Compiled, CompileError> {
+    Ok(Compiled::from(compile_unit(&[("main", input)])?))
+}
+
+// A program with a single entry point, such as one loaded from a program cache
+impl From<Program> for Compiled {
+    fn from(program: Program) -> Self {
+        let (chunk, mut entries) = program.into_parts();
+        let params = entries.pop().map(|entry| entry.params().to_vec());
+        Compiled {
+            chunk,
+            params: params.unwrap_or_default(),
+        }
+    }
+}
+
+impl Compiled {
+    pub fn bytecode(&self) -> &[u8] {
+        self.chunk.code()
+    }
+
+    pub fn params(&self) -> &[String] {
+        &self.params
+    }
+
+    // View a formula with exactly one free variable, whatever its name, as a function of
+    // that variable. The returned closure reuses one VM across calls.
+    pub fn as_unary(&self) -> Option<impl FnMut(f64) -> Option<Value>> {
+        if self.params.len() != 1 {
+            return None;
+        }
+        let mut vm = Vm::new(self.chunk.clone(), STACK_SIZE);
+        Some(move |x: f64| vm.run_with_args(&[Value::Float(x)]).ok())
+    }
+
+    // Evaluate a unary formula at `start`, `start + step`, ... up to and including `end`.
+    // Points are computed from their index so rounding errors do not accumulate.
+    pub fn tabulate(
+        &self,
+        start: f64,
+        end: f64,
+        step: f64,
+    ) -> Result<Vec<(f64, Option<Value>)>, TabulateError> {
+        let mut f = self.as_unary().ok_or(TabulateError::NotUnary)?;
+        if step <= 0.0 || end < start {
+            return Ok(Vec::new());
+        }
+        // Infinite for an infinite range and NaN for one that is not a number
+        let count = ((end - start) / step + 1e-9).floor();
+        if count.is_nan() || count >= MAX_ROWS as f64 {
+            return Err(TabulateError::TooManyRows(MAX_ROWS));
+        }
+        let table = (0..=count as usize)
+            .map(|index| {
+                let x = start + index as f64 * step;
+                (x, f(x))
+            })
+            .collect();
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("x * x - 2", 3.0, Value::Float(7.0))]
+    #[case("sqrt(t) + 1", 16.0, Value::Float(5.0))]
+    #[case("fn sq(a) { a * a } sq(y) + pi - pi", 2.0, Value::Float(4.0))]
+    fn test_as_unary(#[case] input: &str, #[case] x: f64, #[case] expected: Value) {
+        let compiled = compile_formula(input).unwrap();
+        let mut f = compiled.as_unary().unwrap();
+        assert_eq!(f(x), Some(expected.clone()));
+        assert_eq!(f(x), Some(expected));
+    }
+
+    #[rstest]
+    #[case("1 + 2")]
+    #[case("x + y")]
+    fn test_not_unary(#[case] input: &str) {
+        let compiled = compile_formula(input).unwrap();
+        assert!(compiled.as_unary().is_none());
+        assert_eq!(
+            compiled.tabulate(0.0, 1.0, 0.5),
+            Err(TabulateError::NotUnary)
+        );
+    }
+
+    #[test]
+    fn test_tabulate() {
+        let compiled = compile_formula("x * 2").unwrap();
+        let table = compiled.tabulate(0.0, 1.0, 0.1).unwrap();
+        assert_eq!(table.len(), 11);
+        assert_eq!(
+            table[3],
+            (0.30000000000000004, Some(Value::Float(0.6000000000000001)))
+        );
+        assert_eq!(table[10], (1.0, Some(Value::Float(2.0))));
+    }
+
+    #[rstest]
+    #[case(0.0, 1.0, 0.0)]
+    #[case(0.0, 1.0, -1.0)]
+    #[case(1.0, 0.0, 0.5)]
+    fn test_tabulate_empty(#[case] start: f64, #[case] end: f64, #[case] step: f64) {
+        let compiled = compile_formula("x").unwrap();
+        assert_eq!(compiled.tabulate(start, end, step), Ok(Vec::new()));
+    }
+
+    #[rstest]
+    #[case(0.0, MAX_ROWS as f64 - 1.0, 1.0, Ok(MAX_ROWS))]
+    #[case(0.0, MAX_ROWS as f64, 1.0, Err(TabulateError::TooManyRows(MAX_ROWS)))]
+    #[case(0.0, 1.0, 1e-300, Err(TabulateError::TooManyRows(MAX_ROWS)))]
+    #[case(0.0, f64::INFINITY, 1.0, Err(TabulateError::TooManyRows(MAX_ROWS)))]
+    #[case(f64::NEG_INFINITY, 0.0, 1.0, Err(TabulateError::TooManyRows(MAX_ROWS)))]
+    #[case(0.0, f64::NAN, 1.0, Err(TabulateError::TooManyRows(MAX_ROWS)))]
+    fn test_tabulate_limit(
+        #[case] start: f64,
+        #[case] end: f64,
+        #[case] step: f64,
+        #[case] expected: Result<usize, TabulateError>,
+    ) {
+        let compiled = compile_formula("x").unwrap();
+        let rows = compiled.tabulate(start, end, step).map(|table| table.len());
+        assert_eq!(rows, expected);
+    }
+}
@@ -0,0 +1,355 @@
+use std::fmt::Display;
+
+use crate::{cursor::Cursor, error::DecodeError, opcode::Opcode, value::Value};
+
+// A single decoded instruction along with its operands
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    Literal(Value),
+    Addition,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Return,
+    Factorial,
+    Sqrt,
+    Call { address: usize, argc: usize },
+    LoadArg(usize),
+    Pow,
+    Abs,
+    Min,
+    Max,
+    Not,
+    Negate,
+    Len,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or,
+    Jump(usize),
+    JumpIfFalse(usize),
+    ShiftLeft,
+    BitAnd,
+    // A call in tail position, which reuses the frame of the caller
+    TailCall { address: usize, argc: usize },
+    // A call to the function the host registered under the name at `id` in the host
+    // function table of the chunk
+    CallHost { id: usize, argc: usize },
+}
+
+// Decode the whole bytecode into instructions paired with their offsets
+pub(crate) fn decode_all(bytecode: &[u8]) -> Vec<(usize, Instruction)> {
+    let mut instructions = Vec::new();
+    let mut position = 0;
+    while position < bytecode.len() {
+        let (instruction, size) = Instruction::decode(bytecode, position);
+        instructions.push((position, instruction));
+        position += size;
+    }
+    instructions
+}
+
+// Like `decode_all` for bytecode that may be malformed
+pub(crate) fn try_decode_all(bytecode: &[u8]) -> Result<Vec<(usize, Instruction)>, DecodeError> {
+    let mut instructions = Vec::new();
+    let mut position = 0;
+    while position < bytecode.len() {
+        let (instruction, size) = Instruction::try_decode(bytecode, position)?;
+        instructions.push((position, instruction));
+        position += size;
+    }
+    Ok(instructions)
+}
+
+impl Instruction {
+    // Decode the instruction starting at `position`, returning it with its encoded length.
+    // The bytecode is trusted to be well formed, see `try_decode` otherwise.
+    pub fn decode(bytecode: &[u8], position: usize) -> (Instruction, usize) {
+        Instruction::try_decode(bytecode, position)
+            .unwrap_or_else(|e| panic!("invalid bytecode at {}: {}", position, e))
+    }
+
+    pub fn try_decode(
+        bytecode: &[u8],
+        position: usize,
+    ) -> Result<(Instruction, usize), DecodeError> {
+        let mut cursor = Cursor::new(bytecode, position);
+        let instruction = match Opcode::decode(cursor.read_u8()?)? {
+            Opcode::Literal => Instruction::Literal(cursor.read_value()?),
+            Opcode::Addition => Instruction::Addition,
+            Opcode::Subtract => Instruction::Subtract,
+            Opcode::Multiply => Instruction::Multiply,
+            Opcode::Divide => Instruction::Divide,
+            Opcode::Modulo => Instruction::Modulo,
+            Opcode::Return => Instruction::Return,
+            Opcode::Factorial => Instruction::Factorial,
+            Opcode::Sqrt => Instruction::Sqrt,
+            Opcode::Call => Instruction::Call {
+                address: cursor.read_u32()? as usize,
+                argc: cursor.read_u8()? as usize,
+            },
+            Opcode::LoadArg => Instruction::LoadArg(cursor.read_u8()? as usize),
+            Opcode::Pow => Instruction::Pow,
+            Opcode::Abs => Instruction::Abs,
+            Opcode::Min => Instruction::Min,
+            Opcode::Max => Instruction::Max,
+            Opcode::Not => Instruction::Not,
+            Opcode::Negate => Instruction::Negate,
+            Opcode::Len => Instruction::Len,
+            Opcode::Equal => Instruction::Equal,
+            Opcode::NotEqual => Instruction::NotEqual,
+            Opcode::Less => Instruction::Less,
+            Opcode::LessEqual => Instruction::LessEqual,
+            Opcode::Greater => Instruction::Greater,
+            Opcode::GreaterEqual => Instruction::GreaterEqual,
+            Opcode::And => Instruction::And,
+            Opcode::Or => Instruction::Or,
+            Opcode::Jump => Instruction::Jump(cursor.read_u32()? as usize),
+            Opcode::JumpIfFalse => Instruction::JumpIfFalse(cursor.read_u32()? as usize),
+            Opcode::ShiftLeft => Instruction::ShiftLeft,
+            Opcode::BitAnd => Instruction::BitAnd,
+            Opcode::TailCall => Instruction::TailCall {
+                address: cursor.read_u32()? as usize,
+                argc: cursor.read_u8()? as usize,
+            },
+            Opcode::CallHost => Instruction::CallHost {
+                id: cursor.read_u8()? as usize,
+                argc: cursor.read_u8()? as usize,
+            },
+        };
+        Ok((instruction, cursor.position() - position))
+    }
+
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Instruction::Literal(_) => Opcode::Literal,
+            Instruction::Addition => Opcode::Addition,
+            Instruction::Subtract => Opcode::Subtract,
+            Instruction::Multiply => Opcode::Multiply,
+            Instruction::Divide => Opcode::Divide,
+            Instruction::Modulo => Opcode::Modulo,
+            Instruction::Return => Opcode::Return,
+            Instruction::Factorial => Opcode::Factorial,
+            Instruction::Sqrt => Opcode::Sqrt,
+            Instruction::Call { .. } => Opcode::Call,
+            Instruction::LoadArg(_) => Opcode::LoadArg,
+            Instruction::Pow => Opcode::Pow,
+            Instruction::Abs => Opcode::Abs,
+            Instruction::Min => Opcode::Min,
+            Instruction::Max => Opcode::Max,
+            Instruction::Not => Opcode::Not,
+            Instruction::Negate => Opcode::Negate,
+            Instruction::Len => Opcode::Len,
+            Instruction::Equal => Opcode::Equal,
+            Instruction::NotEqual => Opcode::NotEqual,
+            Instruction::Less => Opcode::Less,
+            Instruction::LessEqual => Opcode::LessEqual,
+            Instruction::Greater => Opcode::Greater,
+            Instruction::GreaterEqual => Opcode::GreaterEqual,
+            Instruction::And => Opcode::And,
+            Instruction::Or => Opcode::Or,
+            Instruction::Jump(_) => Opcode::Jump,
+            Instruction::JumpIfFalse(_) => Opcode::JumpIfFalse,
+            Instruction::ShiftLeft => Opcode::ShiftLeft,
+            Instruction::BitAnd => Opcode::BitAnd,
+            Instruction::TailCall { .. } => Opcode::TailCall,
+            Instruction::CallHost { .. } => Opcode::CallHost,
+        }
+    }
+
+    // Append the encoded instruction, the inverse of `decode`
+    pub fn encode(&self, bytecode: &mut Vec<u8>) {
+        bytecode.push(self.opcode() as u8);
+        match self {
+            Instruction::Literal(value) => bytecode.extend(value.to_vec()),
+            Instruction::Call { address, argc } | Instruction::TailCall { address, argc } => {
+                bytecode.extend((*address as u32).to_be_bytes());
+                bytecode.push(*argc as u8);
+            }
+            Instruction::CallHost { id, argc } => bytecode.extend([*id as u8, *argc as u8]),
+            Instruction::LoadArg(index) => bytecode.push(*index as u8),
+            Instruction::Jump(address) | Instruction::JumpIfFalse(address) => {
+                bytecode.extend((*address as u32).to_be_bytes());
+            }
+            _ => {}
+        }
+    }
+
+    // Encoded length in bytes, including the opcode
+    pub fn size(&self) -> usize {
+        match self {
+            Instruction::Literal(value) => 1 + value.size(),
+            Instruction::Call { .. } | Instruction::TailCall { .. } => 6,
+            Instruction::CallHost { .. } => 3,
+            Instruction::LoadArg(_) => 2,
+            Instruction::Jump(_) | Instruction::JumpIfFalse(_) => 5,
+            _ => 1,
+        }
+    }
+
+    // Number of values the instruction pops and pushes, the arguments of a call count as
+    // popped although they stay on the stack as the parameters of the callee. A tail call
+    // counts like a call although the callee returns straight to the caller's caller.
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match self {
+            Instruction::Literal(_) | Instruction::LoadArg(_) => (0, 1),
+            Instruction::Call { argc, .. }
+            | Instruction::TailCall { argc, .. }
+            | Instruction::CallHost { argc, .. } => (*argc, 1),
+            Instruction::Jump(_) => (0, 0),
+            Instruction::JumpIfFalse(_) | Instruction::Return => (1, 0),
+            Instruction::Factorial
+            | Instruction::Sqrt
+            | Instruction::Abs
+            | Instruction::Not
+            | Instruction::Negate
+            | Instruction::Len => (1, 1),
+            _ => (2, 1),
+        }
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Literal(_) => "literal",
+            Instruction::Addition => "add",
+            Instruction::Subtract => "sub",
+            Instruction::Multiply => "mul",
+            Instruction::Divide => "div",
+            Instruction::Modulo => "mod",
+            Instruction::Return => "return",
+            Instruction::Factorial => "factorial",
+            Instruction::Sqrt => "sqrt",
+            Instruction::Call { .. } => "call",
+            Instruction::LoadArg(_) => "load_arg",
+            Instruction::Pow => "pow",
+            Instruction::Abs => "abs",
+            Instruction::Min => "min",
+            Instruction::Max => "max",
+            Instruction::Not => "not",
+            Instruction::Negate => "negate",
+            Instruction::Len => "len",
+            Instruction::Equal => "eq",
+            Instruction::NotEqual => "ne",
+            Instruction::Less => "lt",
+            Instruction::LessEqual => "le",
+            Instruction::Greater => "gt",
+            Instruction::GreaterEqual => "ge",
+            Instruction::And => "and",
+            Instruction::Or => "or",
+            Instruction::Jump(_) => "jump",
+            Instruction::JumpIfFalse(_) => "jump_if_false",
+            Instruction::ShiftLeft => "shl",
+            Instruction::BitAnd => "bitand",
+            Instruction::TailCall { .. } => "tail_call",
+            Instruction::CallHost { .. } => "call_host",
+        }
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Instruction::Literal(Value::Int(value)) => write!(f, "literal int {}", value),
+            Instruction::Literal(Value::Float(value)) => write!(f, "literal float {:?}", value),
+            Instruction::Literal(Value::Str(value)) => write!(f, "literal str {:?}", value),
+            Instruction::Literal(Value::Bool(value)) => write!(f, "literal bool {}", value),
+            Instruction::Call { address, argc } | Instruction::TailCall { address, argc } => {
+                write!(f, "{} {:#06x} {}", self.mnemonic(), address, argc)
+            }
+            Instruction::CallHost { id, argc } => write!(f, "call_host {} {}", id, argc),
+            Instruction::LoadArg(index) => write!(f, "load_arg {}", index),
+            Instruction::Jump(address) | Instruction::JumpIfFalse(address) => {
+                write!(f, "{} {:#06x}", self.mnemonic(), address)
+            }
+            _ => f.write_str(self.mnemonic()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case(vec![0x01], Instruction::Addition, 1)]
+    #[case(vec![0x06], Instruction::Return, 1)]
+    #[case(vec![0x0A, 3], Instruction::LoadArg(3), 2)]
+    #[case(vec![0x09, 0, 0, 1, 0, 2], Instruction::Call { address: 256, argc: 2 }, 6)]
+    #[case(vec![0x1B, 0, 0, 0, 9], Instruction::JumpIfFalse(9), 5)]
+    #[case(vec![0x1E, 0, 0, 0, 7, 1], Instruction::TailCall { address: 7, argc: 1 }, 6)]
+    #[case(vec![0x1F, 2, 1], Instruction::CallHost { id: 2, argc: 1 }, 3)]
+    fn test_decode(#[case] bytecode: Vec<u8>, #[case] expected: Instruction, #[case] size: usize) {
+        assert_eq!(Instruction::decode(&bytecode, 0), (expected, size));
+    }
+
+    #[test]
+    fn test_decode_literal() {
+        let mut bytecode = vec![Opcode::Return as u8, Opcode::Literal as u8];
+        bytecode.extend(Value::Float(2.5).to_vec());
+        let (instruction, size) = Instruction::decode(&bytecode, 1);
+        assert_eq!(instruction, Instruction::Literal(Value::Float(2.5)));
+        assert_eq!(size, 10);
+        assert_eq!(instruction.opcode(), Opcode::Literal);
+    }
+
+    #[rstest]
+    #[case(Instruction::Literal(Value::Int(1)), (0, 1))]
+    #[case(Instruction::Call { address: 0, argc: 3 }, (3, 1))]
+    #[case(Instruction::CallHost { id: 0, argc: 2 }, (2, 1))]
+    #[case(Instruction::JumpIfFalse(4), (1, 0))]
+    #[case(Instruction::Sqrt, (1, 1))]
+    #[case(Instruction::Less, (2, 1))]
+    fn test_stack_effect(#[case] instruction: Instruction, #[case] expected: (usize, usize)) {
+        assert_eq!(instruction.stack_effect(), expected);
+    }
+
+    #[rstest]
+    #[case(vec![0xFF], DecodeError::InvalidOpcode(0xFF))]
+    #[case(vec![0x09, 0, 0, 1], DecodeError::Truncated)]
+    #[case(vec![0x00, 7, 0], DecodeError::InvalidValueTag(7))]
+    fn test_try_decode_invalid(#[case] bytecode: Vec<u8>, #[case] expected: DecodeError) {
+        assert_eq!(Instruction::try_decode(&bytecode, 0), Err(expected));
+    }
+
+    #[rstest]
+    #[case(Instruction::Literal(Value::from("abc")))]
+    #[case(Instruction::Call { address: 300, argc: 2 })]
+    #[case(Instruction::TailCall { address: 12, argc: 0 })]
+    #[case(Instruction::CallHost { id: 3, argc: 4 })]
+    #[case(Instruction::LoadArg(4))]
+    #[case(Instruction::JumpIfFalse(70000))]
+    #[case(Instruction::Sqrt)]
+    fn test_encode(#[case] instruction: Instruction) {
+        let mut bytecode = vec![];
+        instruction.encode(&mut bytecode);
+        assert_eq!(bytecode.len(), instruction.size());
+        assert_eq!(
+            Instruction::decode(&bytecode, 0),
+            (instruction.clone(), instruction.size())
+        );
+    }
+
+    #[rstest]
+    #[case(Instruction::Literal(Value::Int(7)), "literal int 7")]
+    #[case(Instruction::Literal(Value::Float(3.0)), "literal float 3.0")]
+    #[case(Instruction::Call { address: 40, argc: 1 }, "call 0x0028 1")]
+    #[case(Instruction::TailCall { address: 40, argc: 2 }, "tail_call 0x0028 2")]
+    #[case(Instruction::CallHost { id: 1, argc: 2 }, "call_host 1 2")]
+    #[case(Instruction::LoadArg(0), "load_arg 0")]
+    #[case(Instruction::Literal(Value::from("a\"b")), "literal str \"a\\\"b\"")]
+    #[case(Instruction::Len, "len")]
+    #[case(Instruction::Literal(Value::Bool(false)), "literal bool false")]
+    #[case(Instruction::LessEqual, "le")]
+    #[case(Instruction::Jump(16), "jump 0x0010")]
+    #[case(Instruction::ShiftLeft, "shl")]
+    #[case(Instruction::Modulo, "mod")]
+    fn test_display(#[case] instruction: Instruction, #[case] expected: &str) {
+        assert_eq!(instruction.to_string(), expected);
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    instruction::{decode_all, Instruction},
+    lexer::Span,
+    opcode::Opcode,
+    value::Value,
+};
+
+// A position in the code that jumps and calls refer to, resolved to an address by `lower`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Label(pub usize);
+
+// Linear intermediate representation between the AST and bytecode. It mirrors the VM
+// instructions except that jumps and calls target labels instead of addresses, so passes
+// can add, drop and resize instructions without relocating anything.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ir {
+    Literal(Value),
+    LoadArg(u8),
+    // Any instruction without operands, like `Addition` or `Return`
+    Op(Opcode),
+    Call { target: Label, argc: u8 },
+    // A call whose result is returned right away, see `mark_tail_calls`
+    TailCall { target: Label, argc: u8 },
+    CallHost { id: u8, argc: u8 },
+    Jump(Label),
+    JumpIfFalse(Label),
+    // Marks where `Label` points, it emits no code
+    Label(Label),
+    // Source of the next instruction, recorded in the debug info and emitting no code
+    Location(Span),
+}
+
+impl Ir {
+    // Number of values the instruction pops and pushes, a return pops the result and
+    // leaves the frame
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match *self {
+            Ir::Literal(_) | Ir::LoadArg(_) => (0, 1),
+            Ir::Call { argc, .. } | Ir::TailCall { argc, .. } | Ir::CallHost { argc, .. } => {
+                (argc as usize, 1)
+            }
+            Ir::Jump(_) | Ir::Label(_) | Ir::Location(_) => (0, 0),
+            Ir::JumpIfFalse(_) | Ir::Op(Opcode::Return) => (1, 0),
+            Ir::Op(
+                Opcode::Factorial
+                | Opcode::Sqrt
+                | Opcode::Abs
+                | Opcode::Not
+                | Opcode::Negate
+                | Opcode::Len,
+            ) => (1, 1),
+            Ir::Op(_) => (2, 1),
+        }
+    }
+}
+
+// Encode `code` as bytecode that will be placed at address `base`. Panics when a jump or
+// call targets a label that is never placed.
+pub fn lower(code: &[Ir], base: usize) -> Vec<u8> {
+    lower_with_locations(code, base).0
+}
+
+// Encode `code` like `lower`, also returning the address and source span of every
+// instruction preceded by a location
+pub fn lower_with_locations(code: &[Ir], base: usize) -> (Vec<u8>, Vec<(usize, Span)>) {
+    let mut bytecode = Vec::new();
+    let mut locations = Vec::new();
+    let mut labels = HashMap::new();
+    // Offsets of addresses waiting for the position of a label
+    let mut fixups = Vec::new();
+    for ir in code {
+        match ir {
+            Ir::Literal(value) => Instruction::Literal(value.clone()).encode(&mut bytecode),
+            Ir::LoadArg(index) => Instruction::LoadArg(*index as usize).encode(&mut bytecode),
+            Ir::Op(opcode) => bytecode.push(*opcode as u8),
+            Ir::CallHost { id, argc } => bytecode.extend([Opcode::CallHost as u8, *id, *argc]),
+            Ir::Call { target, argc } | Ir::TailCall { target, argc } => {
+                let opcode = match ir {
+                    Ir::Call { .. } => Opcode::Call,
+                    _ => Opcode::TailCall,
+                };
+                bytecode.push(opcode as u8);
+                fixups.push((bytecode.len(), *target));
+                bytecode.extend([0; 4]);
+                bytecode.push(*argc);
+            }
+            Ir::Jump(target) | Ir::JumpIfFalse(target) => {
+                let opcode = match ir {
+                    Ir::Jump(_) => Opcode::Jump,
+                    _ => Opcode::JumpIfFalse,
+                };
+                bytecode.push(opcode as u8);
+                fixups.push((bytecode.len(), *target));
+                bytecode.extend([0; 4]);
+            }
+            Ir::Label(label) => {
+                labels.insert(*label, base + bytecode.len());
+            }
+            Ir::Location(span) => locations.push((base + bytecode.len(), *span)),
+        }
+    }
+    for (offset, label) in fixups {
+        let address = *labels.get(&label).expect("undefined label") as u32;
+        bytecode[offset..offset + 4].copy_from_slice(&address.to_be_bytes());
+    }
+    (bytecode, locations)
+}
+
+// Rebuild the IR of bytecode produced by the compiler, placing a label named after its
+// address wherever a jump or call lands
+pub fn lift(bytecode: &[u8]) -> Vec<Ir> {
+    let instructions = decode_all(bytecode);
+    let targets: HashSet<usize> = instructions
+        .iter()
+        .filter_map(|(_, instruction)| match *instruction {
+            Instruction::Jump(address)
+            | Instruction::JumpIfFalse(address)
+            | Instruction::Call { address, .. }
+            | Instruction::TailCall { address, .. } => Some(address),
+            _ => None,
+        })
+        .collect();
+
+    let mut code = Vec::with_capacity(instructions.len());
+    for (position, instruction) in instructions {
+        if targets.contains(&position) {
+            code.push(Ir::Label(Label(position)));
+        }
+        code.push(match instruction {
+            Instruction::Literal(value) => Ir::Literal(value),
+            Instruction::LoadArg(index) => Ir::LoadArg(index as u8),
+            Instruction::Call { address, argc } => Ir::Call {
+                target: Label(address),
+                argc: argc as u8,
+            },
+            Instruction::TailCall { address, argc } => Ir::TailCall {
+                target: Label(address),
+                argc: argc as u8,
+            },
+            Instruction::CallHost { id, argc } => Ir::CallHost {
+                id: id as u8,
+                argc: argc as u8,
+            },
+            Instruction::Jump(address) => Ir::Jump(Label(address)),
+            Instruction::JumpIfFalse(address) => Ir::JumpIfFalse(Label(address)),
+            instruction => Ir::Op(instruction.opcode()),
+        });
+    }
+    if targets.contains(&bytecode.len()) {
+        code.push(Ir::Label(Label(bytecode.len())));
+    }
+    code
+}
+
+// Evaluate arithmetic on literals at compile time. Operations the VM would fail or
+// overflow on, like dividing by zero, are left for it to report. The location of a folded
+// operation is dropped along with it.
+pub fn fold_constants(code: &[Ir]) -> Vec<Ir> {
+    let mut output: Vec<Ir> = Vec::with_capacity(code.len());
+    for ir in code {
+        let located = output
+            .iter()
+            .rev()
+            .take_while(|ir| matches!(ir, Ir::Location(_)))
+            .count();
+        let operands = &output[..output.len() - located];
+        let folded = match (ir, operands) {
+            (Ir::Op(Opcode::Negate), [.., Ir::Literal(value)]) => negate(value).map(|v| (1, v)),
+            (&Ir::Op(opcode), [.., Ir::Literal(lhs), Ir::Literal(rhs)]) => {
+                arithmetic(opcode, lhs, rhs).map(|v| (2, v))
+            }
+            _ => None,
+        };
+        match folded {
+            Some((operands, value)) => {
+                output.truncate(output.len() - located - operands);
+                output.push(Ir::Literal(value));
+            }
+            None => output.push(ir.clone()),
+        }
+    }
+    output
+}
+
+fn negate(value: &Value) -> Option<Value> {
+    match *value {
+        Value::Int(n) => n.checked_neg().map(Value::Int),
+        Value::Float(n) => Some(Value::Float(-n)),
+        _ => None,
+    }
+}
+
+fn arithmetic(opcode: Opcode, lhs: &Value, rhs: &Value) -> Option<Value> {
+    use Value::*;
+    match (lhs, rhs) {
+        (&Int(a), &Int(b)) => match opcode {
+            Opcode::Addition => a.checked_add(b),
+            Opcode::Subtract => a.checked_sub(b),
+            Opcode::Multiply => a.checked_mul(b),
+            Opcode::Divide => a.checked_div(b),
+            Opcode::Modulo => a.checked_rem(b),
+            _ => None,
+        }
+        .map(Int),
+        (Int(_) | Float(_), Int(_) | Float(_)) => {
+            let (a, b) = (lhs.clone(), rhs.clone());
+            match opcode {
+                Opcode::Addition => (a + b).ok(),
+                Opcode::Subtract => (a - b).ok(),
+                Opcode::Multiply => (a * b).ok(),
+                Opcode::Divide => (a / b).ok(),
+                Opcode::Modulo => (a % b).ok(),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// Rewrite multiplication and remainder by a power of two literal into a shift or a mask,
+// which the VM executes without the general arithmetic path when the other operand is an
+// integer. A label between the literal and the operation means the operand may come from
+// another branch, so the pair is left alone.
+pub fn reduce_strength(code: &[Ir]) -> Vec<Ir> {
+    let mut output = Vec::with_capacity(code.len());
+    let mut i = 0;
+    while i < code.len() {
+        // The operation may be preceded by its location, which stays in place
+        let located = matches!(code.get(i + 1), Some(Ir::Location(_)));
+        let operation = code.get(i + 1 + usize::from(located));
+        let reduced = match (&code[i], operation) {
+            (Ir::Literal(Value::Int(n)), Some(Ir::Op(operation)))
+                if *n > 1 && n.count_ones() == 1 =>
+            {
+                match operation {
+                    Opcode::Multiply => Some((n.trailing_zeros() as i64, Opcode::ShiftLeft)),
+                    Opcode::Modulo => Some((n - 1, Opcode::BitAnd)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        match reduced {
+            Some((operand, operation)) => {
+                output.push(Ir::Literal(Value::Int(operand)));
+                if located {
+                    output.push(code[i + 1].clone());
+                }
+                output.push(Ir::Op(operation));
+                i += 2 + usize::from(located);
+            }
+            None => {
+                output.push(code[i].clone());
+                i += 1;
+            }
+        }
+    }
+    output
+}
+
+// Turn the calls of a function body whose result is returned as is into tail calls, which
+// run the callee in the frame of the caller. Following jumps and skipping labels, nothing
+// else may run between the call and the return. The main expression has no frame to reuse
+// and is left alone.
+pub fn mark_tail_calls(body: &mut [Ir]) {
+    let labels: HashMap<Label, usize> = body
+        .iter()
+        .enumerate()
+        .filter_map(|(i, ir)| match ir {
+            Ir::Label(label) => Some((*label, i)),
+            _ => None,
+        })
+        .collect();
+    for i in 0..body.len() {
+        let Ir::Call { target, argc } = body[i] else {
+            continue;
+        };
+        let mut next = i + 1;
+        // Bounded in case the jumps form a loop
+        for _ in 0..body.len() {
+            match body.get(next) {
+                Some(Ir::Label(_) | Ir::Location(_)) => next += 1,
+                Some(Ir::Jump(label)) => match labels.get(label) {
+                    Some(&position) => next = position,
+                    None => break,
+                },
+                Some(Ir::Op(Opcode::Return)) => {
+                    body[i] = Ir::TailCall { target, argc };
+                    break;
+                }
+                _ => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::compile, vm::Vm};
+    use rstest::rstest;
+
+    fn int(n: i64) -> Ir {
+        Ir::Literal(Value::Int(n))
+    }
+
+    fn at(start: usize) -> Ir {
+        Ir::Location(Span {
+            start,
+            end: start + 1,
+        })
+    }
+
+    #[rstest]
+    #[case("1 + 2 * 3")]
+    #[case("true ? 1 : 2")]
+    #[case("fn f(x) { x < 1 ? 0 : f(x - 1) } f(3)")]
+    #[case("fn f(x) { x < 1 ? 0 : f(x - 1) + 1 } f(3)")]
+    fn test_lift_and_lower(#[case] input: &str) {
+        let bytecode = compile(input).unwrap().into_code();
+        assert_eq!(lower(&lift(&bytecode), 0), bytecode);
+    }
+
+    #[test]
+    fn test_lower_resolves_labels() {
+        let code = [
+            Ir::Literal(Value::Bool(false)),
+            Ir::JumpIfFalse(Label(0)),
+            int(1),
+            Ir::Op(Opcode::Return),
+            Ir::Label(Label(0)),
+            int(2),
+            Ir::Op(Opcode::Return),
+        ];
+        let bytecode = lower(&code, 0);
+        assert_eq!(bytecode[4..8], 19u32.to_be_bytes());
+        assert_eq!(Vm::new(bytecode, 4).run(), Ok(Value::Int(2)));
+        assert_eq!(lower(&code, 100)[4..8], 119u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_lower_records_locations() {
+        let code = [
+            int(6),
+            int(0),
+            at(4),
+            Ir::Op(Opcode::Divide),
+            Ir::Op(Opcode::Return),
+        ];
+        let (bytecode, locations) = lower_with_locations(&code, 10);
+        assert_eq!(bytecode, lower(&code, 10));
+        assert_eq!(locations, vec![(30, Span { start: 4, end: 5 })]);
+    }
+
+    #[test]
+    #[should_panic(expected = "undefined label")]
+    fn test_lower_undefined_label() {
+        lower(&[Ir::Jump(Label(7))], 0);
+    }
+
+    #[rstest]
+    #[case(Ir::LoadArg(0), (0, 1))]
+    #[case(Ir::Op(Opcode::Addition), (2, 1))]
+    #[case(Ir::Op(Opcode::Sqrt), (1, 1))]
+    #[case(Ir::Op(Opcode::Return), (1, 0))]
+    #[case(Ir::Call { target: Label(0), argc: 3 }, (3, 1))]
+    #[case(Ir::TailCall { target: Label(0), argc: 2 }, (2, 1))]
+    #[case(Ir::CallHost { id: 0, argc: 0 }, (0, 1))]
+    #[case(Ir::JumpIfFalse(Label(0)), (1, 0))]
+    #[case(Ir::Label(Label(0)), (0, 0))]
+    fn test_stack_effect(#[case] ir: Ir, #[case] expected: (usize, usize)) {
+        assert_eq!(ir.stack_effect(), expected);
+    }
+
+    #[rstest]
+    #[case(vec![int(2), int(3), Ir::Op(Opcode::Multiply), int(1), Ir::Op(Opcode::Addition)], vec![int(7)])]
+    #[case(vec![int(4), Ir::Op(Opcode::Negate)], vec![int(-4)])]
+    #[case(vec![int(1), Ir::Literal(Value::Float(0.5)), Ir::Op(Opcode::Subtract)], vec![Ir::Literal(Value::Float(0.5))])]
+    #[case(vec![int(1), int(0), Ir::Op(Opcode::Divide)], vec![int(1), int(0), Ir::Op(Opcode::Divide)])]
+    #[case(vec![int(i64::MAX), int(1), Ir::Op(Opcode::Addition)], vec![int(i64::MAX), int(1), Ir::Op(Opcode::Addition)])]
+    #[case(vec![int(1), int(2), Ir::Op(Opcode::Less)], vec![int(1), int(2), Ir::Op(Opcode::Less)])]
+    #[case(vec![int(1), Ir::Label(Label(0)), int(2), Ir::Op(Opcode::Addition)], vec![int(1), Ir::Label(Label(0)), int(2), Ir::Op(Opcode::Addition)])]
+    #[case(vec![int(1), int(2), at(3), Ir::Op(Opcode::Addition), int(3), at(9), Ir::Op(Opcode::Multiply)], vec![int(9)])]
+    #[case(vec![int(1), int(0), at(3), Ir::Op(Opcode::Modulo)], vec![int(1), int(0), at(3), Ir::Op(Opcode::Modulo)])]
+    fn test_fold_constants(#[case] code: Vec<Ir>, #[case] expected: Vec<Ir>) {
+        assert_eq!(fold_constants(&code), expected);
+    }
+
+    #[rstest]
+    #[case(vec![Ir::LoadArg(0), int(8), Ir::Op(Opcode::Multiply)], vec![Ir::LoadArg(0), int(3), Ir::Op(Opcode::ShiftLeft)])]
+    #[case(vec![Ir::LoadArg(0), int(8), Ir::Op(Opcode::Modulo)], vec![Ir::LoadArg(0), int(7), Ir::Op(Opcode::BitAnd)])]
+    #[case(vec![Ir::LoadArg(0), int(6), Ir::Op(Opcode::Multiply)], vec![Ir::LoadArg(0), int(6), Ir::Op(Opcode::Multiply)])]
+    #[case(vec![int(8), Ir::Label(Label(0)), Ir::Op(Opcode::Multiply)], vec![int(8), Ir::Label(Label(0)), Ir::Op(Opcode::Multiply)])]
+    #[case(vec![Ir::LoadArg(0), int(4), at(0), Ir::Op(Opcode::Multiply)], vec![Ir::LoadArg(0), int(2), at(0), Ir::Op(Opcode::ShiftLeft)])]
+    fn test_reduce_strength(#[case] code: Vec<Ir>, #[case] expected: Vec<Ir>) {
+        assert_eq!(reduce_strength(&code), expected);
+    }
+
+    fn call(target: usize) -> Ir {
+        Ir::Call {
+            target: Label(target),
+            argc: 1,
+        }
+    }
+
+    fn tail_call(target: usize) -> Ir {
+        Ir::TailCall {
+            target: Label(target),
+            argc: 1,
+        }
+    }
+
+    #[rstest]
+    #[case(vec![call(0), Ir::Op(Opcode::Return)], vec![tail_call(0), Ir::Op(Opcode::Return)])]
+    #[case(vec![call(0), Ir::Op(Opcode::Negate), Ir::Op(Opcode::Return)], vec![call(0), Ir::Op(Opcode::Negate), Ir::Op(Opcode::Return)])]
+    #[case(vec![call(0), Ir::Jump(Label(1)), int(1), Ir::Label(Label(1)), at(0), Ir::Op(Opcode::Return)], vec![tail_call(0), Ir::Jump(Label(1)), int(1), Ir::Label(Label(1)), at(0), Ir::Op(Opcode::Return)])]
+    #[case(vec![call(0), Ir::Jump(Label(1)), Ir::Label(Label(1)), int(1), Ir::Op(Opcode::Return)], vec![call(0), Ir::Jump(Label(1)), Ir::Label(Label(1)), int(1), Ir::Op(Opcode::Return)])]
+    #[case(vec![Ir::Label(Label(1)), call(0), Ir::Jump(Label(1))], vec![Ir::Label(Label(1)), call(0), Ir::Jump(Label(1))])]
+    #[case(vec![call(0)], vec![call(0)])]
+    fn test_mark_tail_calls(#[case] mut code: Vec<Ir>, #[case] expected: Vec<Ir>) {
+        mark_tail_calls(&mut code);
+        assert_eq!(code, expected);
+    }
+}
@@ -0,0 +1,35 @@
+use std::fmt::Write;
+
+// Quote and escape `value` as a JSON string
+pub fn string(value: &str) -> String {
+    let mut output = String::with_capacity(value.len() + 2);
+    output.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(output, "\\u{:04x}", c as u32).unwrap(),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("abc", r#""abc""#)]
+    #[case("a\"b\\c", r#""a\"b\\c""#)]
+    #[case("1\n2\t\u{1}", r#""1\n2\t\u0001""#)]
+    #[case("√π", r#""√π""#)]
+    fn test_string(#[case] input: &str, #[case] expected: &str) {
+        assert_eq!(string(input), expected);
+    }
+}
@@ -0,0 +1,329 @@
+use std::fmt::Debug;
+
+use crate::{error::CompileError, operator::longest_operator, value::Value};
+
+// Byte range of a token in the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+// Operators are stored as a single char like in `Expr`, binary ones are those of the
+// operator table. A `-` directly followed by digits where an operand is expected is part of
+// a negative number instead.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Number(Value),
+    Str(String),
+    Bool(bool),
+    Ident(String),
+    Op(char),
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Comma,
+    Question,
+    Colon,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+// Hook letting embedders take over numeric literals, for example to read every literal as
+// a float or to accept suffixes like `10k`. It is consulted wherever an operand is
+// expected, before the builtin literals and after any prefix operators.
+pub trait NumberParser: Debug + Send + Sync {
+    // Parse a literal at the start of `input`, returning its value and the remaining input,
+    // or `None` to fall back to the builtin integer and float literals
+    fn parse<'a>(&self, input: &'a str) -> Option<(Value, &'a str)>;
+}
+
+// Split `input` into tokens, skipping whitespace along with `# ...`, `// ...` and
+// `/* ... */` comments
+pub fn tokenize(input: &str) -> Result<Vec<Token>, CompileError> {
+    match lex(input, None) {
+        (tokens, None) => Ok(tokens),
+        (_, Some(error)) => Err(error),
+    }
+}
+
+// The tokens up to the first invalid one, and the error for it if there is one. The parser
+// reports what it can make of the valid prefix rather than the lexer error.
+pub(crate) fn lex(
+    input: &str,
+    number_parser: Option<&dyn NumberParser>,
+) -> (Vec<Token>, Option<CompileError>) {
+    let mut lexer = Lexer {
+        input,
+        offset: 0,
+        operand: true,
+        number_parser,
+    };
+    let mut tokens = Vec::new();
+    loop {
+        if let Err(message) = lexer.skip_trivia() {
+            return (tokens, Some(CompileError::at(message, input, lexer.offset)));
+        }
+        if lexer.offset == input.len() {
+            return (tokens, None);
+        }
+        match lexer.token() {
+            Ok(token) => tokens.push(token),
+            Err(message) => return (tokens, Some(CompileError::at(message, input, lexer.offset))),
+        }
+    }
+}
+
+struct Lexer<'a> {
+    input: &'a str,
+    offset: usize,
+    // Whether the next token starts an operand, which decides if `-1` is a negative literal
+    // and if the number parser is consulted
+    operand: bool,
+    number_parser: Option<&'a dyn NumberParser>,
+}
+
+impl<'a> Lexer<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.offset..]
+    }
+
+    fn skip_trivia(&mut self) -> Result<(), &'static str> {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start_matches([' ', '\t', '\r', '\n']);
+            if trimmed.starts_with('#') || trimmed.starts_with("//") {
+                let line = trimmed.find(['\r', '\n']).unwrap_or(trimmed.len());
+                self.offset += rest.len() - trimmed.len() + line;
+            } else if let Some(comment) = trimmed.strip_prefix("/*") {
+                self.offset += rest.len() - trimmed.len();
+                let close = comment.find("*/").ok_or("Unterminated comment")?;
+                self.offset += close + 4;
+            } else {
+                self.offset += rest.len() - trimmed.len();
+                return Ok(());
+            }
+        }
+    }
+
+    fn token(&mut self) -> Result<Token, &'static str> {
+        let start = self.offset;
+        let kind = self.kind()?;
+        // Postfix operators keep the operand open, so `5! - 1` is a subtraction
+        self.operand = match kind {
+            TokenKind::Number(_)
+            | TokenKind::Str(_)
+            | TokenKind::Bool(_)
+            | TokenKind::Ident(_)
+            | TokenKind::RParen => false,
+            TokenKind::Op('!' | '√') => self.operand,
+            _ => true,
+        };
+        Ok(Token {
+            kind,
+            span: Span {
+                start,
+                end: self.offset,
+            },
+        })
+    }
+
+    fn kind(&mut self) -> Result<TokenKind, &'static str> {
+        let rest = self.rest();
+        let mut chars = rest.chars();
+        let first = chars.next().unwrap_or_default();
+        let second = chars.next();
+        let negative = first == '-' && second.is_some_and(|c| c.is_ascii_digit());
+
+        if self.operand && !matches!(first, '!' | '¬' | '√') && (first != '-' || negative) {
+            if let Some(value) = self.custom_number() {
+                return Ok(TokenKind::Number(value));
+            }
+        }
+        if first.is_ascii_digit() || (self.operand && negative) {
+            return self.number();
+        }
+        if first.is_ascii_alphabetic() || first == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            self.offset += len;
+            return Ok(match &rest[..len] {
+                "true" => TokenKind::Bool(true),
+                "false" => TokenKind::Bool(false),
+                name => TokenKind::Ident(name.to_string()),
+            });
+        }
+        if first == '"' {
+            return self.string();
+        }
+
+        if let Some(operator) = longest_operator(rest) {
+            self.offset += operator.spelling.len();
+            return Ok(TokenKind::Op(operator.symbol));
+        }
+        let (kind, len) = match (first, second) {
+            ('!' | '¬' | '√', _) => (TokenKind::Op(first), first.len_utf8()),
+            ('(', _) => (TokenKind::LParen, 1),
+            (')', _) => (TokenKind::RParen, 1),
+            ('{', _) => (TokenKind::LBrace, 1),
+            ('}', _) => (TokenKind::RBrace, 1),
+            (',', _) => (TokenKind::Comma, 1),
+            ('?', _) => (TokenKind::Question, 1),
+            (':', _) => (TokenKind::Colon, 1),
+            _ => return Err("Unexpected character"),
+        };
+        self.offset += len;
+        Ok(kind)
+    }
+
+    // A literal read by the number parser, which must consume at least one character
+    fn custom_number(&mut self) -> Option<Value> {
+        let rest = self.rest();
+        let (value, remaining) = self.number_parser?.parse(rest)?;
+        if remaining.len() >= rest.len() {
+            return None;
+        }
+        self.offset += rest.len() - remaining.len();
+        Some(value)
+    }
+
+    // Integers, or floats when the digits are followed by a decimal point and more digits
+    fn number(&mut self) -> Result<TokenKind, &'static str> {
+        let rest = self.rest();
+        let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let mut len = usize::from(rest.starts_with('-'));
+        len += digits(&rest[len..]);
+        let fraction = rest[len..].strip_prefix('.').map_or(0, digits);
+        let value = if fraction > 0 {
+            len += 1 + fraction;
+            rest[..len].parse().map(Value::Float).ok()
+        } else {
+            rest[..len].parse().map(Value::Int).ok()
+        };
+        let value = value.ok_or("Invalid number")?;
+        self.offset += len;
+        Ok(TokenKind::Number(value))
+    }
+
+    // Double quoted strings, supporting `\"`, `\\`, `\n` and `\t` escapes
+    fn string(&mut self) -> Result<TokenKind, &'static str> {
+        let mut contents = String::new();
+        let mut chars = self.rest()[1..].char_indices();
+        while let Some((index, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.offset += index + 2;
+                    return Ok(TokenKind::Str(contents));
+                }
+                '\\' => contents.push(match chars.next() {
+                    Some((_, '\\')) => '\\',
+                    Some((_, '"')) => '"',
+                    Some((_, 'n')) => '\n',
+                    Some((_, 't')) => '\t',
+                    _ => return Err("Invalid escape sequence"),
+                }),
+                c => contents.push(c),
+            }
+        }
+        Err("Unterminated string")
+    }
+}
+
+// Whether `input` stops in the middle of an expression, with a bracket left open or an
+// operator waiting for its right operand, so an interactive caller should read more before
+// compiling it. Input that does not tokenize is complete, its error is for the compiler.
+pub fn is_incomplete(input: &str) -> bool {
+    let Ok(tokens) = tokenize(input) else {
+        return false;
+    };
+    let depth = tokens.iter().fold(0i64, |depth, token| match token.kind {
+        TokenKind::LParen | TokenKind::LBrace => depth + 1,
+        TokenKind::RParen | TokenKind::RBrace => depth - 1,
+        _ => depth,
+    });
+    // `!` and `√` may close an operand as postfix operators
+    let trailing = match tokens.last().map(|token| &token.kind) {
+        Some(TokenKind::Op('!' | '√')) => false,
+        Some(TokenKind::Op(_) | TokenKind::Question | TokenKind::Colon | TokenKind::Comma) => true,
+        _ => false,
+    };
+    depth > 0 || trailing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn kinds(input: &str) -> Vec<TokenKind> {
+        tokenize(input)
+            .unwrap()
+            .into_iter()
+            .map(|token| token.kind)
+            .collect()
+    }
+
+    #[rstest]
+    #[case("1 + 2.5", vec![TokenKind::Number(Value::Int(1)), TokenKind::Op('+'), TokenKind::Number(Value::Float(2.5))])]
+    #[case("1-2", vec![TokenKind::Number(Value::Int(1)), TokenKind::Op('-'), TokenKind::Number(Value::Int(2))])]
+    #[case("2 * -3", vec![TokenKind::Number(Value::Int(2)), TokenKind::Op('*'), TokenKind::Number(Value::Int(-3))])]
+    #[case("- x", vec![TokenKind::Op('-'), TokenKind::Ident("x".to_string())])]
+    #[case("5! -3", vec![TokenKind::Number(Value::Int(5)), TokenKind::Op('!'), TokenKind::Op('-'), TokenKind::Number(Value::Int(3))])]
+    #[case("!-3", vec![TokenKind::Op('!'), TokenKind::Number(Value::Int(-3))])]
+    #[case("a != b", vec![TokenKind::Ident("a".to_string()), TokenKind::Op('≠'), TokenKind::Ident("b".to_string())])]
+    #[case("x <= 1 && y", vec![TokenKind::Ident("x".to_string()), TokenKind::Op('≤'), TokenKind::Number(Value::Int(1)), TokenKind::Op('&'), TokenKind::Ident("y".to_string())])]
+    #[case("true || truex", vec![TokenKind::Bool(true), TokenKind::Op('|'), TokenKind::Ident("truex".to_string())])]
+    #[case(r#""a\"b\n""#, vec![TokenKind::Str("a\"b\n".to_string())])]
+    #[case("f(1, 2) # call", vec![TokenKind::Ident("f".to_string()), TokenKind::LParen, TokenKind::Number(Value::Int(1)), TokenKind::Comma, TokenKind::Number(Value::Int(2)), TokenKind::RParen])]
+    #[case("c ? /* yes */ 1 : 2", vec![TokenKind::Ident("c".to_string()), TokenKind::Question, TokenKind::Number(Value::Int(1)), TokenKind::Colon, TokenKind::Number(Value::Int(2))])]
+    #[case("-9223372036854775808", vec![TokenKind::Number(Value::Int(i64::MIN))])]
+    #[case(" // only a comment", vec![])]
+    fn test_tokenize(#[case] input: &str, #[case] expected: Vec<TokenKind>) {
+        assert_eq!(kinds(input), expected);
+    }
+
+    #[test]
+    fn test_spans() {
+        let spans: Vec<Span> = tokenize("√x >= 10")
+            .unwrap()
+            .into_iter()
+            .map(|token| token.span)
+            .collect();
+        let span = |start, end| Span { start, end };
+        assert_eq!(spans, vec![span(0, 3), span(3, 4), span(5, 7), span(8, 10)]);
+    }
+
+    #[rstest]
+    #[case("1 $ 2", "Unexpected character", 2)]
+    #[case("1 & 2", "Unexpected character", 2)]
+    #[case(r#"1 + "open"#, "Unterminated string", 4)]
+    #[case(r#""bad \q""#, "Invalid escape sequence", 0)]
+    #[case("1 /* open", "Unterminated comment", 2)]
+    #[case("99999999999999999999", "Invalid number", 0)]
+    fn test_tokenize_errors(#[case] input: &str, #[case] message: &str, #[case] offset: usize) {
+        let error = tokenize(input).unwrap_err();
+        assert_eq!((error.message(), error.offset()), (message, offset));
+    }
+
+    #[rstest]
+    #[case("1 + 2", false)]
+    #[case("(1 + 2", true)]
+    #[case("(1 + 2))", false)]
+    #[case("fn f(x) {", true)]
+    #[case("1 +", true)]
+    #[case("x >= // compared to", true)]
+    #[case("c ? 1 :", true)]
+    #[case("max(1,", true)]
+    #[case("5!", false)]
+    #[case(r#"1 + "open"#, false)]
+    #[case("", false)]
+    fn test_is_incomplete(#[case] input: &str, #[case] expected: bool) {
+        assert_eq!(is_incomplete(input), expected);
+    }
+}
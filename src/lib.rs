@@ -1,5 +1,31 @@
+pub mod cache;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod chunk;
+pub mod compat;
 pub mod compiler;
+pub mod cursor;
+pub mod diagnostic;
+pub mod disasm;
+pub mod error;
+pub mod formula;
+pub mod instruction;
+pub mod ir;
+pub mod json;
+pub mod lexer;
 pub mod opcode;
+pub mod operator;
+pub mod optimize;
+pub mod parser;
+pub mod plot;
+pub mod pretty;
+pub mod program;
+pub mod repl;
+pub mod sandbox;
 pub mod stack;
+pub mod typecheck;
 pub mod value;
+pub mod verify;
 pub mod vm;
+#[cfg(feature = "wasm")]
+pub mod wasm;
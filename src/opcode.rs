@@ -1,3 +1,5 @@
+use crate::error::DecodeError;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(u8)]
 pub enum Opcode {
@@ -10,11 +12,35 @@ pub enum Opcode {
     Return = 0x06,
     Factorial = 0x07,
     Sqrt = 0x08,
+    Call = 0x09,
+    LoadArg = 0x0A,
+    Pow = 0x0B,
+    Abs = 0x0C,
+    Min = 0x0D,
+    Max = 0x0E,
+    Not = 0x0F,
+    Negate = 0x10,
+    Len = 0x11,
+    Equal = 0x12,
+    NotEqual = 0x13,
+    Less = 0x14,
+    LessEqual = 0x15,
+    Greater = 0x16,
+    GreaterEqual = 0x17,
+    And = 0x18,
+    Or = 0x19,
+    Jump = 0x1A,
+    JumpIfFalse = 0x1B,
+    ShiftLeft = 0x1C,
+    BitAnd = 0x1D,
+    TailCall = 0x1E,
+    CallHost = 0x1F,
 }
 
-impl From<u8> for Opcode {
-    fn from(value: u8) -> Self {
-        match value {
+impl Opcode {
+    // The opcode encoded as `value`, bytes outside the instruction set are an error
+    pub fn decode(value: u8) -> Result<Opcode, DecodeError> {
+        let opcode = match value {
             0x00 => Opcode::Literal,
             0x01 => Opcode::Addition,
             0x02 => Opcode::Subtract,
@@ -24,8 +50,32 @@ impl From<u8> for Opcode {
             0x06 => Opcode::Return,
             0x07 => Opcode::Factorial,
             0x08 => Opcode::Sqrt,
-            _ => panic!("invalid opcode"),
-        }
+            0x09 => Opcode::Call,
+            0x0A => Opcode::LoadArg,
+            0x0B => Opcode::Pow,
+            0x0C => Opcode::Abs,
+            0x0D => Opcode::Min,
+            0x0E => Opcode::Max,
+            0x0F => Opcode::Not,
+            0x10 => Opcode::Negate,
+            0x11 => Opcode::Len,
+            0x12 => Opcode::Equal,
+            0x13 => Opcode::NotEqual,
+            0x14 => Opcode::Less,
+            0x15 => Opcode::LessEqual,
+            0x16 => Opcode::Greater,
+            0x17 => Opcode::GreaterEqual,
+            0x18 => Opcode::And,
+            0x19 => Opcode::Or,
+            0x1A => Opcode::Jump,
+            0x1B => Opcode::JumpIfFalse,
+            0x1C => Opcode::ShiftLeft,
+            0x1D => Opcode::BitAnd,
+            0x1E => Opcode::TailCall,
+            0x1F => Opcode::CallHost,
+            _ => return Err(DecodeError::InvalidOpcode(value)),
+        };
+        Ok(opcode)
     }
 }
 
@@ -43,16 +93,41 @@ mod tests {
     #[case(0x05, Opcode::Modulo)]
     #[case(0x06, Opcode::Return)]
     #[case(0x07, Opcode::Factorial)]
+    #[case(0x09, Opcode::Call)]
+    #[case(0x0A, Opcode::LoadArg)]
+    #[case(0x0B, Opcode::Pow)]
+    #[case(0x0C, Opcode::Abs)]
+    #[case(0x0D, Opcode::Min)]
+    #[case(0x0E, Opcode::Max)]
+    #[case(0x0F, Opcode::Not)]
+    #[case(0x10, Opcode::Negate)]
+    #[case(0x11, Opcode::Len)]
+    #[case(0x12, Opcode::Equal)]
+    #[case(0x13, Opcode::NotEqual)]
+    #[case(0x14, Opcode::Less)]
+    #[case(0x15, Opcode::LessEqual)]
+    #[case(0x16, Opcode::Greater)]
+    #[case(0x17, Opcode::GreaterEqual)]
+    #[case(0x18, Opcode::And)]
+    #[case(0x19, Opcode::Or)]
+    #[case(0x1A, Opcode::Jump)]
+    #[case(0x1B, Opcode::JumpIfFalse)]
+    #[case(0x1C, Opcode::ShiftLeft)]
+    #[case(0x1D, Opcode::BitAnd)]
+    #[case(0x1E, Opcode::TailCall)]
+    #[case(0x1F, Opcode::CallHost)]
     fn test_valid_opcodes(#[case] input: u8, #[case] expected: Opcode) {
-        assert_eq!(Opcode::from(input), expected);
+        assert_eq!(Opcode::decode(input), Ok(expected));
     }
 
     #[rstest]
-    #[case(0x09)]
+    #[case(0xF0)]
     #[case(0xFF)]
-    #[should_panic(expected = "invalid opcode")]
     fn test_invalid_opcodes(#[case] invalid_opcode: u8) {
-        let _ = Opcode::from(invalid_opcode);
+        assert_eq!(
+            Opcode::decode(invalid_opcode),
+            Err(DecodeError::InvalidOpcode(invalid_opcode))
+        );
     }
 
     #[rstest]
@@ -64,6 +139,29 @@ mod tests {
     #[case(Opcode::Modulo, 0x05)]
     #[case(Opcode::Return, 0x06)]
     #[case(Opcode::Factorial, 0x07)]
+    #[case(Opcode::Call, 0x09)]
+    #[case(Opcode::LoadArg, 0x0A)]
+    #[case(Opcode::Pow, 0x0B)]
+    #[case(Opcode::Abs, 0x0C)]
+    #[case(Opcode::Min, 0x0D)]
+    #[case(Opcode::Max, 0x0E)]
+    #[case(Opcode::Not, 0x0F)]
+    #[case(Opcode::Negate, 0x10)]
+    #[case(Opcode::Len, 0x11)]
+    #[case(Opcode::Equal, 0x12)]
+    #[case(Opcode::NotEqual, 0x13)]
+    #[case(Opcode::Less, 0x14)]
+    #[case(Opcode::LessEqual, 0x15)]
+    #[case(Opcode::Greater, 0x16)]
+    #[case(Opcode::GreaterEqual, 0x17)]
+    #[case(Opcode::And, 0x18)]
+    #[case(Opcode::Or, 0x19)]
+    #[case(Opcode::Jump, 0x1A)]
+    #[case(Opcode::JumpIfFalse, 0x1B)]
+    #[case(Opcode::ShiftLeft, 0x1C)]
+    #[case(Opcode::BitAnd, 0x1D)]
+    #[case(Opcode::TailCall, 0x1E)]
+    #[case(Opcode::CallHost, 0x1F)]
     fn test_opcode_as_u8(#[case] opcode: Opcode, #[case] expected: u8) {
         assert_eq!(opcode as u8, expected);
     }
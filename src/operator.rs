@@ -0,0 +1,92 @@
+use crate::{compiler::Feature, opcode::Opcode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Associativity {
+    Left,
+    Right,
+}
+
+// A binary operator as written in the source and stored in `Expr::BinOp`, operators with a
+// higher precedence bind tighter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Operator {
+    pub spelling: &'static str,
+    pub symbol: char,
+    pub precedence: u8,
+    pub associativity: Associativity,
+    pub opcode: Opcode,
+    // Feature a restricted compilation must allow for the operator to be used
+    pub feature: Feature,
+}
+
+const fn left(
+    spelling: &'static str,
+    symbol: char,
+    precedence: u8,
+    opcode: Opcode,
+    feature: Feature,
+) -> Operator {
+    Operator {
+        spelling,
+        symbol,
+        precedence,
+        associativity: Associativity::Left,
+        opcode,
+        feature,
+    }
+}
+
+// Every binary operator of the language. Arithmetic has no precedence between its
+// operators and is evaluated left to right, comparisons bind looser, then `&&` and `||`.
+pub const OPERATORS: [Operator; 13] = [
+    left("+", '+', 4, Opcode::Addition, Feature::Addition),
+    left("-", '-', 4, Opcode::Subtract, Feature::Subtraction),
+    left("*", '*', 4, Opcode::Multiply, Feature::Multiplication),
+    left("/", '/', 4, Opcode::Divide, Feature::Division),
+    left("%", '%', 4, Opcode::Modulo, Feature::Modulo),
+    left("==", '=', 3, Opcode::Equal, Feature::Comparisons),
+    left("!=", '≠', 3, Opcode::NotEqual, Feature::Comparisons),
+    left("<=", '≤', 3, Opcode::LessEqual, Feature::Comparisons),
+    left(">=", '≥', 3, Opcode::GreaterEqual, Feature::Comparisons),
+    left("<", '<', 3, Opcode::Less, Feature::Comparisons),
+    left(">", '>', 3, Opcode::Greater, Feature::Comparisons),
+    left("&&", '&', 2, Opcode::And, Feature::Logic),
+    left("||", '|', 1, Opcode::Or, Feature::Logic),
+];
+
+pub fn binary_operator(symbol: char) -> Option<&'static Operator> {
+    OPERATORS.iter().find(|operator| operator.symbol == symbol)
+}
+
+// The operator whose spelling is the longest prefix of `input`
+pub(crate) fn longest_operator(input: &str) -> Option<&'static Operator> {
+    OPERATORS
+        .iter()
+        .filter(|operator| input.starts_with(operator.spelling))
+        .max_by_key(|operator| operator.spelling.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("<= 1", Some('≤'))]
+    #[case("< 1", Some('<'))]
+    #[case("!= 1", Some('≠'))]
+    #[case("! 1", None)]
+    #[case("&& b", Some('&'))]
+    #[case("& b", None)]
+    fn test_longest_operator(#[case] input: &str, #[case] expected: Option<char>) {
+        let symbol = longest_operator(input).map(|operator| operator.symbol);
+        assert_eq!(symbol, expected);
+    }
+
+    #[test]
+    fn test_symbols_are_unique() {
+        for operator in &OPERATORS {
+            assert_eq!(binary_operator(operator.symbol), Some(operator));
+        }
+    }
+}
@@ -0,0 +1,569 @@
+use std::{
+    collections::{HashMap, HashSet},
+    slice::Iter,
+};
+
+use crate::{
+    chunk::Chunk,
+    compiler::{builtin_names, is_numeric, Expr, Function, Script},
+    instruction::{decode_all, try_decode_all, Instruction},
+    ir::{self, lift, lower},
+    lexer::Span,
+    program::Program,
+};
+
+// Remove instructions that no path from `entries` can reach, such as code following an
+// unconditional `return` or `jump` and functions that are never called. Call and jump
+// targets are relocated, the returned addresses are the new addresses of `entries`.
+pub fn eliminate_dead_code(bytecode: &[u8], entries: &[usize]) -> (Vec<u8>, Vec<usize>) {
+    let (output, relocated) = eliminate(bytecode, entries);
+    let entries = entries.iter().map(|entry| relocated[entry]).collect();
+    (output, entries)
+}
+
+// Dead code elimination of code starting at address 0 with its debug locations, which are
+// relocated along with the instructions or dropped with them
+pub(crate) fn eliminate_dead_code_with_locations(
+    bytecode: &[u8],
+    locations: &[(usize, Span)],
+) -> (Vec<u8>, Vec<(usize, Span)>) {
+    let (output, relocated) = eliminate(bytecode, &[0]);
+    let locations = locations
+        .iter()
+        .filter_map(|(address, span)| Some((*relocated.get(address)?, *span)))
+        .collect();
+    (output, locations)
+}
+
+// The reachable code and the new address of every kept instruction, plus the end of the code
+fn eliminate(bytecode: &[u8], entries: &[usize]) -> (Vec<u8>, HashMap<usize, usize>) {
+    let instructions = decode_all(bytecode);
+    let index: HashMap<usize, usize> = instructions
+        .iter()
+        .enumerate()
+        .map(|(i, (position, _))| (*position, i))
+        .collect();
+
+    // Follow every possible successor, a call resumes at the next instruction on return
+    let mut reachable = vec![false; instructions.len()];
+    let mut pending: Vec<usize> = entries.to_vec();
+    while let Some(position) = pending.pop() {
+        let Some(&i) = index.get(&position) else {
+            continue;
+        };
+        if reachable[i] {
+            continue;
+        }
+        reachable[i] = true;
+
+        let (_, instruction) = &instructions[i];
+        let next = position + instruction.size();
+        match *instruction {
+            Instruction::Return => {}
+            Instruction::Jump(address) => pending.push(address),
+            Instruction::JumpIfFalse(address)
+            | Instruction::Call { address, .. }
+            | Instruction::TailCall { address, .. } => {
+                pending.push(address);
+                pending.push(next);
+            }
+            _ => pending.push(next),
+        }
+    }
+
+    let mut relocated = HashMap::new();
+    let mut size = 0;
+    for ((position, instruction), &keep) in instructions.iter().zip(&reachable) {
+        if keep {
+            relocated.insert(*position, size);
+            size += instruction.size();
+        }
+    }
+    relocated.insert(bytecode.len(), size);
+
+    let mut output = Vec::with_capacity(size);
+    for ((_, instruction), &keep) in instructions.iter().zip(&reachable) {
+        if !keep {
+            continue;
+        }
+        let instruction = match *instruction {
+            Instruction::Jump(address) => Instruction::Jump(relocated[&address]),
+            Instruction::JumpIfFalse(address) => Instruction::JumpIfFalse(relocated[&address]),
+            Instruction::Call { address, argc } => Instruction::Call {
+                address: relocated[&address],
+                argc,
+            },
+            Instruction::TailCall { address, argc } => Instruction::TailCall {
+                address: relocated[&address],
+                argc,
+            },
+            ref instruction => instruction.clone(),
+        };
+        instruction.encode(&mut output);
+    }
+    (output, relocated)
+}
+
+// Dead code elimination over a whole program, keeping every entry point alive. Constants
+// and host functions are kept, debug info is dropped since its addresses no longer apply.
+pub fn eliminate_dead_program_code(program: Program) -> Program {
+    let (chunk, entries) = program.into_parts();
+    let addresses: Vec<usize> = entries.iter().map(|entry| entry.address()).collect();
+    let (bytecode, addresses) = eliminate_dead_code(chunk.code(), &addresses);
+    let entries = entries
+        .into_iter()
+        .zip(addresses)
+        .map(|(entry, address)| entry.with_address(address))
+        .collect();
+    let chunk = Chunk::new(bytecode)
+        .with_constants(chunk.constants().to_vec())
+        .with_host_functions(chunk.host_functions().to_vec());
+    Program::with_entries(chunk, entries)
+}
+
+// Strength reduction of compiled bytecode, see `ir::reduce_strength`. The rewritten
+// literals keep their size, so no address moves.
+pub fn reduce_strength(bytecode: &[u8]) -> Vec<u8> {
+    lower(&ir::reduce_strength(&lift(bytecode)), 0)
+}
+
+// Whether swapping the operands of `op` leaves the result unchanged. Addition is only
+// commutative for numbers since it also concatenates strings.
+fn is_commutative(op: char, left: &Expr, right: &Expr) -> bool {
+    match op {
+        '*' | '=' | '≠' | '&' | '|' => true,
+        '+' => is_numeric(left) && is_numeric(right),
+        _ => false,
+    }
+}
+
+// The calls that may have side effects, those of host functions and of the functions of a
+// script calling one, directly or through other functions. Everything else is pure.
+struct Effects {
+    functions: HashSet<String>,
+    effectful: HashSet<String>,
+}
+
+impl Effects {
+    fn new(functions: &[Function]) -> Effects {
+        let mut effects = Effects {
+            functions: functions.iter().map(|f| f.name.clone()).collect(),
+            effectful: HashSet::new(),
+        };
+        // Effects spread to callers until no more functions are found to have them
+        loop {
+            let found: Vec<String> = functions
+                .iter()
+                .filter(|f| !effects.effectful.contains(&f.name) && effects.in_expr(&f.body))
+                .map(|f| f.name.clone())
+                .collect();
+            if found.is_empty() {
+                return effects;
+            }
+            effects.effectful.extend(found);
+        }
+    }
+
+    fn calls(&self, name: &str) -> bool {
+        match self.functions.contains(name) {
+            true => self.effectful.contains(name),
+            false => !builtin_names().any(|builtin| builtin == name),
+        }
+    }
+
+    fn in_expr(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::Number(_) | Expr::Str(_) | Expr::Var(_) => false,
+            Expr::Call(name, args) => self.calls(name) || args.iter().any(|arg| self.in_expr(arg)),
+            Expr::BinOp(left, _, right) => self.in_expr(left) || self.in_expr(right),
+            Expr::UnaryOp(_, operand) => self.in_expr(operand),
+            Expr::Conditional(condition, then, otherwise) => {
+                self.in_expr(condition) || self.in_expr(then) || self.in_expr(otherwise)
+            }
+        }
+    }
+}
+
+// Reorder the operands of commutative operators so the side needing more stack slots is
+// evaluated first, returning the rewritten expression and the slots it needs. Operands
+// containing a call with side effects, such as a host function, keep their order.
+pub fn schedule(expr: &Expr) -> (Expr, usize) {
+    let (expr, depth, _) = schedule_spanned(expr, &Effects::new(&[]), &mut [].iter());
+    (expr, depth)
+}
+
+// Schedule `expr` whose spans, in the order the parser completed its nodes, are next in
+// `spans`. The spans of the rewritten expression are returned in its own completion order.
+fn schedule_spanned(
+    expr: &Expr,
+    effects: &Effects,
+    spans: &mut Iter<Span>,
+) -> (Expr, usize, Vec<Span>) {
+    let mut scheduled_spans = Vec::new();
+    let (expr, depth) = match expr {
+        Expr::Number(_) | Expr::Str(_) | Expr::Var(_) => (expr.clone(), 1),
+        Expr::Call(name, args) => {
+            let mut depth = 1;
+            let mut scheduled = Vec::with_capacity(args.len());
+            for (position, arg) in args.iter().enumerate() {
+                let (arg, needed, arg_spans) = schedule_spanned(arg, effects, spans);
+                depth = depth.max(position + needed);
+                scheduled.push(arg);
+                scheduled_spans.extend(arg_spans);
+            }
+            (Expr::Call(name.clone(), scheduled), depth)
+        }
+        Expr::BinOp(left, op, right) => {
+            let (left, left_depth, left_spans) = schedule_spanned(left, effects, spans);
+            let (right, right_depth, right_spans) = schedule_spanned(right, effects, spans);
+            let swap = right_depth > left_depth
+                && is_commutative(*op, &left, &right)
+                && !effects.in_expr(&left)
+                && !effects.in_expr(&right);
+            if swap {
+                let depth = right_depth.max(left_depth + 1);
+                scheduled_spans.extend(right_spans.into_iter().chain(left_spans));
+                (Expr::BinOp(Box::new(right), *op, Box::new(left)), depth)
+            } else {
+                let depth = left_depth.max(right_depth + 1);
+                scheduled_spans.extend(left_spans.into_iter().chain(right_spans));
+                (Expr::BinOp(Box::new(left), *op, Box::new(right)), depth)
+            }
+        }
+        Expr::UnaryOp(op, operand) => {
+            let (operand, depth, operand_spans) = schedule_spanned(operand, effects, spans);
+            scheduled_spans.extend(operand_spans);
+            (Expr::UnaryOp(*op, Box::new(operand)), depth)
+        }
+        Expr::Conditional(condition, then, otherwise) => {
+            let (condition, condition_depth, condition_spans) =
+                schedule_spanned(condition, effects, spans);
+            let (then, then_depth, then_spans) = schedule_spanned(then, effects, spans);
+            let (otherwise, otherwise_depth, otherwise_spans) =
+                schedule_spanned(otherwise, effects, spans);
+            scheduled_spans.extend(condition_spans.into_iter().chain(then_spans));
+            scheduled_spans.extend(otherwise_spans);
+            let depth = condition_depth.max(then_depth).max(otherwise_depth);
+            let expr = Expr::Conditional(Box::new(condition), Box::new(then), Box::new(otherwise));
+            (expr, depth)
+        }
+    };
+    scheduled_spans.extend(spans.next());
+    (expr, depth, scheduled_spans)
+}
+
+// Schedule the main expression and every function body of a script
+pub fn schedule_script(script: &Script) -> Script {
+    schedule_script_spanned(script, &[]).0
+}
+
+// Schedule a script along with the spans from `parse_script_with`, reordering them to match
+// the rewritten expressions. Scripts without spans get none back.
+pub(crate) fn schedule_script_spanned(script: &Script, spans: &[Span]) -> (Script, Vec<Span>) {
+    let mut spans = spans.iter();
+    let mut scheduled_spans = Vec::new();
+    let effects = Effects::new(&script.functions);
+    let mut functions = Vec::with_capacity(script.functions.len());
+    for function in &script.functions {
+        let (body, _, body_spans) = schedule_spanned(&function.body, &effects, &mut spans);
+        scheduled_spans.extend(body_spans);
+        functions.push(Function {
+            body,
+            ..function.clone()
+        });
+    }
+    let (body, _, body_spans) = schedule_spanned(&script.body, &effects, &mut spans);
+    scheduled_spans.extend(body_spans);
+    (Script { functions, body }, scheduled_spans)
+}
+
+// Most values the stack holds while running the code at `start`, which begins with `args`
+// values already pushed. Calls add the depth of the callee on top of the caller's.
+// Returns `None` for recursive code, whose depth depends on its input, and for bytecode
+// that does not decode.
+pub fn max_stack_depth(bytecode: &[u8], start: usize, args: usize) -> Option<usize> {
+    let instructions = try_decode_all(bytecode).ok()?;
+    let index: HashMap<usize, usize> = instructions
+        .iter()
+        .enumerate()
+        .map(|(i, (position, _))| (*position, i))
+        .collect();
+    let mut analysis = DepthAnalysis {
+        instructions: &instructions,
+        index: &index,
+        functions: HashMap::new(),
+        active: HashSet::new(),
+    };
+    analysis.depth(start, args)
+}
+
+struct DepthAnalysis<'a> {
+    instructions: &'a [(usize, Instruction)],
+    index: &'a HashMap<usize, usize>,
+    // Depth of every function analyzed so far, keyed by address
+    functions: HashMap<usize, Option<usize>>,
+    // Functions on the current call path, reaching one again means recursion
+    active: HashSet<usize>,
+}
+
+impl DepthAnalysis<'_> {
+    fn depth(&mut self, start: usize, args: usize) -> Option<usize> {
+        if let Some(&depth) = self.functions.get(&start) {
+            return depth;
+        }
+        if !self.active.insert(start) {
+            return None;
+        }
+
+        let mut max = args;
+        let mut seen = HashSet::new();
+        let mut pending = vec![(start, args)];
+        while let Some((position, depth)) = pending.pop() {
+            let Some(&i) = self.index.get(&position) else {
+                continue;
+            };
+            if !seen.insert(position) {
+                continue;
+            }
+            let instruction = &self.instructions[i].1;
+            let next = position + instruction.size();
+            match *instruction {
+                Instruction::Return => {}
+                Instruction::Jump(address) => pending.push((address, depth)),
+                Instruction::JumpIfFalse(address) => {
+                    let depth = depth.saturating_sub(1);
+                    pending.push((address, depth));
+                    pending.push((next, depth));
+                }
+                // A tail call needs no more room than a call
+                Instruction::Call { address, argc } | Instruction::TailCall { address, argc } => {
+                    let base = depth.saturating_sub(argc);
+                    let callee = self.depth(address, argc);
+                    max = max.max(base + callee?);
+                    pending.push((next, base + 1));
+                }
+                Instruction::Literal(_) | Instruction::LoadArg(_) => {
+                    max = max.max(depth + 1);
+                    pending.push((next, depth + 1));
+                }
+                Instruction::CallHost { argc, .. } => {
+                    let depth = depth.saturating_sub(argc) + 1;
+                    max = max.max(depth);
+                    pending.push((next, depth));
+                }
+                Instruction::Factorial
+                | Instruction::Sqrt
+                | Instruction::Abs
+                | Instruction::Not
+                | Instruction::Negate
+                | Instruction::Len => pending.push((next, depth)),
+                _ => pending.push((next, depth.saturating_sub(1))),
+            }
+        }
+
+        self.active.remove(&start);
+        self.functions.insert(start, Some(max));
+        Some(max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compiler::{codegen, compile, compile_with_params, parse, parse_script},
+        opcode::Opcode,
+        value::Value,
+        vm::Vm,
+    };
+    use rstest::rstest;
+
+    fn encode(instructions: &[Instruction]) -> Vec<u8> {
+        let mut bytecode = Vec::new();
+        for instruction in instructions {
+            instruction.encode(&mut bytecode);
+        }
+        bytecode
+    }
+
+    #[test]
+    fn test_code_after_return() {
+        let bytecode = encode(&[
+            Instruction::Literal(Value::Int(1)),
+            Instruction::Return,
+            Instruction::Literal(Value::Int(2)),
+            Instruction::Addition,
+        ]);
+        let (output, _) = eliminate_dead_code(&bytecode, &[0]);
+        assert_eq!(
+            output,
+            encode(&[Instruction::Literal(Value::Int(1)), Instruction::Return])
+        );
+    }
+
+    #[test]
+    fn test_relocates_jumps_and_calls() {
+        // The jump skips the literal at 5, the call skips the sqrt at 22
+        let bytecode = encode(&[
+            Instruction::Jump(15),
+            Instruction::Literal(Value::Int(99)),
+            Instruction::Call {
+                address: 23,
+                argc: 0,
+            },
+            Instruction::Return,
+            Instruction::Sqrt,
+            Instruction::Literal(Value::Int(16)),
+            Instruction::Return,
+        ]);
+        let (output, entries) = eliminate_dead_code(&bytecode, &[0]);
+        let expected = encode(&[
+            Instruction::Jump(5),
+            Instruction::Call {
+                address: 12,
+                argc: 0,
+            },
+            Instruction::Return,
+            Instruction::Literal(Value::Int(16)),
+            Instruction::Return,
+        ]);
+        assert_eq!(output, expected);
+        assert_eq!(entries, vec![0]);
+        assert_eq!(Vm::new(output, 8).run(), Ok(Value::Int(16)));
+    }
+
+    #[test]
+    fn test_conditional_branches_are_kept() {
+        let bytecode = crate::compiler::compile("1 < 2 ? 3 : 4")
+            .unwrap()
+            .into_code();
+        let (output, _) = eliminate_dead_code(&bytecode, &[0]);
+        assert_eq!(output, bytecode);
+    }
+
+    #[test]
+    fn test_program_entries() {
+        let bytecode = vec![
+            Opcode::Return as u8,
+            Opcode::LoadArg as u8,
+            0,
+            Opcode::Return as u8,
+            Opcode::Sqrt as u8,
+        ];
+        let entry = crate::program::Entry::new("id", 1, vec!["x".to_string()]);
+        let program = Program::with_entries(bytecode, vec![entry]);
+
+        let program = eliminate_dead_program_code(program);
+        assert_eq!(
+            program.bytecode(),
+            &[Opcode::LoadArg as u8, 0, Opcode::Return as u8]
+        );
+        assert_eq!(program.entry("id").unwrap().address(), 0);
+
+        let env = HashMap::from([("x".to_string(), Value::Int(5))]);
+        assert_eq!(Vm::new(program, 8).run_entry("id", &env), Ok(Value::Int(5)));
+    }
+
+    #[rstest]
+    #[case("x * 8", Instruction::Literal(Value::Int(3)), Instruction::ShiftLeft)]
+    #[case("x % 16", Instruction::Literal(Value::Int(15)), Instruction::BitAnd)]
+    #[case("x * 6", Instruction::Literal(Value::Int(6)), Instruction::Multiply)]
+    #[case("x % 1", Instruction::Literal(Value::Int(1)), Instruction::Modulo)]
+    #[case(
+        "x * 2.0",
+        Instruction::Literal(Value::Float(2.0)),
+        Instruction::Multiply
+    )]
+    fn test_reduce_strength(
+        #[case] input: &str,
+        #[case] operand: Instruction,
+        #[case] operation: Instruction,
+    ) {
+        let bytecode = compile_with_params(input, &["x"]).unwrap().into_code();
+        let reduced = reduce_strength(&bytecode);
+        assert_eq!(reduced.len(), bytecode.len());
+        assert_eq!(
+            encode(&[
+                Instruction::LoadArg(0),
+                operand,
+                operation,
+                Instruction::Return
+            ]),
+            reduced
+        );
+    }
+
+    #[rstest]
+    #[case("x * 4", Value::Int(-7))]
+    #[case("x * 4", Value::Float(1.25))]
+    #[case("x * 1024", Value::Int(i64::MAX / 2048))]
+    #[case("x % 8", Value::Int(29))]
+    #[case("x % 8", Value::Int(-29))]
+    #[case("x % 8", Value::Float(-2.5))]
+    #[case("y * 2 - (y % 4) * 16", Value::Int(11))]
+    fn test_reduce_strength_preserves_results(#[case] input: &str, #[case] x: Value) {
+        let params = if input.contains('y') { ["y"] } else { ["x"] };
+        let bytecode = compile_with_params(input, &params).unwrap().into_code();
+        let expected = Vm::new(bytecode.clone(), 8).run_with_args(std::slice::from_ref(&x));
+        let reduced = Vm::new(reduce_strength(&bytecode), 8).run_with_args(&[x]);
+        assert_eq!(reduced, expected);
+    }
+
+    #[test]
+    fn test_reduce_strength_skips_jump_targets() {
+        // The multiplication is reached from both branches, only one pushes the literal 8
+        let bytecode = compile_with_params("x * (x > 0 ? 3 : 8)", &["x"])
+            .unwrap()
+            .into_code();
+        assert_eq!(reduce_strength(&bytecode), bytecode);
+    }
+
+    #[rstest]
+    #[case("1 + (2 + (3 + 4))", 4, 2)]
+    #[case("2 * (3 * (4 * (5 * 6)))", 5, 2)]
+    #[case("(1 + 2) * (3 + 4)", 3, 3)]
+    #[case("\"a\" + (\"b\" + (\"c\" + \"d\"))", 4, 4)]
+    #[case("1 - (2 - (3 - 4))", 4, 4)]
+    #[case("max(1, 2 * (3 * 4))", 4, 3)]
+    #[case("1 == (2 == (3 == 4)) ? 5 : 6", 4, 2)]
+    fn test_schedule(#[case] input: &str, #[case] before: usize, #[case] after: usize) {
+        let expr = parse(input).unwrap();
+        let bytecode = codegen(&expr).unwrap();
+        let (scheduled, depth) = schedule(&expr);
+        let optimized = codegen(&scheduled).unwrap();
+        assert_eq!(max_stack_depth(bytecode.code(), 0, 0), Some(before));
+        assert_eq!(max_stack_depth(optimized.code(), 0, 0), Some(after));
+        assert_eq!(depth, after);
+        assert_eq!(
+            Vm::new(optimized, after).run(),
+            Vm::new(bytecode, before).run()
+        );
+    }
+
+    #[test]
+    fn test_schedule_script() {
+        let input = "fn f(x) { x * (x * (x * x)) } 1 + (2 + sqrt(f(3)))";
+        let script = schedule_script(&parse_script(input).unwrap());
+        assert_eq!(
+            script.functions[0].body,
+            parse("((x * x) * x) * x").unwrap()
+        );
+        assert_eq!(script.body, parse("(2 + sqrt(f(3))) + 1").unwrap());
+
+        // Calls to host functions, or to functions calling them, keep their order
+        let input = "fn f(x) { x * (x * log(x)) } fn g(x) { f(x) } 1 + (2 + g(3))";
+        let script = schedule_script(&parse_script(input).unwrap());
+        assert_eq!(script.functions[0].body, parse("x * (x * log(x))").unwrap());
+        assert_eq!(script.body, parse("1 + (2 + g(3))").unwrap());
+        let (scheduled, _) = schedule(&parse("log(1) * (2 * log(3))").unwrap());
+        assert_eq!(scheduled, parse("log(1) * (2 * log(3))").unwrap());
+    }
+
+    #[rstest]
+    #[case("fn sq(x) { x * x } 1 + sq(2 + 3)", Some(4))]
+    #[case("fn f(x) { f(x) } f(1)", None)]
+    fn test_max_stack_depth_calls(#[case] input: &str, #[case] expected: Option<usize>) {
+        let bytecode = compile(input).unwrap();
+        assert_eq!(max_stack_depth(bytecode.code(), 0, 0), expected);
+    }
+}
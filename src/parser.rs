@@ -0,0 +1,336 @@
+use crate::{
+    compiler::{Expr, Function, Script},
+    error::CompileError,
+    lexer::{lex, NumberParser, Span, Token, TokenKind},
+    operator::{binary_operator, Associativity},
+};
+
+// Errors from `parse` and `parse_script`, which share the location reporting of compilation
+pub type ParseError = CompileError;
+
+// A rule that does not match fails with the offset it stopped at, its caller restores the
+// position to try another alternative or to end a repetition
+type Parsed<T> = Result<T, usize>;
+
+// Parse a complete source, failing on anything left over after the main expression
+pub fn parse_script(input: &str) -> Result<Script, ParseError> {
+    parse_script_with(input, None).map(|(script, _)| script)
+}
+
+// Parse a complete source along with the span of every expression in the order they are
+// completed, that is children before their parent, function bodies before the main
+// expression and arguments from left to right
+pub(crate) fn parse_script_with(
+    input: &str,
+    number_parser: Option<&dyn NumberParser>,
+) -> Result<(Script, Vec<Span>), ParseError> {
+    let mut parser = Parser::new(input, number_parser);
+    let script = parser.script().map_err(|offset| parser.error(offset))?;
+    parser.finish()?;
+    Ok((script, parser.spans))
+}
+
+// Parse a single expression without function definitions
+pub fn parse(input: &str) -> Result<Expr, ParseError> {
+    let mut parser = Parser::new(input, None);
+    let expr = parser.expr().map_err(|offset| parser.error(offset))?;
+    parser.finish()?;
+    Ok(expr)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<Token>,
+    // Where the tokens end, before the end of the input when the lexer met an invalid token
+    end: usize,
+    position: usize,
+    spans: Vec<Span>,
+}
+
+impl Parser<'_> {
+    fn new<'a>(input: &'a str, number_parser: Option<&dyn NumberParser>) -> Parser<'a> {
+        let (tokens, error) = lex(input, number_parser);
+        let end = error.map_or(input.len(), |e| e.offset());
+        Parser {
+            input,
+            tokens,
+            end,
+            position: 0,
+            spans: Vec::new(),
+        }
+    }
+
+    fn error(&self, offset: usize) -> CompileError {
+        CompileError::at("Failed to parse expression", self.input, offset)
+    }
+
+    fn finish(&self) -> Result<(), CompileError> {
+        let offset = self.offset();
+        if offset < self.input.len() {
+            return Err(CompileError::at(
+                "Unexpected trailing input",
+                self.input,
+                offset,
+            ));
+        }
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.position).map(|token| &token.kind)
+    }
+
+    // Source offset of the next token
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .map_or(self.end, |token| token.span.start)
+    }
+
+    fn next(&mut self) -> Option<TokenKind> {
+        let kind = self.peek()?.clone();
+        self.position += 1;
+        Some(kind)
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> Parsed<()> {
+        if self.peek() != Some(&kind) {
+            return Err(self.offset());
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    // The next token if it is one of `ops`
+    fn operator(&mut self, ops: &[char]) -> Option<char> {
+        match self.peek() {
+            Some(&TokenKind::Op(op)) if ops.contains(&op) => {
+                self.position += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    // Run `rule`, leaving the position and spans untouched when it fails
+    fn attempt<T>(&mut self, rule: impl FnOnce(&mut Self) -> Parsed<T>) -> Parsed<T> {
+        let (position, spans) = (self.position, self.spans.len());
+        let result = rule(self);
+        if result.is_err() {
+            self.position = position;
+            self.spans.truncate(spans);
+        }
+        result
+    }
+
+    // Record the span of `expr`, from `start` to the end of the last token it consumed
+    fn node(&mut self, start: usize, expr: Expr) -> Expr {
+        let end = self.tokens[self.position - 1].span.end;
+        self.spans.push(Span { start, end });
+        expr
+    }
+
+    fn identifier(&mut self) -> Parsed<String> {
+        match self.peek() {
+            Some(TokenKind::Ident(name)) => {
+                let name = name.clone();
+                self.position += 1;
+                Ok(name)
+            }
+            _ => Err(self.offset()),
+        }
+    }
+
+    // Parse a comma separated list of items wrapped in parentheses
+    fn arguments<T>(&mut self, mut item: impl FnMut(&mut Self) -> Parsed<T>) -> Parsed<Vec<T>> {
+        self.expect(TokenKind::LParen)?;
+        let mut items = Vec::new();
+        if let Ok(first) = self.attempt(&mut item) {
+            items.push(first);
+            while let Ok(next) = self.attempt(|p| {
+                p.expect(TokenKind::Comma)?;
+                item(p)
+            }) {
+                items.push(next);
+            }
+        }
+        self.expect(TokenKind::RParen)?;
+        Ok(items)
+    }
+
+    // Parse a number, boolean, string, call, variable or parenthesized expression
+    fn operand(&mut self) -> Parsed<Expr> {
+        let offset = self.offset();
+        let expr = match self.next() {
+            Some(TokenKind::Number(value)) => Expr::Number(value),
+            Some(TokenKind::Bool(b)) => Expr::Number(b.into()),
+            Some(TokenKind::Str(s)) => Expr::Str(s),
+            Some(TokenKind::Ident(name)) => match self.attempt(|p| p.arguments(Self::expr)) {
+                Ok(args) => Expr::Call(name, args),
+                Err(_) => Expr::Var(name),
+            },
+            Some(TokenKind::LParen) => {
+                let expr = self.expr()?;
+                self.expect(TokenKind::RParen)?;
+                return Ok(expr);
+            }
+            _ => return Err(offset),
+        };
+        Ok(self.node(offset, expr))
+    }
+
+    // Parse an operand with its unary operators. Postfix operators bind tighter than prefix
+    // ones, so `!x!` is `!(x!)`.
+    fn term(&mut self) -> Parsed<Expr> {
+        let mut prefixes = Vec::new();
+        let mut start = self.offset();
+        while let Some(op) = self.operator(&['!', '¬', '√', '-']) {
+            prefixes.push((op, start));
+            start = self.offset();
+        }
+        let operand = self.operand()?;
+        let mut expr = match self.operator(&['!', '√']) {
+            Some(op) => self.node(start, Expr::UnaryOp(op, Box::new(operand))),
+            None => operand,
+        };
+
+        // Prefix `!` is logical not, `¬` is accepted as an alias for it
+        for (prefix, start) in prefixes.into_iter().rev() {
+            let op = if prefix == '!' { '¬' } else { prefix };
+            expr = self.node(start, Expr::UnaryOp(op, Box::new(expr)));
+        }
+        Ok(expr)
+    }
+
+    // Parse a chain of terms joined by binary operators of at least `precedence`, each
+    // operator taking its right hand side from a recursive call at its own binding power. An
+    // operator without a valid right hand side is left for the caller.
+    fn binary(&mut self, precedence: u8) -> Parsed<Expr> {
+        let start = self.offset();
+        let mut left = self.term()?;
+        while let Some(&TokenKind::Op(symbol)) = self.peek() {
+            let Some(operator) = binary_operator(symbol) else {
+                break;
+            };
+            if operator.precedence < precedence {
+                break;
+            }
+            let next = match operator.associativity {
+                Associativity::Left => operator.precedence + 1,
+                Associativity::Right => operator.precedence,
+            };
+            let Ok(right) = self.attempt(|p| {
+                p.position += 1;
+                p.binary(next)
+            }) else {
+                break;
+            };
+            left = self.node(start, Expr::BinOp(Box::new(left), symbol, Box::new(right)));
+        }
+        Ok(left)
+    }
+
+    // Main expression parser, `cond ? a : b` has the lowest precedence and nests to the right
+    fn expr(&mut self) -> Parsed<Expr> {
+        let start = self.offset();
+        let condition = self.binary(0)?;
+        let branches = self.attempt(|p| {
+            p.expect(TokenKind::Question)?;
+            let then = p.expr()?;
+            p.expect(TokenKind::Colon)?;
+            Ok((then, p.expr()?))
+        });
+        match branches {
+            Ok((then, otherwise)) => {
+                let conditional =
+                    Expr::Conditional(Box::new(condition), Box::new(then), Box::new(otherwise));
+                Ok(self.node(start, conditional))
+            }
+            Err(_) => Ok(condition),
+        }
+    }
+
+    // Parse function definitions like `fn double(x) { x * 2 }`
+    fn function(&mut self) -> Parsed<Function> {
+        if self.identifier()? != "fn" {
+            return Err(self.offset());
+        }
+        let name = self.identifier()?;
+        let params = self.arguments(Self::identifier)?;
+        self.expect(TokenKind::LBrace)?;
+        let body = self.expr()?;
+        self.expect(TokenKind::RBrace)?;
+        Ok(Function { name, params, body })
+    }
+
+    // Parse any number of function definitions followed by the main expression
+    fn script(&mut self) -> Parsed<Script> {
+        let mut functions = Vec::new();
+        while let Ok(function) = self.attempt(Self::function) {
+            functions.push(function);
+        }
+        let body = self.expr()?;
+        Ok(Script { functions, body })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+    use rstest::rstest;
+
+    fn int(n: i64) -> Box<Expr> {
+        Box::new(Expr::Number(Value::Int(n)))
+    }
+
+    #[rstest]
+    #[case("1 - -2", Expr::BinOp(int(1), '-', int(-2)))]
+    #[case("-(2)", Expr::UnaryOp('-', int(2)))]
+    #[case("!-3!", Expr::UnaryOp('¬', Box::new(Expr::UnaryOp('!', int(-3)))))]
+    #[case("x ? 1 : 2", Expr::Conditional(Box::new(Expr::Var("x".to_string())), int(1), int(2)))]
+    #[case("f()", Expr::Call("f".to_string(), vec![]))]
+    fn test_parse(#[case] input: &str, #[case] expected: Expr) {
+        assert_eq!(parse(input), Ok(expected));
+    }
+
+    #[rstest]
+    #[case("1 + * 2", "Unexpected trailing input", 2)]
+    #[case("1 ? 2", "Unexpected trailing input", 2)]
+    #[case("1 < 2 + * 3", "Unexpected trailing input", 6)]
+    #[case("f(1,)", "Unexpected trailing input", 1)]
+    #[case("2 + 3 $", "Unexpected trailing input", 6)]
+    #[case("(1 + 2", "Failed to parse expression", 6)]
+    #[case(r#""open"#, "Failed to parse expression", 0)]
+    fn test_parse_error_offsets(#[case] input: &str, #[case] message: &str, #[case] offset: usize) {
+        let error = parse(input).unwrap_err();
+        assert_eq!((error.message(), error.offset()), (message, offset));
+    }
+
+    #[test]
+    fn test_spans() {
+        let (_, spans) = parse_script_with("fn f(x) { -x } 1 + f((2))!", None).unwrap();
+        let spans: Vec<(usize, usize)> = spans.iter().map(|span| (span.start, span.end)).collect();
+        assert_eq!(
+            spans,
+            vec![
+                (11, 12),
+                (10, 12),
+                (15, 16),
+                (22, 23),
+                (19, 25),
+                (19, 26),
+                (15, 26)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_incomplete_function_is_an_expression() {
+        let error = parse_script("fn f(x) { x").unwrap_err();
+        assert_eq!(
+            (error.message(), error.token()),
+            ("Unexpected trailing input", "f")
+        );
+    }
+}
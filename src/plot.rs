@@ -0,0 +1,114 @@
+use std::fmt::Write;
+
+use crate::value::Value;
+
+// Render a tabulated function as two aligned columns, failed evaluations show as `error`
+pub fn render_table(header: &str, rows: &[(f64, Option<Value>)]) -> String {
+    let cells: Vec<(String, String)> = rows
+        .iter()
+        .map(|(x, value)| (x.to_string(), display(value)))
+        .collect();
+    let width = cells.iter().map(|(x, _)| x.len()).max().unwrap_or(1);
+
+    let mut output = String::new();
+    writeln!(output, "{:>width$} | {}", "x", header).unwrap();
+    writeln!(
+        output,
+        "{}-+-{}",
+        "-".repeat(width),
+        "-".repeat(header.len())
+    )
+    .unwrap();
+    for (x, value) in cells {
+        writeln!(output, "{:>width$} | {}", x, value).unwrap();
+    }
+    output
+}
+
+// Render one row per point with a `*` placed between the smallest and largest numeric
+// values, rows whose value is not a number are left empty
+pub fn render_plot(rows: &[(f64, Option<Value>)], width: usize) -> String {
+    let numbers: Vec<Option<f64>> = rows.iter().map(|(_, value)| number(value)).collect();
+    let (min, max) = numbers
+        .iter()
+        .flatten()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &n| {
+            (min.min(n), max.max(n))
+        });
+    let labels: Vec<String> = rows.iter().map(|(x, _)| x.to_string()).collect();
+    let label_width = labels.iter().map(String::len).max().unwrap_or(0);
+    let last = width.saturating_sub(1) as f64;
+
+    let mut output = String::new();
+    for (label, n) in labels.iter().zip(numbers) {
+        let column = match n {
+            Some(n) if max > min => Some(((n - min) / (max - min) * last).round() as usize),
+            Some(_) => Some(0),
+            None => None,
+        };
+        let line = match column {
+            Some(column) => format!("{}*", " ".repeat(column)),
+            None => String::new(),
+        };
+        writeln!(output, "{:>label_width$} |{}", label, line).unwrap();
+    }
+    output
+}
+
+fn display(value: &Option<Value>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "error".to_string(),
+    }
+}
+
+fn number(value: &Option<Value>) -> Option<f64> {
+    match *value {
+        Some(Value::Int(n)) => Some(n as f64),
+        Some(Value::Float(n)) if n.is_finite() => Some(n),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_table() {
+        let rows = [
+            (0.0, Some(Value::Float(-2.0))),
+            (0.5, Some(Value::Float(-1.75))),
+            (10.0, None),
+        ];
+        let expected = "  x | x*x - 2
+----+--------
+  0 | -2
+0.5 | -1.75
+ 10 | error
+";
+        assert_eq!(render_table("x*x - 2", &rows), expected);
+    }
+
+    #[test]
+    fn test_render_plot() {
+        let rows = [
+            (0.0, Some(Value::Int(0))),
+            (1.0, Some(Value::Int(2))),
+            (2.0, Some(Value::Float(f64::NAN))),
+            (3.0, Some(Value::Float(4.0))),
+        ];
+        let expected = "0 |*
+1 |  *
+2 |
+3 |    *
+";
+        assert_eq!(render_plot(&rows, 5), expected);
+    }
+
+    #[test]
+    fn test_render_plot_constant() {
+        let rows = [(0.0, Some(Value::Int(7))), (1.0, Some(Value::Int(7)))];
+        assert_eq!(render_plot(&rows, 10), "0 |*\n1 |*\n");
+    }
+}
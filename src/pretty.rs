@@ -0,0 +1,262 @@
+use crate::{
+    compiler::{Expr, Script},
+    error::CompileError,
+    lexer::tokenize,
+    operator::binary_operator,
+    parser::parse_script,
+    value::Value,
+};
+
+// Write `script` in the canonical layout: one function definition per line followed by
+// the main expression, single spaces around binary operators and after commas, and
+// parentheses only where the tree needs them to read back the same
+pub fn pretty(script: &Script) -> String {
+    let mut output = String::new();
+    for function in &script.functions {
+        output.push_str(&format!(
+            "fn {}({}) {{ {} }}\n",
+            function.name,
+            function.params.join(", "),
+            pretty_expr(&function.body)
+        ));
+    }
+    output.push_str(&pretty_expr(&script.body));
+    output.push('\n');
+    output
+}
+
+pub fn pretty_expr(expr: &Expr) -> String {
+    match expr {
+        Expr::Number(value) => literal(value),
+        Expr::Str(value) => quote(value),
+        Expr::Var(name) => name.clone(),
+        Expr::Call(name, args) => {
+            let args: Vec<String> = args.iter().map(pretty_expr).collect();
+            format!("{}({})", name, args.join(", "))
+        }
+        Expr::BinOp(left, op, right) => {
+            let binding = precedence(expr);
+            let left = match precedence(left) < binding {
+                true => format!("({})", pretty_expr(left)),
+                false => pretty_expr(left),
+            };
+            // Operators are left associative, so an equal one on the right was grouped
+            let right = match precedence(right) <= binding {
+                true => format!("({})", pretty_expr(right)),
+                false => pretty_expr(right),
+            };
+            let spelling = binary_operator(*op).map_or("?", |operator| operator.spelling);
+            format!("{} {} {}", left, spelling, right)
+        }
+        // Factorial is the only operator written after its operand. It binds tighter than
+        // the prefix operators and an operand takes only one of it.
+        Expr::UnaryOp('!', operand) => match **operand {
+            Expr::Number(_) | Expr::Str(_) | Expr::Var(_) | Expr::Call(..) => {
+                format!("{}!", pretty_expr(operand))
+            }
+            _ => format!("({})!", pretty_expr(operand)),
+        },
+        Expr::UnaryOp(op, operand) => {
+            let op = if *op == '¬' { '!' } else { *op };
+            let operand = match **operand {
+                Expr::BinOp(..) | Expr::Conditional(..) => format!("({})", pretty_expr(operand)),
+                _ => pretty_expr(operand),
+            };
+            // A negation directly followed by digits would read back as a negative literal
+            match op == '-' && operand.starts_with(|c: char| c.is_ascii_digit()) {
+                true => format!("-({})", operand),
+                false => format!("{}{}", op, operand),
+            }
+        }
+        Expr::Conditional(condition, then, otherwise) => {
+            let condition = match **condition {
+                Expr::Conditional(..) => format!("({})", pretty_expr(condition)),
+                _ => pretty_expr(condition),
+            };
+            format!(
+                "{} ? {} : {}",
+                condition,
+                pretty_expr(then),
+                pretty_expr(otherwise)
+            )
+        }
+    }
+}
+
+// Parse `input` and write it back with `pretty`. Comments are not part of the syntax tree,
+// so sources with comments are refused rather than formatted without them.
+pub fn format_source(input: &str) -> Result<String, CompileError> {
+    let script = parse_script(input)?;
+    // Anything but whitespace between the tokens is a comment
+    let tokens = tokenize(input)?;
+    let ends = [0]
+        .into_iter()
+        .chain(tokens.iter().map(|token| token.span.end));
+    let starts = tokens
+        .iter()
+        .map(|token| token.span.start)
+        .chain([input.len()]);
+    for (end, start) in ends.zip(starts) {
+        let gap = &input[end..start];
+        let trimmed = gap.trim_start();
+        if !trimmed.is_empty() {
+            let offset = end + gap.len() - trimmed.len();
+            return Err(CompileError::new("Comments cannot be formatted").spanning(
+                input,
+                offset,
+                offset + trimmed.trim_end().len(),
+            ));
+        }
+    }
+    Ok(pretty(&script))
+}
+
+// Draw the syntax tree of `script` with one node per line, children indented below their
+// parent. Nodes are named like in `Expr::to_json`.
+pub fn tree(script: &Script) -> String {
+    let mut output = String::new();
+    for function in &script.functions {
+        output.push_str(&format!(
+            "function {}({})\n",
+            function.name,
+            function.params.join(", ")
+        ));
+        tree_node(&function.body, 1, &mut output);
+    }
+    tree_node(&script.body, 0, &mut output);
+    output
+}
+
+fn tree_node(expr: &Expr, depth: usize, output: &mut String) {
+    let (label, children): (String, Vec<&Expr>) = match expr {
+        Expr::Number(value) => (format!("{} {}", value.type_name(), literal(value)), vec![]),
+        Expr::Str(value) => (format!("string {}", quote(value)), vec![]),
+        Expr::Var(name) => (format!("variable {}", name), vec![]),
+        Expr::Call(name, args) => (format!("call {}", name), args.iter().collect()),
+        Expr::BinOp(left, op, right) => {
+            let spelling = binary_operator(*op).map_or("?", |operator| operator.spelling);
+            (format!("binary {}", spelling), vec![left, right])
+        }
+        Expr::UnaryOp(op, operand) => {
+            let name = match op {
+                '!' => "factorial",
+                '¬' => "not",
+                '√' => "sqrt",
+                '-' => "negate",
+                _ => "unknown",
+            };
+            (format!("unary {}", name), vec![operand])
+        }
+        Expr::Conditional(condition, then, otherwise) => {
+            ("conditional".to_string(), vec![condition, then, otherwise])
+        }
+    };
+    output.push_str(&format!("{}{}\n", "  ".repeat(depth), label));
+    for child in children {
+        tree_node(child, depth + 1, output);
+    }
+}
+
+// Binding power of an expression as an operand, operands that never need parentheses bind
+// tightest and conditionals loosest
+fn precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::BinOp(_, op, _) => binary_operator(*op).map_or(0, |operator| operator.precedence),
+        Expr::Conditional(..) => 0,
+        _ => u8::MAX,
+    }
+}
+
+// Numbers as the lexer reads them back, floats always with a fraction so they stay floats.
+// Literals are never infinite or NaN, those only come from the constants of the same name.
+fn literal(value: &Value) -> String {
+    match value {
+        Value::Float(value) if value.is_nan() => "nan".to_string(),
+        Value::Float(value) if value.is_infinite() && *value > 0.0 => "inf".to_string(),
+        Value::Float(value) if value.is_infinite() => "-inf".to_string(),
+        Value::Float(value) if value.fract() == 0.0 => format!("{}.0", value),
+        Value::Str(value) => quote(value),
+        value => value.to_string(),
+    }
+}
+
+fn quote(value: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("1+2*3", "1 + 2 * 3\n")]
+    #[case("1 + (2 * 3)", "1 + (2 * 3)\n")]
+    #[case("(1 + 2) * 3", "1 + 2 * 3\n")]
+    #[case("1 < 2 == (3 < 4)", "1 < 2 == (3 < 4)\n")]
+    #[case("(a || b) && c", "(a || b) && c\n")]
+    #[case("a || (b && c)", "a || b && c\n")]
+    #[case("1.50 + 2.0 - -3", "1.5 + 2.0 - -3\n")]
+    #[case("-(3)!", "-(3!)\n")]
+    #[case("(-3)!", "-3!\n")]
+    #[case("(-x)! + (x!)!", "(-x)! + (x!)!\n")]
+    #[case("x√ + √-x", "√x + √-x\n")]
+    #[case("!(a && b) ? ¬c : -(1 + x)", "!(a && b) ? !c : -(1 + x)\n")]
+    #[case("(a ? b : c) ? d : (e ? f : g)", "(a ? b : c) ? d : e ? f : g\n")]
+    #[case("1 + (a ? 2 : 3)", "1 + (a ? 2 : 3)\n")]
+    #[case(r#"len( "a\"b\\c\n" )"#, "len(\"a\\\"b\\\\c\\n\")\n")]
+    #[case(
+        "fn f(x,y){x*y}\n\n  fn g( ) { 1 }  f(2,g())",
+        "fn f(x, y) { x * y }\nfn g() { 1 }\nf(2, g())\n"
+    )]
+    fn test_format_source(#[case] input: &str, #[case] expected: &str) {
+        let formatted = format_source(input).unwrap();
+        assert_eq!(formatted, expected);
+        assert_eq!(parse_script(&formatted), parse_script(input));
+        assert_eq!(format_source(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn test_tree() {
+        let script = parse_script("fn f(x) { -x! } f(1.0) > 2 ? \"a\" : len(s)").unwrap();
+        assert_eq!(
+            tree(&script),
+            [
+                "function f(x)",
+                "  unary negate",
+                "    unary factorial",
+                "      variable x",
+                "conditional",
+                "  binary >",
+                "    call f",
+                "      float 1.0",
+                "    int 2",
+                "  string \"a\"",
+                "  call len",
+                "    variable s",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[rstest]
+    #[case("1 + # one\n2", "Comments cannot be formatted", "# one")]
+    #[case("1 /* two */ + 2", "Comments cannot be formatted", "/* two */")]
+    #[case("1 +", "Unexpected trailing input", "+")]
+    fn test_format_errors(#[case] input: &str, #[case] message: &str, #[case] token: &str) {
+        let error = format_source(input).unwrap_err();
+        assert_eq!((error.message(), error.token()), (message, token));
+    }
+}
@@ -0,0 +1,721 @@
+use std::{
+    io::{self, Read, Write},
+    marker::PhantomData,
+};
+
+use crate::{
+    chunk::{Chunk, DebugInfo},
+    error::DecodeError,
+    lexer::Span,
+    opcode::Opcode,
+    optimize::max_stack_depth,
+    value::Value,
+};
+
+// Serialized programs start with a fixed magic and format version
+pub const MAGIC: &[u8; 4] = b"RVMB";
+pub const FORMAT_VERSION: u8 = 2;
+// At most one compression flag is set, the sections are compressed as a whole
+const FLAG_ZSTD: u8 = 0b0000_0001;
+const FLAG_DEFLATE: u8 = 0b0000_0010;
+
+// Identifiers of the sections following the header. Each section is its identifier, the
+// length of its contents and the contents, readers skip identifiers they do not know.
+const SECTION_CONSTANTS: u8 = 1;
+const SECTION_CODE: u8 = 2;
+const SECTION_DEBUG_INFO: u8 = 3;
+const SECTION_FUNCTIONS: u8 = 4;
+const SECTION_HOST_FUNCTIONS: u8 = 5;
+
+// Compression of the sections of a serialized program along with its level, each kind
+// needs the feature of the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+    #[cfg(feature = "deflate")]
+    Deflate(u32),
+}
+
+// Name of the entry point holding the main expression, of cached programs and of those
+// compiled with free variables
+pub const ENTRY: &str = "main";
+
+// A named entry point into a program along with the parameters it expects
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    name: String,
+    address: usize,
+    params: Vec<String>,
+}
+
+impl Entry {
+    pub fn new<N>(name: N, address: usize, params: Vec<String>) -> Entry
+    where
+        N: Into<String>,
+    {
+        Entry {
+            name: name.into(),
+            address,
+            params,
+        }
+    }
+
+    // The same entry point moved to `address`
+    pub fn with_address(self, address: usize) -> Entry {
+        Entry { address, ..self }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn address(&self) -> usize {
+        self.address
+    }
+
+    pub fn params(&self) -> &[String] {
+        &self.params
+    }
+}
+
+// Compiled code and its named entry points, ready to be handed to the VM
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    chunk: Chunk,
+    entries: Vec<Entry>,
+}
+
+impl Program {
+    pub fn with_entries<C>(chunk: C, entries: Vec<Entry>) -> Program
+    where
+        C: Into<Chunk>,
+    {
+        Program {
+            chunk: chunk.into(),
+            entries,
+        }
+    }
+
+    pub fn with_debug_info(self, debug_info: DebugInfo) -> Program {
+        Program {
+            chunk: self.chunk.with_debug_info(debug_info),
+            ..self
+        }
+    }
+
+    pub fn builder() -> ProgramBuilder<Empty> {
+        ProgramBuilder {
+            bytecode: Vec::new(),
+            state: PhantomData,
+        }
+    }
+
+    pub fn bytecode(&self) -> &[u8] {
+        self.chunk.code()
+    }
+
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    pub fn entry(&self, name: &str) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.chunk.debug_info()
+    }
+
+    pub fn host_functions(&self) -> &[String] {
+        self.chunk.host_functions()
+    }
+
+    // Most values the stack holds while running any entry point, or the code at address 0
+    // when there are none. This is the smallest stack size the program runs with, `None`
+    // when it recurses and the depth depends on the input.
+    pub fn stack_depth(&self) -> Option<usize> {
+        if self.entries.is_empty() {
+            return max_stack_depth(self.bytecode(), 0, 0);
+        }
+        self.entries.iter().try_fold(0, |depth, entry| {
+            let entry_depth = max_stack_depth(self.bytecode(), entry.address, entry.params.len())?;
+            Some(depth.max(entry_depth))
+        })
+    }
+
+    pub fn into_parts(self) -> (Chunk, Vec<Entry>) {
+        (self.chunk, self.entries)
+    }
+
+    // Serialize into the container format: magic, version and flags followed by the
+    // sections, the function tables and debug info are only written when there are any
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = header(0);
+        bytes.extend(self.body());
+        bytes
+    }
+
+    // Serialize like `to_bytes` with the sections compressed, the header flags record how
+    // so `from_bytes` needs no options
+    #[cfg(any(feature = "zstd", feature = "deflate"))]
+    pub fn to_compressed_bytes(&self, compression: Compression) -> Vec<u8> {
+        let body = self.body();
+        let (flags, body) = match compression {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => (
+                FLAG_ZSTD,
+                zstd::bulk::compress(&body, level).expect("zstd compression failed"),
+            ),
+            #[cfg(feature = "deflate")]
+            Compression::Deflate(level) => {
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::new(level));
+                encoder
+                    .write_all(&body)
+                    .expect("deflate compression failed");
+                (
+                    FLAG_DEFLATE,
+                    encoder.finish().expect("deflate compression failed"),
+                )
+            }
+        };
+        let mut bytes = header(flags);
+        bytes.extend(body);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, DecodeError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(DecodeError::InvalidMagic);
+        }
+        let version = reader.u8()?;
+        if version != FORMAT_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+
+        match reader.u8()? {
+            0 => Program::from_body(reader.bytes),
+            FLAG_ZSTD => Program::from_body(&unzstd(reader.bytes)?),
+            FLAG_DEFLATE => Program::from_body(&inflate(reader.bytes)?),
+            flags => Err(DecodeError::UnsupportedFlags(flags)),
+        }
+    }
+
+    // Write the serialized program to a file or any other sink, see `to_bytes`
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+
+    // Write the program compressed, see `to_compressed_bytes`. `read_from` reads it back.
+    #[cfg(any(feature = "zstd", feature = "deflate"))]
+    pub fn write_compressed_to<W: Write>(
+        &self,
+        mut writer: W,
+        compression: Compression,
+    ) -> io::Result<()> {
+        writer.write_all(&self.to_compressed_bytes(compression))
+    }
+
+    // Read a program written by `write_to` until the end of `reader`. Programs that fail to
+    // decode, like those of another format version, are reported as `InvalidData` errors
+    // wrapping the `DecodeError`.
+    pub fn read_from<R: Read>(mut reader: R) -> io::Result<Program> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        Program::from_bytes(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn body(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        if !self.entries.is_empty() {
+            let mut functions = Vec::new();
+            functions.extend((self.entries.len() as u32).to_be_bytes());
+            for entry in &self.entries {
+                write_str(&mut functions, &entry.name);
+                functions.extend((entry.address as u32).to_be_bytes());
+                functions.push(entry.params.len() as u8);
+                for param in &entry.params {
+                    write_str(&mut functions, param);
+                }
+            }
+            write_section(&mut bytes, SECTION_FUNCTIONS, &functions);
+        }
+        if !self.chunk.constants().is_empty() {
+            let mut constants = Vec::new();
+            for constant in self.chunk.constants() {
+                let value = constant.to_vec();
+                constants.extend((value.len() as u32).to_be_bytes());
+                constants.extend(value);
+            }
+            write_section(&mut bytes, SECTION_CONSTANTS, &constants);
+        }
+        if !self.host_functions().is_empty() {
+            let mut names = Vec::new();
+            for name in self.host_functions() {
+                write_str(&mut names, name);
+            }
+            write_section(&mut bytes, SECTION_HOST_FUNCTIONS, &names);
+        }
+        write_section(&mut bytes, SECTION_CODE, self.bytecode());
+        if let Some(debug_info) = self.debug_info() {
+            let mut locations = Vec::new();
+            for &(address, span) in debug_info.locations() {
+                for field in [address, span.start, span.end] {
+                    locations.extend((field as u32).to_be_bytes());
+                }
+            }
+            write_section(&mut bytes, SECTION_DEBUG_INFO, &locations);
+        }
+        bytes
+    }
+
+    fn from_body(body: &[u8]) -> Result<Program, DecodeError> {
+        let mut reader = Reader { bytes: body };
+        let mut seen = Vec::new();
+        let (mut bytecode, mut entries) = (None, Vec::new());
+        let (mut constants, mut debug_info) = (Vec::new(), None);
+        let mut host_functions = Vec::new();
+        while !reader.bytes.is_empty() {
+            let id = reader.u8()?;
+            let length = reader.u32()? as usize;
+            let mut section = Reader {
+                bytes: reader.take(length)?,
+            };
+            if seen.contains(&id) {
+                return Err(DecodeError::DuplicateSection(id));
+            }
+            seen.push(id);
+            match id {
+                SECTION_CONSTANTS => constants = section.constants()?,
+                SECTION_CODE => bytecode = Some(section.take(length)?.to_vec()),
+                SECTION_FUNCTIONS => entries = section.entries()?,
+                SECTION_DEBUG_INFO => debug_info = Some(section.debug_info()?),
+                SECTION_HOST_FUNCTIONS => host_functions = section.strings()?,
+                // Written by a newer version of the format
+                _ => continue,
+            }
+            if !section.bytes.is_empty() {
+                return Err(DecodeError::TrailingBytes);
+            }
+        }
+
+        let bytecode = bytecode.ok_or(DecodeError::MissingSection(SECTION_CODE))?;
+        let mut chunk = Chunk::new(bytecode)
+            .with_constants(constants)
+            .with_host_functions(host_functions);
+        if let Some(debug_info) = debug_info {
+            chunk = chunk.with_debug_info(debug_info);
+        }
+        Ok(Program::with_entries(chunk, entries))
+    }
+}
+
+fn write_section(bytes: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    bytes.push(id);
+    bytes.extend((contents.len() as u32).to_be_bytes());
+    bytes.extend(contents);
+}
+
+fn header(flags: u8) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(FORMAT_VERSION);
+    bytes.push(flags);
+    bytes
+}
+
+fn write_str(bytes: &mut Vec<u8>, value: &str) {
+    bytes.extend((value.len() as u16).to_be_bytes());
+    bytes.extend(value.as_bytes());
+}
+
+#[cfg(feature = "zstd")]
+fn unzstd(bytes: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    zstd::stream::decode_all(bytes).map_err(|_| DecodeError::InvalidCompressed)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn unzstd(_: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    Err(DecodeError::CompressionUnsupported("zstd"))
+}
+
+#[cfg(feature = "deflate")]
+fn inflate(bytes: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut body = Vec::new();
+    flate2::read::DeflateDecoder::new(bytes)
+        .read_to_end(&mut body)
+        .map_err(|_| DecodeError::InvalidCompressed)?;
+    Ok(body)
+}
+
+#[cfg(not(feature = "deflate"))]
+fn inflate(_: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    Err(DecodeError::CompressionUnsupported("deflate"))
+}
+
+// Fallible reader for untrusted serialized programs
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < length {
+            return Err(DecodeError::Truncated);
+        }
+        let (head, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let length = self.u16()? as usize;
+        let bytes = self.take(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    fn entries(&mut self) -> Result<Vec<Entry>, DecodeError> {
+        let count = self.u32()? as usize;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let name = self.string()?;
+            let address = self.u32()? as usize;
+            let params = (0..self.u8()?)
+                .map(|_| self.string())
+                .collect::<Result<Vec<String>, DecodeError>>()?;
+            entries.push(Entry::new(name, address, params));
+        }
+        Ok(entries)
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>, DecodeError> {
+        let mut strings = Vec::new();
+        while !self.bytes.is_empty() {
+            strings.push(self.string()?);
+        }
+        Ok(strings)
+    }
+
+    fn constants(&mut self) -> Result<Vec<Value>, DecodeError> {
+        let mut constants = Vec::new();
+        while !self.bytes.is_empty() {
+            let length = self.u32()? as usize;
+            constants.push(Value::try_from(self.take(length)?)?);
+        }
+        Ok(constants)
+    }
+
+    fn debug_info(&mut self) -> Result<DebugInfo, DecodeError> {
+        let mut locations = Vec::new();
+        while !self.bytes.is_empty() {
+            let address = self.u32()? as usize;
+            let (start, end) = (self.u32()? as usize, self.u32()? as usize);
+            locations.push((address, Span { start, end }));
+        }
+        Ok(DebugInfo::new(locations))
+    }
+}
+
+impl From<Vec<u8>> for Program {
+    fn from(bytecode: Vec<u8>) -> Self {
+        Program::with_entries(bytecode, Vec::new())
+    }
+}
+
+impl From<&[u8]> for Program {
+    fn from(bytecode: &[u8]) -> Self {
+        Program::from(bytecode.to_vec())
+    }
+}
+
+impl From<Chunk> for Program {
+    fn from(chunk: Chunk) -> Self {
+        Program::with_entries(chunk, Vec::new())
+    }
+}
+
+impl From<Program> for Vec<u8> {
+    fn from(program: Program) -> Self {
+        program.chunk.into_code()
+    }
+}
+
+// Type level stack depth used by the builder: `Push<Push<Empty>>` holds two values
+pub struct Empty;
+pub struct Push<S>(PhantomData<S>);
+pub struct Returned;
+
+// Builds bytecode while tracking the stack depth in its type, so programs that would
+// underflow the stack or return the wrong number of values are rejected by rustc
+pub struct ProgramBuilder<S> {
+    bytecode: Vec<u8>,
+    state: PhantomData<S>,
+}
+
+impl<S> ProgramBuilder<S> {
+    fn emit<T>(mut self, opcode: Opcode) -> ProgramBuilder<T> {
+        self.bytecode.push(opcode as u8);
+        ProgramBuilder {
+            bytecode: self.bytecode,
+            state: PhantomData,
+        }
+    }
+
+    pub fn lit<V>(self, value: V) -> ProgramBuilder<Push<S>>
+    where
+        V: Into<Value>,
+    {
+        let mut builder = self.emit(Opcode::Literal);
+        builder.bytecode.extend(value.into().to_vec());
+        builder
+    }
+}
+
+impl<S> ProgramBuilder<Push<S>> {
+    pub fn factorial(self) -> ProgramBuilder<Push<S>> {
+        self.emit(Opcode::Factorial)
+    }
+
+    pub fn sqrt(self) -> ProgramBuilder<Push<S>> {
+        self.emit(Opcode::Sqrt)
+    }
+}
+
+impl<S> ProgramBuilder<Push<Push<S>>> {
+    pub fn add(self) -> ProgramBuilder<Push<S>> {
+        self.emit(Opcode::Addition)
+    }
+
+    pub fn sub(self) -> ProgramBuilder<Push<S>> {
+        self.emit(Opcode::Subtract)
+    }
+
+    pub fn mul(self) -> ProgramBuilder<Push<S>> {
+        self.emit(Opcode::Multiply)
+    }
+
+    pub fn div(self) -> ProgramBuilder<Push<S>> {
+        self.emit(Opcode::Divide)
+    }
+
+    pub fn rem(self) -> ProgramBuilder<Push<S>> {
+        self.emit(Opcode::Modulo)
+    }
+}
+
+impl ProgramBuilder<Push<Empty>> {
+    pub fn ret(self) -> ProgramBuilder<Returned> {
+        self.emit(Opcode::Return)
+    }
+}
+
+impl ProgramBuilder<Returned> {
+    pub fn build(self) -> Program {
+        Program::from(self.bytecode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compiler::{compile, compile_program, compile_unit, CompileOptions},
+        vm::Vm,
+    };
+    use rstest::rstest;
+
+    fn run(program: Program) -> Value {
+        let mut vm = Vm::new(program, 32);
+        vm.run().unwrap()
+    }
+
+    #[test]
+    fn test_builder_arithmetic() {
+        let program = Program::builder().lit(2).lit(3).add().ret().build();
+        assert_eq!(run(program), Value::Int(5));
+
+        let program = Program::builder()
+            .lit(10)
+            .lit(2.5)
+            .lit(1.5)
+            .add()
+            .div()
+            .ret()
+            .build();
+        assert_eq!(run(program), Value::Float(2.5));
+    }
+
+    #[test]
+    fn test_builder_unary() {
+        let program = Program::builder().lit(4).factorial().ret().build();
+        assert_eq!(run(program), Value::Int(24));
+
+        let program = Program::builder().lit(16).sqrt().ret().build();
+        assert_eq!(run(program), Value::Float(4.0));
+    }
+
+    #[rstest]
+    #[case(Program::builder().lit(1).lit(2).add().ret().build(), "1 + 2")]
+    #[case(Program::builder().lit(7).lit(3).rem().ret().build(), "7 % 3")]
+    #[case(Program::builder().lit(2).lit(3).mul().lit(1).sub().ret().build(), "2 * 3 - 1")]
+    #[case(Program::builder().lit(5).factorial().ret().build(), "5!")]
+    fn test_builder_matches_compiler(#[case] program: Program, #[case] input: &str) {
+        assert_eq!(program.bytecode(), compile(input).unwrap().code());
+    }
+
+    #[test]
+    fn test_stack_depth() {
+        let program = compile_unit(&[("a", "x + (y + 1)"), ("b", "2 * x")]).unwrap();
+        assert_eq!(program.stack_depth(), Some(5));
+        let program = Program::builder().lit(1).lit(2).add().ret().build();
+        assert_eq!(program.stack_depth(), Some(2));
+        assert_eq!(Program::from(vec![0xFF]).stack_depth(), None);
+        assert_eq!(
+            Program::from(vec![Opcode::Literal as u8]).stack_depth(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_serialization_roundtrip() {
+        let program = compile_unit(&[("area", "w * h"), ("double", "2 * x")]).unwrap();
+        let bytes = program.to_bytes();
+        assert_eq!(&bytes[..7], b"RVMB\x02\x00\x04");
+        assert_eq!(Program::from_bytes(&bytes), Ok(program));
+
+        let program = Program::builder().lit(1).lit(2).add().ret().build();
+        assert_eq!(Program::from_bytes(&program.to_bytes()), Ok(program));
+    }
+
+    #[test]
+    fn test_debug_info_roundtrip() {
+        let options = CompileOptions::new().debug_info(true);
+        let program = compile_program("(1 + 2) / 0", &options).unwrap();
+        let bytes = program.to_bytes();
+        assert_eq!(bytes[6], SECTION_CODE);
+        assert_eq!(Program::from_bytes(&bytes), Ok(program.clone()));
+
+        let debug_info = program.debug_info().unwrap();
+        let &(address, span) = debug_info.locations().last().unwrap();
+        assert_eq!(program.bytecode()[address], Opcode::Divide as u8);
+        assert_eq!((span.start, span.end), (0, 11));
+        assert_eq!(debug_info.span_at(address + 1), None);
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let program = compile_unit(&[("area", "w * h")]).unwrap();
+        let mut file = Vec::new();
+        program.write_to(&mut file).unwrap();
+        assert_eq!(file, program.to_bytes());
+        assert_eq!(Program::read_from(file.as_slice()).unwrap(), program);
+
+        let error = Program::read_from(&b"RVMB\x07\x00"[..]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let cause = error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<DecodeError>());
+        assert_eq!(cause, Some(&DecodeError::UnsupportedVersion(7)));
+    }
+
+    #[rstest]
+    #[case(b"RVMA\x02\x00".to_vec(), DecodeError::InvalidMagic)]
+    #[case(b"RVMB\x01\x00".to_vec(), DecodeError::UnsupportedVersion(1))]
+    #[case(b"RVMB\x02".to_vec(), DecodeError::Truncated)]
+    #[case(b"RVMB\x02\x03".to_vec(), DecodeError::UnsupportedFlags(3))]
+    #[case(b"RVMB\x02\x04".to_vec(), DecodeError::UnsupportedFlags(4))]
+    #[case(b"RVMB\x02\x00\x02\x00\x00\x00\x05\x06".to_vec(), DecodeError::Truncated)]
+    #[case(b"RVMB\x02\x00".to_vec(), DecodeError::MissingSection(2))]
+    #[case(b"RVMB\x02\x00\x02\x00\x00\x00\x01\x06\x02\x00\x00\x00\x01\x06".to_vec(), DecodeError::DuplicateSection(2))]
+    #[case(b"RVMB\x02\x00\x04\x00\x00\x00\x05\x00\x00\x00\x00\x06".to_vec(), DecodeError::TrailingBytes)]
+    fn test_invalid_serialized_program(#[case] bytes: Vec<u8>, #[case] expected: DecodeError) {
+        assert_eq!(Program::from_bytes(&bytes), Err(expected));
+    }
+
+    #[test]
+    fn test_constants_roundtrip() {
+        let chunk = Chunk::new(compile("1").unwrap().into_code())
+            .with_constants(vec![Value::Int(7), Value::from("seven")]);
+        let program = Program::from(chunk);
+        let bytes = program.to_bytes();
+        assert_eq!(bytes[6], SECTION_CONSTANTS);
+        assert_eq!(Program::from_bytes(&bytes), Ok(program));
+    }
+
+    #[test]
+    fn test_host_functions_roundtrip() {
+        let chunk = Chunk::new(vec![Opcode::CallHost as u8, 1, 0, Opcode::Return as u8])
+            .with_host_functions(vec!["now".to_string(), "price".to_string()]);
+        let program = Program::from(chunk);
+        let bytes = program.to_bytes();
+        assert_eq!(bytes[6], SECTION_HOST_FUNCTIONS);
+        assert_eq!(Program::from_bytes(&bytes), Ok(program));
+    }
+
+    #[test]
+    fn test_unknown_sections_are_skipped() {
+        let bytes = b"RVMB\x02\x00\x09\x00\x00\x00\x02\xaa\xbb\x02\x00\x00\x00\x01\x06";
+        assert_eq!(Program::from_bytes(bytes), Ok(Program::from(vec![6])));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compressed_roundtrip() {
+        let formula = vec!["1 + 2"; 200].join(" + ");
+        let program = compile_unit(&[("big", formula.as_str())]).unwrap();
+        let compressed = program.to_compressed_bytes(Compression::Zstd(3));
+        assert_eq!(compressed[5], FLAG_ZSTD);
+        assert!(compressed.len() < program.to_bytes().len());
+        assert_eq!(Program::from_bytes(&compressed), Ok(program));
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    fn test_deflate_roundtrip() {
+        let formula = vec!["1 + 2"; 200].join(" + ");
+        let program = compile_unit(&[("big", formula.as_str())]).unwrap();
+        let mut file = Vec::new();
+        program
+            .write_compressed_to(&mut file, Compression::Deflate(6))
+            .unwrap();
+        assert_eq!(file[5], FLAG_DEFLATE);
+        assert!(file.len() < program.to_bytes().len());
+        assert_eq!(Program::read_from(file.as_slice()).unwrap(), program);
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn test_compressed_without_feature() {
+        let bytes = b"RVMB\x02\x01\x28\xb5\x2f\xfd";
+        assert_eq!(
+            Program::from_bytes(bytes),
+            Err(DecodeError::CompressionUnsupported("zstd"))
+        );
+    }
+
+    #[cfg(not(feature = "deflate"))]
+    #[test]
+    fn test_deflated_without_feature() {
+        assert_eq!(
+            Program::from_bytes(b"RVMB\x02\x02\x03\x00"),
+            Err(DecodeError::CompressionUnsupported("deflate"))
+        );
+    }
+}
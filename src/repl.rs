@@ -0,0 +1,435 @@
+use std::{borrow::Cow, time::Duration};
+
+use crate::{
+    error::ConfigError,
+    json,
+    program::Program,
+    value::{FloatFormat, FloatNotation, Value},
+    vm::Vm,
+};
+
+// Resource limits applied to every evaluation of the session of `rvmd`, and how its results
+// are shown. Each is changed by name with `set`, `None` means no limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settings {
+    pub stack_size: usize,
+    pub timeout: Option<Duration>,
+    // Instructions an evaluation may execute
+    pub fuel: Option<u64>,
+    // Bytes of strings the VM stack may hold
+    pub heap: Option<usize>,
+    pub float: FloatFormat,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            stack_size: 32,
+            timeout: Some(Duration::from_millis(5000)),
+            fuel: None,
+            heap: None,
+            float: FloatFormat::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("invalid value {} for {}", value, name);
+        let number = || value.parse::<u64>().map_err(|_| invalid());
+        match name {
+            "stack" if number()? > 0 => self.stack_size = number()? as usize,
+            "stack" => return Err("stack must hold at least one value".to_string()),
+            "timeout" if number()? > 0 => self.timeout = Some(Duration::from_millis(number()?)),
+            "timeout" => self.timeout = None,
+            "fuel" if number()? > 0 => self.fuel = Some(number()?),
+            "fuel" => self.fuel = None,
+            "heap" if number()? > 0 => self.heap = Some(number()? as usize),
+            "heap" => self.heap = None,
+            "precision" if number()? > 0 => self.float.precision = Some(number()? as usize),
+            "precision" => self.float.precision = None,
+            "notation" => {
+                self.float.notation = match value {
+                    "auto" => FloatNotation::Auto,
+                    "fixed" => FloatNotation::Fixed,
+                    "scientific" => FloatNotation::Scientific,
+                    _ => return Err(invalid()),
+                }
+            }
+            "trim" => {
+                self.float.trim_zeros = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(invalid()),
+                }
+            }
+            _ => return Err(format!("unknown setting {}", name)),
+        }
+        Ok(())
+    }
+
+    pub fn show(&self) -> String {
+        format!("{}\n{}", self.show_limits(), self.show_format())
+    }
+
+    pub fn show_limits(&self) -> String {
+        let off = |limit: Option<String>| limit.unwrap_or_else(|| "off".to_string());
+        let timeout = self
+            .timeout
+            .map(|timeout| format!("{} ms", timeout.as_millis()));
+        let fuel = self.fuel.map(|fuel| format!("{} instructions", fuel));
+        let heap = self.heap.map(|heap| format!("{} bytes", heap));
+        format!(
+            "stack      {}\ntimeout    {}\nfuel       {}\nheap       {}",
+            self.stack_size,
+            off(timeout),
+            off(fuel),
+            off(heap)
+        )
+    }
+
+    pub fn show_format(&self) -> String {
+        let precision = match self.float.precision {
+            Some(digits) => digits.to_string(),
+            None => "shortest".to_string(),
+        };
+        let notation = match self.float.notation {
+            FloatNotation::Auto => "auto",
+            FloatNotation::Fixed => "fixed",
+            FloatNotation::Scientific => "scientific",
+        };
+        let trim = if self.float.trim_zeros { "on" } else { "off" };
+        format!(
+            "precision  {}\nnotation   {}\ntrim       {}",
+            precision, notation, trim
+        )
+    }
+
+    // A VM for `program` within the limits, integer arithmetic that overflows fails
+    pub fn vm<P: Into<Program>>(&self, program: P) -> Vm {
+        let mut builder = Vm::builder(program)
+            .stack_size(self.stack_size)
+            .checked_arithmetic(true);
+        if let Some(fuel) = self.fuel {
+            builder = builder.fuel(fuel);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(heap) = self.heap {
+            builder = builder.memory_limit(heap);
+        }
+        builder.build()
+    }
+
+    // Fill the fuel of `vm` back up to the limit before an evaluation, the runs of a VM
+    // share its fuel while the limit is on each of them
+    pub fn refuel(&self, vm: &mut Vm) {
+        if let Some(fuel) = self.fuel {
+            vm.refuel(fuel.saturating_sub(vm.fuel().unwrap_or(0)));
+        }
+    }
+}
+
+// The value of a config setting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigValue<'a> {
+    // A string in double quotes, with its escapes replaced
+    Str(String),
+    Bool(bool),
+    // Anything else as written, like a number or `scientific`
+    Bare(&'a str),
+}
+
+// A key of the config and its value
+pub type Setting<'a> = (&'a str, ConfigValue<'a>);
+
+// Parse the flat subset of TOML the config of `rvmd` needs, a `key = value` per line with
+// strings in double quotes and `#` comments. Every setting comes with its line number,
+// counted from one, and a bad line with the error that kept it from being read so the
+// caller can report it and go on with the rest.
+pub fn parse_config(source: &str) -> Vec<(usize, Result<Setting<'_>, ConfigError>)> {
+    source
+        .lines()
+        .enumerate()
+        .filter_map(|(number, line)| {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                return None;
+            }
+            let setting = match line.split_once('=') {
+                Some((key, value)) => config_value(value.trim()).map(|value| (key.trim(), value)),
+                None => Err(ConfigError::ExpectedKeyValue),
+            };
+            Some((number + 1, setting))
+        })
+        .collect()
+}
+
+fn config_value(value: &str) -> Result<ConfigValue<'_>, ConfigError> {
+    match value {
+        "true" => Ok(ConfigValue::Bool(true)),
+        "false" => Ok(ConfigValue::Bool(false)),
+        value if value.starts_with('"') => unquote(value).map(ConfigValue::Str),
+        value => Ok(ConfigValue::Bare(value)),
+    }
+}
+
+// `line` up to a `#` outside of a string
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+// The contents of a TOML basic string
+fn unquote(value: &str) -> Result<String, ConfigError> {
+    let inner = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .ok_or_else(|| ConfigError::UnterminatedString(value.to_string()))?;
+    let mut output = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => output.push('\n'),
+            Some('t') => output.push('\t'),
+            Some(c @ ('"' | '\\')) => output.push(c),
+            _ => return Err(ConfigError::InvalidEscape(value.to_string())),
+        }
+    }
+    Ok(output)
+}
+
+// The name and expression of an input of the session that assigns a variable, like
+// `x = 2 * y`. A compound one like `x *= y + 1` gives `x * (y + 1)`, the operators of
+// arithmetic share a precedence.
+pub fn assignment(input: &str) -> Option<(&str, Cow<'_, str>)> {
+    let (target, expr) = input.split_once('=')?;
+    let target = target.trim_end();
+    let (name, operator) = match target.char_indices().last()? {
+        (i, operator @ ('+' | '-' | '*' | '/' | '%')) => (target[..i].trim(), Some(operator)),
+        _ => (target.trim(), None),
+    };
+    let mut chars = name.chars();
+    let identifier = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_');
+    // Rule out comparisons like `x == 1`
+    if !identifier || expr.starts_with('=') {
+        return None;
+    }
+    let expr = match operator {
+        Some(operator) => Cow::Owned(format!("{} {} ({})", name, operator, expr.trim())),
+        None => Cow::Borrowed(expr.trim()),
+    };
+    Some((name, expr))
+}
+
+// The object printed for a value with `--output json`, tagged with its type, whether it is
+// exact and what is off about it, so `3` and a float rounded to it can be told apart
+pub fn json_result(value: &Value) -> String {
+    let warnings = match value.warning() {
+        Some(warning) => format!("[{}]", json::string(&warning.to_string())),
+        None => "[]".to_string(),
+    };
+    format!(
+        r#"{{"ok":true,"type":"{}","value":{},"exact":{},"warnings":{}}}"#,
+        value.type_name(),
+        value.to_json(),
+        value.is_exact(),
+        warnings
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::compile, error::RuntimeError, opcode::Opcode};
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("stack", "64", Ok(Settings { stack_size: 64, ..Settings::default() }))]
+    #[case("stack", "0", Err("stack must hold at least one value"))]
+    #[case("timeout", "0", Ok(Settings { timeout: None, ..Settings::default() }))]
+    #[case("fuel", "1000", Ok(Settings { fuel: Some(1000), ..Settings::default() }))]
+    #[case("fuel", "0", Ok(Settings::default()))]
+    #[case("fuel", "-1", Err("invalid value -1 for fuel"))]
+    #[case("heap", "4096", Ok(Settings { heap: Some(4096), ..Settings::default() }))]
+    #[case("heap", "0", Ok(Settings::default()))]
+    #[case("heap", "4k", Err("invalid value 4k for heap"))]
+    #[case("speed", "1", Err("unknown setting speed"))]
+    fn test_settings_set(
+        #[case] name: &str,
+        #[case] value: &str,
+        #[case] expected: Result<Settings, &str>,
+    ) {
+        let mut settings = Settings::default();
+        let result = settings.set(name, value).map(|()| settings);
+        assert_eq!(result, expected.map_err(str::to_string));
+    }
+
+    #[test]
+    fn test_show_limits() {
+        let mut settings = Settings::default();
+        assert_eq!(
+            settings.show_limits(),
+            "stack      32\ntimeout    5000 ms\nfuel       off\nheap       off"
+        );
+        settings.fuel = Some(100);
+        settings.heap = Some(1024);
+        settings.timeout = None;
+        assert_eq!(
+            settings.show_limits(),
+            "stack      32\ntimeout    off\nfuel       100 instructions\nheap       1024 bytes"
+        );
+    }
+
+    #[test]
+    fn test_settings_vm() {
+        let settings = Settings {
+            fuel: Some(10),
+            heap: Some(8),
+            ..Settings::default()
+        };
+        // Each evaluation gets the whole fuel once refueled
+        let mut vm = settings.vm(compile("1 + 2 + 3").unwrap());
+        for _ in 0..5 {
+            settings.refuel(&mut vm);
+            assert_eq!(vm.run(), Ok(Value::Int(6)));
+        }
+        // Without it the runs use up the fuel between them
+        assert_eq!(vm.run(), Err(RuntimeError::OutOfFuel));
+        vm.load(compile("fn f(n) { n < 1 ? 0 : f(n - 1) } f(100)").unwrap());
+        settings.refuel(&mut vm);
+        assert_eq!(vm.run(), Err(RuntimeError::OutOfFuel));
+        vm.load(compile("\"abcd\" + \"efgh\" + 1").unwrap());
+        settings.refuel(&mut vm);
+        assert_eq!(vm.run(), Err(RuntimeError::OutOfMemory));
+        vm.load(compile("9223372036854775807 + 1").unwrap());
+        settings.refuel(&mut vm);
+        assert_eq!(
+            vm.run(),
+            Err(RuntimeError::Overflow {
+                op: Opcode::Addition,
+                operands: vec![i64::MAX, 1],
+            })
+        );
+
+        // A loop that never ends is stopped at the timeout by the VM
+        let timeout = Duration::from_millis(10);
+        let settings = Settings {
+            timeout: Some(timeout),
+            ..Settings::default()
+        };
+        let mut vm = settings.vm(compile("fn f(x) { f(x) } f(1)").unwrap());
+        assert_eq!(vm.run(), Err(RuntimeError::Timeout(timeout)));
+    }
+
+    #[rstest]
+    #[case("prompt = \"λ> \"", "prompt", ConfigValue::Str("λ> ".to_string()))]
+    #[case("prompt = \"a # b\"  # comment", "prompt", ConfigValue::Str("a # b".to_string()))]
+    #[case(r#"a = "say \"hi\" # there""#, "a", ConfigValue::Str("say \"hi\" # there".to_string()))]
+    #[case(r#"prompt = "a\tb\\ \n""#, "prompt", ConfigValue::Str("a\tb\\ \n".to_string()))]
+    #[case("prompt = \"\"", "prompt", ConfigValue::Str(String::new()))]
+    #[case("trim = true", "trim", ConfigValue::Bool(true))]
+    #[case("  trim=false  ", "trim", ConfigValue::Bool(false))]
+    #[case("timeout = 5000 # ms", "timeout", ConfigValue::Bare("5000"))]
+    #[case("notation = scientific", "notation", ConfigValue::Bare("scientific"))]
+    #[case("startup = \"~/init.rvm\"", "startup", ConfigValue::Str("~/init.rvm".to_string()))]
+    fn test_parse_config(
+        #[case] source: &str,
+        #[case] key: &str,
+        #[case] value: ConfigValue<'static>,
+    ) {
+        assert_eq!(parse_config(source), vec![(1, Ok((key, value)))]);
+    }
+
+    #[rstest]
+    #[case("prompt", ConfigError::ExpectedKeyValue)]
+    #[case("# prompt = \"> \"\nprompt \"> \"", ConfigError::ExpectedKeyValue)]
+    #[case("prompt = \"open", ConfigError::UnterminatedString("\"open".to_string()))]
+    #[case("prompt = \"a\" b", ConfigError::UnterminatedString("\"a\" b".to_string()))]
+    #[case(r#"prompt = "a\qb""#, ConfigError::InvalidEscape(r#""a\qb""#.to_string()))]
+    #[case(r#"prompt = "a\""#, ConfigError::InvalidEscape(r#""a\""#.to_string()))]
+    fn test_parse_config_error(#[case] source: &str, #[case] expected: ConfigError) {
+        let settings = parse_config(source);
+        let number = source.lines().count();
+        assert_eq!(settings, vec![(number, Err(expected))]);
+    }
+
+    #[test]
+    fn test_parse_config_lines() {
+        let source = "# rvmd\n\nstack = 64\nbad\n  # indented\ntrim = on";
+        let settings = parse_config(source);
+        assert_eq!(
+            settings,
+            vec![
+                (3, Ok(("stack", ConfigValue::Bare("64")))),
+                (4, Err(ConfigError::ExpectedKeyValue)),
+                (6, Ok(("trim", ConfigValue::Bare("on")))),
+            ]
+        );
+        assert_eq!(parse_config(""), vec![]);
+    }
+
+    #[rstest]
+    #[case("x = 2 * y", Some(("x", "2 * y")))]
+    #[case("  _a1=3", Some(("_a1", "3")))]
+    #[case("x = y == 1", Some(("x", "y == 1")))]
+    #[case("x += 1", Some(("x", "x + (1)")))]
+    #[case("x -= -1", Some(("x", "x - (-1)")))]
+    #[case("x *= y + 1", Some(("x", "x * (y + 1)")))]
+    #[case("x/=2", Some(("x", "x / (2)")))]
+    #[case("x %= 3", Some(("x", "x % (3)")))]
+    #[case("x == 1", None)]
+    #[case("x <= 1", None)]
+    #[case("x >= 1", None)]
+    #[case("x != 1", None)]
+    #[case("2 = 3", None)]
+    #[case("= 3", None)]
+    #[case("+= 3", None)]
+    #[case("f(x) = 1", None)]
+    #[case("x y = 1", None)]
+    #[case("x + 1", None)]
+    fn test_assignment(#[case] input: &str, #[case] expected: Option<(&str, &str)>) {
+        let assignment = assignment(input);
+        let assignment = assignment
+            .as_ref()
+            .map(|(name, expr)| (*name, expr.as_ref()));
+        assert_eq!(assignment, expected);
+    }
+
+    #[rstest]
+    #[case(
+        Value::Int(3),
+        r#"{"ok":true,"type":"int","value":3,"exact":true,"warnings":[]}"#
+    )]
+    #[case(
+        Value::Float(3.0),
+        r#"{"ok":true,"type":"float","value":3.0,"exact":false,"warnings":[]}"#
+    )]
+    #[case(Value::Str("a\"b".into()), r#"{"ok":true,"type":"string","value":"a\"b","exact":true,"warnings":[]}"#)]
+    #[case(
+        Value::Bool(true),
+        r#"{"ok":true,"type":"bool","value":true,"exact":true,"warnings":[]}"#
+    )]
+    #[case(Value::Float(f64::INFINITY), r#"{"ok":true,"type":"float","value":null,"exact":false,"warnings":["result is infinite"]}"#)]
+    #[case(Value::Float(f64::NAN), r#"{"ok":true,"type":"float","value":null,"exact":false,"warnings":["result is not a number"]}"#)]
+    #[case(Value::Float(1e300), r#"{"ok":true,"type":"float","value":1e300,"exact":false,"warnings":["result is too large to be exact"]}"#)]
+    fn test_json_result(#[case] value: Value, #[case] expected: &str) {
+        assert_eq!(json_result(&value), expected);
+    }
+}
@@ -1,43 +1,911 @@
-use std::io::{self, Write};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    env, fs,
+    io::{self, BufRead, BufReader, IsTerminal},
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, Instant},
+};
 
-use librvm::{compiler::compile, vm::Vm};
+use librvm::{
+    chunk::Chunk,
+    compiler::{
+        builtin_names, compile_program, compile_with_options, constant_names, CompileError,
+        CompileOptions,
+    },
+    diagnostic::Diagnostic,
+    disasm::disassemble,
+    error::{Error, RuntimeError},
+    json,
+    lexer::{is_incomplete, tokenize, Span, TokenKind},
+    operator::OPERATORS,
+    program::{Program, ENTRY},
+    repl::{assignment, json_result, parse_config, ConfigValue, Settings},
+    value::Value,
+    vm::Vm,
+};
+use rustyline::{
+    completion::{Completer, Pair},
+    error::ReadlineError,
+    highlight::{CmdKind, Highlighter},
+    hint::Hinter,
+    history::DefaultHistory,
+    validate::Validator,
+    Config as EditorConfig, Context, Editor,
+};
+
+const HELP: &str = "\
+Enter an expression to evaluate it, or name = expression to also keep its value, which
+name += expression and the like update. The last value is kept as ans and _.
+  :help [limits|format]  this help, the limits on evaluations or how values are shown
+  :set [name value]   show or change a limit or the format of values
+  :stack              the VM stack after the last evaluation and the variables
+  :disasm             the bytecode of the last expression
+  :time <expr>        run an expression 1000 times and report how long a run takes
+  :load <path>        run a script file, its free variables are read from the variables
+  :reload             run the loaded script again once its file has changed, it must
+                      still read the same variables
+  :clear              forget the variables, the last expression and the loaded script
+  :quit               end the session, like Ctrl-D";
+
+// Commands offered by tab completion, see `execute_command`
+const COMMANDS: [&str; 9] = [
+    "help", "set", "stack", "disasm", "time", "load", "reload", "clear", "quit",
+];
+
+// Entries kept in the history file, older ones are dropped
+const HISTORY_SIZE: usize = 1000;
+
+// Runs of an expression timed by `:time`
+const ITERATIONS: usize = 1000;
+
+const USAGE: &str = "usage: rvmd [--output text|json] [--no-config] [script]";
+
+// Exit codes, the same as those of `rvm`
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_COMPILE: i32 = 3;
+const EXIT_RUNTIME: i32 = 4;
+const EXIT_IO: i32 = 6;
+
+// Scripts are not watched by anyone, so they run without a timeout and with the stack a
+// VM gets by default rather than the small one that guards the interactive session
+const SCRIPT_STACK_SIZE: usize = 1 << 16;
+
+const LIMITS_HELP: &str = "\
+Limits protect the session from runaway evaluations, change them with :set <name> <value>
+  stack    number of values the VM stack may hold
+  timeout  milliseconds before an evaluation is abandoned, 0 disables the timeout
+  fuel     instructions an evaluation may execute, 0 for no limit
+  heap     bytes of strings the VM stack may hold, 0 for no limit";
+
+const FORMAT_HELP: &str = "\
+How floats are shown, change it with :set <name> <value>
+  precision  significant digits, 0 for as many as it takes to tell the float apart
+  notation   auto, fixed or scientific, auto switches to scientific for large and small
+  trim       on or off, whether zeros ending the fraction are dropped";
+
+// Customizations of the interactive session read from its config file
+struct Config {
+    settings: Settings,
+    prompt: String,
+    // File of inputs run before the first prompt, like `name = expr` definitions
+    startup: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            settings: Settings::default(),
+            prompt: "> ".to_string(),
+            startup: None,
+        }
+    }
+}
+
+impl Config {
+    // `rvm/config.toml` under `$XDG_CONFIG_HOME`, or else under `~/.config`
+    fn path() -> Option<PathBuf> {
+        let base = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(base.join("rvm").join("config.toml"))
+    }
+
+    // Read the config at `path`, a missing file is the default config
+    fn load(path: &Path) -> Config {
+        match fs::read_to_string(path) {
+            Ok(source) => Config::parse(&source, path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Config::default(),
+            Err(e) => {
+                eprintln!("Warning: {}: {}", path.display(), e);
+                Config::default()
+            }
+        }
+    }
+
+    // The keys are those of `:set` plus `prompt` and `startup`. Bad lines are reported and
+    // skipped so they do not keep anyone out of the session.
+    fn parse(source: &str, path: &Path) -> Config {
+        let mut config = Config::default();
+        for (number, setting) in parse_config(source) {
+            let result = setting
+                .map_err(|e| e.to_string())
+                .and_then(|(key, value)| config.set(key, value, path));
+            if let Err(e) = result {
+                eprintln!("Warning: {}:{}: {}", path.display(), number, e);
+            }
+        }
+        config
+    }
+
+    fn set(&mut self, key: &str, value: ConfigValue, path: &Path) -> Result<(), String> {
+        let value = match value {
+            ConfigValue::Bool(true) => "on".to_string(),
+            ConfigValue::Bool(false) => "off".to_string(),
+            ConfigValue::Str(value) => value,
+            ConfigValue::Bare(value) => value.to_string(),
+        };
+        match key {
+            "prompt" => self.prompt = value,
+            // Relative to the directory of the config, `~` is the home directory
+            "startup" => {
+                let startup = match (value.strip_prefix("~/"), env::var_os("HOME")) {
+                    (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+                    _ => path.parent().unwrap_or(Path::new("")).join(value),
+                };
+                self.startup = Some(startup);
+            }
+            key => self.settings.set(key, &value)?,
+        }
+        Ok(())
+    }
+}
+
+// `rvm/history` under `$XDG_DATA_HOME`, or else under `~/.local/share`
+fn history_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("share"))
+        })?;
+    Some(base.join("rvm").join("history"))
+}
+
+// State kept from one input to the next
+#[derive(Default)]
+struct Session {
+    settings: Settings,
+    // Kept from one evaluation to the next, so the VM and its allocations live as long as
+    // the session. `:set` drops it and the next evaluation builds one with the new limits.
+    vm: Option<Vm>,
+    // Values bound with `name = expr` and the last value as `ans` and `_`, every input is
+    // compiled with them as parameters
+    variables: Vec<(String, Value)>,
+    // Bytecode of the last expression that compiled
+    last: Option<Chunk>,
+    // Print results and errors as one JSON object per line, see `emit`
+    json: bool,
+    // Script file run by `:load`
+    loaded: Option<Loaded>,
+}
+
+// A script file loaded into the session, `:reload` swaps its edited version into the VM
+struct Loaded {
+    path: PathBuf,
+    // Source of the program, errors point into it
+    source: String,
+    program: Program,
+    // Dropped by `:set` like `Session::vm`, rebuilt from `program` by the next run
+    vm: Option<Vm>,
+}
+
+impl Session {
+    fn assign(&mut self, name: &str, value: Value) {
+        match self.variables.iter_mut().find(|(known, _)| known == name) {
+            Some((_, known)) => *known = value,
+            None => self.variables.push((name.to_string(), value)),
+        }
+    }
+}
 
 fn main() {
-    loop {
-        print!("> ");
-        // Ensure the prompt is displayed before reading input
-        io::stdout().flush().unwrap();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // Flags apply to every mode, so they are taken out first
+    let mut json = false;
+    if let Some(index) = args.iter().position(|arg| arg == "--output") {
+        json = match args.get(index + 1).map(String::as_str) {
+            Some("json") => true,
+            Some("text") => false,
+            _ => usage(),
+        };
+        args.drain(index..index + 2);
+    }
+    let config = match args.iter().position(|arg| arg == "--no-config") {
+        Some(index) => {
+            args.remove(index);
+            Config::default()
+        }
+        None => Config::path().map_or_else(Config::default, |path| Config::load(&path)),
+    };
+    match args.as_slice() {
+        [] if io::stdin().is_terminal() => repl(json, config),
+        [] => pipe(json),
+        [path] if !path.starts_with('-') => run_script(path, json),
+        _ => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Error: {}", USAGE);
+    process::exit(EXIT_USAGE);
+}
+
+// Compile and run the file at `path`, printing its value or exiting with the status for
+// what went wrong
+fn run_script(path: &str, json: bool) {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Error: {}: {}", path, e);
+            process::exit(EXIT_IO);
+        }
+    };
+    let mut session = Session {
+        settings: Settings {
+            stack_size: SCRIPT_STACK_SIZE,
+            timeout: None,
+            ..Settings::default()
+        },
+        json,
+        ..Session::default()
+    };
+    let result = evaluate(&source, &mut session);
+    emit(&result, &source, Some(""), &session);
+    match result {
+        Err((Error::Runtime(_), _)) => process::exit(EXIT_RUNTIME),
+        Err(_) => process::exit(EXIT_COMPILE),
+        Ok(_) => {}
+    }
+}
+
+// Line editor support for the interactive session
+#[derive(Default)]
+struct Helper {
+    // Names of the session's variables, refreshed after every input
+    variables: Vec<String>,
+}
+
+impl Completer for Helper {
+    type Candidate = Pair;
+
+    // Complete the word before the cursor with a command after a leading colon, or else with
+    // a builtin function, a constant or a variable
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos]
+            .char_indices()
+            .rev()
+            .take_while(|&(_, c)| c.is_alphanumeric() || c == '_')
+            .last()
+            .map_or(pos, |(i, _)| i);
+        let word = &line[start..pos];
+        let mut candidates: Vec<Pair> = if line[..start].trim_start() == ":" {
+            COMMANDS
+                .iter()
+                .map(|command| Pair {
+                    display: format!(":{}", command),
+                    replacement: command.to_string(),
+                })
+                .collect()
+        } else {
+            let functions = builtin_names().map(|name| Pair {
+                display: format!("{}()", name),
+                replacement: format!("{}(", name),
+            });
+            let names = constant_names()
+                .map(str::to_string)
+                .chain(self.variables.iter().cloned())
+                .map(|name| Pair {
+                    display: name.clone(),
+                    replacement: name,
+                });
+            functions.chain(names).collect()
+        };
+        candidates.retain(|candidate| candidate.replacement.starts_with(word));
+        candidates.sort_by(|a, b| a.replacement.cmp(&b.replacement));
+        candidates.dedup_by(|a, b| a.replacement == b.replacement);
+        Ok((start, candidates))
+    }
+}
 
-        let mut input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
+impl Hinter for Helper {
+    type Hint = String;
+}
 
-        // Trim whitespace and check for exit condition
-        let input = input.trim();
-        if input.eq_ignore_ascii_case("exit") || input.eq_ignore_ascii_case("quit") {
+impl Highlighter for Helper {
+    fn highlight<'l>(&self, line: &'l str, _: usize) -> Cow<'l, str> {
+        // Commands are not expressions
+        if line.trim_start().starts_with(':') {
+            return Cow::Borrowed(line);
+        }
+        Cow::Owned(highlight(line))
+    }
+
+    // Colors depend on the whole line, so it is redrawn on every edit
+    fn highlight_char(&self, _: &str, _: usize, _: CmdKind) -> bool {
+        true
+    }
+}
+
+impl Validator for Helper {}
+
+impl rustyline::Helper for Helper {}
+
+// ANSI colors of the highlighted input
+const NUMBER: &str = "\x1b[33m";
+const STRING: &str = "\x1b[32m";
+const BOOL: &str = "\x1b[35m";
+const OPERATOR: &str = "\x1b[36m";
+const INVALID: &str = "\x1b[31;4m";
+const RESET: &str = "\x1b[0m";
+
+// Color the tokens of `line`, from the first one the lexer rejects on the line is marked
+// as invalid
+fn highlight(line: &str) -> String {
+    let (tokens, invalid) = match tokenize(line) {
+        Ok(tokens) => (tokens, line.len()),
+        Err(e) => (
+            tokenize(&line[..e.offset()]).unwrap_or_default(),
+            e.offset(),
+        ),
+    };
+    let mut output = String::with_capacity(line.len() * 2);
+    let mut end = 0;
+    for token in tokens {
+        let color = match token.kind {
+            TokenKind::Number(_) => NUMBER,
+            TokenKind::Str(_) => STRING,
+            TokenKind::Bool(_) => BOOL,
+            TokenKind::Op(_) | TokenKind::Question | TokenKind::Colon => OPERATOR,
+            _ => continue,
+        };
+        output.push_str(&line[end..token.span.start]);
+        output.push_str(color);
+        output.push_str(&line[token.span.start..token.span.end]);
+        output.push_str(RESET);
+        end = token.span.end;
+    }
+    output.push_str(&line[end..invalid]);
+    if invalid < line.len() {
+        output.push_str(INVALID);
+        output.push_str(&line[invalid..]);
+        output.push_str(RESET);
+    }
+    output
+}
+
+// The value of an input, or its error with the span of the failing instruction
+type Evaluation = Result<Value, (Error, Option<Span>)>;
+
+// What became of one input
+enum Outcome {
+    Done,
+    Failed,
+    Quit,
+}
+
+// The config only applies here, pipes and scripts behave the same for everyone
+fn repl(json: bool, config: Config) {
+    let mut session = Session {
+        settings: config.settings,
+        json,
+        ..Session::default()
+    };
+    if let Some(path) = &config.startup {
+        let result =
+            fs::File::open(path).and_then(|file| feed(BufReader::new(file), &mut session, None));
+        if let Err(e) = result {
+            eprintln!("Warning: {}: {}", path.display(), e);
+        }
+    }
+    // An input repeating the one before it is not added to the history again
+    let editor = EditorConfig::builder()
+        .max_history_size(HISTORY_SIZE)
+        .and_then(|builder| builder.history_ignore_dups(true))
+        .and_then(|builder| Editor::with_config(builder.build()));
+    let mut editor: Editor<Helper, DefaultHistory> = match editor {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(EXIT_FAILURE);
+        }
+    };
+    editor.set_helper(Some(Helper::default()));
+    let history = history_path();
+    if let Some(path) = &history {
+        match editor.load_history(path) {
+            Err(ReadlineError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("Warning: {}: {}", path.display(), e),
+            Ok(()) => {}
+        }
+    }
+    // Lines of an expression that goes on past the end of its first line
+    let mut pending = String::new();
+    loop {
+        // Ctrl-C drops the input being edited, Ctrl-D ends the session
+        let prompt = if pending.is_empty() {
+            config.prompt.as_str()
+        } else {
+            "..> "
+        };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                pending.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                break;
+            }
+        };
+        pending.push_str(&line);
+        if continues(&pending) {
+            pending.push('\n');
+            continue;
+        }
+        let input = std::mem::take(&mut pending);
+        if !input.trim().is_empty() {
+            let _ = editor.add_history_entry(input.trim());
+        }
+        if let Outcome::Quit = respond(&input, &mut session, Some("= ")) {
             break;
         }
+        if let Some(helper) = editor.helper_mut() {
+            helper.variables = session
+                .variables
+                .iter()
+                .map(|(name, _)| name.clone())
+                .collect();
+        }
+    }
+    if let Some(path) = &history {
+        let saved = match path.parent() {
+            Some(parent) => fs::create_dir_all(parent).map_err(ReadlineError::Io),
+            None => Ok(()),
+        }
+        .and_then(|()| editor.save_history(path));
+        if let Err(e) = saved {
+            eprintln!("Warning: {}: {}", path.display(), e);
+        }
+    }
+}
+
+// Read inputs from a pipe or file without prompts, one result per line on stdout, and exit
+// with status 1 if any of them failed
+fn pipe(json: bool) {
+    let mut session = Session {
+        json,
+        ..Session::default()
+    };
+    match feed(io::stdin().lock(), &mut session, Some("")) {
+        Ok(false) => {}
+        Ok(true) => process::exit(EXIT_FAILURE),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(EXIT_IO);
+        }
+    }
+}
 
-        // Skip empty lines
-        if input.is_empty() {
+// Run the inputs read from `reader` line by line, returning whether any of them failed.
+// Results are printed after `prefix` unless it is `None`.
+fn feed<R: BufRead>(reader: R, session: &mut Session, prefix: Option<&str>) -> io::Result<bool> {
+    let mut failed = false;
+    let mut pending = String::new();
+    for line in reader.lines() {
+        pending.push_str(&line?);
+        if continues(&pending) {
+            pending.push('\n');
             continue;
         }
+        match respond(&std::mem::take(&mut pending), session, prefix) {
+            Outcome::Done => {}
+            Outcome::Failed => failed = true,
+            Outcome::Quit => break,
+        }
+    }
+    // Input that ends halfway through an expression still gets its error
+    if !pending.is_empty() {
+        if let Outcome::Failed = respond(&pending, session, prefix) {
+            failed = true;
+        }
+    }
+    Ok(failed)
+}
+
+// Whether the input read so far needs more lines, commands always fit on one
+fn continues(pending: &str) -> bool {
+    !pending.trim_start().starts_with(':') && is_incomplete(pending)
+}
+
+// Run one input of the session, printing results after `prefix` on stdout and errors on
+// stderr. Without a prefix only errors are printed.
+fn respond(input: &str, session: &mut Session, prefix: Option<&str>) -> Outcome {
+    // Trim whitespace and check for exit condition
+    let input = input.trim();
+    if input.eq_ignore_ascii_case("exit") || input.eq_ignore_ascii_case("quit") || input == ":quit"
+    {
+        return Outcome::Quit;
+    }
+
+    // Skip empty lines
+    if input.is_empty() {
+        return Outcome::Done;
+    }
+
+    // Commands start with a colon and never reach the compiler, except that a loaded
+    // script gives a value like an input
+    if let Some(command) = input.strip_prefix(':') {
+        let script = match command.strip_prefix("load ") {
+            Some(path) => Some(load(Path::new(path.trim()), session)),
+            None if command == "reload" => Some(reload(session)),
+            None => None,
+        };
+        return match script {
+            Some(Ok((source, result))) => conclude(result, &source, None, prefix, session),
+            Some(Err(e)) => reject(&e, session),
+            None => match execute_command(command, session) {
+                Ok(output) => {
+                    if prefix.is_some() {
+                        println!("{}", output);
+                    }
+                    Outcome::Done
+                }
+                Err(e) => reject(&e, session),
+            },
+        };
+    }
 
-        // Compile and run the input
-        match evaluate(input) {
-            Ok(result) => println!("= {}", result),
-            Err(e) => eprintln!("Error: {}", e),
+    // Compile and run the input, binding the result when it is an assignment
+    let (name, input) = match assignment(input) {
+        Some((name, expr)) => (Some(name), expr),
+        None => (None, Cow::Borrowed(input)),
+    };
+    let result = evaluate(&input, session);
+    conclude(result, &input, name, prefix, session)
+}
+
+// Print the result of evaluating `input` and keep its value as `ans`, `_` and `name`
+fn conclude(
+    result: Evaluation,
+    input: &str,
+    name: Option<&str>,
+    prefix: Option<&str>,
+    session: &mut Session,
+) -> Outcome {
+    emit(&result, input, prefix, session);
+    match result {
+        Ok(result) => {
+            if let Some(name) = name {
+                session.assign(name, result.clone());
+            }
+            session.assign("ans", result.clone());
+            session.assign("_", result);
+            Outcome::Done
         }
+        Err(_) => Outcome::Failed,
+    }
+}
+
+// Print the error of a command that could not be carried out
+fn reject(error: &str, session: &Session) -> Outcome {
+    if session.json {
+        println!(
+            r#"{{"ok":false,"kind":"command","error":{{"message":{}}}}}"#,
+            json::string(error)
+        );
+    } else {
+        eprintln!("Error: {}", error);
     }
+    Outcome::Failed
 }
 
-fn evaluate(input: &str) -> Result<librvm::value::Value, &'static str> {
-    // Attempt to compile the input
-    let bytecode = match compile(input) {
-        Ok(code) => code,
-        Err(_) => return Err("Failed to compile expression"),
+// Print the result of evaluating `input`. As text values go to stdout after `prefix` and
+// errors to stderr, as JSON both go to stdout as a single line object with an `ok` field.
+fn emit(result: &Evaluation, input: &str, prefix: Option<&str>, session: &Session) {
+    let json = session.json;
+    match (result, prefix) {
+        (Ok(_), None) => {}
+        (Ok(value), _) if json => println!("{}", json_result(value)),
+        (Ok(value), Some(prefix)) => {
+            println!("{}{}", prefix, value.format(&session.settings.float))
+        }
+        (Err((Error::Compile(e), _)), _) if json => println!(
+            r#"{{"ok":false,"kind":"compile","error":{}}}"#,
+            Diagnostic::from(e).to_json()
+        ),
+        (Err((e, span)), _) if json => {
+            let span = match span {
+                Some(span) => {
+                    let (line, column) = position(input, span.start);
+                    format!(
+                        r#"{{"start":{},"end":{},"line":{},"column":{}}}"#,
+                        span.start, span.end, line, column
+                    )
+                }
+                None => "null".to_string(),
+            };
+            println!(
+                r#"{{"ok":false,"kind":"runtime","error":{{"message":{},"span":{}}}}}"#,
+                json::string(&e.to_string()),
+                span
+            )
+        }
+        (Err((e, span)), _) => eprintln!("Error: {}", report(e, input, *span)),
+    }
+}
+
+// An error of `evaluate` with the part of `input` it points at
+fn report(error: &Error, input: &str, span: Option<Span>) -> String {
+    match (error, span) {
+        (Error::Compile(e), _) => e.render(input),
+        (e, Some(span)) => render_span(e, input, span),
+        (e, None) => e.to_string(),
+    }
+}
+
+fn execute_command(command: &str, session: &mut Session) -> Result<String, String> {
+    if let Some(input) = command.strip_prefix("time ") {
+        return time(input.trim(), session);
+    }
+    let settings = &mut session.settings;
+    let words: Vec<&str> = command.split_whitespace().collect();
+    match words.as_slice() {
+        ["set"] => Ok(settings.show()),
+        ["set", name, value] => {
+            settings.set(name, value)?;
+            // The stack size is fixed when the VM is built
+            session.vm = None;
+            if let Some(loaded) = &mut session.loaded {
+                loaded.vm = None;
+            }
+            Ok(session.settings.show())
+        }
+        ["help"] => Ok(format!("{}\n\n{}", HELP, language())),
+        ["help", "limits"] => Ok(format!("{}\n\n{}", LIMITS_HELP, settings.show_limits())),
+        ["help", "format"] => Ok(format!("{}\n\n{}", FORMAT_HELP, settings.show_format())),
+        ["stack"] => {
+            let format = &settings.float;
+            let stack = match &session.vm {
+                Some(vm) => {
+                    let values: Vec<String> = vm
+                        .stack()
+                        .as_slice()
+                        .iter()
+                        .map(|value| value.format(format))
+                        .collect();
+                    format!("[{}]", values.join(", "))
+                }
+                None => "empty".to_string(),
+            };
+            let mut output = format!("stack  {}", stack);
+            for (name, value) in &session.variables {
+                output.push_str(&format!("\n{} = {}", name, value.format(format)));
+            }
+            Ok(output)
+        }
+        ["disasm"] => match &session.last {
+            Some(chunk) => disassemble(chunk.code())
+                .map(|output| output.trim_end().to_string())
+                .map_err(|e| e.to_string()),
+            None => Err("nothing evaluated yet".to_string()),
+        },
+        ["clear"] => {
+            *session = Session {
+                settings: std::mem::take(&mut session.settings),
+                json: session.json,
+                ..Session::default()
+            };
+            Ok("cleared".to_string())
+        }
+        _ => Err(format!("unknown command :{}", command)),
+    }
+}
+
+// The operators, functions and constants of the language
+fn language() -> String {
+    let operators: Vec<&str> = OPERATORS.iter().map(|operator| operator.spelling).collect();
+    let functions: Vec<&str> = builtin_names().collect();
+    let constants: Vec<&str> = constant_names().collect();
+    format!(
+        "Operators  {} ! √ ¬ - and cond ? a : b\nFunctions  {}\nConstants  {}",
+        operators.join(" "),
+        functions.join(" "),
+        constants.join(" ")
+    )
+}
+
+// Run `input` on a fresh VM `ITERATIONS` times and report the wall time of a run along
+// with the instructions it executes
+fn time(input: &str, session: &Session) -> Result<String, String> {
+    let (bytecode, args) = prepare(input, session).map_err(|e| e.render(input))?;
+    let settings = session.settings;
+    let benchmark = || -> Result<(Vec<Duration>, u64), RuntimeError> {
+        // Counted on a run of its own since fuel takes the VM off its fast path, the run also
+        // holds the input to the fuel limit so the timed ones go without. Those go without
+        // the timeout of the VM too, it is on all of them together.
+        let started = Instant::now();
+        let fuel = settings.fuel.unwrap_or(u64::MAX);
+        let mut counter = settings.vm(bytecode.clone()).with_fuel(fuel);
+        counter.run_with_args(&args)?;
+        let instructions = fuel - counter.fuel().unwrap_or(fuel);
+        let mut vm = Settings {
+            fuel: None,
+            timeout: None,
+            ..settings
+        }
+        .vm(bytecode);
+        let times = (0..ITERATIONS)
+            .map(|_| {
+                if let Some(timeout) = settings.timeout.filter(|t| started.elapsed() > *t) {
+                    return Err(RuntimeError::Timeout(timeout));
+                }
+                let start = Instant::now();
+                vm.run_with_args(&args).map(|_| start.elapsed())
+            })
+            .collect::<Result<Vec<Duration>, RuntimeError>>()?;
+        Ok((times, instructions))
     };
+    let (times, instructions) = benchmark().map_err(|e| e.to_string())?;
+    let min = times.iter().min().copied().unwrap_or_default();
+    let max = times.iter().max().copied().unwrap_or_default();
+    let average = times.iter().sum::<Duration>() / ITERATIONS as u32;
+    Ok(format!(
+        "{} runs  min {:?}  avg {:?}  max {:?}  {} instructions",
+        ITERATIONS, min, average, max, instructions
+    ))
+}
+
+// Compile `input` with the session's variables as parameters, returning the bytecode and
+// the arguments to run it with
+fn prepare(input: &str, session: &Session) -> Result<(Chunk, Vec<Value>), CompileError> {
+    let names: Vec<&str> = session
+        .variables
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect();
+    let options = CompileOptions::new().debug_info(true).params(&names);
+    let bytecode = compile_with_options(input, &options)?;
+    let args = session
+        .variables
+        .iter()
+        .map(|(_, value)| value.clone())
+        .collect();
+    Ok((bytecode, args))
+}
+
+// Compile and run `input` with the session's variables, runtime errors come with the span
+// of the failing instruction
+fn evaluate(input: &str, session: &mut Session) -> Evaluation {
+    let (bytecode, args) = prepare(input, session).map_err(|e| (e.into(), None))?;
+    session.last = Some(bytecode.clone());
+
+    let vm = match &mut session.vm {
+        Some(vm) => {
+            vm.load(bytecode);
+            vm
+        }
+        None => session.vm.insert(session.settings.vm(bytecode)),
+    };
+    execute(vm, &session.settings, |vm| vm.run_with_args(&args))
+}
+
+// Run `vm` with `run` on fresh fuel, the VM itself stops it at the timeout
+fn execute<F>(vm: &mut Vm, settings: &Settings, run: F) -> Evaluation
+where
+    F: FnOnce(&mut Vm) -> Result<Value, RuntimeError>,
+{
+    settings.refuel(vm);
+    run(vm).map_err(|e| (e.into(), vm.fault_span()))
+}
+
+// The source of a loaded script and what running it gave
+type ScriptResult = (String, Evaluation);
+
+// Compile and run the script at `path`, keeping it for `:reload`. Its free variables are
+// the parameters of its entry point, bound by name to the session's variables.
+fn load(path: &Path, session: &mut Session) -> Result<ScriptResult, String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let program = match compile_script(&source) {
+        Ok(program) => program,
+        Err(e) => return Ok((source, Err((e.into(), None)))),
+    };
+    let loaded = session.loaded.insert(Loaded {
+        path: path.to_path_buf(),
+        vm: Some(session.settings.vm(program.clone())),
+        source,
+        program,
+    });
+    let result = run_loaded(loaded, &session.settings, &session.variables);
+    Ok((loaded.source.clone(), result))
+}
+
+// Compile the loaded script again and swap it into its VM, which takes it only when it
+// reads the same variables, then run it like `:load`
+fn reload(session: &mut Session) -> Result<ScriptResult, String> {
+    let loaded = session.loaded.as_mut().ok_or("nothing loaded yet")?;
+    let path = loaded.path.display();
+    let source = fs::read_to_string(&loaded.path).map_err(|e| format!("{}: {}", path, e))?;
+    let program = match compile_script(&source) {
+        Ok(program) => program,
+        Err(e) => return Ok((source, Err((e.into(), None)))),
+    };
+    let vm = loaded
+        .vm
+        .get_or_insert_with(|| session.settings.vm(loaded.program.clone()));
+    vm.swap_program(&program)
+        .map_err(|e| format!("{}: {}, :load it to start over", path, e))?;
+    loaded.source = source;
+    loaded.program = program;
+    let result = run_loaded(loaded, &session.settings, &session.variables);
+    Ok((loaded.source.clone(), result))
+}
+
+fn compile_script(source: &str) -> Result<Program, CompileError> {
+    let options = CompileOptions::new().debug_info(true).free_variables(true);
+    compile_program(source, &options)
+}
+
+// Run the loaded script with its free variables bound to the session's variables, a script
+// without any has no entry point and takes none
+fn run_loaded(
+    loaded: &mut Loaded,
+    settings: &Settings,
+    variables: &[(String, Value)],
+) -> Evaluation {
+    let env: HashMap<String, Value> = match loaded.program.entry(ENTRY) {
+        Some(_) => variables.iter().cloned().collect(),
+        None => HashMap::new(),
+    };
+    let vm = loaded
+        .vm
+        .get_or_insert_with(|| settings.vm(loaded.program.clone()));
+    execute(vm, settings, |vm| vm.run_with(&env))
+}
+
+// Render the line of `input` holding `span` with the span underlined, like compile errors
+fn render_span(error: &Error, input: &str, span: Span) -> String {
+    let line_start = input[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let line = input[line_start..].lines().next().unwrap_or("");
+    let (line_number, column) = position(input, span.start);
+    // A span running over several lines is underlined to the end of its first one
+    let end = span.end.min(line_start + line.len());
+    let width = input[span.start..end.max(span.start)]
+        .chars()
+        .count()
+        .max(1);
+    format!(
+        "{} at {}:{}\n{}\n{}{}",
+        error,
+        line_number,
+        column,
+        line,
+        " ".repeat(column - 1),
+        "^".repeat(width)
+    )
+}
 
-    // Create VM and execute bytecode
-    let mut vm = Vm::new(bytecode, 32);
-    vm.run().ok_or("Failed to execute expression")
+// One based line and column in characters of the byte `offset` in `input`
+fn position(input: &str, offset: usize) -> (usize, usize) {
+    let line_start = input[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = input[..offset].matches('\n').count() + 1;
+    (line, input[line_start..offset].chars().count() + 1)
 }
@@ -0,0 +1,619 @@
+use std::{
+    collections::HashMap,
+    env,
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use librvm::{
+    cache::ProgramCache,
+    compiler::{
+        compile_program, compile_unit, parse_script, CompileError, CompileOptions, OptLevel,
+    },
+    diagnostic::Diagnostic,
+    disasm::disassemble,
+    error::RuntimeError,
+    formula::Compiled,
+    json,
+    lexer::{tokenize, TokenKind},
+    plot::{render_plot, render_table},
+    pretty::{format_source, tree},
+    program::{Program, ENTRY, MAGIC},
+    verify::verify,
+    vm::Vm,
+};
+
+const STACK_SIZE: usize = 64;
+
+// Exit codes telling scripts what went wrong, 2 is what clap exits with on invalid
+// arguments
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_COMPILE: i32 = 3;
+const EXIT_RUNTIME: i32 = 4;
+const EXIT_INVALID: i32 = 5;
+const EXIT_IO: i32 = 6;
+
+const EXIT_CODES: &str = "Exit status:
+  0  success
+  1  any other failure, like unformatted files for fmt --check
+  2  invalid arguments
+  3  the source failed to parse or compile
+  4  the program failed at runtime
+  5  a bytecode file failed to decode or verify
+  6  a file could not be read or written";
+
+// Width in columns of the `--plot` output
+const PLOT_WIDTH: usize = 60;
+
+#[derive(Parser)]
+#[command(
+    name = "rvm",
+    version,
+    about = "Compile and run rvm expressions",
+    after_help = EXIT_CODES
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // `rvm -e <expr>` evaluates without naming a command
+    #[command(flatten)]
+    eval: EvalArgs,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Diagnostics::Human,
+        global = true,
+        help = "Report compile errors for people or as JSON lines"
+    )]
+    diagnostics: Diagnostics,
+
+    #[arg(
+        long,
+        default_value_t = STACK_SIZE,
+        global = true,
+        help = "Number of values the VM stack may hold"
+    )]
+    stack_size: usize,
+
+    #[arg(
+        short = 'O',
+        long,
+        value_parser = parse_opt_level,
+        default_value = "basic",
+        global = true,
+        help = "Optimizations applied to compiled source files: basic, size or speed"
+    )]
+    opt_level: OptLevel,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Emit::Result,
+        global = true,
+        help = "Stage at which eval and run stop, printing what it produced"
+    )]
+    emit: Emit,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Evaluate an expression")]
+    Eval(EvalArgs),
+    #[command(about = "Tabulate or plot an expression of one variable")]
+    Tab(TabArgs),
+    #[command(about = "Start the interactive session")]
+    Repl {
+        #[arg(
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            help = "Arguments passed on to rvmd"
+        )]
+        args: Vec<String>,
+    },
+    #[command(about = "Run a source file, or a bytecode file from rvm compile")]
+    Run { file: PathBuf },
+    #[command(about = "Compile a source file into a bytecode file")]
+    Compile {
+        file: PathBuf,
+        #[arg(
+            short,
+            long,
+            help = "Where to write the bytecode, - for stdout [default: the file with the .rvmb extension]"
+        )]
+        output: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "Leave out the source locations reported with runtime errors"
+        )]
+        strip: bool,
+    },
+    #[command(about = "Check source or bytecode files without running them")]
+    Check {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    #[command(about = "Rewrite source files in the canonical layout")]
+    Fmt {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        #[arg(
+            long,
+            help = "Fail on files that are not formatted instead of rewriting them"
+        )]
+        check: bool,
+    },
+    #[command(about = "Print the instructions of a bytecode file")]
+    Disasm { file: PathBuf },
+    #[command(about = "Print a completion script for a shell")]
+    Completions { shell: Shell },
+}
+
+#[derive(Args)]
+struct EvalArgs {
+    #[arg(short, long, help = "Expression to evaluate")]
+    expr: Option<String>,
+
+    #[arg(long, help = "Keep compiled programs in this directory")]
+    cache_dir: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct TabArgs {
+    #[arg(short, long, help = "Expression to tabulate")]
+    expr: String,
+
+    #[arg(
+        long,
+        value_parser = parse_range,
+        default_value = "0..10",
+        help = "Values of the variable, as <start>..<end>"
+    )]
+    range: (f64, f64),
+
+    #[arg(
+        long,
+        default_value_t = 1.0,
+        help = "Distance between values of the variable"
+    )]
+    step: f64,
+
+    #[arg(long, help = "Draw a plot rather than a table")]
+    plot: bool,
+
+    #[arg(long, help = "Keep compiled programs in this directory")]
+    cache_dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Diagnostics {
+    Human,
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    Tokens,
+    Ast,
+    Bytecode,
+    Result,
+}
+
+// A command failure, compile and runtime errors are kept whole so they can be reported as
+// diagnostics
+enum Failure {
+    Message(String),
+    Io(String),
+    // Bytecode that does not decode or is rejected by the verifier
+    Invalid(String),
+    // The failures of a command working through several files, with the exit code of the
+    // first one
+    Summary(String, i32),
+    Compile(CompileError, String),
+    // The address of the failing instruction and its place in the source when known
+    Runtime {
+        error: RuntimeError,
+        address: Option<usize>,
+        location: Option<String>,
+    },
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Message(_) => EXIT_FAILURE,
+            Failure::Io(_) => EXIT_IO,
+            Failure::Invalid(_) => EXIT_INVALID,
+            Failure::Summary(_, code) => *code,
+            Failure::Compile(..) => EXIT_COMPILE,
+            Failure::Runtime { .. } => EXIT_RUNTIME,
+        }
+    }
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Failure::Message(message)
+    }
+}
+
+impl From<&str> for Failure {
+    fn from(message: &str) -> Self {
+        Failure::Message(message.to_string())
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let result = match &cli.command {
+        Some(Command::Eval(args)) => eval(args, &cli),
+        None if cli.eval.expr.is_some() => eval(&cli.eval, &cli),
+        None => {
+            let _ = Cli::command().print_help();
+            process::exit(EXIT_USAGE);
+        }
+        Some(Command::Tab(args)) => tab(args),
+        Some(Command::Repl { args }) => repl(args),
+        Some(Command::Run { file }) => run(file, &cli),
+        Some(Command::Compile {
+            file,
+            output,
+            strip,
+        }) => compile_file(file, output.as_deref(), *strip, &cli),
+        Some(Command::Check { files }) => check(files, &cli),
+        Some(Command::Fmt { files, check }) => fmt(files, *check, &cli),
+        Some(Command::Disasm { file }) => disasm(file),
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(*shell, &mut Cli::command(), "rvm", &mut io::stdout());
+            Ok(String::new())
+        }
+    };
+    match result {
+        Ok(output) => print!("{}", output),
+        Err(failure) => {
+            let code = failure.exit_code();
+            report(failure, &cli);
+            process::exit(code);
+        }
+    }
+}
+
+fn report(failure: Failure, cli: &Cli) {
+    match failure {
+        Failure::Compile(error, _) if cli.diagnostics == Diagnostics::Json => {
+            eprintln!("{}", Diagnostic::from(&error).to_json())
+        }
+        Failure::Compile(error, source) => eprintln!("Error: {}", error.render(&source)),
+        Failure::Runtime {
+            error,
+            address,
+            location,
+        } if cli.diagnostics == Diagnostics::Json => {
+            let address = address.map_or("null".to_string(), |a| a.to_string());
+            let location = location.map_or("null".to_string(), |l| json::string(&l));
+            eprintln!(
+                r#"{{"severity":"error","message":{},"address":{},"location":{}}}"#,
+                json::string(&error.to_string()),
+                address,
+                location
+            )
+        }
+        Failure::Runtime {
+            error,
+            address,
+            location,
+        } => match (location, address) {
+            (Some(location), _) => eprintln!("Error: {} at {}", error, location),
+            (None, Some(address)) => eprintln!("Error: {} at {:#06x}", error, address),
+            (None, None) => eprintln!("Error: {}", error),
+        },
+        Failure::Message(message)
+        | Failure::Io(message)
+        | Failure::Invalid(message)
+        | Failure::Summary(message, _) => eprintln!("Error: {}", message),
+    }
+}
+
+fn parse_opt_level(input: &str) -> Result<OptLevel, String> {
+    match input {
+        "basic" => Ok(OptLevel::Basic),
+        "size" => Ok(OptLevel::Size),
+        "speed" => Ok(OptLevel::Speed),
+        _ => Err(format!("invalid optimization level {}", input)),
+    }
+}
+
+fn parse_range(input: &str) -> Result<(f64, f64), String> {
+    let (start, end) = input
+        .split_once("..")
+        .ok_or(format!("invalid range {}", input))?;
+    Ok((parse_number(start)?, parse_number(end)?))
+}
+
+fn parse_number(input: &str) -> Result<f64, String> {
+    input
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid number {}", input))
+}
+
+// Compile through the cache when one is configured
+fn compile(source: &str, cache: Option<&ProgramCache>) -> Result<Program, Failure> {
+    let program = match cache {
+        Some(cache) => cache.compile(source),
+        None => compile_unit(&[(ENTRY, source)]),
+    };
+    program.map_err(|e| Failure::Compile(e, source.to_string()))
+}
+
+// Compile a source file with the optimizations selected on the command line, keeping the
+// source locations for runtime errors unless `strip` is set
+fn compile_source(file: &Path, strip: bool, cli: &Cli) -> Result<(Program, String), Failure> {
+    let source = read_source(file)?;
+    let options = CompileOptions::new()
+        .opt_level(cli.opt_level)
+        .debug_info(!strip);
+    let program =
+        compile_program(&source, &options).map_err(|e| Failure::Compile(e, source.clone()))?;
+    Ok((program, source))
+}
+
+fn read_source(file: &Path) -> Result<String, Failure> {
+    fs::read_to_string(file).map_err(|e| io_failure(file, e))
+}
+
+fn read_bytes(file: &Path) -> Result<Vec<u8>, Failure> {
+    fs::read(file).map_err(|e| io_failure(file, e))
+}
+
+fn io_failure(file: &Path, error: io::Error) -> Failure {
+    Failure::Io(format!("{}: {}", file.display(), error))
+}
+
+fn invalid(file: &Path, error: impl Display) -> Failure {
+    Failure::Invalid(format!("{}: {}", file.display(), error))
+}
+
+fn eval(args: &EvalArgs, cli: &Cli) -> Result<String, Failure> {
+    let expr = args
+        .expr
+        .as_deref()
+        .ok_or("missing expression, pass it with -e")?;
+    if let Some(output) = emit_source(expr, cli.emit)? {
+        return Ok(output);
+    }
+    let cache = args.cache_dir.as_ref().map(ProgramCache::new);
+
+    let program = compile(expr, cache.as_ref())?;
+    if cli.emit == Emit::Bytecode {
+        return disassemble(program.bytecode()).map_err(|e| Failure::Invalid(e.to_string()));
+    }
+    if let Some(param) = program
+        .entry(ENTRY)
+        .and_then(|entry| entry.params().first())
+    {
+        return Err(format!("unbound variable {}", param).into());
+    }
+    let result = Vm::new(program, cli.stack_size)
+        .run_entry(ENTRY, &HashMap::new())
+        .map_err(|error| Failure::Runtime {
+            error,
+            address: None,
+            location: None,
+        })?;
+    Ok(format!("{}\n", result))
+}
+
+fn tab(args: &TabArgs) -> Result<String, Failure> {
+    if args.step <= 0.0 {
+        return Err("step must be positive".into());
+    }
+    let cache = args.cache_dir.as_ref().map(ProgramCache::new);
+    let compiled = Compiled::from(compile(&args.expr, cache.as_ref())?);
+    let (start, end) = args.range;
+    let rows = compiled
+        .tabulate(start, end, args.step)
+        .map_err(|e| e.to_string())?;
+    if args.plot {
+        Ok(render_plot(&rows, PLOT_WIDTH))
+    } else {
+        Ok(render_table(&args.expr, &rows))
+    }
+}
+
+// Hand over to `rvmd`, which is installed next to this binary
+fn repl(args: &[String]) -> Result<String, Failure> {
+    let rvmd = env::current_exe()
+        .map_err(|e| Failure::Io(e.to_string()))?
+        .with_file_name(format!("rvmd{}", env::consts::EXE_SUFFIX));
+    let status = process::Command::new(&rvmd)
+        .args(args)
+        .status()
+        .map_err(|e| io_failure(&rvmd, e))?;
+    process::exit(status.code().unwrap_or(EXIT_FAILURE));
+}
+
+// Run a source file, or a bytecode file once its header decodes and its code passes the
+// verifier, since it may come from anywhere
+fn run(file: &Path, cli: &Cli) -> Result<String, Failure> {
+    let bytes = read_bytes(file)?;
+    let (program, source) = if bytes.starts_with(MAGIC) {
+        if matches!(cli.emit, Emit::Tokens | Emit::Ast) {
+            return Err(
+                format!("{}: bytecode files have no source to emit", file.display()).into(),
+            );
+        }
+        let program = Program::from_bytes(&bytes).map_err(|e| invalid(file, e))?;
+        verify(&program).map_err(|e| invalid(file, e))?;
+        (program, None)
+    } else {
+        if let Some(output) = emit_source(&read_source(file)?, cli.emit)? {
+            return Ok(output);
+        }
+        let (program, source) = compile_source(file, false, cli)?;
+        (program, Some(source))
+    };
+    if cli.emit == Emit::Bytecode {
+        return disassemble(program.bytecode()).map_err(|e| Failure::Invalid(e.to_string()));
+    }
+    let mut vm = Vm::new(program, cli.stack_size);
+    match vm.run() {
+        Ok(result) => Ok(format!("{}\n", result)),
+        Err(error) => {
+            let location = source.zip(vm.fault_span()).map(|(source, span)| {
+                let (line, column) = position(&source, span.start);
+                format!("{}:{}:{}", file.display(), line, column)
+            });
+            Err(Failure::Runtime {
+                error,
+                address: vm.fault_address(),
+                location,
+            })
+        }
+    }
+}
+
+// The tokens or syntax tree of `source` when those are the stage to stop at
+fn emit_source(source: &str, emit: Emit) -> Result<Option<String>, Failure> {
+    let failure = |e| Failure::Compile(e, source.to_string());
+    match emit {
+        Emit::Tokens => {
+            let mut output = String::new();
+            for token in tokenize(source).map_err(failure)? {
+                let (line, column) = position(source, token.span.start);
+                let kind = match token.kind {
+                    TokenKind::Number(value) => value.type_name(),
+                    TokenKind::Str(_) => "string",
+                    TokenKind::Bool(_) => "bool",
+                    TokenKind::Ident(_) => "identifier",
+                    TokenKind::Op(_) => "operator",
+                    _ => "punctuation",
+                };
+                let text = &source[token.span.start..token.span.end];
+                output.push_str(&format!("{}:{}\t{}\t{}\n", line, column, kind, text));
+            }
+            Ok(Some(output))
+        }
+        Emit::Ast => Ok(Some(tree(&parse_script(source).map_err(failure)?))),
+        Emit::Bytecode | Emit::Result => Ok(None),
+    }
+}
+
+// Write the program compiled from `file` in the container format of `Program::to_bytes`
+fn compile_file(
+    file: &Path,
+    output: Option<&Path>,
+    strip: bool,
+    cli: &Cli,
+) -> Result<String, Failure> {
+    let (program, _) = compile_source(file, strip, cli)?;
+    let output = output.map_or_else(|| file.with_extension("rvmb"), Path::to_path_buf);
+    let written = if output == Path::new("-") {
+        program.write_to(io::stdout().lock())
+    } else {
+        fs::File::create(&output).and_then(|file| program.write_to(io::BufWriter::new(file)))
+    };
+    written.map_err(|e| io_failure(&output, e))?;
+    Ok(String::new())
+}
+
+// Apply `action` to every file, reporting each failure as it happens rather than stopping
+// at the first. Fails with `summary` when any file failed.
+fn for_each_file(
+    files: &[PathBuf],
+    summary: &str,
+    cli: &Cli,
+    mut action: impl FnMut(&Path) -> Result<(), Failure>,
+) -> Result<String, Failure> {
+    let mut failed = 0;
+    let mut code = EXIT_FAILURE;
+    for file in files {
+        if let Err(failure) = action(file) {
+            if failed == 0 {
+                code = failure.exit_code();
+            }
+            // Name the file in rendered errors, JSON diagnostics are reported as they are
+            let failure = match failure {
+                Failure::Compile(error, source) if cli.diagnostics == Diagnostics::Human => {
+                    Failure::Message(format!("{}: {}", file.display(), error.render(&source)))
+                }
+                failure => failure,
+            };
+            report(failure, cli);
+            failed += 1;
+        }
+    }
+    match failed {
+        0 => Ok(String::new()),
+        1 => Err(Failure::Summary(format!("1 file {}", summary), code)),
+        failed => Err(Failure::Summary(
+            format!("{} files {}", failed, summary),
+            code,
+        )),
+    }
+}
+
+fn check(files: &[PathBuf], cli: &Cli) -> Result<String, Failure> {
+    for_each_file(files, "failed the check", cli, check_file)
+}
+
+// Decode and verify a bytecode file, or parse, type check and compile a source file with
+// its free variables as parameters, like the formulas `rvm tab` and `rvm eval` compile
+fn check_file(file: &Path) -> Result<(), Failure> {
+    let bytes = read_bytes(file)?;
+    if bytes.starts_with(MAGIC) {
+        let program = Program::from_bytes(&bytes).map_err(|e| invalid(file, e))?;
+        return verify(&program).map_err(|e| invalid(file, e));
+    }
+    let source = String::from_utf8(bytes)
+        .map_err(|_| Failure::Io(format!("{}: invalid UTF-8", file.display())))?;
+    let failure = |e| Failure::Compile(e, source.clone());
+    let program = compile_unit(&[(ENTRY, &source)]).map_err(failure)?;
+    let params: Vec<&str> = program
+        .entry(ENTRY)
+        .map(|entry| entry.params().iter().map(String::as_str).collect())
+        .unwrap_or_default();
+    let options = CompileOptions::new().params(&params).type_check(true);
+    compile_program(&source, &options).map_err(failure)?;
+    Ok(())
+}
+
+// Rewrite the files that are not in the layout of `format_source`, or with `check` only
+// report them
+fn fmt(files: &[PathBuf], check: bool, cli: &Cli) -> Result<String, Failure> {
+    let summary = if check {
+        "not formatted"
+    } else {
+        "failed to format"
+    };
+    for_each_file(files, summary, cli, |file| {
+        let source = read_source(file)?;
+        let formatted = format_source(&source).map_err(|e| Failure::Compile(e, source.clone()))?;
+        if formatted == source {
+            return Ok(());
+        }
+        if check {
+            return Err(format!("{}: not formatted", file.display()).into());
+        }
+        fs::write(file, formatted).map_err(|e| io_failure(file, e))
+    })
+}
+
+fn disasm(file: &Path) -> Result<String, Failure> {
+    let bytes = read_bytes(file)?;
+    let program = Program::from_bytes(&bytes).map_err(|e| invalid(file, e))?;
+    disassemble(program.bytecode()).map_err(|e| invalid(file, e))
+}
+
+// One based line and column in characters of the byte `offset` in `source`
+fn position(source: &str, offset: usize) -> (usize, usize) {
+    let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = source[..offset].matches('\n').count() + 1;
+    (line, source[line_start..offset].chars().count() + 1)
+}
@@ -0,0 +1,253 @@
+use crate::{
+    compiler::{compile_restricted, Allowlist, Expr, Feature},
+    error::SandboxError,
+    parser::parse_script,
+    value::Value,
+    vm::Vm,
+};
+
+// Resource caps applied by `eval_untrusted`, the defaults are deliberately conservative
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    // Longest accepted source in bytes, this also bounds the length of the generated code
+    pub max_source_len: usize,
+    // Deepest accepted nesting of operations, calls, conditionals and parentheses. The parser
+    // and the compiler recurse over the expression, this keeps them within the native stack.
+    pub max_nesting: usize,
+    // Number of values the VM stack may hold
+    pub stack_size: usize,
+    // Instructions the VM may execute, see `Vm::with_fuel`
+    pub fuel: u64,
+    // Bytes of string data the VM stack may hold, see `Vm::with_memory_limit`
+    pub memory_limit: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_source_len: 4096,
+            max_nesting: 128,
+            stack_size: 64,
+            fuel: 100_000,
+            memory_limit: 64 * 1024,
+        }
+    }
+}
+
+// Syntax accepted from untrusted sources. User defined functions are the only way to loop
+// and factorial runs in time linear to its operand, denying both means every accepted
+// program finishes in time and memory bounded by its source length.
+fn untrusted_features() -> Allowlist {
+    Allowlist::all()
+        .deny(Feature::Functions)
+        .deny(Feature::Factorial)
+}
+
+// Compile and run an expression from an untrusted source in one call, within `limits`
+pub fn eval_untrusted(src: &str, limits: &Limits) -> Result<Value, SandboxError> {
+    if src.len() > limits.max_source_len {
+        return Err(SandboxError::SourceTooLong);
+    }
+    if nesting(src) > limits.max_nesting {
+        return Err(SandboxError::NestedTooDeeply);
+    }
+    // Chains of operators are parsed in a loop but give trees as deep as they are long
+    let script = parse_script(src)?;
+    let bodies = script.functions.iter().map(|function| &function.body);
+    if bodies
+        .chain([&script.body])
+        .any(|body| depth(body) > limits.max_nesting)
+    {
+        return Err(SandboxError::NestedTooDeeply);
+    }
+    let bytecode = compile_restricted(src, &untrusted_features())?;
+
+    let mut vm = Vm::builder(bytecode)
+        .stack_size(limits.stack_size)
+        .checked_arithmetic(true)
+        .fuel(limits.fuel)
+        .memory_limit(limits.memory_limit)
+        .build();
+    Ok(vm.run()?)
+}
+
+// Bound on the recursion of the parser, which recurses into parentheses and into the
+// branches of every conditional
+fn nesting(src: &str) -> usize {
+    let mut depth: usize = 0;
+    let mut deepest = 0;
+    let mut conditionals = 0;
+    for c in src.chars() {
+        match c {
+            '(' => {
+                depth += 1;
+                deepest = deepest.max(depth);
+            }
+            ')' => depth = depth.saturating_sub(1),
+            '?' => conditionals += 1,
+            _ => {}
+        }
+    }
+    deepest + conditionals
+}
+
+// Operations nested in `expr`, counted without recursion since the tree may be deeper than
+// the native stack allows
+fn depth(expr: &Expr) -> usize {
+    let mut deepest = 0;
+    let mut pending = vec![(expr, 0)];
+    while let Some((expr, depth)) = pending.pop() {
+        deepest = deepest.max(depth);
+        match expr {
+            Expr::Number(_) | Expr::Str(_) | Expr::Var(_) => {}
+            Expr::Call(_, args) => pending.extend(args.iter().map(|arg| (arg, depth + 1))),
+            Expr::UnaryOp(_, operand) => pending.push((operand, depth + 1)),
+            Expr::BinOp(lhs, _, rhs) => pending.extend([(&**lhs, depth + 1), (&**rhs, depth + 1)]),
+            Expr::Conditional(condition, then, otherwise) => {
+                pending.extend([condition, then, otherwise].map(|expr| (&**expr, depth + 1)))
+            }
+        }
+    }
+    deepest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::RuntimeError, opcode::Opcode};
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("1 + 2", Value::Int(3))]
+    #[case("sqrt(16) * pi", Value::Float(4.0 * std::f64::consts::PI))]
+    #[case("1 < 2 && len(\"ab\") == 2", Value::Bool(true))]
+    fn test_eval_untrusted(#[case] src: &str, #[case] expected: Value) {
+        assert_eq!(eval_untrusted(src, &Limits::default()), Ok(expected));
+    }
+
+    #[rstest]
+    #[case("fn f(x) { f(x) + f(x) } f(1)", "Functions are not allowed")]
+    #[case("100000000000!", "Factorial is not allowed")]
+    #[case("factorial(5)", "Factorial is not allowed")]
+    #[case("1 +", "Unexpected trailing input")]
+    fn test_eval_untrusted_errors(#[case] src: &str, #[case] expected: &str) {
+        match eval_untrusted(src, &Limits::default()) {
+            Err(SandboxError::Compile(error)) => assert_eq!(error.message(), expected),
+            result => panic!("expected a compile error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_eval_untrusted_runtime_errors() {
+        assert_eq!(
+            eval_untrusted("\"a\" * 2", &Limits::default()),
+            Err(SandboxError::Runtime(RuntimeError::TypeMismatch {
+                op: Opcode::Multiply,
+                lhs: "string",
+                rhs: Some("int"),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_source_len: 16,
+            max_nesting: 2,
+            stack_size: 2,
+            ..Limits::default()
+        };
+        assert_eq!(
+            eval_untrusted("1 + 1 + 1 + 1 + 1 + 1", &limits),
+            Err(SandboxError::SourceTooLong)
+        );
+        assert_eq!(
+            eval_untrusted("(((1)))", &limits),
+            Err(SandboxError::NestedTooDeeply)
+        );
+        assert_eq!(
+            eval_untrusted("--!1", &limits),
+            Err(SandboxError::NestedTooDeeply)
+        );
+        assert_eq!(
+            eval_untrusted("1+1+1+1", &limits),
+            Err(SandboxError::NestedTooDeeply)
+        );
+        assert_eq!(
+            eval_untrusted("1?2:3?4:5?6:7", &limits),
+            Err(SandboxError::NestedTooDeeply)
+        );
+        assert_eq!(
+            eval_untrusted("1 + (2 + (3))", &limits),
+            Err(SandboxError::Runtime(RuntimeError::StackOverflow))
+        );
+        assert_eq!(eval_untrusted("(1 + 2) + 3", &limits), Ok(Value::Int(6)));
+    }
+
+    #[rstest]
+    #[case("(0 - 9223372036854775807 - 1) / -1", Opcode::Divide, vec![i64::MIN, -1])]
+    #[case("(0 - 9223372036854775807 - 1) % -1", Opcode::Modulo, vec![i64::MIN, -1])]
+    #[case("9223372036854775807 + 1", Opcode::Addition, vec![i64::MAX, 1])]
+    #[case("pow(3, 99)", Opcode::Pow, vec![3, 99])]
+    fn test_eval_untrusted_overflow(
+        #[case] src: &str,
+        #[case] op: Opcode,
+        #[case] operands: Vec<i64>,
+    ) {
+        assert_eq!(
+            eval_untrusted(src, &Limits::default()),
+            Err(SandboxError::Runtime(RuntimeError::Overflow {
+                op,
+                operands
+            }))
+        );
+    }
+
+    #[rstest]
+    #[case("!".repeat(4000) + "1")]
+    #[case("- ".repeat(2000) + "1")]
+    #[case("√".repeat(1300) + "1")]
+    #[case("1".to_string() + &"+1".repeat(2000))]
+    #[case("1".to_string() + &" ? 1 : 1".repeat(500))]
+    #[case("len(".repeat(1000) + "1")]
+    fn test_eval_untrusted_deep(#[case] src: String) {
+        assert!(src.len() <= Limits::default().max_source_len);
+        assert_eq!(
+            eval_untrusted(&src, &Limits::default()),
+            Err(SandboxError::NestedTooDeeply)
+        );
+    }
+
+    #[test]
+    fn test_eval_untrusted_deepest() {
+        let limits = Limits::default();
+        let src = "- ".repeat(limits.max_nesting) + "1";
+        assert_eq!(eval_untrusted(&src, &limits), Ok(Value::Int(1)));
+        let src = "- ".repeat(limits.max_nesting + 1) + "1";
+        assert_eq!(
+            eval_untrusted(&src, &limits),
+            Err(SandboxError::NestedTooDeeply)
+        );
+    }
+
+    #[test]
+    fn test_fuel_and_memory_limits() {
+        let limits = Limits {
+            fuel: 4,
+            ..Limits::default()
+        };
+        assert_eq!(
+            eval_untrusted("1 + len(\"ab\") * 3", &limits),
+            Err(SandboxError::Runtime(RuntimeError::OutOfFuel))
+        );
+        let limits = Limits {
+            memory_limit: 8,
+            ..Limits::default()
+        };
+        assert_eq!(
+            eval_untrusted("\"abcd\" + \"efgh\" + 1", &limits),
+            Err(SandboxError::Runtime(RuntimeError::OutOfMemory))
+        );
+        assert_eq!(eval_untrusted("len(\"abcd\")", &limits), Ok(Value::Int(4)));
+    }
+}
@@ -1,26 +1,239 @@
-use crate::value::Value;
+use std::fmt::Display;
 
+use crate::{error::StackError, value::Value};
+
+// Slots allocated up front, the stack doubles them as it fills up
+const INITIAL_CAPACITY: usize = 16;
+
+// Stack of values growing on demand up to a hard cap. The values from the base pointer up
+// belong to the running function, its arguments first, see `push_frame`.
+#[derive(Debug)]
 pub struct Stack {
     max: usize,
     data: Vec<Value>,
+    base: usize,
+    // Most values held at once since the last `reset_peak`
+    peak: usize,
+    // Bytes of the strings held and the most there may be, see `has_room_for`
+    heap: usize,
+    memory_limit: Option<usize>,
 }
 
 impl Stack {
     pub fn new(max: usize) -> Stack {
         Stack {
             max,
-            data: Vec::with_capacity(max),
+            data: Vec::with_capacity(max.min(INITIAL_CAPACITY)),
+            base: 0,
+            peak: 0,
+            heap: 0,
+            memory_limit: None,
         }
     }
 
-    pub fn push(&mut self, value: Value) {
-        assert!(self.data.len() < self.max, "stack overflow");
+    // Push `value`, failing once the stack holds its most values or the strings would go
+    // past the memory limit
+    pub fn push(&mut self, value: Value) -> Result<(), StackError> {
+        if self.is_full() {
+            return Err(StackError::Overflow);
+        }
+        if !self.has_room_for(&value) {
+            return Err(StackError::OutOfMemory);
+        }
+        if self.data.len() == self.data.capacity() {
+            let grown = (self.data.capacity() * 2).clamp(1, self.max);
+            self.data.reserve_exact(grown - self.data.len());
+        }
+        self.heap += heap_size(&value);
         self.data.push(value);
+        self.peak = self.peak.max(self.data.len());
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Result<Value, StackError> {
+        let value = self.data.pop().ok_or(StackError::Underflow)?;
+        self.heap -= heap_size(&value);
+        Ok(value)
     }
 
-    pub fn pop(&mut self) -> Value {
+    // Pop a value the caller knows is there, like the result of an operation it just
+    // pushed. Panics on an empty stack.
+    pub(crate) fn pop_unchecked(&mut self) -> Value {
         assert!(!self.data.is_empty(), "stack underflow");
-        self.data.pop().unwrap()
+        self.pop().unwrap()
+    }
+
+    // The value on top, without popping it
+    pub fn peek(&self) -> Option<&Value> {
+        self.data.last()
+    }
+
+    // The top `n` values in the order they were pushed, `None` when the stack holds fewer
+    pub fn peek_n(&self, n: usize) -> Option<&[Value]> {
+        let start = self.data.len().checked_sub(n)?;
+        Some(&self.data[start..])
+    }
+
+    pub fn get(&self, index: usize) -> Value {
+        assert!(index < self.data.len(), "stack underflow");
+        self.data[index].clone()
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        let removed: usize = self
+            .data
+            .get(len..)
+            .unwrap_or(&[])
+            .iter()
+            .map(heap_size)
+            .sum();
+        self.heap -= removed;
+        self.data.truncate(len);
+    }
+
+    // Start the frame of a call whose arguments are the top `argc` values, they become its
+    // first locals. The base of the calling frame is for the caller to keep, to hand back
+    // to `pop_frame`.
+    pub fn push_frame(&mut self, argc: usize) -> Result<(), StackError> {
+        if argc > self.data.len() {
+            return Err(StackError::Underflow);
+        }
+        self.base = self.data.len() - argc;
+        Ok(())
+    }
+
+    // Drop the values of the running frame and go back to the frame starting at `base`
+    pub fn pop_frame(&mut self, base: usize) {
+        self.truncate(self.base);
+        self.base = base.min(self.data.len());
+    }
+
+    // Start of the running frame, zero outside of any call
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    // Move the base pointer, like `pop_frame` without dropping anything. Used to resume a
+    // run whose frames were saved.
+    pub fn set_base(&mut self, base: usize) {
+        assert!(base <= self.data.len(), "stack underflow");
+        self.base = base;
+    }
+
+    // Local `index` of the running frame, counted from its base
+    pub fn local(&self, index: usize) -> Result<Value, StackError> {
+        self.data
+            .get(self.base + index)
+            .cloned()
+            .ok_or(StackError::Underflow)
+    }
+
+    pub fn set_local(&mut self, index: usize, value: Value) -> Result<(), StackError> {
+        let slot = self
+            .data
+            .get_mut(self.base + index)
+            .ok_or(StackError::Underflow)?;
+        self.heap = self.heap - heap_size(slot) + heap_size(&value);
+        *slot = value;
+        Ok(())
+    }
+
+    // Drop every value and frame
+    pub fn clear(&mut self) {
+        self.truncate(0);
+        self.base = 0;
+    }
+
+    // Move the top `count` values down to `base`, dropping the values in between
+    pub fn squash(&mut self, base: usize, count: usize) {
+        assert!(base + count <= self.data.len(), "stack underflow");
+        let end = self.data.len() - count;
+        let removed: usize = self.data[base..end].iter().map(heap_size).sum();
+        self.heap -= removed;
+        self.data.drain(base..end);
+    }
+
+    // The values from the top of the stack to the bottom
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = &Value> {
+        self.data.iter().rev()
+    }
+
+    // The values from the bottom of the stack to the top
+    pub fn as_slice(&self) -> &[Value] {
+        &self.data
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    // Most values the stack can hold
+    pub fn capacity(&self) -> usize {
+        self.max
+    }
+
+    // Slots allocated so far, from a few up to `capacity` as values are pushed
+    pub fn allocated(&self) -> usize {
+        self.data.capacity()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.data.len() >= self.max
+    }
+
+    // Most values the stack held at once, the high-water mark
+    pub fn peak(&self) -> usize {
+        self.peak
+    }
+
+    pub fn reset_peak(&mut self) {
+        self.peak = self.data.len();
+    }
+
+    // Count `depth` towards the peak, for code that skips pushing a value it would use
+    // right away
+    pub(crate) fn record_depth(&mut self, depth: usize) {
+        self.peak = self.peak.max(depth);
+    }
+
+    // Bytes of the strings on the stack, a string pushed twice counts twice
+    pub fn heap(&self) -> usize {
+        self.heap
+    }
+
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+    }
+
+    // Whether pushing `value` keeps the strings within the memory limit
+    pub fn has_room_for(&self, value: &Value) -> bool {
+        self.memory_limit
+            .is_none_or(|limit| self.heap + heap_size(value) <= limit)
+    }
+}
+
+// The values from the bottom up like `[1, 2.5, abc]`, the order they were pushed in
+impl Display for Stack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[")?;
+        for (i, value) in self.data.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}", value)?;
+        }
+        f.write_str("]")
+    }
+}
+
+fn heap_size(value: &Value) -> usize {
+    match value {
+        Value::Str(value) => value.len(),
+        _ => 0,
     }
 }
 
@@ -38,26 +251,52 @@ mod tests {
     #[test]
     fn test_push_and_pop() {
         let mut stack = Stack::new(2);
-        stack.push(Value::Int(1));
-        stack.push(Value::Int(2));
-        assert_eq!(stack.pop(), Value::Int(2));
-        assert_eq!(stack.pop(), Value::Int(1));
+        stack.push(Value::Int(1)).unwrap();
+        stack.push(Value::Int(2)).unwrap();
+        assert_eq!(stack.pop(), Ok(Value::Int(2)));
+        assert_eq!(stack.pop(), Ok(Value::Int(1)));
     }
 
     #[test]
-    #[should_panic(expected = "stack overflow")]
     fn test_stack_overflow() {
         let mut stack = Stack::new(2);
-        stack.push(Value::Int(1));
-        stack.push(Value::Int(2));
-        stack.push(Value::Int(3)); // Should panic
+        stack.push(Value::Int(1)).unwrap();
+        stack.push(Value::Int(2)).unwrap();
+        assert_eq!(stack.push(Value::Int(3)), Err(StackError::Overflow));
+        assert_eq!(stack.len(), 2);
+    }
+
+    #[test]
+    fn test_growth() {
+        let mut stack = Stack::new(100);
+        assert_eq!(stack.allocated(), INITIAL_CAPACITY);
+        for n in 0..=INITIAL_CAPACITY {
+            stack.push(Value::Int(n as i64)).unwrap();
+        }
+        assert_eq!(stack.allocated(), INITIAL_CAPACITY * 2);
+
+        // Growth stops at the cap
+        for n in stack.len()..100 {
+            stack.push(Value::Int(n as i64)).unwrap();
+        }
+        assert_eq!(stack.allocated(), 100);
+        assert_eq!(stack.push(Value::Int(0)), Err(StackError::Overflow));
+        assert_eq!(stack.get(99), Value::Int(99));
     }
 
     #[test]
-    #[should_panic(expected = "stack underflow")]
     fn test_stack_underflow() {
         let mut stack = Stack::new(2);
-        stack.pop(); // Should panic
+        assert_eq!(stack.pop(), Err(StackError::Underflow));
+    }
+
+    #[test]
+    #[should_panic(expected = "stack underflow")]
+    fn test_pop_unchecked() {
+        let mut stack = Stack::new(2);
+        stack.push(Value::Int(1)).unwrap();
+        assert_eq!(stack.pop_unchecked(), Value::Int(1));
+        stack.pop_unchecked(); // Should panic
     }
 
     #[test]
@@ -65,19 +304,160 @@ mod tests {
         let mut stack = Stack::new(3);
         
         // Push some values
-        stack.push(Value::Int(1));
-        stack.push(Value::Int(2));
+        stack.push(Value::Int(1)).unwrap();
+        stack.push(Value::Int(2)).unwrap();
         
         // Pop one and verify
-        assert_eq!(stack.pop(), Value::Int(2));
+        assert_eq!(stack.pop(), Ok(Value::Int(2)));
         
         // Push more
-        stack.push(Value::Int(3));
-        stack.push(Value::Int(4));
+        stack.push(Value::Int(3)).unwrap();
+        stack.push(Value::Int(4)).unwrap();
         
         // Verify final state
-        assert_eq!(stack.pop(), Value::Int(4));
-        assert_eq!(stack.pop(), Value::Int(3));
-        assert_eq!(stack.pop(), Value::Int(1));
+        assert_eq!(stack.pop(), Ok(Value::Int(4)));
+        assert_eq!(stack.pop(), Ok(Value::Int(3)));
+        assert_eq!(stack.pop(), Ok(Value::Int(1)));
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut stack = Stack::new(3);
+        assert_eq!(stack.peek(), None);
+        assert_eq!(stack.peek_n(0), Some(&[][..]));
+        stack.push(Value::Int(1)).unwrap();
+        stack.push(Value::Int(2)).unwrap();
+        assert_eq!(stack.peek(), Some(&Value::Int(2)));
+        assert_eq!(stack.peek_n(2), Some(&[Value::Int(1), Value::Int(2)][..]));
+        assert_eq!(stack.peek_n(3), None);
+        assert_eq!(stack.len(), 2);
+    }
+
+    #[test]
+    fn test_iter_and_display() {
+        let mut stack = Stack::new(4);
+        assert_eq!(stack.to_string(), "[]");
+        stack.push(Value::Int(1)).unwrap();
+        stack.push(Value::Float(2.5)).unwrap();
+        stack.push(Value::from("abc")).unwrap();
+        let values: Vec<&Value> = stack.iter().collect();
+        assert_eq!(
+            values,
+            [&Value::from("abc"), &Value::Float(2.5), &Value::Int(1)]
+        );
+        assert_eq!(stack.to_string(), "[1, 2.5, abc]");
+        assert!(format!("{:?}", stack).starts_with("Stack { max: 4, data: [Int(1), "));
+    }
+
+    #[test]
+    fn test_get_and_truncate() {
+        let mut stack = Stack::new(3);
+        stack.push(Value::Int(1)).unwrap();
+        stack.push(Value::Int(2)).unwrap();
+        stack.push(Value::Int(3)).unwrap();
+        assert_eq!(stack.get(1), Value::Int(2));
+        assert_eq!(
+            stack.as_slice(),
+            &[Value::Int(1), Value::Int(2), Value::Int(3)]
+        );
+
+        assert!(stack.is_full());
+
+        stack.truncate(1);
+        assert_eq!(stack.len(), 1);
+        assert_eq!(stack.pop(), Ok(Value::Int(1)));
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn test_squash() {
+        let mut stack = Stack::new(5);
+        for value in [
+            Value::Int(1),
+            Value::from("ab"),
+            Value::Int(3),
+            Value::Int(4),
+        ] {
+            stack.push(value).unwrap();
+        }
+        stack.squash(1, 2);
+        assert_eq!(
+            stack.as_slice(),
+            &[Value::Int(1), Value::Int(3), Value::Int(4)]
+        );
+        assert_eq!(stack.heap(), 0);
+
+        stack.squash(3, 0);
+        assert_eq!(stack.len(), 3);
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut stack = Stack::new(8);
+        stack.push(Value::Int(1)).unwrap();
+        stack.push(Value::Int(2)).unwrap();
+        stack.push(Value::Int(3)).unwrap();
+        assert_eq!(stack.push_frame(4), Err(StackError::Underflow));
+
+        stack.push_frame(2).unwrap();
+        assert_eq!(stack.base(), 1);
+        assert_eq!(stack.local(1), Ok(Value::Int(3)));
+        stack.set_local(0, Value::from("ab")).unwrap();
+        assert_eq!(stack.heap(), 2);
+        assert_eq!(stack.local(2), Err(StackError::Underflow));
+
+        // A nested call, returning drops its locals and temporaries
+        stack.push(Value::Int(4)).unwrap();
+        stack.push_frame(1).unwrap();
+        stack.push(Value::Int(5)).unwrap();
+        assert_eq!(stack.local(0), Ok(Value::Int(4)));
+        stack.pop_frame(1);
+        assert_eq!(stack.len(), 3);
+        assert_eq!(stack.local(0), Ok(Value::from("ab")));
+
+        stack.pop_frame(0);
+        assert_eq!((stack.len(), stack.base(), stack.heap()), (1, 0, 0));
+        stack.clear();
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn test_peak() {
+        let mut stack = Stack::new(4);
+        stack.push(Value::Int(1)).unwrap();
+        stack.push(Value::Int(2)).unwrap();
+        stack.pop().unwrap();
+        assert_eq!(stack.peak(), 2);
+        stack.record_depth(3);
+        assert_eq!(stack.peak(), 3);
+        stack.reset_peak();
+        assert_eq!(stack.peak(), 1);
+    }
+
+    #[test]
+    fn test_heap() {
+        let mut stack = Stack::new(4);
+        stack.set_memory_limit(Some(8));
+        stack.push(Value::from("abc")).unwrap();
+        stack.push(Value::Int(1)).unwrap();
+        stack.push(Value::from("de")).unwrap();
+        assert_eq!(stack.heap(), 5);
+        assert!(stack.has_room_for(&Value::from("fgh")));
+        assert!(!stack.has_room_for(&Value::from("fghi")));
+
+        assert_eq!(stack.pop(), Ok(Value::from("de")));
+        assert_eq!(stack.heap(), 3);
+        stack.truncate(0);
+        assert_eq!(stack.heap(), 0);
+        stack.set_memory_limit(None);
+        assert!(stack.has_room_for(&Value::from("a long string")));
+    }
+
+    #[test]
+    #[should_panic(expected = "stack underflow")]
+    fn test_get_out_of_bounds() {
+        let mut stack = Stack::new(2);
+        stack.push(Value::Int(1)).unwrap();
+        stack.get(1);
     }
 }
@@ -0,0 +1,279 @@
+use crate::{
+    compiler::{constant_names, CompileError, Expr, Script},
+    lexer::Span,
+    operator::binary_operator,
+    parser::parse_script_with,
+    value::Value,
+};
+
+// Type of a value as far as it can be told before running, `Number` is an integer or a
+// float and `Any` is anything at all, like parameters and the results of user functions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    Int,
+    Float,
+    Number,
+    Str,
+    Bool,
+    Any,
+}
+
+use Type::*;
+
+impl Type {
+    fn of(value: &Value) -> Type {
+        match value {
+            Value::Int(_) => Int,
+            Value::Float(_) => Float,
+            Value::Str(_) => Str,
+            Value::Bool(_) => Bool,
+        }
+    }
+
+    fn is_numeric(self) -> bool {
+        matches!(self, Int | Float | Number)
+    }
+
+    // Type of arithmetic on two operands, integers only stay integers together
+    fn arithmetic(self, other: Type) -> Type {
+        match (self, other) {
+            (Int, Int) => Int,
+            (Float, b) if b.is_numeric() => Float,
+            (a, Float) if a.is_numeric() => Float,
+            _ => Number,
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Int => "an integer",
+            Float => "a float",
+            Number => "a number",
+            Str => "a string",
+            Bool => "a boolean",
+            Any => "any value",
+        }
+    }
+}
+
+// Check `input` for operations the VM would reject at run time, like the factorial of a
+// float or subtracting from a string. Only types evident from the source are checked,
+// parameters and user function results may be anything.
+pub fn type_check(input: &str) -> Result<(), CompileError> {
+    let (script, spans) = parse_script_with(input, None)?;
+    check_script(input, &script, &spans, &[])
+}
+
+// Check a script parsed by `parse_script_with`, whose main expression reads `params`
+pub(crate) fn check_script(
+    input: &str,
+    script: &Script,
+    spans: &[Span],
+    params: &[String],
+) -> Result<(), CompileError> {
+    let mut checker = Checker {
+        input,
+        spans,
+        next: 0,
+        params: &[],
+    };
+    for function in &script.functions {
+        checker.params = &function.params;
+        checker.check(&function.body)?;
+    }
+    checker.params = params;
+    checker.check(&script.body)?;
+    Ok(())
+}
+
+struct Checker<'a> {
+    input: &'a str,
+    // Spans in the order the parser completed the expressions, which is the order `check`
+    // finishes them in
+    spans: &'a [Span],
+    next: usize,
+    // Parameters in scope, which shadow the builtin constants
+    params: &'a [String],
+}
+
+impl Checker<'_> {
+    fn check(&mut self, expr: &Expr) -> Result<Type, CompileError> {
+        let result = match expr {
+            Expr::Number(value) => Ok(Type::of(value)),
+            Expr::Str(_) => Ok(Str),
+            Expr::Var(name) if self.params.contains(name) => Ok(Any),
+            Expr::Var(name) if constant_names().any(|constant| constant == name) => Ok(Float),
+            Expr::Var(_) => Ok(Any),
+            Expr::Call(name, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.check(arg))
+                    .collect::<Result<Vec<Type>, CompileError>>()?;
+                call(name, &args)
+            }
+            Expr::UnaryOp(op, operand) => {
+                let operand = self.check(operand)?;
+                unary(*op, operand)
+            }
+            Expr::BinOp(left, op, right) => {
+                let left = self.check(left)?;
+                let right = self.check(right)?;
+                binary(*op, left, right)
+            }
+            Expr::Conditional(condition, then, otherwise) => {
+                let condition = self.check(condition)?;
+                let then = self.check(then)?;
+                let otherwise = self.check(otherwise)?;
+                match (condition, then, otherwise) {
+                    (Str, _, _) => Err("a string as a condition".to_string()),
+                    (_, a, b) if a == b => Ok(a),
+                    (_, a, b) if a.is_numeric() && b.is_numeric() => Ok(a.arithmetic(b)),
+                    _ => Ok(Any),
+                }
+            }
+        };
+        // Children were checked above, so the next span is the one of `expr`
+        let span = self.spans.get(self.next).copied();
+        self.next += 1;
+        result.map_err(|detail| {
+            let error =
+                CompileError::new("Type error").with_detail(format!("Cannot use {}", detail));
+            match span {
+                Some(span) => error.spanning(self.input, span.start, span.end),
+                None => error,
+            }
+        })
+    }
+}
+
+fn unary(op: char, operand: Type) -> Result<Type, String> {
+    let allowed = match op {
+        '!' => matches!(operand, Int | Number | Any),
+        '¬' => operand != Str,
+        _ => operand.is_numeric() || operand == Any,
+    };
+    if !allowed {
+        let name = match op {
+            '!' => "factorial",
+            '¬' => "logical not",
+            '√' => "square root",
+            _ => "negation",
+        };
+        return Err(format!("{} on {}", name, operand.describe()));
+    }
+    Ok(match (op, operand) {
+        ('!', _) => Int,
+        ('√', _) => Float,
+        ('¬', Bool) => Bool,
+        ('¬', Any) => Any,
+        ('¬', _) => Int,
+        (_, Any) => Number,
+        (_, operand) => operand,
+    })
+}
+
+fn binary(op: char, left: Type, right: Type) -> Result<Type, String> {
+    let numbers = |t: Type| t.is_numeric() || t == Any;
+    let (allowed, result) = match op {
+        // Adding anything to a string concatenates
+        '+' if left == Str || right == Str => (true, Str),
+        '+' if left == Any || right == Any => (left != Bool && right != Bool, Any),
+        '-' | '*' | '/' | '%' | '+' => (numbers(left) && numbers(right), left.arithmetic(right)),
+        '<' | '≤' | '>' | '≥' => {
+            let strings = |t: Type| t == Str || t == Any;
+            let ordered = (numbers(left) && numbers(right)) || (strings(left) && strings(right));
+            (ordered, Bool)
+        }
+        '&' | '|' => (left != Str && right != Str, Bool),
+        _ => (true, Bool),
+    };
+    if !allowed {
+        let spelling = binary_operator(op).map_or("?", |operator| operator.spelling);
+        return Err(format!(
+            "{} on {} and {}",
+            spelling,
+            left.describe(),
+            right.describe()
+        ));
+    }
+    Ok(result)
+}
+
+fn call(name: &str, args: &[Type]) -> Result<Type, String> {
+    match (name, args) {
+        ("factorial", &[arg]) => unary('!', arg),
+        ("sqrt", _) => Ok(Float),
+        ("len", _) => Ok(Int),
+        ("abs", &[arg]) if arg.is_numeric() => Ok(arg),
+        ("min" | "max" | "mod", &[a, b]) if a.is_numeric() && b.is_numeric() => Ok(a.arithmetic(b)),
+        ("abs" | "pow" | "min" | "max" | "mod", _) => Ok(Number),
+        _ => Ok(Any),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("3! + 2.5")]
+    #[case("x!")]
+    #[case(r#""a" + 1 + true"#)]
+    #[case(r#""b" < "a" ? 1 : 2"#)]
+    #[case("fn f(x) { x - 1 } f(2)!")]
+    #[case("min(1, 2)!")]
+    #[case("pi * 2")]
+    #[case("!0 && 1 < 2")]
+    #[case("fn f(e) { e! } f(3)")]
+    fn test_well_typed(#[case] input: &str) {
+        assert_eq!(type_check(input), Ok(()));
+    }
+
+    #[rstest]
+    #[case("2.5!", "Cannot use factorial on a float", "2.5!", 1)]
+    #[case(
+        "factorial(1 / 2.0)",
+        "Cannot use factorial on a float",
+        "factorial(1 / 2.0)",
+        1
+    )]
+    #[case(
+        r#"1 + ("a" - 1)"#,
+        "Cannot use - on a string and an integer",
+        r#""a" - 1"#,
+        6
+    )]
+    #[case("true * 2", "Cannot use * on a boolean and an integer", "true * 2", 1)]
+    #[case(r#"-"a""#, "Cannot use negation on a string", r#"-"a""#, 1)]
+    #[case(
+        r#"1 < "a""#,
+        "Cannot use < on an integer and a string",
+        r#"1 < "a""#,
+        1
+    )]
+    #[case(
+        r#""a" ? 1 : 2"#,
+        "Cannot use a string as a condition",
+        r#""a" ? 1 : 2"#,
+        1
+    )]
+    #[case(
+        "fn f(x) { √true } 1",
+        "Cannot use square root on a boolean",
+        "√true",
+        11
+    )]
+    #[case("sqrt(2)!", "Cannot use factorial on a float", "sqrt(2)!", 1)]
+    fn test_type_errors(
+        #[case] input: &str,
+        #[case] detail: &str,
+        #[case] token: &str,
+        #[case] column: usize,
+    ) {
+        let error = type_check(input).unwrap_err();
+        assert_eq!(error.message(), "Type error");
+        assert_eq!(error.to_string(), detail);
+        assert_eq!((error.token(), error.column()), (token, column));
+    }
+}
@@ -1,28 +1,190 @@
 use std::{
+    cmp::Ordering,
     fmt::Display,
-    ops::{Add, Div, Mul, Rem, Sub},
+    ops::{Add, Div, Mul, Neg, Not, Rem, Sub},
+    sync::Arc,
 };
 
-#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+use crate::{
+    error::{DecodeError, RuntimeError},
+    json,
+    opcode::Opcode,
+};
+
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum Value {
     Int(i64),
     Float(f64),
+    Str(Arc<str>),
+    Bool(bool),
 }
 
 impl Value {
+    // Encode with the bytecode codec, see `ValueCodec`
     pub fn to_vec(&self) -> Vec<u8> {
+        ValueCodec::BYTECODE.encode_to_vec(self)
+    }
+
+    pub fn pow(self, rhs: Value) -> Result<Value, RuntimeError> {
+        use Value::*;
+        Ok(match (self, rhs) {
+            (Int(a), Int(b)) => match u32::try_from(b) {
+                Ok(b) => Int(a.wrapping_pow(b)),
+                Err(_) => Float((a as f64).powf(b as f64)),
+            },
+            (Float(a), Float(b)) => Float(a.powf(b)),
+            (Int(a), Float(b)) => Float((a as f64).powf(b)),
+            (Float(a), Int(b)) => Float(a.powf(b as f64)),
+            (a, b) => return Err(mismatch(Opcode::Pow, &a, Some(&b))),
+        })
+    }
+
+    pub fn abs(self) -> Result<Value, RuntimeError> {
         use Value::*;
         match self {
-            Int(value) => {
-                let mut bytes = vec![0];
-                bytes.extend_from_slice(&value.to_be_bytes());
-                bytes
-            }
-            Float(value) => {
-                let mut bytes = vec![1];
-                bytes.extend_from_slice(&value.to_be_bytes());
-                bytes
+            Int(a) => Ok(Int(a.wrapping_abs())),
+            Float(a) => Ok(Float(a.abs())),
+            a => Err(mismatch(Opcode::Abs, &a, None)),
+        }
+    }
+
+    pub fn min(self, rhs: Value) -> Result<Value, RuntimeError> {
+        use Value::*;
+        match (self, rhs) {
+            (Int(a), Int(b)) => Ok(Int(a.min(b))),
+            (a, b) => match (a.as_f64(), b.as_f64()) {
+                (Some(x), Some(y)) => Ok(Float(x.min(y))),
+                _ => Err(mismatch(Opcode::Min, &a, Some(&b))),
+            },
+        }
+    }
+
+    pub fn max(self, rhs: Value) -> Result<Value, RuntimeError> {
+        use Value::*;
+        match (self, rhs) {
+            (Int(a), Int(b)) => Ok(Int(a.max(b))),
+            (a, b) => match (a.as_f64(), b.as_f64()) {
+                (Some(x), Some(y)) => Ok(Float(x.max(y))),
+                _ => Err(mismatch(Opcode::Max, &a, Some(&b))),
+            },
+        }
+    }
+
+    // Multiply by `2^rhs`. Integers that do not overflow are shifted, every other operand
+    // takes the `*` path, so the result is always the same as multiplying.
+    pub fn shift_left(self, rhs: Value) -> Result<Value, RuntimeError> {
+        use Value::*;
+        match (self, rhs) {
+            (Int(a), Int(b)) if (0..63).contains(&b) && (a << b) >> b == a => Ok(Int(a << b)),
+            (a, Int(b)) if (0..63).contains(&b) => a * Int(1 << b),
+            (a, b) => Err(mismatch(Opcode::ShiftLeft, &a, Some(&b))),
+        }
+    }
+
+    // Remainder by `rhs + 1` where `rhs` is a mask of low bits. Non-negative integers are
+    // masked, every other operand takes the `%` path, so the result is always the same as
+    // taking the remainder.
+    pub fn bit_and(self, rhs: Value) -> Result<Value, RuntimeError> {
+        use Value::*;
+        match (self, rhs) {
+            (Int(a), Int(b)) if a >= 0 => Ok(Int(a & b)),
+            (a, Int(b)) => a % Int(b + 1),
+            (a, b) => Err(mismatch(Opcode::BitAnd, &a, Some(&b))),
+        }
+    }
+
+    // Length of a string in characters
+    pub fn len(self) -> Result<Value, RuntimeError> {
+        match self {
+            Value::Str(value) => Ok(Value::Int(value.chars().count() as i64)),
+            a => Err(mismatch(Opcode::Len, &a, None)),
+        }
+    }
+
+    // Equality as seen by the language, integers and floats compare by numeric value
+    pub fn equals(&self, rhs: &Value) -> bool {
+        use Value::*;
+        match (self, rhs) {
+            (Int(_) | Float(_), Int(_) | Float(_)) => {
+                self.compare(Opcode::Equal, rhs) == Ok(Some(Ordering::Equal))
             }
+            _ => self == rhs,
+        }
+    }
+
+    // Ordering of numbers or of strings for the comparison `op`, `None` when either side is
+    // NaN
+    pub fn compare(&self, op: Opcode, rhs: &Value) -> Result<Option<Ordering>, RuntimeError> {
+        use Value::*;
+        match (self, rhs) {
+            (Int(a), Int(b)) => Ok(Some(a.cmp(b))),
+            (Str(a), Str(b)) => Ok(Some(a.cmp(b))),
+            _ => match (self.as_f64(), rhs.as_f64()) {
+                (Some(a), Some(b)) => Ok(a.partial_cmp(&b)),
+                _ => Err(mismatch(op, self, Some(rhs))),
+            },
+        }
+    }
+
+    // Truthiness used by `op`, zero is false and anything else is true
+    pub fn is_truthy(&self, op: Opcode) -> Result<bool, RuntimeError> {
+        match *self {
+            Value::Int(value) => Ok(value != 0),
+            Value::Float(value) => Ok(value != 0.0),
+            Value::Bool(value) => Ok(value),
+            Value::Str(_) => Err(mismatch(op, self, None)),
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Int(value) => Some(value as f64),
+            Value::Float(value) => Some(value),
+            Value::Str(_) | Value::Bool(_) => None,
+        }
+    }
+
+    // Whether the value is known exactly, every value but a float, which may have been
+    // rounded, is
+    pub fn is_exact(&self) -> bool {
+        !matches!(self, Value::Float(_))
+    }
+
+    // What is off about the value as a result, if anything
+    pub fn warning(&self) -> Option<Warning> {
+        match *self {
+            Value::Float(value) if value.is_nan() => Some(Warning::NotANumber),
+            Value::Float(value) if value.is_infinite() => Some(Warning::Infinite),
+            Value::Float(value) if value.abs() > MAX_EXACT_FLOAT => Some(Warning::Imprecise),
+            _ => None,
+        }
+    }
+
+    // Name of the type as used in JSON output
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Str(_) => "string",
+            Value::Bool(_) => "bool",
+        }
+    }
+
+    // Encode as a JSON value, floats that JSON cannot hold become null
+    pub fn to_json(&self) -> String {
+        match self {
+            Value::Float(value) if value.is_finite() => format!("{:?}", value),
+            Value::Float(_) => "null".to_string(),
+            Value::Str(value) => json::string(value),
+            value => value.to_string(),
+        }
+    }
+
+    // Render like `Display`, with floats written by `format`
+    pub fn format(&self, format: &FloatFormat) -> String {
+        match self {
+            Value::Float(value) => format.format(*value),
+            value => value.to_string(),
         }
     }
 
@@ -31,6 +193,8 @@ impl Value {
         match self {
             Int(_) => 9,
             Float(_) => 9,
+            Str(value) => 5 + value.len(),
+            Bool(_) => 2,
         }
     }
 }
@@ -40,88 +204,368 @@ impl Display for Value {
         match self {
             Value::Int(value) => write!(f, "{}", value),
             Value::Float(value) => write!(f, "{}", value),
+            Value::Str(value) => write!(f, "{}", value),
+            Value::Bool(value) => write!(f, "{}", value),
         }
     }
 }
 
-impl From<&[u8]> for Value {
-    fn from(bytes: &[u8]) -> Self {
-        match bytes[0] {
-            0 => {
-                debug_assert!(bytes.len() >= 9, "invalid byte length");
-                Value::Int(i64::from_be_bytes(bytes[1..9].try_into().unwrap()))
+// Largest float below which every integer is representable, 2^53
+const MAX_EXACT_FLOAT: f64 = 9007199254740992.0;
+
+// A result that is valid but likely not what was meant
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Warning {
+    // NaN, from an operation such as `0.0 / 0.0`
+    NotANumber,
+    // An infinity, from an overflow or a division by zero
+    Infinite,
+    // A float too large for its integer part to be exact
+    Imprecise,
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::NotANumber => f.write_str("result is not a number"),
+            Warning::Infinite => f.write_str("result is infinite"),
+            Warning::Imprecise => f.write_str("result is too large to be exact"),
+        }
+    }
+}
+
+// Notation of floats written with a `FloatFormat`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum FloatNotation {
+    // Scientific for exponents below -4 or from the precision on, like printf's `%g`
+    #[default]
+    Auto,
+    Fixed,
+    Scientific,
+}
+
+// How floats are written for people. The default is the shortest text that reads back as
+// the same float, which is what `Display` writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FloatFormat {
+    // Significant digits, `None` for as many as it takes to tell the float apart
+    pub precision: Option<usize>,
+    pub notation: FloatNotation,
+    // Drop the zeros ending the fraction, and the point when nothing is left after it
+    pub trim_zeros: bool,
+}
+
+impl Default for FloatFormat {
+    fn default() -> FloatFormat {
+        FloatFormat {
+            precision: None,
+            notation: FloatNotation::Auto,
+            trim_zeros: true,
+        }
+    }
+}
+
+impl FloatFormat {
+    pub fn format(&self, value: f64) -> String {
+        if !value.is_finite() {
+            return value.to_string();
+        }
+        let text = match (self.precision, self.notation) {
+            (None, FloatNotation::Scientific) => format!("{:e}", value),
+            (None, _) => value.to_string(),
+            (Some(digits), notation) => {
+                let digits = digits.max(1);
+                // The exponent after rounding, 9.99 to two digits is 1.0e1
+                let scientific = format!("{:.*e}", digits - 1, value);
+                let exponent: i32 = scientific[scientific.find('e').unwrap() + 1..]
+                    .parse()
+                    .unwrap();
+                let fixed = notation == FloatNotation::Fixed
+                    || notation == FloatNotation::Auto && (-4..digits as i32).contains(&exponent);
+                if fixed {
+                    let decimals = (digits as i32 - 1 - exponent).max(0) as usize;
+                    format!("{:.*}", decimals, value)
+                } else {
+                    scientific
+                }
+            }
+        };
+        if self.trim_zeros {
+            trim_zeros(&text)
+        } else {
+            text
+        }
+    }
+}
+
+// Drop the zeros ending the fraction of `text`, keeping any exponent
+fn trim_zeros(text: &str) -> String {
+    let (mantissa, exponent) = match text.find('e') {
+        Some(index) => text.split_at(index),
+        None => (text, ""),
+    };
+    if !mantissa.contains('.') {
+        return text.to_string();
+    }
+    let mantissa = mantissa.trim_end_matches('0').trim_end_matches('.');
+    format!("{}{}", mantissa, exponent)
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Float(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Str(value.into())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Str(value.into())
+    }
+}
+
+// Decode a single value serialized by `to_vec`, the slice must hold exactly one value
+impl TryFrom<&[u8]> for Value {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        ValueCodec::BYTECODE.decode_exact(bytes)
+    }
+}
+
+// Byte order of the multi-byte fields of an encoded value
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Endianness {
+    #[default]
+    Big,
+    Little,
+}
+
+// Encoding of a value as a tag byte followed by its payload:
+//
+//   tag 0, int     8 byte two's complement integer
+//   tag 1, float   8 byte IEEE 754 binary64
+//   tag 2, string  4 byte unsigned length, then that many bytes of UTF-8
+//   tag 3, bool    1 byte, zero is false and anything else true
+//
+// Integers, floats and lengths are written in the byte order of the codec. Bytecode and
+// serialized programs always use `ValueCodec::BYTECODE`, so they read the same on every
+// platform, the little endian codec is meant for exchanging values with other hosts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ValueCodec {
+    endianness: Endianness,
+}
+
+impl ValueCodec {
+    pub const BYTECODE: ValueCodec = ValueCodec::new(Endianness::Big);
+
+    pub const fn new(endianness: Endianness) -> ValueCodec {
+        ValueCodec { endianness }
+    }
+
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+
+    pub fn encode(&self, value: &Value, output: &mut Vec<u8>) {
+        match value {
+            Value::Int(value) => {
+                output.push(0);
+                output.extend(self.order(value.to_be_bytes()));
+            }
+            Value::Float(value) => {
+                output.push(1);
+                output.extend(self.order(value.to_be_bytes()));
             }
-            1 => Value::Float(f64::from_be_bytes(bytes[1..9].try_into().unwrap())),
-            _ => panic!("invalid value type"),
+            Value::Str(value) => {
+                output.push(2);
+                output.extend(self.order((value.len() as u32).to_be_bytes()));
+                output.extend(value.as_bytes());
+            }
+            Value::Bool(value) => output.extend([3, *value as u8]),
+        }
+    }
+
+    pub fn encode_to_vec(&self, value: &Value) -> Vec<u8> {
+        let mut output = Vec::with_capacity(value.size());
+        self.encode(value, &mut output);
+        output
+    }
+
+    // Decode the value at the start of `bytes`, returning it with the number of bytes read
+    pub fn decode(&self, bytes: &[u8]) -> Result<(Value, usize), DecodeError> {
+        let (&tag, rest) = bytes.split_first().ok_or(DecodeError::Truncated)?;
+        let (value, size) = match tag {
+            0 => (Value::Int(i64::from_be_bytes(self.order(array(rest)?))), 8),
+            1 => (
+                Value::Float(f64::from_be_bytes(self.order(array(rest)?))),
+                8,
+            ),
+            2 => {
+                let length = u32::from_be_bytes(self.order(array(rest)?)) as usize;
+                let bytes = rest.get(4..4 + length).ok_or(DecodeError::Truncated)?;
+                let value = std::str::from_utf8(bytes).map_err(|_| DecodeError::InvalidUtf8)?;
+                (Value::from(value), 4 + length)
+            }
+            3 => (Value::Bool(array::<1>(rest)?[0] != 0), 1),
+            tag => return Err(DecodeError::InvalidValueTag(tag)),
+        };
+        Ok((value, 1 + size))
+    }
+
+    // Decode `bytes` holding exactly one value
+    pub fn decode_exact(&self, bytes: &[u8]) -> Result<Value, DecodeError> {
+        let (value, size) = self.decode(bytes)?;
+        if bytes.len() > size {
+            return Err(DecodeError::TrailingBytes);
         }
+        Ok(value)
     }
+
+    // Convert big endian bytes to the codec's byte order, or back
+    fn order<const N: usize>(&self, mut bytes: [u8; N]) -> [u8; N] {
+        if self.endianness == Endianness::Little {
+            bytes.reverse();
+        }
+        bytes
+    }
+}
+
+// The error for `op` applied to operands it does not accept
+fn mismatch(op: Opcode, lhs: &Value, rhs: Option<&Value>) -> RuntimeError {
+    RuntimeError::TypeMismatch {
+        op,
+        lhs: lhs.type_name(),
+        rhs: rhs.map(Value::type_name),
+    }
+}
+
+fn array<const N: usize>(bytes: &[u8]) -> Result<[u8; N], DecodeError> {
+    bytes
+        .first_chunk::<N>()
+        .copied()
+        .ok_or(DecodeError::Truncated)
 }
 
+// Integer arithmetic wraps around on overflow in every build profile, the VM checks it only
+// with `Vm::checked_arithmetic`. Integer division by zero fails.
 impl Add for Value {
-    type Output = Value;
+    type Output = Result<Value, RuntimeError>;
 
     fn add(self, rhs: Self) -> Self::Output {
         use Value::*;
-        match (self, rhs) {
-            (Int(a), Int(b)) => Int(a + b),
+        Ok(match (self, rhs) {
+            (Int(a), Int(b)) => Int(a.wrapping_add(b)),
             (Float(a), Float(b)) => Float(a + b),
             (Int(a), Float(b)) => Float(a as f64 + b),
             (Float(a), Int(b)) => Float(a + b as f64),
-        }
+            // Adding anything to a string concatenates its display form
+            (a @ Str(_), b) | (a, b @ Str(_)) => Str(format!("{}{}", a, b).into()),
+            (a, b) => return Err(mismatch(Opcode::Addition, &a, Some(&b))),
+        })
     }
 }
 
 impl Sub for Value {
-    type Output = Value;
+    type Output = Result<Value, RuntimeError>;
 
     fn sub(self, rhs: Self) -> Self::Output {
         use Value::*;
-        match (self, rhs) {
-            (Int(a), Int(b)) => Int(a - b),
+        Ok(match (self, rhs) {
+            (Int(a), Int(b)) => Int(a.wrapping_sub(b)),
             (Float(a), Float(b)) => Float(a - b),
             (Int(a), Float(b)) => Float(a as f64 - b),
             (Float(a), Int(b)) => Float(a - b as f64),
-        }
+            (a, b) => return Err(mismatch(Opcode::Subtract, &a, Some(&b))),
+        })
     }
 }
 
 impl Mul for Value {
-    type Output = Value;
+    type Output = Result<Value, RuntimeError>;
 
     fn mul(self, rhs: Self) -> Self::Output {
         use Value::*;
-        match (self, rhs) {
-            (Int(a), Int(b)) => Int(a * b),
+        Ok(match (self, rhs) {
+            (Int(a), Int(b)) => Int(a.wrapping_mul(b)),
             (Float(a), Float(b)) => Float(a * b),
             (Int(a), Float(b)) => Float(a as f64 * b),
             (Float(a), Int(b)) => Float(a * b as f64),
-        }
+            (a, b) => return Err(mismatch(Opcode::Multiply, &a, Some(&b))),
+        })
     }
 }
 
 impl Div for Value {
-    type Output = Value;
+    type Output = Result<Value, RuntimeError>;
     fn div(self, rhs: Self) -> Self::Output {
         use Value::*;
-        match (self, rhs) {
-            (Int(a), Int(b)) => Int(a / b),
+        Ok(match (self, rhs) {
+            (Int(_), Int(0)) => return Err(RuntimeError::DivisionByZero),
+            (Int(a), Int(b)) => Int(a.wrapping_div(b)),
             (Float(a), Float(b)) => Float(a / b),
             (Int(a), Float(b)) => Float(a as f64 / b),
             (Float(a), Int(b)) => Float(a / b as f64),
+            (a, b) => return Err(mismatch(Opcode::Divide, &a, Some(&b))),
+        })
+    }
+}
+
+impl Neg for Value {
+    type Output = Result<Value, RuntimeError>;
+    fn neg(self) -> Self::Output {
+        use Value::*;
+        match self {
+            Int(a) => Ok(Int(a.wrapping_neg())),
+            Float(a) => Ok(Float(-a)),
+            a => Err(mismatch(Opcode::Negate, &a, None)),
+        }
+    }
+}
+
+// Logical negation treating zero as false and anything else as true, booleans stay booleans
+impl Not for Value {
+    type Output = Result<Value, RuntimeError>;
+    fn not(self) -> Self::Output {
+        use Value::*;
+        match self {
+            Int(a) => Ok(Int((a == 0) as i64)),
+            Float(a) => Ok(Int((a == 0.0) as i64)),
+            Bool(a) => Ok(Bool(!a)),
+            a => Err(mismatch(Opcode::Not, &a, None)),
         }
     }
 }
 
 impl Rem for Value {
-    type Output = Value;
+    type Output = Result<Value, RuntimeError>;
     fn rem(self, rhs: Self) -> Self::Output {
         use Value::*;
-        match (self, rhs) {
-            (Int(a), Int(b)) => Int(a % b),
+        Ok(match (self, rhs) {
+            (Int(_), Int(0)) => return Err(RuntimeError::DivisionByZero),
+            (Int(a), Int(b)) => Int(a.wrapping_rem(b)),
             (Float(a), Float(b)) => Float(a % b),
             (Int(a), Float(b)) => Float(a as f64 % b),
             (Float(a), Int(b)) => Float(a % b as f64),
-        }
+            (a, b) => return Err(mismatch(Opcode::Modulo, &a, Some(&b))),
+        })
     }
 }
 
@@ -137,8 +581,9 @@ mod tests {
     #[case(Value::Float(5.0), Value::Int(3), Value::Float(8.0))]
     #[case(Value::Int(-5), Value::Int(3), Value::Int(-2))]
     #[case(Value::Float(-5.0), Value::Float(3.0), Value::Float(-2.0))]
+    #[case(Value::Int(i64::MAX), Value::Int(1), Value::Int(i64::MIN))]
     fn test_addition(#[case] a: Value, #[case] b: Value, #[case] expected: Value) {
-        assert_eq!(a + b, expected);
+        assert_eq!(a + b, Ok(expected));
     }
 
     #[rstest]
@@ -148,8 +593,9 @@ mod tests {
     #[case(Value::Float(5.0), Value::Int(3), Value::Float(2.0))]
     #[case(Value::Int(-5), Value::Int(-3), Value::Int(-2))]
     #[case(Value::Float(-5.0), Value::Float(-3.0), Value::Float(-2.0))]
+    #[case(Value::Int(i64::MIN), Value::Int(1), Value::Int(i64::MAX))]
     fn test_subtraction(#[case] a: Value, #[case] b: Value, #[case] expected: Value) {
-        assert_eq!(a - b, expected);
+        assert_eq!(a - b, Ok(expected));
     }
 
     #[rstest]
@@ -159,8 +605,9 @@ mod tests {
     #[case(Value::Float(5.0), Value::Int(3), Value::Float(15.0))]
     #[case(Value::Int(-5), Value::Int(-3), Value::Int(15))]
     #[case(Value::Float(-5.0), Value::Float(-3.0), Value::Float(15.0))]
+    #[case(Value::Int(i64::MAX), Value::Int(2), Value::Int(-2))]
     fn test_multiplication(#[case] a: Value, #[case] b: Value, #[case] expected: Value) {
-        assert_eq!(a * b, expected);
+        assert_eq!(a * b, Ok(expected));
     }
 
     #[rstest]
@@ -172,8 +619,9 @@ mod tests {
     #[case(Value::Int(5), Value::Float(2.0), Value::Float(2.5))]
     #[case(Value::Int(-6), Value::Int(-2), Value::Int(3))]
     #[case(Value::Float(-6.0), Value::Float(-2.0), Value::Float(3.0))]
+    #[case(Value::Int(i64::MIN), Value::Int(-1), Value::Int(i64::MIN))]
     fn test_division(#[case] a: Value, #[case] b: Value, #[case] expected: Value) {
-        assert_eq!(a / b, expected);
+        assert_eq!(a / b, Ok(expected));
     }
 
     #[rstest]
@@ -183,8 +631,87 @@ mod tests {
     #[case(Value::Float(7.0), Value::Int(3), Value::Float(1.0))]
     #[case(Value::Int(-7), Value::Int(3), Value::Int(-1))]
     #[case(Value::Float(-7.0), Value::Float(3.0), Value::Float(-1.0))]
+    #[case(Value::Int(i64::MIN), Value::Int(-1), Value::Int(0))]
     fn test_remainder(#[case] a: Value, #[case] b: Value, #[case] expected: Value) {
-        assert_eq!(a % b, expected);
+        assert_eq!(a % b, Ok(expected));
+    }
+
+    #[rstest]
+    #[case(Value::Int(1), Value::Int(0), Err(RuntimeError::DivisionByZero))]
+    #[case(Value::Int(i64::MIN), Value::Int(0), Err(RuntimeError::DivisionByZero))]
+    #[case(Value::Int(1), Value::Float(0.0), Ok(Value::Float(f64::INFINITY)))]
+    fn test_division_by_zero(
+        #[case] a: Value,
+        #[case] b: Value,
+        #[case] expected: Result<Value, RuntimeError>,
+    ) {
+        assert_eq!(a.clone() / b.clone(), expected);
+        let remainder = (a % b).map_err(|e| e == RuntimeError::DivisionByZero);
+        assert_eq!(remainder.is_err(), expected.is_err());
+    }
+
+    #[rstest]
+    #[case(Value::Int(2), Value::Int(10), Value::Int(1024))]
+    #[case(Value::Int(2), Value::Int(-2), Value::Float(0.25))]
+    #[case(Value::Float(2.0), Value::Int(3), Value::Float(8.0))]
+    #[case(Value::Int(9), Value::Float(0.5), Value::Float(3.0))]
+    #[case(Value::Int(2), Value::Int(64), Value::Int(0))]
+    fn test_pow(#[case] a: Value, #[case] b: Value, #[case] expected: Value) {
+        assert_eq!(a.pow(b), Ok(expected));
+    }
+
+    #[rstest]
+    #[case(Value::Int(5), Value::Int(3), Value::Int(40))]
+    #[case(Value::Int(-5), Value::Int(1), Value::Int(-10))]
+    #[case(Value::Float(1.5), Value::Int(2), Value::Float(6.0))]
+    fn test_shift_left(#[case] a: Value, #[case] b: Value, #[case] expected: Value) {
+        assert_eq!(a.clone().shift_left(b.clone()), Ok(expected.clone()));
+        assert_eq!(
+            a * Value::Int(1 << b.as_f64().unwrap() as i64),
+            Ok(expected)
+        );
+    }
+
+    #[rstest]
+    #[case(Value::Int(13), Value::Int(7), Value::Int(5))]
+    #[case(Value::Int(-13), Value::Int(7), Value::Int(-5))]
+    #[case(Value::Float(9.5), Value::Int(3), Value::Float(1.5))]
+    fn test_bit_and(#[case] a: Value, #[case] b: Value, #[case] expected: Value) {
+        assert_eq!(a.bit_and(b), Ok(expected));
+    }
+
+    #[rstest]
+    #[case(Value::Int(2), Value::Int(3), Value::Int(2), Value::Int(3))]
+    #[case(Value::Int(2), Value::Float(1.5), Value::Float(1.5), Value::Float(2.0))]
+    #[case(Value::Float(-1.0), Value::Int(4), Value::Float(-1.0), Value::Float(4.0))]
+    fn test_min_max(#[case] a: Value, #[case] b: Value, #[case] min: Value, #[case] max: Value) {
+        assert_eq!(a.clone().min(b.clone()), Ok(min));
+        assert_eq!(a.max(b), Ok(max));
+    }
+
+    #[rstest]
+    #[case(Value::Int(-4), Value::Int(4))]
+    #[case(Value::Float(-2.5), Value::Float(2.5))]
+    #[case(Value::Int(i64::MIN), Value::Int(i64::MIN))]
+    fn test_abs(#[case] a: Value, #[case] expected: Value) {
+        assert_eq!(a.abs(), Ok(expected));
+    }
+
+    #[rstest]
+    #[case(Value::Int(5), Value::Int(-5))]
+    #[case(Value::Float(-2.5), Value::Float(2.5))]
+    #[case(Value::Int(i64::MIN), Value::Int(i64::MIN))]
+    fn test_negation(#[case] a: Value, #[case] expected: Value) {
+        assert_eq!(-a, Ok(expected));
+    }
+
+    #[rstest]
+    #[case(Value::Int(0), Value::Int(1))]
+    #[case(Value::Int(-3), Value::Int(0))]
+    #[case(Value::Float(0.0), Value::Int(1))]
+    #[case(Value::Float(0.5), Value::Int(0))]
+    fn test_not(#[case] a: Value, #[case] expected: Value) {
+        assert_eq!(!a, Ok(expected));
     }
 
     #[test]
@@ -192,39 +719,213 @@ mod tests {
         // Test Int serialization/deserialization
         let int_value = Value::Int(42);
         let bytes = int_value.to_vec();
-        assert_eq!(Value::from(bytes.as_slice()), int_value);
+        assert_eq!(Value::try_from(bytes.as_slice()).unwrap(), int_value);
 
         // Test Float serialization/deserialization
         let float_value = Value::Float(3.11);
         let bytes = float_value.to_vec();
-        assert_eq!(Value::from(bytes.as_slice()), float_value);
+        assert_eq!(Value::try_from(bytes.as_slice()).unwrap(), float_value);
+    }
+
+    #[test]
+    fn test_from_primitives() {
+        assert_eq!(Value::from(42), Value::Int(42));
+        assert_eq!(Value::from(2.5), Value::Float(2.5));
+    }
+
+    #[rstest]
+    #[case(Value::from("foo"), Value::from("bar"), Value::from("foobar"))]
+    #[case(Value::from("n = "), Value::Int(4), Value::from("n = 4"))]
+    #[case(Value::Float(2.5), Value::from("m"), Value::from("2.5m"))]
+    fn test_string_concatenation(#[case] a: Value, #[case] b: Value, #[case] expected: Value) {
+        assert_eq!(a + b, Ok(expected));
+    }
+
+    #[rstest]
+    #[case(Value::from(""), 0)]
+    #[case(Value::from("hello"), 5)]
+    #[case(Value::from("√16"), 3)]
+    fn test_string_len(#[case] value: Value, #[case] expected: i64) {
+        assert_eq!(value.len(), Ok(Value::Int(expected)));
+    }
+
+    #[rstest]
+    #[case(Value::from("a") * Value::Int(2), Opcode::Multiply, "string", Some("int"))]
+    #[case(Value::from("a") - Value::Int(1), Opcode::Subtract, "string", Some("int"))]
+    #[case(Value::Bool(true) + Value::Int(1), Opcode::Addition, "bool", Some("int"))]
+    #[case(-Value::from("a"), Opcode::Negate, "string", None)]
+    #[case(!Value::from("a"), Opcode::Not, "string", None)]
+    #[case(Value::Bool(true).pow(Value::Int(2)), Opcode::Pow, "bool", Some("int"))]
+    #[case(Value::Int(1).min(Value::from("a")), Opcode::Min, "int", Some("string"))]
+    #[case(Value::Int(1).len(), Opcode::Len, "int", None)]
+    fn test_type_mismatch(
+        #[case] result: Result<Value, RuntimeError>,
+        #[case] op: Opcode,
+        #[case] lhs: &'static str,
+        #[case] rhs: Option<&'static str>,
+    ) {
+        assert_eq!(result, Err(RuntimeError::TypeMismatch { op, lhs, rhs }));
+    }
+
+    #[test]
+    fn test_string_serialization() {
+        let value = Value::from("hello");
+        let bytes = value.to_vec();
+        assert_eq!(bytes.len(), value.size());
+        assert_eq!(Value::try_from(bytes.as_slice()).unwrap(), value);
+    }
+
+    #[rstest]
+    #[case(Value::Int(2), Value::Float(2.0), true)]
+    #[case(Value::Int(2), Value::Int(3), false)]
+    #[case(Value::from("a"), Value::from("a"), true)]
+    #[case(Value::Bool(true), Value::Bool(false), false)]
+    #[case(Value::Bool(true), Value::Int(1), false)]
+    #[case(Value::Float(f64::NAN), Value::Float(f64::NAN), false)]
+    fn test_equals(#[case] a: Value, #[case] b: Value, #[case] expected: bool) {
+        assert_eq!(a.equals(&b), expected);
+    }
+
+    #[rstest]
+    #[case(Value::Int(1), Value::Int(2), Some(Ordering::Less))]
+    #[case(Value::Float(2.5), Value::Int(2), Some(Ordering::Greater))]
+    #[case(Value::from("abc"), Value::from("abd"), Some(Ordering::Less))]
+    #[case(Value::Float(f64::NAN), Value::Int(0), None)]
+    fn test_compare(#[case] a: Value, #[case] b: Value, #[case] expected: Option<Ordering>) {
+        assert_eq!(a.compare(Opcode::Less, &b), Ok(expected));
+    }
+
+    #[test]
+    fn test_compare_mismatched_types() {
+        let error = Value::from("1")
+            .compare(Opcode::Less, &Value::Int(1))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Type mismatch in Less of string and int");
+    }
+
+    #[rstest]
+    #[case(Value::Bool(false), false)]
+    #[case(Value::Bool(true), true)]
+    #[case(Value::Int(0), false)]
+    #[case(Value::Float(0.5), true)]
+    fn test_is_truthy(#[case] value: Value, #[case] expected: bool) {
+        assert_eq!(value.is_truthy(Opcode::And), Ok(expected));
+    }
+
+    #[test]
+    fn test_bool_serialization() {
+        let value = Value::Bool(true);
+        let bytes = value.to_vec();
+        assert_eq!(bytes, vec![3, 1]);
+        assert_eq!(bytes.len(), value.size());
+        assert_eq!(Value::try_from(bytes.as_slice()).unwrap(), value);
+    }
+
+    #[rstest]
+    #[case(Value::Int(i64::MAX), true, None)]
+    #[case(Value::from("a"), true, None)]
+    #[case(Value::Float(3.0), false, None)]
+    #[case(Value::Float(f64::NAN), false, Some(Warning::NotANumber))]
+    #[case(Value::Float(f64::NEG_INFINITY), false, Some(Warning::Infinite))]
+    #[case(Value::Float(1e16), false, Some(Warning::Imprecise))]
+    fn test_exactness(#[case] value: Value, #[case] exact: bool, #[case] warning: Option<Warning>) {
+        assert_eq!(value.is_exact(), exact);
+        assert_eq!(value.warning(), warning);
     }
 
     #[test]
     fn test_display() {
         assert_eq!(Value::Int(42).to_string(), "42");
         assert_eq!(Value::Float(3.11).to_string(), "3.11");
+        assert_eq!(Value::from("text").to_string(), "text");
+        assert_eq!(Value::Bool(true).to_string(), "true");
     }
 
-    #[test]
-    #[should_panic(expected = "invalid byte length")]
-    fn test_invalid_deserialization() {
-        // Test with invalid byte length
-        let invalid_bytes = vec![0, 1, 2];
-        let _ = Value::from(invalid_bytes.as_slice());
+    #[rstest]
+    #[case(FloatFormat::default(), 0.1 + 0.2, "0.30000000000000004")]
+    #[case(FloatFormat::default(), 2.0, "2")]
+    #[case(FloatFormat { precision: Some(4), ..FloatFormat::default() }, 1.23456, "1.235")]
+    #[case(FloatFormat { precision: Some(4), ..FloatFormat::default() }, 2.5, "2.5")]
+    #[case(FloatFormat { precision: Some(4), trim_zeros: false, ..FloatFormat::default() }, 2.5, "2.500")]
+    #[case(FloatFormat { precision: Some(2), ..FloatFormat::default() }, 9.99, "10")]
+    #[case(FloatFormat { precision: Some(3), ..FloatFormat::default() }, 123456.0, "1.23e5")]
+    #[case(FloatFormat { precision: Some(3), ..FloatFormat::default() }, 0.00001234, "1.23e-5")]
+    #[case(FloatFormat { precision: Some(3), ..FloatFormat::default() }, 0.0001234, "0.000123")]
+    #[case(FloatFormat { precision: Some(3), notation: FloatNotation::Fixed, ..FloatFormat::default() }, 123456.0, "123456")]
+    #[case(FloatFormat { precision: Some(3), notation: FloatNotation::Scientific, ..FloatFormat::default() }, 1.0, "1e0")]
+    #[case(FloatFormat { precision: Some(3), notation: FloatNotation::Scientific, trim_zeros: false }, -1.0, "-1.00e0")]
+    #[case(FloatFormat { notation: FloatNotation::Scientific, ..FloatFormat::default() }, 1500.0, "1.5e3")]
+    #[case(FloatFormat { precision: Some(3), ..FloatFormat::default() }, f64::NEG_INFINITY, "-inf")]
+    fn test_float_format(#[case] format: FloatFormat, #[case] value: f64, #[case] expected: &str) {
+        assert_eq!(format.format(value), expected);
+        assert_eq!(Value::Float(value).format(&format), expected);
     }
 
-    #[test]
-    #[should_panic(expected = "invalid value type")]
-    fn test_invalid_value_type() {
-        let invalid_bytes = vec![2, 0, 0, 0, 0, 0, 0, 0, 0]; // First byte is 2, which is invalid
-        let _ = Value::from(invalid_bytes.as_slice());
+    #[rstest]
+    #[case(Value::Int(-4), "int", "-4")]
+    #[case(Value::Float(2.0), "float", "2.0")]
+    #[case(Value::Float(f64::NAN), "float", "null")]
+    #[case(Value::from("a\"b"), "string", r#""a\"b""#)]
+    #[case(Value::Bool(false), "bool", "false")]
+    fn test_to_json(#[case] value: Value, #[case] type_name: &str, #[case] expected: &str) {
+        assert_eq!(
+            (value.type_name(), value.to_json().as_str()),
+            (type_name, expected)
+        );
+    }
+
+    #[rstest]
+    #[case(Value::Int(-2))]
+    #[case(Value::Float(f64::MIN_POSITIVE))]
+    #[case(Value::from("héllo"))]
+    #[case(Value::from(""))]
+    #[case(Value::Bool(false))]
+    fn test_codec_roundtrip(#[case] value: Value) {
+        for endianness in [Endianness::Big, Endianness::Little] {
+            let codec = ValueCodec::new(endianness);
+            let bytes = codec.encode_to_vec(&value);
+            assert_eq!(bytes.len(), value.size());
+            assert_eq!(codec.decode_exact(&bytes), Ok(value.clone()));
+        }
+    }
+
+    #[rstest]
+    #[case(Value::Int(258), Endianness::Big, vec![0, 0, 0, 0, 0, 0, 0, 1, 2])]
+    #[case(Value::Int(258), Endianness::Little, vec![0, 2, 1, 0, 0, 0, 0, 0, 0])]
+    #[case(Value::Float(1.0), Endianness::Little, vec![1, 0, 0, 0, 0, 0, 0, 0xF0, 0x3F])]
+    #[case(Value::from("ab"), Endianness::Little, vec![2, 2, 0, 0, 0, b'a', b'b'])]
+    #[case(Value::Bool(true), Endianness::Little, vec![3, 1])]
+    fn test_codec_layout(
+        #[case] value: Value,
+        #[case] endianness: Endianness,
+        #[case] expected: Vec<u8>,
+    ) {
+        assert_eq!(ValueCodec::new(endianness).encode_to_vec(&value), expected);
     }
 
     #[test]
-    #[should_panic(expected = "invalid byte length")]
-    fn test_invalid_byte_length_short() {
-        let invalid_bytes = vec![0, 1, 2]; // Too short
-        let _ = Value::from(invalid_bytes.as_slice());
+    fn test_codec_decode_prefix() {
+        let mut bytes = Vec::new();
+        let codec = ValueCodec::new(Endianness::Little);
+        codec.encode(&Value::from("x"), &mut bytes);
+        codec.encode(&Value::Int(7), &mut bytes);
+        assert_eq!(codec.decode(&bytes), Ok((Value::from("x"), 6)));
+        assert_eq!(codec.decode(&bytes[6..]), Ok((Value::Int(7), 9)));
+        // The byte order is not recorded, reading with the other codec gives another value
+        assert_ne!(
+            ValueCodec::BYTECODE.decode(&bytes[6..]),
+            Ok((Value::Int(7), 9))
+        );
+    }
+
+    #[rstest]
+    #[case(vec![0, 1, 2], DecodeError::Truncated)]
+    #[case(vec![], DecodeError::Truncated)]
+    #[case(vec![2, 0, 0, 0, 3, b'a'], DecodeError::Truncated)]
+    #[case(vec![2, 0, 0, 0, 1, 0xFF], DecodeError::InvalidUtf8)]
+    #[case(vec![4, 0, 0, 0, 0, 0, 0, 0, 0], DecodeError::InvalidValueTag(4))]
+    #[case(vec![3, 1, 0], DecodeError::TrailingBytes)]
+    fn test_invalid_deserialization(#[case] bytes: Vec<u8>, #[case] expected: DecodeError) {
+        assert_eq!(Value::try_from(bytes.as_slice()), Err(expected));
     }
 }
@@ -0,0 +1,215 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{error::VerifyError, instruction::Instruction, program::Program};
+
+// Check that the code of a program from an untrusted source, like a file written by an
+// older or foreign compiler, is safe to hand to the VM: every instruction decodes, jumps
+// and calls land on instructions, no path pops values it did not push or reads arguments
+// it was not given, host calls name a function of the host function table, the depth of
+// the stack at every instruction is the same on all paths and all code ends in a return. Every entry point is checked with its parameters, a
+// program without entry points from address 0 without arguments. Functions are checked
+// with the argument count of each call to them.
+pub fn verify(program: &Program) -> Result<(), VerifyError> {
+    let bytecode = program.bytecode();
+    let mut instructions = HashMap::new();
+    let mut position = 0;
+    while position < bytecode.len() {
+        let (instruction, size) = Instruction::try_decode(bytecode, position)
+            .map_err(|e| VerifyError::InvalidInstruction(position, e))?;
+        instructions.insert(position, instruction);
+        position += size;
+    }
+
+    let mut pending = Vec::new();
+    for entry in program.entries() {
+        if !instructions.contains_key(&entry.address()) {
+            return Err(VerifyError::InvalidEntry(entry.name().to_string()));
+        }
+        pending.push((entry.address(), entry.params().len()));
+    }
+    if program.entries().is_empty() {
+        pending.push((0, 0));
+    }
+    let mut verified = HashSet::new();
+    while let Some((start, args)) = pending.pop() {
+        if verified.insert((start, args)) {
+            let hosts = program.host_functions().len();
+            pending.extend(verify_function(
+                &instructions,
+                bytecode.len(),
+                hosts,
+                start,
+                args,
+            )?);
+        }
+    }
+    Ok(())
+}
+
+// Follow every path of the function at `start` called with `args` arguments, returning the
+// functions it calls along with their argument counts. Depths count the arguments, which
+// sit at the bottom of the frame.
+fn verify_function(
+    instructions: &HashMap<usize, Instruction>,
+    end: usize,
+    hosts: usize,
+    start: usize,
+    args: usize,
+) -> Result<Vec<(usize, usize)>, VerifyError> {
+    let mut calls = Vec::new();
+    let mut depths: HashMap<usize, usize> = HashMap::new();
+    let mut pending = vec![(start, start, args)];
+    while let Some((from, position, depth)) = pending.pop() {
+        let Some(instruction) = instructions.get(&position) else {
+            return Err(match position {
+                position if position >= end => VerifyError::MissingReturn(from),
+                position => VerifyError::InvalidTarget(from, position),
+            });
+        };
+        match depths.insert(position, depth) {
+            Some(known) if known == depth => continue,
+            Some(_) => return Err(VerifyError::UnbalancedStack(position)),
+            None => {}
+        }
+        let (pops, pushes) = instruction.stack_effect();
+        if depth < args + pops {
+            return Err(VerifyError::StackUnderflow(position));
+        }
+        let depth = depth - pops + pushes;
+        let next = position + instruction.size();
+        match *instruction {
+            Instruction::Return => {}
+            Instruction::Jump(address) => pending.push((position, address, depth)),
+            Instruction::JumpIfFalse(address) => {
+                pending.push((position, address, depth));
+                pending.push((position, next, depth));
+            }
+            Instruction::Call { address, argc } | Instruction::TailCall { address, argc } => {
+                if !instructions.contains_key(&address) {
+                    return Err(VerifyError::InvalidTarget(position, address));
+                }
+                calls.push((address, argc));
+                // The frame of a tail call makes way for the callee, which returns in its
+                // place
+                if let Instruction::Call { .. } = instruction {
+                    pending.push((position, next, depth));
+                }
+            }
+            Instruction::LoadArg(index) if index >= args => {
+                return Err(VerifyError::InvalidArgument(position, index));
+            }
+            Instruction::CallHost { id, .. } if id >= hosts => {
+                return Err(VerifyError::InvalidHostFunction(position, id));
+            }
+            _ => pending.push((position, next, depth)),
+        }
+    }
+    Ok(calls)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compiler::{compile_program, compile_unit, CompileOptions, OptLevel},
+        error::DecodeError,
+        program::Entry,
+        value::Value,
+    };
+    use rstest::rstest;
+
+    fn encode(instructions: &[Instruction]) -> Vec<u8> {
+        let mut bytecode = Vec::new();
+        for instruction in instructions {
+            instruction.encode(&mut bytecode);
+        }
+        bytecode
+    }
+
+    #[rstest]
+    #[case("1 + 2 * 3")]
+    #[case("true ? 1 : 2 > 3 ? 4 : 5")]
+    #[case("fn f(n) { n < 2 ? n : f(n - 1) + f(n - 2) } f(10)")]
+    #[case("fn f(n, acc) { n == 0 ? acc : f(n - 1, acc * n) } f(5, 1)")]
+    #[case("fn g(a, b) { a - b } fn f(x) { g(x, 1) } f(2) + g(3, 4)")]
+    fn test_compiled_programs(#[case] source: &str) {
+        for opt_level in [OptLevel::Basic, OptLevel::Size, OptLevel::Speed] {
+            let options = CompileOptions::new().opt_level(opt_level);
+            assert_eq!(verify(&compile_program(source, &options).unwrap()), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_entry_points() {
+        let program = compile_unit(&[("a", "x * y"), ("b", "fn f(n) { n } f(z)")]).unwrap();
+        assert_eq!(verify(&program), Ok(()));
+
+        // Without its parameters the entry reads arguments it is not given
+        let entries = vec![Entry::new("a", 0, vec!["x".to_string()])];
+        let program = Program::with_entries(program.bytecode().to_vec(), entries);
+        assert!(matches!(
+            verify(&program),
+            Err(VerifyError::InvalidArgument(_, 1))
+        ));
+
+        let entries = vec![Entry::new("a", 1, Vec::new())];
+        let program = Program::with_entries(program.bytecode().to_vec(), entries);
+        assert_eq!(
+            verify(&program),
+            Err(VerifyError::InvalidEntry("a".to_string()))
+        );
+    }
+
+    #[rstest]
+    #[case(vec![0xFF], VerifyError::InvalidInstruction(0, DecodeError::InvalidOpcode(0xFF)))]
+    #[case(
+        encode(&[Instruction::Literal(Value::Int(1))])[..4].to_vec(),
+        VerifyError::InvalidInstruction(0, DecodeError::Truncated)
+    )]
+    #[case(
+        encode(&[Instruction::Jump(6), Instruction::Literal(Value::Int(1)), Instruction::Return]),
+        VerifyError::InvalidTarget(0, 6)
+    )]
+    #[case(
+        encode(&[Instruction::Call { address: 40, argc: 0 }, Instruction::Return]),
+        VerifyError::InvalidTarget(0, 40)
+    )]
+    #[case(
+        encode(&[Instruction::Literal(Value::Int(1)), Instruction::Addition, Instruction::Return]),
+        VerifyError::StackUnderflow(10)
+    )]
+    #[case(encode(&[Instruction::Return]), VerifyError::StackUnderflow(0))]
+    #[case(encode(&[Instruction::LoadArg(0), Instruction::Return]), VerifyError::InvalidArgument(0, 0))]
+    #[case(
+        encode(&[Instruction::CallHost { id: 0, argc: 0 }, Instruction::Return]),
+        VerifyError::InvalidHostFunction(0, 0)
+    )]
+    #[case(
+        encode(&[Instruction::Literal(Value::Int(1))]),
+        VerifyError::MissingReturn(0)
+    )]
+    #[case(
+        // Only one branch pushes a value before they meet at the return
+        encode(&[
+            Instruction::Literal(Value::Bool(true)),
+            Instruction::JumpIfFalse(18),
+            Instruction::Literal(Value::Int(1)),
+            Instruction::Return,
+        ]),
+        VerifyError::UnbalancedStack(18)
+    )]
+    #[case(
+        // The function returns with an argument it was not called with
+        encode(&[
+            Instruction::Call { address: 7, argc: 0 },
+            Instruction::Return,
+            Instruction::LoadArg(0),
+            Instruction::Return,
+        ]),
+        VerifyError::InvalidArgument(7, 0)
+    )]
+    fn test_invalid_code(#[case] bytecode: Vec<u8>, #[case] expected: VerifyError) {
+        let program = Program::with_entries(bytecode, Vec::new());
+        assert_eq!(verify(&program), Err(expected));
+    }
+}
@@ -1,81 +1,1405 @@
-use crate::{opcode::Opcode, stack::Stack, value::Value};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt::Display,
+    io::Write,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    chunk::Chunk,
+    cursor::Cursor,
+    error::{DecodeError, RuntimeError},
+    instruction::Instruction,
+    lexer::Span,
+    opcode::Opcode,
+    program::{Entry, Program, ENTRY},
+    stack::Stack,
+    value::{Value, ValueCodec},
+    vm::fused::{fuse, FusedCode},
+};
+
+mod fused;
+pub mod regvm;
+
+// Upper bound on nested function calls, guarding against runaway recursion
+const MAX_CALL_DEPTH: usize = 1024;
+
+// Stack size of a `VmBuilder` for programs whose depth is not known, see
+// `Program::stack_depth`. The stack starts small and only grows this far when needed.
+const DEFAULT_STACK_SIZE: usize = 1 << 16;
+
+// Instructions between two readings of the clock in a run with a timeout
+const CLOCK_INTERVAL: u32 = 1024;
+
+// Outcome of executing a single instruction with `Vm::step`
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepResult {
+    // The run goes on at the next instruction
+    Continue,
+    // The main expression returned, ending the run
+    Returned(Value),
+    // There is no run to step, it ended without a value or was never started
+    Halted,
+}
+
+// What a hook sees of the VM around an instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VmState<'a> {
+    // Address of the instruction before it runs, of the next one after
+    pub ip: usize,
+    pub stack: &'a [Value],
+    pub call_depth: usize,
+}
+
+// Callback observing each instruction, see `Vm::set_hook`
+pub type Hook = Box<dyn FnMut(&VmState, &Instruction) + Send>;
+
+// Body of a host function, see `Vm::register_fn`
+type HostFn = Arc<dyn Fn(&[Value]) -> Result<Value, String> + Send + Sync>;
+
+// A function registered with `Vm::register_fn`, called with exactly `arity` arguments
+#[derive(Clone)]
+struct HostFunction {
+    arity: usize,
+    call: HostFn,
+}
+
+// An entry of the host function table of the loaded chunk along with the function
+// registered under its name, if any
+#[derive(Clone)]
+struct HostSlot {
+    name: String,
+    function: Option<HostFunction>,
+}
+
+// Bookkeeping for an active function call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Frame {
+    return_address: usize,
+    base: usize,
+}
+
+// What the factorial of an integer whose result does not fit does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum FactorialOverflow {
+    // Fail with `RuntimeError::Overflow`
+    #[default]
+    Error,
+    // Give the nearest float instead, infinity beyond `170!`
+    Float,
+}
+
+// Configuration of a VM, from `Vm::builder`. Every option defaults to the behaviour of
+// `Vm::new`, except the stack which fits the program when its depth is known.
+pub struct VmBuilder {
+    program: Program,
+    stack_size: Option<usize>,
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    memory_limit: Option<usize>,
+    checked: bool,
+    factorial_overflow: FactorialOverflow,
+    before: Option<Hook>,
+    after: Option<Hook>,
+    trace: Option<Box<dyn Write + Send>>,
+}
+
+impl VmBuilder {
+    pub fn stack_size(mut self, stack_size: usize) -> VmBuilder {
+        self.stack_size = Some(stack_size);
+        self
+    }
+
+    // See `Vm::with_fuel`
+    pub fn fuel(mut self, fuel: u64) -> VmBuilder {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    // See `Vm::with_timeout`
+    pub fn timeout(mut self, timeout: Duration) -> VmBuilder {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // See `Vm::with_memory_limit`
+    pub fn memory_limit(mut self, bytes: usize) -> VmBuilder {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    // See `Vm::checked_arithmetic`
+    pub fn checked_arithmetic(mut self, enabled: bool) -> VmBuilder {
+        self.checked = enabled;
+        self
+    }
+
+    pub fn factorial_overflow(mut self, overflow: FactorialOverflow) -> VmBuilder {
+        self.factorial_overflow = overflow;
+        self
+    }
+    // See `Vm::set_hook`
+    pub fn hook<F>(mut self, hook: F) -> VmBuilder
+    where
+        F: FnMut(&VmState, &Instruction) + Send + 'static,
+    {
+        self.before = Some(Box::new(hook));
+        self
+    }
+
+    // See `Vm::set_post_hook`
+    pub fn post_hook<F>(mut self, hook: F) -> VmBuilder
+    where
+        F: FnMut(&VmState, &Instruction) + Send + 'static,
+    {
+        self.after = Some(Box::new(hook));
+        self
+    }
+
+    // Trace every instruction to `sink`, see `Vm::set_trace`
+    pub fn trace<W>(mut self, sink: W) -> VmBuilder
+    where
+        W: Write + Send + 'static,
+    {
+        self.trace = Some(Box::new(sink));
+        self
+    }
+
+    pub fn build(self) -> Vm {
+        let stack_size = self
+            .stack_size
+            .or_else(|| self.program.stack_depth())
+            .unwrap_or(DEFAULT_STACK_SIZE);
+        let (chunk, entries) = self.program.into_parts();
+        let fused = fuse_chunk(&chunk, &entries);
+        let mut stack = Stack::new(stack_size);
+        stack.set_memory_limit(self.memory_limit);
+        let hosts = bind_hosts(&chunk, &HashMap::new());
+        Vm {
+            stack,
+            chunk,
+            entries,
+            fault: None,
+            checked: self.checked,
+            factorial_overflow: self.factorial_overflow,
+            fuel: self.fuel,
+            timeout: self.timeout,
+            frames: Vec::new(),
+            ip: None,
+            before: self.before,
+            after: self.after,
+            trace: self.trace,
+            fused,
+            host_functions: HashMap::new(),
+            hosts,
+        }
+    }
+}
+
+// Figures about the run of a `Vm`, see `Vm::stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VmStats {
+    // Most values the stack held at once, the smallest stack size the run fits in
+    pub max_stack_depth: usize,
+}
+
+// State of a run captured by `Vm::snapshot`, which `Vm::restore` continues from. The
+// program is not part of it, a snapshot only makes sense for the VM of the same program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmSnapshot {
+    ip: Option<usize>,
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    fuel: Option<u64>,
+}
+
+impl VmSnapshot {
+    // Address of the next instruction, `None` when no run was in progress
+    pub fn ip(&self) -> Option<usize> {
+        self.ip
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    pub fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    // Serialize as the ip and fuel, each behind a presence byte, followed by the frames and
+    // the stack values, each behind a u32 count. Values use `ValueCodec::BYTECODE`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self.ip {
+            Some(ip) => {
+                bytes.push(1);
+                bytes.extend((ip as u32).to_be_bytes());
+            }
+            None => bytes.push(0),
+        }
+        match self.fuel {
+            Some(fuel) => {
+                bytes.push(1);
+                bytes.extend(fuel.to_be_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes.extend((self.frames.len() as u32).to_be_bytes());
+        for frame in &self.frames {
+            bytes.extend((frame.return_address as u32).to_be_bytes());
+            bytes.extend((frame.base as u32).to_be_bytes());
+        }
+        bytes.extend((self.stack.len() as u32).to_be_bytes());
+        for value in &self.stack {
+            ValueCodec::BYTECODE.encode(value, &mut bytes);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<VmSnapshot, DecodeError> {
+        let mut cursor = Cursor::new(bytes, 0);
+        let ip = match cursor.read_u8()? {
+            0 => None,
+            _ => Some(cursor.read_u32()? as usize),
+        };
+        let fuel = match cursor.read_u8()? {
+            0 => None,
+            _ => {
+                let fuel = cursor.read_bytes(8)?;
+                Some(u64::from_be_bytes(fuel.try_into().unwrap()))
+            }
+        };
+        let frames = (0..cursor.read_u32()?)
+            .map(|_| {
+                Ok(Frame {
+                    return_address: cursor.read_u32()? as usize,
+                    base: cursor.read_u32()? as usize,
+                })
+            })
+            .collect::<Result<Vec<Frame>, DecodeError>>()?;
+        let stack = (0..cursor.read_u32()?)
+            .map(|_| cursor.read_value())
+            .collect::<Result<Vec<Value>, DecodeError>>()?;
+        if !cursor.is_at_end() {
+            return Err(DecodeError::TrailingBytes);
+        }
+        Ok(VmSnapshot {
+            ip,
+            stack,
+            frames,
+            fuel,
+        })
+    }
+}
 
 pub struct Vm {
     stack: Stack,
-    bytecode: Vec<u8>,
+    chunk: Chunk,
+    entries: Vec<Entry>,
+    // Address of the instruction that failed the last run
+    fault: Option<usize>,
+    checked: bool,
+    factorial_overflow: FactorialOverflow,
+    // Instructions left to execute, `None` for no limit
+    fuel: Option<u64>,
+    // Longest a run may take, `None` for no limit
+    timeout: Option<Duration>,
+    // Calls in progress and the address of the next instruction of a run that is being
+    // stepped or ran out of fuel
+    frames: Vec<Frame>,
+    ip: Option<usize>,
+    before: Option<Hook>,
+    after: Option<Hook>,
+    trace: Option<Box<dyn Write + Send>>,
+    // The code pre-decoded for runs without hooks, tracing, fuel or a timeout
+    fused: Option<FusedCode>,
+    // Functions registered by the host by name, and bound to the table of the chunk
+    host_functions: HashMap<String, HostFunction>,
+    hosts: Vec<HostSlot>,
+}
+
+impl Vm {
+    pub fn new<P>(program: P, stack_size: usize) -> Vm
+    where
+        P: Into<Program>,
+    {
+        Vm::builder(program).stack_size(stack_size).build()
+    }
+
+    pub fn builder<P>(program: P) -> VmBuilder
+    where
+        P: Into<Program>,
+    {
+        VmBuilder {
+            program: program.into(),
+            stack_size: None,
+            fuel: None,
+            timeout: None,
+            memory_limit: None,
+            checked: false,
+            factorial_overflow: FactorialOverflow::default(),
+            before: None,
+            after: None,
+            trace: None,
+        }
+    }
+
+    // Write a line to `sink` for each instruction executed, with the values it popped and
+    // the depth of the stack after it, like `0014  mul [2, 3] -> 1`. Write errors are
+    // ignored, a failing sink never fails the run.
+    pub fn set_trace<W>(&mut self, sink: W)
+    where
+        W: Write + Send + 'static,
+    {
+        self.trace = Some(Box::new(sink));
+    }
+
+    pub fn clear_trace(&mut self) {
+        self.trace = None;
+    }
+
+    // Call `hook` before each instruction runs. Instructions are decoded a second time
+    // while a hook is set, which slows execution down.
+    pub fn set_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(&VmState, &Instruction) + Send + 'static,
+    {
+        self.before = Some(Box::new(hook));
+    }
+
+    // Call `hook` after each instruction that succeeds, including the final return
+    pub fn set_post_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(&VmState, &Instruction) + Send + 'static,
+    {
+        self.after = Some(Box::new(hook));
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.before = None;
+        self.after = None;
+    }
+
+    // Expose `function` to programs as `name`, which the compiler resolves calls to when
+    // declared with `CompileOptions::host_function`. A call with other than `arity`
+    // arguments fails with `RuntimeError::HostArity` and an error of the function with
+    // `RuntimeError::Host`. Registering a name again replaces the function.
+    pub fn register_fn<F, E>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, E> + Send + Sync + 'static,
+        E: Display,
+    {
+        let call = Arc::new(move |args: &[Value]| function(args).map_err(|e| e.to_string()));
+        self.host_functions
+            .insert(name.to_string(), HostFunction { arity, call });
+        self.hosts = bind_hosts(&self.chunk, &self.host_functions);
+    }
+
+    // Limit the number of instructions executed to `fuel`, shared by every run. A run that
+    // uses up the fuel fails with `RuntimeError::OutOfFuel` and can be continued with
+    // `resume` after a `refuel`.
+    pub fn with_fuel(mut self, fuel: u64) -> Vm {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    // Fail a run that takes longer than `timeout` with `RuntimeError::Timeout`. The clock is
+    // read every `CLOCK_INTERVAL` instructions, a call to a host function that blocks is not
+    // interrupted. Each run and each `resume` gets the whole timeout.
+    pub fn with_timeout(mut self, timeout: Duration) -> Vm {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // Limit the bytes of the strings the stack holds at once, a run that would go over it
+    // fails with `RuntimeError::OutOfMemory`. Strings are the only values that allocate.
+    pub fn with_memory_limit(mut self, bytes: usize) -> Vm {
+        self.stack.set_memory_limit(Some(bytes));
+        self
+    }
+
+    // Figures about the last run, or the one in progress, to size the stack of a deployment
+    // from a representative run instead of guessing
+    pub fn stats(&self) -> VmStats {
+        VmStats {
+            max_stack_depth: self.stack.peak(),
+        }
+    }
+
+    // Bytes of the strings the stack holds, counted against the memory limit
+    pub fn memory_used(&self) -> usize {
+        self.stack.heap()
+    }
+
+    // Instructions left to execute, `None` when unlimited
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    // Add to the fuel left, limiting the VM if it was not already
+    pub fn refuel(&mut self, fuel: u64) {
+        self.fuel = Some(self.fuel.unwrap_or(0).saturating_add(fuel));
+    }
+
+    // Continue the last run from where it ran out of fuel, or a started run to its end
+    pub fn resume(&mut self) -> Result<Value, RuntimeError> {
+        let address = self.ip.take().ok_or(RuntimeError::NotSuspended)?;
+        self.execute(address)
+    }
+
+    // Start a run of the main expression like `run_with_args` without executing anything,
+    // leaving it to `step` or `resume`
+    pub fn start(&mut self, args: &[Value]) -> Result<(), RuntimeError> {
+        self.load_args(args.iter().cloned())?;
+        self.ip = Some(0);
+        Ok(())
+    }
+
+    // Execute the next instruction of the run in progress
+    pub fn step(&mut self) -> Result<StepResult, RuntimeError> {
+        let Some(start) = self.ip.take() else {
+            return Ok(StepResult::Halted);
+        };
+        let mut address = start;
+        match self.dispatch(start, &mut address, true) {
+            Ok(Some(value)) => Ok(StepResult::Returned(value)),
+            Ok(None) if address >= self.chunk.len() => Ok(StepResult::Halted),
+            Ok(None) => {
+                self.ip = Some(address);
+                Ok(StepResult::Continue)
+            }
+            Err(e) => Err(self.fail(e, address)),
+        }
+    }
+
+    // Fail integer arithmetic that overflows with `RuntimeError::Overflow` instead of
+    // wrapping, at the cost of a check on every operation
+    pub fn checked_arithmetic(mut self, enabled: bool) -> Vm {
+        self.checked = enabled;
+        self
+    }
+
+    // Factorials are always checked, whatever the arithmetic mode, this selects what one
+    // that overflows gives
+    pub fn factorial_overflow(mut self, overflow: FactorialOverflow) -> Vm {
+        self.factorial_overflow = overflow;
+        self
+    }
+
+    // Run with host supplied arguments, readable as the parameters of the main expression
+    pub fn run_with_args(&mut self, args: &[Value]) -> Result<Value, RuntimeError> {
+        self.load_args(args.iter().cloned())?;
+        self.execute(0)
+    }
+
+    // Run the main expression with its parameters bound from `env` by name, like the rows
+    // of a table evaluated one after another. These are the parameters of the entry point
+    // `main` of programs from `compile_unit` or compiled with free variables. Programs
+    // without it are run when `env` is empty and fail with `RuntimeError::UnknownEntry`
+    // otherwise, since nothing would read the values.
+    pub fn run_with(&mut self, env: &HashMap<String, Value>) -> Result<Value, RuntimeError> {
+        let has_entry = self.entries.iter().any(|entry| entry.name() == ENTRY);
+        match (has_entry, env.is_empty()) {
+            (true, _) => self.run_entry(ENTRY, env),
+            (false, true) => self.run(),
+            (false, false) => Err(RuntimeError::UnknownEntry(ENTRY.to_string())),
+        }
+    }
+
+    // Run the named entry point, binding its parameters from `env`
+    pub fn run_entry(
+        &mut self,
+        name: &str,
+        env: &HashMap<String, Value>,
+    ) -> Result<Value, RuntimeError> {
+        let entry = self
+            .entries
+            .iter()
+            .find(|entry| entry.name() == name)
+            .ok_or_else(|| RuntimeError::UnknownEntry(name.to_string()))?;
+        let address = entry.address();
+        let args = entry
+            .params()
+            .iter()
+            .map(|param| {
+                env.get(param)
+                    .cloned()
+                    .ok_or_else(|| RuntimeError::MissingArgument(param.clone()))
+            })
+            .collect::<Result<Vec<Value>, RuntimeError>>()?;
+
+        self.load_args(args)?;
+        self.execute(address)
+    }
+
+    pub fn run(&mut self) -> Result<Value, RuntimeError> {
+        self.load_args([])?;
+        self.execute(0)
+    }
+
+    // Run the main expression and return every value left on the stack as it returns,
+    // from the bottom to the returned value. Bytecode that computes several results, like
+    // a quotient and a remainder, can hand them all back this way.
+    pub fn run_all(&mut self) -> Result<Vec<Value>, RuntimeError> {
+        self.load_args([])?;
+        let value = self.execute(0)?;
+        let mut values = self.stack.as_slice().to_vec();
+        values.push(value);
+        self.stack.clear();
+        Ok(values)
+    }
+
+    // Replace the loaded program, the new one must expose the same entry points with the
+    // same parameters so callers of `run_entry` keep working across the swap
+    pub fn swap_program(&mut self, program: &Program) -> Result<(), RuntimeError> {
+        let compatible = self.entries.len() == program.entries().len()
+            && self.entries.iter().all(|entry| {
+                program
+                    .entry(entry.name())
+                    .is_some_and(|new| new.params() == entry.params())
+            });
+        if !compatible {
+            return Err(RuntimeError::IncompatibleProgram);
+        }
+
+        self.chunk = program.chunk().clone();
+        self.entries = program.entries().to_vec();
+        self.fused = fuse_chunk(&self.chunk, &self.entries);
+        self.hosts = bind_hosts(&self.chunk, &self.host_functions);
+        self.ip = None;
+        Ok(())
+    }
+
+    // Replace the loaded program by any other, unlike `swap_program` the entry points may
+    // change. The stack and call frames keep their allocations for the next run.
+    pub fn load<P>(&mut self, program: P)
+    where
+        P: Into<Program>,
+    {
+        let (chunk, entries) = program.into().into_parts();
+        self.fused = fuse_chunk(&chunk, &entries);
+        self.hosts = bind_hosts(&chunk, &self.host_functions);
+        self.chunk = chunk;
+        self.entries = entries;
+        self.reset();
+    }
+
+    // Drop the state left by the last run, that is its stack, calls in progress and fault,
+    // and any run suspended or being stepped. Settings and the fuel left are kept.
+    pub fn reset(&mut self) {
+        self.fault = None;
+        self.ip = None;
+        self.frames.clear();
+        self.stack.clear();
+        self.stack.reset_peak();
+    }
+
+    // Capture the run in progress, to `restore` later or on another VM of the same program
+    pub fn snapshot(&self) -> VmSnapshot {
+        VmSnapshot {
+            ip: self.ip,
+            stack: self.stack.as_slice().to_vec(),
+            frames: self.frames.clone(),
+            fuel: self.fuel,
+        }
+    }
+
+    // Replace the state of the VM with `snapshot`, continuing its run with `step` or
+    // `resume`. Fails when the stack does not fit or a call frame lies beyond it.
+    pub fn restore(&mut self, snapshot: &VmSnapshot) -> Result<(), RuntimeError> {
+        self.load_args(snapshot.stack.iter().cloned())?;
+        if snapshot
+            .frames
+            .iter()
+            .any(|frame| frame.base > snapshot.stack.len())
+        {
+            return Err(RuntimeError::StackUnderflow);
+        }
+        self.frames = snapshot.frames.clone();
+        self.stack
+            .set_base(self.frames.last().map_or(0, |frame| frame.base));
+        self.ip = snapshot.ip;
+        self.fuel = snapshot.fuel;
+        Ok(())
+    }
+
+    // Address of the instruction the last run failed at, `None` when it succeeded or failed
+    // before executing anything
+    pub fn fault_address(&self) -> Option<usize> {
+        self.fault
+    }
+
+    // Source span of the instruction the last run failed at, for programs compiled with
+    // debug info
+    pub fn fault_span(&self) -> Option<Span> {
+        self.chunk.debug_info()?.span_at(self.fault?)
+    }
+
+    fn load_args<I>(&mut self, args: I) -> Result<(), RuntimeError>
+    where
+        I: IntoIterator<Item = Value>,
+    {
+        self.reset();
+        for arg in args {
+            self.stack.push(arg)?;
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, start: usize) -> Result<Value, RuntimeError> {
+        let observed = self.before.is_some() || self.after.is_some() || self.trace.is_some();
+        let fast = match &mut self.fused {
+            Some(fused) if !observed && self.fuel.is_none() && self.timeout.is_none() => {
+                fused.index_of(start).map(|index| (fused, index))
+            }
+            _ => None,
+        };
+        if let Some((fused, index)) = fast {
+            let result = fused.run(
+                index,
+                &mut self.stack,
+                &self.hosts,
+                self.checked,
+                self.factorial_overflow,
+            );
+            return match result {
+                Ok(Some(value)) => Ok(value),
+                Ok(None) => Err(RuntimeError::NoResult),
+                Err((e, address)) => Err(self.fail(e, address)),
+            };
+        }
+
+        let mut address = start;
+        match self.dispatch(start, &mut address, false) {
+            Ok(Some(value)) => Ok(value),
+            Ok(None) => Err(RuntimeError::NoResult),
+            Err(e) => Err(self.fail(e, address)),
+        }
+    }
+
+    // Address of the next instruction of the run being stepped or out of fuel
+    pub fn ip(&self) -> Option<usize> {
+        self.ip
+    }
+
+    // The instruction at `ip`, `None` at the end of the code or on malformed bytecode
+    pub fn current_instruction(&self) -> Option<Instruction> {
+        let (instruction, _) = Instruction::try_decode(self.chunk.code(), self.ip?).ok()?;
+        Some(instruction)
+    }
+
+    // Values on the stack from the bottom to the top, the arguments of the run come first
+    pub fn stack_slice(&self) -> &[Value] {
+        self.stack.as_slice()
+    }
+
+    // The stack itself, to print or walk from the top with `Stack::iter`
+    pub fn stack(&self) -> &Stack {
+        &self.stack
+    }
+
+    // Number of function calls in progress
+    pub fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    // Record the failure of the instruction at `address`, a run out of fuel can go on there
+    fn fail(&mut self, error: RuntimeError, address: usize) -> RuntimeError {
+        self.fault = Some(address);
+        if error == RuntimeError::OutOfFuel {
+            self.ip = Some(address);
+        }
+        error
+    }
+
+    // Run from `start` until the outermost return, keeping `address` at the instruction
+    // being executed. A `single` step stops after one instruction with `address` at the
+    // next, as does running past the end of the code.
+    fn dispatch(
+        &mut self,
+        start: usize,
+        address: &mut usize,
+        single: bool,
+    ) -> Result<Option<Value>, RuntimeError> {
+        let traced = self.trace.is_some();
+        let hooked = traced || self.before.is_some() || self.after.is_some();
+        let mut machine = Machine {
+            cursor: Cursor::new(self.chunk.code(), start),
+            stack: &mut self.stack,
+            frames: &mut self.frames,
+            hosts: &self.hosts,
+            checked: self.checked,
+            factorial_overflow: self.factorial_overflow,
+        };
+        let deadline = match self.timeout {
+            Some(timeout) if !single => Some((Instant::now() + timeout, timeout)),
+            _ => None,
+        };
+        let mut executed: u32 = 0;
+        while !machine.cursor.is_at_end() {
+            *address = machine.cursor.position();
+            if let Some(fuel) = &mut self.fuel {
+                if *fuel == 0 {
+                    return Err(RuntimeError::OutOfFuel);
+                }
+                *fuel -= 1;
+            }
+            if let Some((deadline, timeout)) = deadline {
+                executed = executed.wrapping_add(1);
+                if executed.is_multiple_of(CLOCK_INTERVAL) && Instant::now() >= deadline {
+                    return Err(RuntimeError::Timeout(timeout));
+                }
+            }
+            let instruction = if hooked {
+                Some(Instruction::try_decode(self.chunk.code(), *address)?.0)
+            } else {
+                None
+            };
+            notify(
+                &mut self.before,
+                &instruction,
+                *address,
+                machine.stack,
+                machine.frames,
+            );
+            let popped = match &instruction {
+                Some(instruction) if traced => top(machine.stack, instruction.stack_effect().0),
+                _ => Vec::new(),
+            };
+            let opcode = machine.cursor.read_u8()?;
+            if let Flow::Return(value) = HANDLERS[opcode as usize](&mut machine, opcode)? {
+                notify(
+                    &mut self.after,
+                    &instruction,
+                    machine.cursor.position(),
+                    machine.stack,
+                    machine.frames,
+                );
+                trace(
+                    &mut self.trace,
+                    *address,
+                    &instruction,
+                    &popped,
+                    machine.stack,
+                );
+                return Ok(Some(value));
+            }
+            notify(
+                &mut self.after,
+                &instruction,
+                machine.cursor.position(),
+                machine.stack,
+                machine.frames,
+            );
+            trace(
+                &mut self.trace,
+                *address,
+                &instruction,
+                &popped,
+                machine.stack,
+            );
+            if single {
+                break;
+            }
+        }
+        *address = machine.cursor.position();
+        Ok(None)
+    }
+}
+
+// What the handlers of a dispatch loop operate on, the cursor is past the opcode byte
+struct Machine<'a> {
+    cursor: Cursor<'a>,
+    stack: &'a mut Stack,
+    frames: &'a mut Vec<Frame>,
+    hosts: &'a [HostSlot],
+    checked: bool,
+    factorial_overflow: FactorialOverflow,
+}
+
+// Whether the dispatch loop goes on after a handler
+enum Flow {
+    Next,
+    Return(Value),
+}
+
+// Executes the instruction whose opcode byte was just read, given as the second argument
+type Handler = fn(&mut Machine, u8) -> Result<Flow, RuntimeError>;
+
+// Handler of every opcode byte, indexed by the byte. Bytes outside the instruction set
+// fail with `RuntimeError::InvalidOpcode`.
+static HANDLERS: [Handler; 256] = handlers();
+
+const fn handlers() -> [Handler; 256] {
+    let mut table: [Handler; 256] = [invalid; 256];
+    table[Opcode::Literal as usize] = literal;
+    table[Opcode::Addition as usize] = arithmetic;
+    table[Opcode::Subtract as usize] = arithmetic;
+    table[Opcode::Multiply as usize] = arithmetic;
+    table[Opcode::Divide as usize] = division;
+    table[Opcode::Modulo as usize] = division;
+    table[Opcode::Return as usize] = ret;
+    table[Opcode::Factorial as usize] = factorial_op;
+    table[Opcode::Sqrt as usize] = sqrt;
+    table[Opcode::Call as usize] = call;
+    table[Opcode::LoadArg as usize] = load_arg;
+    table[Opcode::Pow as usize] = arithmetic;
+    table[Opcode::Abs as usize] = sign;
+    table[Opcode::Min as usize] = min;
+    table[Opcode::Max as usize] = max;
+    table[Opcode::Not as usize] = not;
+    table[Opcode::Negate as usize] = sign;
+    table[Opcode::Len as usize] = len;
+    table[Opcode::Equal as usize] = equal;
+    table[Opcode::NotEqual as usize] = not_equal;
+    table[Opcode::Less as usize] = less;
+    table[Opcode::LessEqual as usize] = less_equal;
+    table[Opcode::Greater as usize] = greater;
+    table[Opcode::GreaterEqual as usize] = greater_equal;
+    table[Opcode::And as usize] = and;
+    table[Opcode::Or as usize] = or;
+    table[Opcode::Jump as usize] = jump;
+    table[Opcode::JumpIfFalse as usize] = jump_if_false;
+    table[Opcode::ShiftLeft as usize] = arithmetic;
+    table[Opcode::BitAnd as usize] = bit_and;
+    table[Opcode::TailCall as usize] = tail_call;
+    table[Opcode::CallHost as usize] = call_host;
+    table
+}
+
+fn invalid(_: &mut Machine, opcode: u8) -> Result<Flow, RuntimeError> {
+    Err(RuntimeError::InvalidOpcode(opcode))
+}
+
+fn literal(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    let value = m.cursor.read_value()?;
+    m.stack.push(value)?;
+    Ok(Flow::Next)
+}
+
+// Addition, subtraction, multiplication, powers and shifts, which overflow in checked mode
+fn arithmetic(m: &mut Machine, opcode: u8) -> Result<Flow, RuntimeError> {
+    let op = Opcode::decode(opcode)?;
+    if m.checked {
+        execute_fallible_binary_op(m.stack, |lhs, rhs| checked_binary(op, lhs, rhs))?;
+    } else {
+        execute_fallible_binary_op(m.stack, |lhs, rhs| unchecked_binary(op, lhs, rhs))?;
+    }
+    Ok(Flow::Next)
+}
+
+fn division(m: &mut Machine, opcode: u8) -> Result<Flow, RuntimeError> {
+    let op = Opcode::decode(opcode)?;
+    if m.checked {
+        execute_division(m.stack, |lhs, rhs| checked_binary(op, lhs, rhs))?;
+    } else {
+        execute_division(m.stack, |lhs, rhs| unchecked_binary(op, lhs, rhs))?;
+    }
+    Ok(Flow::Next)
+}
+
+// Negation and absolute value, which overflow in checked mode
+fn sign(m: &mut Machine, opcode: u8) -> Result<Flow, RuntimeError> {
+    let op = Opcode::decode(opcode)?;
+    if m.checked {
+        execute_fallible_unary_op(m.stack, |value| checked_unary(op, value))?;
+    } else if op == Opcode::Negate {
+        execute_fallible_unary_op(m.stack, |value| -value)?;
+    } else {
+        execute_fallible_unary_op(m.stack, Value::abs)?;
+    }
+    Ok(Flow::Next)
+}
+
+fn min(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    execute_fallible_binary_op(m.stack, Value::min)?;
+    Ok(Flow::Next)
+}
+
+fn max(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    execute_fallible_binary_op(m.stack, Value::max)?;
+    Ok(Flow::Next)
+}
+
+fn bit_and(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    execute_fallible_binary_op(m.stack, Value::bit_and)?;
+    Ok(Flow::Next)
+}
+
+fn equal(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    execute_binary_op(m.stack, |lhs, rhs| Value::Bool(lhs.equals(&rhs)))?;
+    Ok(Flow::Next)
+}
+
+fn not_equal(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    execute_binary_op(m.stack, |lhs, rhs| Value::Bool(!lhs.equals(&rhs)))?;
+    Ok(Flow::Next)
+}
+
+fn less(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    execute_comparison(m.stack, Opcode::Less, Ordering::is_lt)?;
+    Ok(Flow::Next)
+}
+
+fn less_equal(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    execute_comparison(m.stack, Opcode::LessEqual, Ordering::is_le)?;
+    Ok(Flow::Next)
+}
+
+fn greater(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    execute_comparison(m.stack, Opcode::Greater, Ordering::is_gt)?;
+    Ok(Flow::Next)
+}
+
+fn greater_equal(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    execute_comparison(m.stack, Opcode::GreaterEqual, Ordering::is_ge)?;
+    Ok(Flow::Next)
+}
+
+fn and(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    execute_fallible_binary_op(m.stack, |lhs, rhs| {
+        Ok(Value::Bool(
+            lhs.is_truthy(Opcode::And)? && rhs.is_truthy(Opcode::And)?,
+        ))
+    })?;
+    Ok(Flow::Next)
+}
+
+fn or(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    execute_fallible_binary_op(m.stack, |lhs, rhs| {
+        Ok(Value::Bool(
+            lhs.is_truthy(Opcode::Or)? || rhs.is_truthy(Opcode::Or)?,
+        ))
+    })?;
+    Ok(Flow::Next)
+}
+
+fn not(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    execute_fallible_unary_op(m.stack, |value| !value)?;
+    Ok(Flow::Next)
+}
+
+fn len(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    execute_fallible_unary_op(m.stack, Value::len)?;
+    Ok(Flow::Next)
+}
+
+fn factorial_op(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    let overflow = m.factorial_overflow;
+    execute_fallible_unary_op(m.stack, |value| match value {
+        Value::Int(value) => factorial(value, overflow),
+        value => Err(mismatch(Opcode::Factorial, &value)),
+    })?;
+    Ok(Flow::Next)
+}
+
+fn sqrt(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    execute_fallible_unary_op(m.stack, square_root)?;
+    Ok(Flow::Next)
+}
+
+fn call(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    let address = m.cursor.read_u32()? as usize;
+    let argc = m.cursor.read_u8()? as usize;
+
+    if m.frames.len() >= MAX_CALL_DEPTH {
+        return Err(RuntimeError::CallStackOverflow);
+    }
+    m.stack.push_frame(argc)?;
+    m.frames.push(Frame {
+        return_address: m.cursor.position(),
+        base: m.stack.base(),
+    });
+    m.cursor.jump(address);
+    Ok(Flow::Next)
+}
+
+// Call from the frame of the running function, whose arguments and temporaries the
+// arguments of the callee replace, so the callee returns straight to the caller. The main
+// expression has no frame to reuse and makes a plain call.
+fn tail_call(m: &mut Machine, opcode: u8) -> Result<Flow, RuntimeError> {
+    if m.frames.is_empty() {
+        return call(m, opcode);
+    }
+    let address = m.cursor.read_u32()? as usize;
+    let argc = m.cursor.read_u8()? as usize;
+
+    let base = m.stack.base();
+    if base + argc > m.stack.len() {
+        return Err(RuntimeError::StackUnderflow);
+    }
+    m.stack.squash(base, argc);
+    m.cursor.jump(address);
+    Ok(Flow::Next)
+}
+
+fn call_host(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    let id = m.cursor.read_u8()? as usize;
+    let argc = m.cursor.read_u8()? as usize;
+    host_call(m.hosts, id, argc, m.stack)?;
+    Ok(Flow::Next)
+}
+
+fn jump(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    let address = m.cursor.read_u32()? as usize;
+    m.cursor.jump(address);
+    Ok(Flow::Next)
+}
+
+fn jump_if_false(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    let address = m.cursor.read_u32()? as usize;
+    if !m.stack.pop()?.is_truthy(Opcode::JumpIfFalse)? {
+        m.cursor.jump(address);
+    }
+    Ok(Flow::Next)
+}
+
+fn load_arg(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    let index = m.cursor.read_u8()? as usize;
+    let value = m.stack.local(index)?;
+    m.stack.push(value)?;
+    Ok(Flow::Next)
+}
+
+// Return to the caller, or end the run with the value from the main expression
+fn ret(m: &mut Machine, _: u8) -> Result<Flow, RuntimeError> {
+    let value = m.stack.pop()?;
+    match m.frames.pop() {
+        Some(frame) => {
+            m.stack
+                .pop_frame(m.frames.last().map_or(0, |caller| caller.base));
+            m.stack.push(value)?;
+            m.cursor.jump(frame.return_address);
+            Ok(Flow::Next)
+        }
+        None => Ok(Flow::Return(value)),
+    }
+}
+
+// Bind every name of the host function table of `chunk` to the function registered under it
+fn bind_hosts(chunk: &Chunk, functions: &HashMap<String, HostFunction>) -> Vec<HostSlot> {
+    chunk
+        .host_functions()
+        .iter()
+        .map(|name| HostSlot {
+            name: name.clone(),
+            function: functions.get(name).cloned(),
+        })
+        .collect()
+}
+
+// Call host function `id` with the top `argc` values as its arguments, which its result
+// replaces
+fn host_call(
+    hosts: &[HostSlot],
+    id: usize,
+    argc: usize,
+    stack: &mut Stack,
+) -> Result<(), RuntimeError> {
+    let slot = hosts
+        .get(id)
+        .ok_or_else(|| RuntimeError::UnknownHostFunction(format!("#{}", id)))?;
+    let function = slot
+        .function
+        .as_ref()
+        .ok_or_else(|| RuntimeError::UnknownHostFunction(slot.name.clone()))?;
+    if function.arity != argc {
+        return Err(RuntimeError::HostArity {
+            name: slot.name.clone(),
+            expected: function.arity,
+            got: argc,
+        });
+    }
+    let args = stack.peek_n(argc).ok_or(RuntimeError::StackUnderflow)?;
+    let value = (function.call)(args).map_err(|message| RuntimeError::Host {
+        name: slot.name.clone(),
+        message,
+    })?;
+    stack.truncate(stack.len() - argc);
+    stack.push(value)?;
+    Ok(())
+}
+
+// The operations of the handlers on values instead of the stack, for the other backends
+fn apply_unary(
+    op: Opcode,
+    value: Value,
+    checked: bool,
+    overflow: FactorialOverflow,
+) -> Result<Value, RuntimeError> {
+    match op {
+        Opcode::Negate | Opcode::Abs if checked => checked_unary(op, value),
+        Opcode::Negate => -value,
+        Opcode::Abs => value.abs(),
+        Opcode::Not => !value,
+        Opcode::Len => value.len(),
+        Opcode::Factorial => match value {
+            Value::Int(value) => factorial(value, overflow),
+            value => Err(mismatch(op, &value)),
+        },
+        Opcode::Sqrt => square_root(value),
+        _ => unreachable!("{:?} is not a unary operation", op),
+    }
+}
+
+fn apply_binary(op: Opcode, lhs: Value, rhs: Value, checked: bool) -> Result<Value, RuntimeError> {
+    let comparison =
+        |test: fn(Ordering) -> bool| Ok(Value::Bool(lhs.compare(op, &rhs)?.is_some_and(test)));
+    match op {
+        Opcode::Divide | Opcode::Modulo
+            if matches!((&lhs, &rhs), (Value::Int(_), Value::Int(0))) =>
+        {
+            Err(RuntimeError::DivisionByZero)
+        }
+        Opcode::Addition
+        | Opcode::Subtract
+        | Opcode::Multiply
+        | Opcode::Divide
+        | Opcode::Modulo
+        | Opcode::Pow
+        | Opcode::ShiftLeft
+            if checked =>
+        {
+            checked_binary(op, lhs, rhs)
+        }
+        Opcode::Addition
+        | Opcode::Subtract
+        | Opcode::Multiply
+        | Opcode::Divide
+        | Opcode::Modulo
+        | Opcode::Pow
+        | Opcode::ShiftLeft => unchecked_binary(op, lhs, rhs),
+        Opcode::Less => comparison(Ordering::is_lt),
+        Opcode::LessEqual => comparison(Ordering::is_le),
+        Opcode::Greater => comparison(Ordering::is_gt),
+        Opcode::GreaterEqual => comparison(Ordering::is_ge),
+        Opcode::Min => lhs.min(rhs),
+        Opcode::Max => lhs.max(rhs),
+        Opcode::BitAnd => lhs.bit_and(rhs),
+        Opcode::Equal => Ok(Value::Bool(lhs.equals(&rhs))),
+        Opcode::NotEqual => Ok(Value::Bool(!lhs.equals(&rhs))),
+        Opcode::And => Ok(Value::Bool(lhs.is_truthy(op)? && rhs.is_truthy(op)?)),
+        Opcode::Or => Ok(Value::Bool(lhs.is_truthy(op)? || rhs.is_truthy(op)?)),
+        _ => unreachable!("{:?} is not a binary operation", op),
+    }
+}
+
+// Pre-decode the code for the runs that may start at address 0 or any entry point
+fn fuse_chunk(chunk: &Chunk, entries: &[Entry]) -> Option<FusedCode> {
+    let mut starts = vec![0];
+    starts.extend(entries.iter().map(Entry::address));
+    fuse(chunk.code(), &starts)
+}
+
+#[inline]
+fn notify(
+    hook: &mut Option<Hook>,
+    instruction: &Option<Instruction>,
+    ip: usize,
+    stack: &Stack,
+    frames: &[Frame],
+) {
+    if let (Some(hook), Some(instruction)) = (hook, instruction) {
+        let state = VmState {
+            ip,
+            stack: stack.as_slice(),
+            call_depth: frames.len(),
+        };
+        hook(&state, instruction);
+    }
+}
+
+fn trace(
+    sink: &mut Option<Box<dyn Write + Send>>,
+    address: usize,
+    instruction: &Option<Instruction>,
+    popped: &[Value],
+    stack: &Stack,
+) {
+    if let (Some(sink), Some(instruction)) = (sink, instruction) {
+        let popped = match popped {
+            [] => String::new(),
+            values => {
+                let values: Vec<String> = values.iter().map(Value::to_string).collect();
+                format!(" [{}]", values.join(", "))
+            }
+        };
+        let _ = writeln!(
+            sink,
+            "{:04x}  {}{} -> {}",
+            address,
+            instruction,
+            popped,
+            stack.len()
+        );
+    }
+}
+
+// The top `count` values of the stack, fewer when it holds less
+fn top(stack: &Stack, count: usize) -> Vec<Value> {
+    let count = count.min(stack.len());
+    stack.peek_n(count).unwrap_or_default().to_vec()
+}
+
+#[inline]
+fn execute_fallible_unary_op<F>(stack: &mut Stack, op: F) -> Result<(), RuntimeError>
+where
+    F: FnOnce(Value) -> Result<Value, RuntimeError>,
+{
+    let value = stack.pop()?;
+    Ok(stack.push(op(value)?)?)
+}
+
+#[inline]
+fn execute_binary_op<F>(stack: &mut Stack, op: F) -> Result<(), RuntimeError>
+where
+    F: FnOnce(Value, Value) -> Value,
+{
+    execute_fallible_binary_op(stack, |lhs, rhs| Ok(op(lhs, rhs)))
+}
+
+#[inline]
+fn execute_fallible_binary_op<F>(stack: &mut Stack, op: F) -> Result<(), RuntimeError>
+where
+    F: FnOnce(Value, Value) -> Result<Value, RuntimeError>,
+{
+    let rhs = stack.pop()?;
+    let lhs = stack.pop()?;
+    Ok(stack.push(op(lhs, rhs)?)?)
+}
+
+fn factorial(n: i64, overflow: FactorialOverflow) -> Result<Value, RuntimeError> {
+    if n < 0 {
+        return Err(RuntimeError::NegativeFactorial(n));
+    }
+    // The product overflows from `21!` on, so the loop runs at most 20 times
+    let product = (1..=n).try_fold(1i64, |product, i| product.checked_mul(i));
+    match (product, overflow) {
+        (Some(product), _) => Ok(Value::Int(product)),
+        (None, FactorialOverflow::Error) => Err(RuntimeError::Overflow {
+            op: Opcode::Factorial,
+            operands: vec![n],
+        }),
+        // Floats overflow to infinity from `171!` on
+        (None, FactorialOverflow::Float) if n > 170 => Ok(Value::Float(f64::INFINITY)),
+        (None, FactorialOverflow::Float) => Ok(Value::Float((1..=n).map(|i| i as f64).product())),
+    }
 }
 
-impl Vm {
-    pub fn new<C>(bytecode: C, stack_size: usize) -> Vm
-    where
-        C: Into<Vec<u8>>,
-    {
-        Vm {
-            stack: Stack::new(stack_size),
-            bytecode: bytecode.into(),
-        }
+// Arithmetic of the checked mode, integer results that do not fit fail. Every other
+// operand takes the unchecked path.
+fn checked_binary(op: Opcode, lhs: Value, rhs: Value) -> Result<Value, RuntimeError> {
+    let (&Value::Int(a), &Value::Int(b)) = (&lhs, &rhs) else {
+        return unchecked_binary(op, lhs, rhs);
+    };
+    let result = match op {
+        Opcode::Addition => a.checked_add(b),
+        Opcode::Subtract => a.checked_sub(b),
+        Opcode::Multiply => a.checked_mul(b),
+        Opcode::Divide => a.checked_div(b),
+        Opcode::Modulo => a.checked_rem(b),
+        // Negative exponents give a float, shifts out of range are not arithmetic
+        Opcode::Pow => match u32::try_from(b) {
+            Ok(b) => a.checked_pow(b),
+            Err(_) => return lhs.pow(rhs),
+        },
+        Opcode::ShiftLeft if (0..63).contains(&b) => a.checked_mul(1 << b),
+        _ => return unchecked_binary(op, lhs, rhs),
+    };
+    result.map(Value::Int).ok_or(RuntimeError::Overflow {
+        op,
+        operands: vec![a, b],
+    })
+}
+
+fn unchecked_binary(op: Opcode, lhs: Value, rhs: Value) -> Result<Value, RuntimeError> {
+    match op {
+        Opcode::Addition => lhs + rhs,
+        Opcode::Subtract => lhs - rhs,
+        Opcode::Multiply => lhs * rhs,
+        Opcode::Divide => lhs / rhs,
+        Opcode::Modulo => lhs % rhs,
+        Opcode::Pow => lhs.pow(rhs),
+        Opcode::ShiftLeft => lhs.shift_left(rhs),
+        _ => unreachable!("{:?} is not arithmetic", op),
     }
+}
 
-    #[inline]
-    fn execute_binary_op<F>(&mut self, op: F)
-    where
-        F: FnOnce(Value, Value) -> Value,
-    {
-        let rhs = self.stack.pop();
-        let lhs = self.stack.pop();
-        self.stack.push(op(lhs, rhs));
-    }
-
-    pub fn run(&mut self) -> Option<Value> {
-        let mut position = 0;
-        while position < self.bytecode.len() {
-            let opcode = self.bytecode[position];
-            position += 1;
-
-            match Opcode::from(opcode) {
-                Opcode::Literal => {
-                    let value = Value::from(&self.bytecode[position..]);
-                    position += value.size();
-                    self.stack.push(value);
-                }
-                Opcode::Addition => self.execute_binary_op(|lhs, rhs| lhs + rhs),
-                Opcode::Subtract => self.execute_binary_op(|lhs, rhs| lhs - rhs),
-                Opcode::Multiply => self.execute_binary_op(|lhs, rhs| lhs * rhs),
-                Opcode::Divide => self.execute_binary_op(|lhs, rhs| lhs / rhs),
-                Opcode::Modulo => self.execute_binary_op(|lhs, rhs| lhs % rhs),
-                Opcode::Factorial => {
-                    let value = self.stack.pop();
-                    match value {
-                        Value::Int(value) => {
-                            self.stack.push(Value::Int((1..=value).product()));
-                        }
-                        _ => panic!("invalid value type"),
-                    }
-                }
-                Opcode::Sqrt => {
-                    let value = self.stack.pop();
-                    match value {
-                        Value::Int(n) => {
-                            let result = (n as f64).sqrt();
-                            self.stack.push(Value::Float(result));
-                        }
-                        Value::Float(n) => {
-                            self.stack.push(Value::Float(n.sqrt()));
-                        }
-                    }
-                }
-                Opcode::Return => {
-                    return Some(self.stack.pop());
-                }
-            }
-        }
-        None
+fn square_root(value: Value) -> Result<Value, RuntimeError> {
+    match value {
+        Value::Int(n) => Ok(Value::Float((n as f64).sqrt())),
+        Value::Float(n) => Ok(Value::Float(n.sqrt())),
+        value => Err(mismatch(Opcode::Sqrt, &value)),
     }
 }
 
+// The error for the unary `op` applied to an operand it does not accept
+fn mismatch(op: Opcode, value: &Value) -> RuntimeError {
+    RuntimeError::TypeMismatch {
+        op,
+        lhs: value.type_name(),
+        rhs: None,
+    }
+}
+
+fn checked_unary(op: Opcode, value: Value) -> Result<Value, RuntimeError> {
+    let negate = op == Opcode::Negate;
+    let Value::Int(a) = value else {
+        return if negate { -value } else { value.abs() };
+    };
+    let result = if negate {
+        a.checked_neg()
+    } else {
+        a.checked_abs()
+    };
+    result.map(Value::Int).ok_or(RuntimeError::Overflow {
+        op,
+        operands: vec![a],
+    })
+}
+
+// Integer division and remainder by zero fail. With a float on either side they follow
+// IEEE 754 instead, giving an infinity or NaN.
+#[inline]
+fn execute_division<F>(stack: &mut Stack, op: F) -> Result<(), RuntimeError>
+where
+    F: FnOnce(Value, Value) -> Result<Value, RuntimeError>,
+{
+    let rhs = stack.pop()?;
+    let lhs = stack.pop()?;
+    if matches!((&lhs, &rhs), (Value::Int(_), Value::Int(0))) {
+        return Err(RuntimeError::DivisionByZero);
+    }
+    Ok(stack.push(op(lhs, rhs)?)?)
+}
+
+// Compare the top two values with `op`, unordered operands (NaN) make every comparison false
+#[inline]
+fn execute_comparison<F>(stack: &mut Stack, op: Opcode, test: F) -> Result<(), RuntimeError>
+where
+    F: FnOnce(Ordering) -> bool,
+{
+    execute_fallible_binary_op(stack, |lhs, rhs| {
+        Ok(Value::Bool(lhs.compare(op, &rhs)?.is_some_and(test)))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        compiler::{
+            compile, compile_program, compile_unit, compile_with_options, CompileOptions, OptLevel,
+        },
+        error::DecodeError,
+    };
     use rstest::rstest;
 
     fn create_binary_op_bytecode(lhs: i64, rhs: i64, op: Opcode) -> Vec<u8> {
@@ -156,6 +1480,172 @@ mod tests {
         assert_eq!(ret, Value::Int(expected));
     }
 
+    #[rstest]
+    #[case(Opcode::Divide)]
+    #[case(Opcode::Modulo)]
+    fn test_division_by_zero(#[case] op: Opcode) {
+        let bytecode = create_binary_op_bytecode(6, 0, op);
+        let mut vm = Vm::new(bytecode, 10);
+        assert_eq!(vm.run(), Err(RuntimeError::DivisionByZero));
+        assert_eq!(vm.fault_address(), Some(20));
+    }
+
+    #[rstest]
+    #[case("1 / 0.0", f64::INFINITY)]
+    #[case("-1.0 / 0", f64::NEG_INFINITY)]
+    #[case("0.0 / 0", f64::NAN)]
+    #[case("1.5 % 0", f64::NAN)]
+    fn test_float_division_by_zero(#[case] input: &str, #[case] expected: f64) {
+        let mut vm = Vm::new(compile(input).unwrap(), 10);
+        match vm.run() {
+            Ok(Value::Float(result)) if expected.is_nan() => assert!(result.is_nan()),
+            result => assert_eq!(result, Ok(Value::Float(expected))),
+        }
+    }
+
+    #[test]
+    fn test_fault_span() {
+        let input = "fn f(x) { 10 / x } 1 + f(2 - 2)";
+        let options = CompileOptions::new().debug_info(true);
+        let mut vm = Vm::new(compile_program(input, &options).unwrap(), 10);
+        assert_eq!(vm.run(), Err(RuntimeError::DivisionByZero));
+        let span = vm.fault_span().unwrap();
+        assert_eq!(&input[span.start..span.end], "10 / x");
+
+        // A later run that fails before executing anything leaves no fault behind
+        assert_eq!(
+            vm.run_with_args(&vec![Value::Int(0); 11]),
+            Err(RuntimeError::StackOverflow)
+        );
+        assert_eq!((vm.fault_address(), vm.fault_span()), (None, None));
+    }
+
+    #[rstest]
+    #[case(i64::MAX, 1, Opcode::Addition)]
+    #[case(i64::MIN, 1, Opcode::Subtract)]
+    #[case(i64::MAX, 2, Opcode::Multiply)]
+    #[case(i64::MIN, -1, Opcode::Divide)]
+    #[case(i64::MIN, -1, Opcode::Modulo)]
+    #[case(3, 40, Opcode::Pow)]
+    #[case(1 << 60, 4, Opcode::ShiftLeft)]
+    fn test_checked_overflow(#[case] lhs: i64, #[case] rhs: i64, #[case] op: Opcode) {
+        let bytecode = create_binary_op_bytecode(lhs, rhs, op);
+        let mut vm = Vm::new(bytecode, 10).checked_arithmetic(true);
+        let expected = RuntimeError::Overflow {
+            op,
+            operands: vec![lhs, rhs],
+        };
+        assert_eq!(vm.run(), Err(expected));
+        assert_eq!(vm.fault_address(), Some(20));
+    }
+
+    #[rstest]
+    #[case(Opcode::Negate)]
+    #[case(Opcode::Abs)]
+    fn test_checked_unary_overflow(#[case] op: Opcode) {
+        let bytecode = create_unary_op_bytecode(i64::MIN, op);
+        let mut vm = Vm::new(bytecode, 10).checked_arithmetic(true);
+        let error = vm.run().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("Integer overflow in {:?} of {}", op, i64::MIN)
+        );
+    }
+
+    #[rstest]
+    #[case("2 * 3 - 10", Value::Int(-4))]
+    #[case("-(2 + 1) + 1", Value::Int(-2))]
+    #[case("pow(2, -1)", Value::Float(0.5))]
+    #[case("7 % -2 + 9 / 2", Value::Int(5))]
+    #[case("abs(-3) * 1.5", Value::Float(4.5))]
+    #[case("6 / 0.0", Value::Float(f64::INFINITY))]
+    fn test_checked_arithmetic(#[case] input: &str, #[case] expected: Value) {
+        let bytecode =
+            compile_with_options(input, &CompileOptions::new().opt_level(OptLevel::Speed));
+        let mut vm = Vm::new(bytecode.unwrap(), 10).checked_arithmetic(true);
+        assert_eq!(vm.run(), Ok(expected));
+    }
+
+    #[rstest]
+    #[case(r#""a" - 1"#, Opcode::Subtract, "string", Some("int"))]
+    #[case("true + 1", Opcode::Addition, "bool", Some("int"))]
+    #[case(r#"-"a""#, Opcode::Negate, "string", None)]
+    #[case(r#"!"a""#, Opcode::Not, "string", None)]
+    #[case("1 < 2 < 3", Opcode::Less, "bool", Some("int"))]
+    fn test_type_mismatch(
+        #[case] input: &str,
+        #[case] op: Opcode,
+        #[case] lhs: &'static str,
+        #[case] rhs: Option<&'static str>,
+        #[values(false, true)] checked: bool,
+    ) {
+        let expected = Err(RuntimeError::TypeMismatch { op, lhs, rhs });
+        let program = compile(input).unwrap();
+        let mut fused = Vm::new(program.clone(), 16).checked_arithmetic(checked);
+        assert_eq!(fused.run(), expected);
+        let mut dispatched = Vm::new(program, 16).checked_arithmetic(checked);
+        dispatched.set_hook(|_, _| {});
+        assert_eq!(dispatched.run(), expected);
+    }
+
+    // Without checks integer overflow wraps around in every build profile
+    #[rstest]
+    #[case(i64::MAX, 1, Opcode::Addition, i64::MIN)]
+    #[case(i64::MIN, 1, Opcode::Subtract, i64::MAX)]
+    #[case(i64::MAX, 2, Opcode::Multiply, -2)]
+    #[case(i64::MIN, -1, Opcode::Divide, i64::MIN)]
+    #[case(i64::MIN, -1, Opcode::Modulo, 0)]
+    #[case(3, 40, Opcode::Pow, 3i64.wrapping_pow(40))]
+    #[case(1 << 60, 4, Opcode::ShiftLeft, 0)]
+    fn test_wrapping_overflow(
+        #[case] lhs: i64,
+        #[case] rhs: i64,
+        #[case] op: Opcode,
+        #[case] expected: i64,
+    ) {
+        let bytecode = create_binary_op_bytecode(lhs, rhs, op);
+        let mut vm = Vm::new(bytecode, 10);
+        assert_eq!(vm.run(), Ok(Value::Int(expected)));
+    }
+
+    #[rstest]
+    #[case(
+        "(0 - 9223372036854775807 - 1) / -1",
+        Value::Int(i64::MIN),
+        Opcode::Divide
+    )]
+    #[case("(0 - 9223372036854775807 - 1) % -1", Value::Int(0), Opcode::Modulo)]
+    #[case("9223372036854775807 + 1", Value::Int(i64::MIN), Opcode::Addition)]
+    #[case("-(0 - 9223372036854775807 - 1)", Value::Int(i64::MIN), Opcode::Negate)]
+    #[case("abs(0 - 9223372036854775807 - 1)", Value::Int(i64::MIN), Opcode::Abs)]
+    fn test_overflow(
+        #[case] input: &str,
+        #[case] wrapped: Value,
+        #[case] op: Opcode,
+        #[values(false, true)] checked: bool,
+    ) {
+        let program = compile(input).unwrap();
+        let mut fused = Vm::new(program.clone(), 16).checked_arithmetic(checked);
+        let mut dispatched = Vm::new(program, 16).checked_arithmetic(checked);
+        dispatched.set_hook(|_, _| {});
+        for result in [fused.run(), dispatched.run()] {
+            match result {
+                Err(RuntimeError::Overflow { op: failed, .. }) => {
+                    assert!(checked);
+                    assert_eq!(failed, op);
+                }
+                result => assert_eq!((checked, result), (false, Ok(wrapped.clone()))),
+            }
+        }
+    }
+
+    #[test]
+    fn test_checked_division_by_zero() {
+        let bytecode = create_binary_op_bytecode(1, 0, Opcode::Divide);
+        let mut vm = Vm::new(bytecode, 10).checked_arithmetic(true);
+        assert_eq!(vm.run(), Err(RuntimeError::DivisionByZero));
+    }
+
     #[rstest]
     #[case(5, 120)]  // 5! = 5 * 4 * 3 * 2 * 1 = 120
     #[case(3, 6)]    // 3! = 3 * 2 * 1 = 6
@@ -168,6 +1658,24 @@ mod tests {
         assert_eq!(ret, Value::Int(expected));
     }
 
+    #[rstest]
+    #[case(-1, FactorialOverflow::Error, Err(RuntimeError::NegativeFactorial(-1)))]
+    #[case(-3, FactorialOverflow::Float, Err(RuntimeError::NegativeFactorial(-3)))]
+    #[case(20, FactorialOverflow::Error, Ok(Value::Int(2432902008176640000)))]
+    #[case(21, FactorialOverflow::Error, Err(RuntimeError::Overflow { op: Opcode::Factorial, operands: vec![21] }))]
+    #[case(21, FactorialOverflow::Float, Ok(Value::Float(51090942171709440000.0)))]
+    #[case(171, FactorialOverflow::Float, Ok(Value::Float(f64::INFINITY)))]
+    #[case(i64::MAX, FactorialOverflow::Error, Err(RuntimeError::Overflow { op: Opcode::Factorial, operands: vec![i64::MAX] }))]
+    fn test_factorial_limits(
+        #[case] value: i64,
+        #[case] overflow: FactorialOverflow,
+        #[case] expected: Result<Value, RuntimeError>,
+    ) {
+        let bytecode = create_unary_op_bytecode(value, Opcode::Factorial);
+        let mut vm = Vm::new(bytecode, 10).factorial_overflow(overflow);
+        assert_eq!(vm.run(), expected);
+    }
+
     #[test]
     fn test_sqrt() {
         let mut bytecode = vec![Opcode::Literal as u8];
@@ -195,4 +1703,781 @@ mod tests {
         let ret = vm.run().unwrap();
         assert_eq!(ret, Value::Float(expected));
     }
+
+    #[test]
+    fn test_call_and_return() {
+        // Main: push 20 and 22, call `add` at the end of the main code, return the result
+        let mut bytecode = vec![Opcode::Literal as u8];
+        bytecode.extend(Value::Int(20).to_vec());
+        bytecode.push(Opcode::Literal as u8);
+        bytecode.extend(Value::Int(22).to_vec());
+        bytecode.push(Opcode::Call as u8);
+        bytecode.extend(27u32.to_be_bytes());
+        bytecode.push(2);
+        bytecode.push(Opcode::Return as u8);
+
+        // add(a, b): a + b
+        assert_eq!(bytecode.len(), 27);
+        bytecode.extend([Opcode::LoadArg as u8, 0, Opcode::LoadArg as u8, 1]);
+        bytecode.push(Opcode::Addition as u8);
+        bytecode.push(Opcode::Return as u8);
+
+        let mut vm = Vm::new(bytecode, 10);
+        assert_eq!(vm.run().unwrap(), Value::Int(42));
+    }
+
+    #[test]
+    fn test_unbounded_recursion() {
+        // f(): f()
+        let mut bytecode = vec![Opcode::Call as u8];
+        bytecode.extend(0u32.to_be_bytes());
+        bytecode.push(0);
+
+        let mut vm = Vm::new(bytecode, 10);
+        assert_eq!(vm.run(), Err(RuntimeError::CallStackOverflow));
+    }
+
+    #[rstest]
+    #[case(
+        "fn f(n, acc) { n < 1 ? acc : f(n - 1, acc + n) } f(100000, 0)",
+        Ok(Value::Int(5000050000))
+    )]
+    #[case(
+        "fn f(a, b, n) { n < 1 ? a : f(b, a + b, n - 1) } fn fib(n) { f(0, 1, n) } fib(90)",
+        Ok(Value::Int(2880067194370816120))
+    )]
+    #[case("fn even(n) { n == 0 ? true : odd(n - 1) } fn odd(n) { n == 0 ? false : even(n - 1) } even(5001)", Ok(Value::Bool(false)))]
+    #[case(
+        "fn f(n) { n < 1 ? 0 : f(n - 1) + 1 } f(100000)",
+        Err(RuntimeError::CallStackOverflow)
+    )]
+    fn test_tail_calls(#[case] input: &str, #[case] expected: Result<Value, RuntimeError>) {
+        let mut vm = Vm::new(compile(input).unwrap(), 1 << 12);
+        assert_eq!(vm.run(), expected);
+
+        let mut dispatched = Vm::new(compile(input).unwrap(), 1 << 12);
+        dispatched.set_hook(|_, _| {});
+        assert_eq!(dispatched.run(), expected);
+    }
+
+    #[test]
+    fn test_tail_call_from_main() {
+        // Main: tail call `double` with 21, there is no frame to reuse
+        let mut bytecode = vec![Opcode::Literal as u8];
+        bytecode.extend(Value::Int(21).to_vec());
+        bytecode.push(Opcode::TailCall as u8);
+        bytecode.extend(17u32.to_be_bytes());
+        bytecode.push(1);
+        bytecode.push(Opcode::Return as u8);
+
+        // double(x): x + x
+        assert_eq!(bytecode.len(), 17);
+        bytecode.extend([Opcode::LoadArg as u8, 0, Opcode::LoadArg as u8, 0]);
+        bytecode.push(Opcode::Addition as u8);
+        bytecode.push(Opcode::Return as u8);
+
+        let mut vm = Vm::new(bytecode, 4);
+        assert_eq!(vm.run(), Ok(Value::Int(42)));
+    }
+
+    #[rstest]
+    #[case(vec![Opcode::Addition as u8], RuntimeError::StackUnderflow)]
+    #[case(vec![Opcode::Return as u8], RuntimeError::StackUnderflow)]
+    #[case(vec![Opcode::LoadArg as u8, 0], RuntimeError::StackUnderflow)]
+    #[case(vec![Opcode::Call as u8, 0, 0, 0, 0, 1], RuntimeError::StackUnderflow)]
+    #[case(vec![Opcode::LoadArg as u8], RuntimeError::UnexpectedEnd)]
+    #[case(vec![Opcode::Jump as u8, 0, 0], RuntimeError::UnexpectedEnd)]
+    #[case(vec![0xEE], RuntimeError::InvalidOpcode(0xEE))]
+    #[case(vec![Opcode::Literal as u8, 9], RuntimeError::InvalidLiteral(DecodeError::InvalidValueTag(9)))]
+    #[case(vec![Opcode::Literal as u8, 2, 0, 0, 0, 1, 0xC0], RuntimeError::InvalidLiteral(DecodeError::InvalidUtf8))]
+    fn test_malformed_bytecode(#[case] bytecode: Vec<u8>, #[case] expected: RuntimeError) {
+        let mut vm = Vm::new(bytecode, 10);
+        assert_eq!(vm.run(), Err(expected));
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let bytecode = create_binary_op_bytecode(1, 2, Opcode::Addition);
+        let mut vm = Vm::new(bytecode, 1);
+        assert_eq!(vm.run(), Err(RuntimeError::StackOverflow));
+
+        // Arguments beyond the stack size are rejected the same way
+        let mut vm = Vm::new(vec![Opcode::LoadArg as u8, 0, Opcode::Return as u8], 1);
+        let result = vm.run_with_args(&[Value::Int(1), Value::Int(2)]);
+        assert_eq!(result, Err(RuntimeError::StackOverflow));
+    }
+
+    #[test]
+    fn test_fuel() {
+        // 2 * 3 + 4 takes 5 instructions and the return
+        let bytecode = compile("2 * 3 + 4").unwrap();
+        let mut vm = Vm::new(bytecode.clone(), 10).with_fuel(6);
+        assert_eq!(vm.run(), Ok(Value::Int(10)));
+        assert_eq!(vm.fuel(), Some(0));
+        assert_eq!(vm.run(), Err(RuntimeError::OutOfFuel));
+
+        let mut vm = Vm::new(bytecode, 10).with_fuel(3);
+        assert_eq!(vm.run(), Err(RuntimeError::OutOfFuel));
+        assert_eq!(vm.fault_address(), Some(21));
+        assert_eq!(vm.resume(), Err(RuntimeError::OutOfFuel));
+        vm.refuel(2);
+        assert_eq!(vm.resume(), Err(RuntimeError::OutOfFuel));
+        vm.refuel(10);
+        assert_eq!(vm.resume(), Ok(Value::Int(10)));
+        assert_eq!(vm.fuel(), Some(9));
+        assert_eq!(vm.resume(), Err(RuntimeError::NotSuspended));
+    }
+
+    #[test]
+    fn test_resume_inside_call() {
+        let program = compile("fn f(x) { x * x } f(3) + f(4)").unwrap();
+        let mut vm = Vm::new(program, 10).with_fuel(0);
+        let mut refuels = 0;
+        let mut result = vm.run();
+        while result == Err(RuntimeError::OutOfFuel) {
+            refuels += 1;
+            vm.refuel(1);
+            result = vm.resume();
+        }
+        assert_eq!(result, Ok(Value::Int(25)));
+        assert!(refuels > 10);
+    }
+
+    #[test]
+    fn test_timeout() {
+        let timeout = Duration::from_millis(20);
+        let program = compile("fn f(x) { f(x) } f(1)").unwrap();
+        let mut vm = Vm::builder(program).timeout(timeout).build();
+        let started = Instant::now();
+        assert_eq!(vm.run(), Err(RuntimeError::Timeout(timeout)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(vm.resume(), Err(RuntimeError::NotSuspended));
+
+        // A run that finishes in time is unaffected, with or without fuel
+        let mut vm = Vm::new(compile("2 * 3 + 4").unwrap(), 10).with_timeout(timeout);
+        assert_eq!(vm.run(), Ok(Value::Int(10)));
+        let mut vm = vm.with_fuel(3);
+        assert_eq!(vm.run(), Err(RuntimeError::OutOfFuel));
+    }
+
+    #[test]
+    fn test_step() {
+        let program = compile("fn f(x) { x + 1 } f(2) * 3").unwrap();
+        let mut vm = Vm::new(program.clone(), 10);
+        assert_eq!(vm.step(), Ok(StepResult::Halted));
+
+        vm.start(&[]).unwrap();
+        let mut steps = 1;
+        let mut result = vm.step();
+        while result == Ok(StepResult::Continue) {
+            steps += 1;
+            result = vm.step();
+        }
+        assert_eq!(result, Ok(StepResult::Returned(Value::Int(9))));
+        // Every instruction runs once, `f` is called a single time
+        assert_eq!(steps, program.instructions().count());
+        assert_eq!(vm.step(), Ok(StepResult::Halted));
+    }
+
+    #[test]
+    fn test_introspection() {
+        let program = compile("fn f(x) { x + 1 } f(2) * 3").unwrap();
+        let mut vm = Vm::new(program, 10);
+        assert_eq!((vm.ip(), vm.current_instruction()), (None, None));
+
+        vm.start(&[]).unwrap();
+        assert_eq!(vm.ip(), Some(0));
+        assert_eq!(
+            vm.current_instruction(),
+            Some(Instruction::Literal(Value::Int(2)))
+        );
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.call_depth(), 1);
+        assert_eq!(vm.current_instruction(), Some(Instruction::LoadArg(0)));
+        vm.step().unwrap();
+        assert_eq!(vm.stack_slice(), &[Value::Int(2), Value::Int(2)]);
+        assert_eq!(vm.stack().to_string(), "[2, 2]");
+
+        while vm.step() == Ok(StepResult::Continue) {}
+        assert_eq!((vm.ip(), vm.call_depth()), (None, 0));
+    }
+
+    #[test]
+    fn test_step_then_resume() {
+        let mut vm = Vm::new(compile("(1 + 2) * 3").unwrap(), 10);
+        vm.start(&[]).unwrap();
+        assert_eq!(vm.step(), Ok(StepResult::Continue));
+        assert_eq!(vm.resume(), Ok(Value::Int(9)));
+        assert_eq!(vm.resume(), Err(RuntimeError::NotSuspended));
+    }
+
+    #[test]
+    fn test_step_errors() {
+        let bytecode = create_binary_op_bytecode(1, 0, Opcode::Divide);
+        let mut vm = Vm::new(&bytecode[..bytecode.len() - 1], 10);
+        vm.start(&[]).unwrap();
+        assert_eq!(vm.step(), Ok(StepResult::Continue));
+        assert_eq!(vm.step(), Ok(StepResult::Continue));
+        assert_eq!(vm.step(), Err(RuntimeError::DivisionByZero));
+        assert_eq!(vm.fault_address(), Some(20));
+        assert_eq!(vm.step(), Ok(StepResult::Halted));
+
+        // Without a return the run halts at the end of the code
+        let bytecode = create_binary_op_bytecode(1, 2, Opcode::Addition);
+        let mut vm = Vm::new(&bytecode[..bytecode.len() - 1], 10);
+        vm.start(&[]).unwrap();
+        let steps: Vec<_> = std::iter::from_fn(|| Some(vm.step())).take(4).collect();
+        assert_eq!(steps[2..], [Ok(StepResult::Halted), Ok(StepResult::Halted)]);
+    }
+
+    #[rstest]
+    #[case("1 + 2 * 3", 2)]
+    #[case("fn f(x) { x * 2 } f(3) + 1", 3)]
+    #[case("fn f(n) { n < 1 ? 0 : f(n - 1) + 1 } f(10)", 13)]
+    fn test_stats(#[case] input: &str, #[case] expected: usize) {
+        let program = compile(input).unwrap();
+        let mut vm = Vm::new(program.clone(), 64);
+        assert_eq!(vm.stats(), VmStats::default());
+        vm.run().unwrap();
+        assert_eq!(vm.stats().max_stack_depth, expected);
+
+        let mut dispatched = Vm::new(program.clone(), 64);
+        dispatched.set_hook(|_, _| {});
+        dispatched.run().unwrap();
+        assert_eq!(dispatched.stats(), vm.stats());
+
+        // The peak is the smallest stack the run fits in
+        assert!(Vm::new(program.clone(), expected).run().is_ok());
+        assert_eq!(
+            Vm::new(program, expected - 1).run(),
+            Err(RuntimeError::StackOverflow)
+        );
+    }
+
+    #[test]
+    fn test_run_all() {
+        let mut bytecode = create_binary_op_bytecode(7, 2, Opcode::Divide);
+        bytecode.pop();
+        bytecode.extend(create_binary_op_bytecode(7, 2, Opcode::Modulo));
+        let mut vm = Vm::new(bytecode, 4);
+        assert_eq!(vm.run_all(), Ok(vec![Value::Int(3), Value::Int(1)]));
+        assert_eq!(vm.stack_slice(), &[]);
+
+        let mut vm = Vm::new(compile("fn f(x) { x * 2 } f(3) + 1").unwrap(), 4);
+        assert_eq!(vm.run_all(), Ok(vec![Value::Int(7)]));
+        let mut vm = Vm::new(create_binary_op_bytecode(1, 0, Opcode::Divide), 4);
+        assert_eq!(vm.run_all(), Err(RuntimeError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_handler_table() {
+        for byte in 0..=u8::MAX {
+            let result = Vm::new(vec![byte], 4).run();
+            let invalid = result == Err(RuntimeError::InvalidOpcode(byte));
+            assert_eq!(
+                invalid,
+                Opcode::decode(byte).is_err(),
+                "opcode {:#04x}",
+                byte
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_with_args() {
+        let mut bytecode = vec![Opcode::LoadArg as u8, 1, Opcode::LoadArg as u8, 0];
+        bytecode.push(Opcode::Subtract as u8);
+        bytecode.push(Opcode::Return as u8);
+
+        let mut vm = Vm::new(bytecode, 10);
+        let ret = vm.run_with_args(&[Value::Int(2), Value::Int(44)]).unwrap();
+        assert_eq!(ret, Value::Int(42));
+    }
+
+    #[rstest]
+    #[case(2, 10, Opcode::Pow, 1024)]
+    #[case(-3, 3, Opcode::Pow, -27)]
+    #[case(3, 7, Opcode::Min, 3)]
+    #[case(3, 7, Opcode::Max, 7)]
+    #[case(3, 4, Opcode::ShiftLeft, 48)]
+    #[case(13, 3, Opcode::BitAnd, 1)]
+    fn test_builtin_binary_ops(
+        #[case] lhs: i64,
+        #[case] rhs: i64,
+        #[case] op: Opcode,
+        #[case] expected: i64,
+    ) {
+        let bytecode = create_binary_op_bytecode(lhs, rhs, op);
+        let mut vm = Vm::new(bytecode, 10);
+        assert_eq!(vm.run().unwrap(), Value::Int(expected));
+    }
+
+    #[rstest]
+    #[case(0, 1)]
+    #[case(7, 0)]
+    fn test_not(#[case] value: i64, #[case] expected: i64) {
+        let bytecode = create_unary_op_bytecode(value, Opcode::Not);
+        let mut vm = Vm::new(bytecode, 10);
+        assert_eq!(vm.run().unwrap(), Value::Int(expected));
+    }
+
+    #[rstest]
+    #[case(3, -3)]
+    #[case(-8, 8)]
+    fn test_negate(#[case] value: i64, #[case] expected: i64) {
+        let bytecode = create_unary_op_bytecode(value, Opcode::Negate);
+        let mut vm = Vm::new(bytecode, 10);
+        assert_eq!(vm.run().unwrap(), Value::Int(expected));
+    }
+
+    #[test]
+    fn test_abs() {
+        let bytecode = create_unary_op_bytecode(-5, Opcode::Abs);
+        let mut vm = Vm::new(bytecode, 10);
+        assert_eq!(vm.run().unwrap(), Value::Int(5));
+    }
+
+    #[rstest]
+    #[case(1, 2, Opcode::Less, true)]
+    #[case(2, 2, Opcode::LessEqual, true)]
+    #[case(2, 1, Opcode::Greater, true)]
+    #[case(1, 2, Opcode::GreaterEqual, false)]
+    #[case(3, 3, Opcode::Equal, true)]
+    #[case(3, 3, Opcode::NotEqual, false)]
+    #[case(1, 0, Opcode::And, false)]
+    #[case(1, 0, Opcode::Or, true)]
+    fn test_comparisons(
+        #[case] lhs: i64,
+        #[case] rhs: i64,
+        #[case] op: Opcode,
+        #[case] expected: bool,
+    ) {
+        let bytecode = create_binary_op_bytecode(lhs, rhs, op);
+        let mut vm = Vm::new(bytecode, 10);
+        assert_eq!(vm.run().unwrap(), Value::Bool(expected));
+    }
+
+    #[rstest]
+    #[case(1, 10)]
+    #[case(0, 20)]
+    fn test_jumps(#[case] condition: i64, #[case] expected: i64) {
+        // condition ? 10 : 20
+        let mut bytecode = vec![Opcode::Literal as u8];
+        bytecode.extend(Value::Int(condition).to_vec());
+        bytecode.push(Opcode::JumpIfFalse as u8);
+        bytecode.extend(30u32.to_be_bytes());
+        bytecode.push(Opcode::Literal as u8);
+        bytecode.extend(Value::Int(10).to_vec());
+        bytecode.push(Opcode::Jump as u8);
+        bytecode.extend(40u32.to_be_bytes());
+        bytecode.push(Opcode::Literal as u8);
+        bytecode.extend(Value::Int(20).to_vec());
+        bytecode.push(Opcode::Return as u8);
+        let mut vm = Vm::new(bytecode, 10);
+        assert_eq!(vm.run().unwrap(), Value::Int(expected));
+    }
+
+    #[test]
+    fn test_len() {
+        let mut bytecode = vec![Opcode::Literal as u8];
+        bytecode.extend(Value::from("héllo").to_vec());
+        bytecode.push(Opcode::Len as u8);
+        bytecode.push(Opcode::Return as u8);
+        let mut vm = Vm::new(bytecode, 10);
+        assert_eq!(vm.run().unwrap(), Value::Int(5));
+    }
+
+    #[test]
+    fn test_no_result() {
+        let bytecode = create_binary_op_bytecode(1, 2, Opcode::Addition);
+        let mut vm = Vm::new(&bytecode[..bytecode.len() - 1], 10);
+        assert_eq!(vm.run(), Err(RuntimeError::NoResult));
+    }
+
+    #[test]
+    fn test_run_entry_missing() {
+        let bytecode = vec![Opcode::LoadArg as u8, 0, Opcode::Return as u8];
+        let entry = Entry::new("identity", 0, vec!["x".to_string()]);
+        let mut vm = Vm::new(Program::with_entries(bytecode, vec![entry]), 10);
+
+        let env = HashMap::from([("x".to_string(), Value::Int(7))]);
+        assert_eq!(vm.run_entry("identity", &env), Ok(Value::Int(7)));
+        assert_eq!(
+            vm.run_entry("missing", &env),
+            Err(RuntimeError::UnknownEntry("missing".to_string()))
+        );
+        assert_eq!(
+            vm.run_entry("identity", &HashMap::new()),
+            Err(RuntimeError::MissingArgument("x".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_hooks() {
+        use std::sync::{Arc, Mutex};
+
+        let program = compile("fn f(x) { -x } f(2) + 1").unwrap();
+        let mut vm = Vm::new(program, 10);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let before = Arc::clone(&log);
+        vm.set_hook(move |state, instruction| {
+            let entry = format!("{:02} {} {}", state.ip, state.call_depth, instruction);
+            before.lock().unwrap().push(entry);
+        });
+        let after = Arc::clone(&log);
+        vm.set_post_hook(move |state, _| {
+            after.lock().unwrap().push(format!("   {:?}", state.stack));
+        });
+        assert_eq!(vm.run(), Ok(Value::Int(-1)));
+
+        let log = log.lock().unwrap().join("\n");
+        let expected = "\
+00 0 literal int 2
+   [Int(2)]
+10 0 call 0x001c 1
+   [Int(2)]
+28 1 load_arg 0
+   [Int(2), Int(2)]
+30 1 negate
+   [Int(2), Int(-2)]
+31 1 return
+   [Int(-2)]
+16 0 literal int 1
+   [Int(-2), Int(1)]
+26 0 add
+   [Int(-1)]
+27 0 return
+   []";
+        assert_eq!(log, expected);
+
+        vm.clear_hooks();
+        assert_eq!(vm.run(), Ok(Value::Int(-1)));
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn take(&self) -> String {
+            String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut vm = Vm::new(compile("fn f(x) { x * 2 } f(3) + 1").unwrap(), 10);
+        let buffer = SharedBuffer::default();
+        vm.set_trace(buffer.clone());
+        assert_eq!(vm.run(), Ok(Value::Int(7)));
+
+        let expected = "\
+0000  literal int 3 -> 1
+000a  call 0x001c 1 [3] -> 1
+001c  load_arg 0 -> 2
+001e  literal int 2 -> 3
+0028  mul [3, 2] -> 2
+0029  return [6] -> 1
+0010  literal int 1 -> 2
+001a  add [6, 1] -> 1
+001b  return [7] -> 0
+";
+        assert_eq!(buffer.take(), expected);
+
+        // A failing instruction is not traced
+        vm.load(compile("1 / 0").unwrap());
+        assert_eq!(vm.run(), Err(RuntimeError::DivisionByZero));
+        assert_eq!(buffer.take().lines().count(), 2);
+
+        vm.clear_trace();
+        assert_eq!(vm.run(), Err(RuntimeError::DivisionByZero));
+        assert_eq!(buffer.take(), "");
+    }
+
+    #[test]
+    fn test_builder() {
+        let program = compile("fn f(n) { n > 0 ? f(n - 1) : 0 } 10! + f(3)").unwrap();
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = calls.clone();
+        let buffer = SharedBuffer::default();
+        let mut vm = Vm::builder(program.clone())
+            .stack_size(16)
+            .fuel(1000)
+            .checked_arithmetic(true)
+            .factorial_overflow(FactorialOverflow::Float)
+            .hook(move |_, _| {
+                counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            })
+            .trace(buffer.clone())
+            .build();
+        assert_eq!(vm.run(), Ok(Value::Int(3628800)));
+        let executed = calls.load(std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(vm.fuel(), Some(1000 - executed as u64));
+        assert_eq!(buffer.take().lines().count(), executed);
+
+        // Without a stack size the program's own depth is used, or the default when it recurses
+        let fitted = Vm::builder(compile("1 + 2 * 3").unwrap()).build();
+        assert_eq!(fitted.stack.capacity(), 2);
+        let recursive = Vm::builder(program).build();
+        assert_eq!(recursive.stack.capacity(), DEFAULT_STACK_SIZE);
+        // So does bytecode that does not decode, which fails once run
+        let mut corrupt = Vm::builder(vec![0xFF]).build();
+        assert_eq!(corrupt.stack.capacity(), DEFAULT_STACK_SIZE);
+        assert_eq!(corrupt.run(), Err(RuntimeError::InvalidOpcode(0xFF)));
+
+        // The default stack only allocates what deep recursion actually uses
+        let deep = compile("fn f(n) { n < 1 ? 0 : f(n - 1) + 1 } f(1000)").unwrap();
+        let mut deep = Vm::builder(deep).build();
+        assert_eq!(deep.run(), Ok(Value::Int(1000)));
+        assert!(deep.stack.allocated() < DEFAULT_STACK_SIZE);
+
+        let mut overflowing = Vm::builder(compile("9223372036854775807 + 1").unwrap())
+            .checked_arithmetic(true)
+            .build();
+        assert!(matches!(
+            overflowing.run(),
+            Err(RuntimeError::Overflow { .. })
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let program = compile("fn f(x) { x * 2 } f(3) + 1").unwrap();
+        let mut vm = Vm::new(program.clone(), 8);
+        vm.start(&[]).unwrap();
+        for _ in 0..4 {
+            assert_eq!(vm.step(), Ok(StepResult::Continue));
+        }
+        let snapshot = vm.snapshot();
+        assert_eq!(snapshot.ip(), vm.ip());
+        assert_eq!(
+            snapshot.stack(),
+            &[Value::Int(3), Value::Int(3), Value::Int(2)]
+        );
+        assert_eq!(snapshot.call_depth(), 1);
+        assert_eq!(vm.resume(), Ok(Value::Int(7)));
+        assert_eq!(vm.snapshot().ip(), None);
+
+        let bytes = snapshot.to_bytes();
+        let restored = VmSnapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(restored, snapshot);
+        let mut other = Vm::new(program, 8);
+        other.restore(&restored).unwrap();
+        assert_eq!(other.resume(), Ok(Value::Int(7)));
+
+        // Restoring rewinds the VM it was taken from as well
+        vm.restore(&snapshot).unwrap();
+        assert_eq!(vm.stack_slice(), snapshot.stack());
+        assert_eq!(vm.resume(), Ok(Value::Int(7)));
+
+        assert_eq!(
+            VmSnapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(DecodeError::Truncated)
+        );
+        assert_eq!(
+            Vm::new(compile("1").unwrap(), 2).restore(&snapshot),
+            Err(RuntimeError::StackOverflow)
+        );
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let input = r#"fn f(s, n) { n > 0 ? f(s + s, n - 1) : len(s) } f("ab", 9)"#;
+        let mut vm = Vm::new(compile(input).unwrap(), 64);
+        assert_eq!(vm.run(), Ok(Value::Int(1024)));
+        assert_eq!(vm.memory_used(), 0);
+
+        let mut vm = Vm::new(compile(input).unwrap(), 64).with_memory_limit(1000);
+        assert_eq!(vm.run(), Err(RuntimeError::OutOfMemory));
+        // Tail calls drop the arguments of the caller, the strings on the stack are the
+        // arguments of the innermost call and their copies
+        assert!(vm.memory_used() <= 1000);
+
+        let mut vm = Vm::builder(compile(input).unwrap())
+            .stack_size(64)
+            .memory_limit(4096)
+            .build();
+        assert_eq!(vm.run(), Ok(Value::Int(1024)));
+        assert_eq!(
+            vm.run_with_args(&[Value::from("x".repeat(4097))]),
+            Err(RuntimeError::OutOfMemory)
+        );
+    }
+
+    #[test]
+    fn test_load_and_reset() {
+        let mut vm = Vm::new(compile("1 + 2").unwrap(), 10).with_fuel(100);
+        assert_eq!(vm.run(), Ok(Value::Int(3)));
+
+        vm.load(compile_unit(&[("g", "a * b")]).unwrap());
+        let env = HashMap::from([
+            ("a".to_string(), Value::Int(6)),
+            ("b".to_string(), Value::Int(7)),
+        ]);
+        assert_eq!(vm.run_entry("g", &env), Ok(Value::Int(42)));
+
+        vm.load(compile("(1 + 2) * 3").unwrap());
+        vm.start(&[]).unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.stack_slice(), &[Value::Int(1)]);
+        vm.reset();
+        assert_eq!((vm.ip(), vm.stack_slice()), (None, &[][..]));
+        assert_eq!(vm.resume(), Err(RuntimeError::NotSuspended));
+        assert_eq!(vm.fuel(), Some(91));
+        assert_eq!(vm.run(), Ok(Value::Int(9)));
+    }
+
+    #[test]
+    fn test_swap_program() {
+        let params = vec!["x".to_string()];
+        let double = vec![
+            Opcode::LoadArg as u8,
+            0,
+            Opcode::LoadArg as u8,
+            0,
+            Opcode::Addition as u8,
+            Opcode::Return as u8,
+        ];
+        let square = vec![
+            Opcode::LoadArg as u8,
+            0,
+            Opcode::LoadArg as u8,
+            0,
+            Opcode::Multiply as u8,
+            Opcode::Return as u8,
+        ];
+        let env = HashMap::from([("x".to_string(), Value::Int(5))]);
+
+        let entries = vec![Entry::new("f", 0, params.clone())];
+        let mut vm = Vm::new(Program::with_entries(double, entries.clone()), 10);
+        assert_eq!(vm.run_entry("f", &env), Ok(Value::Int(10)));
+
+        vm.swap_program(&Program::with_entries(square.clone(), entries))
+            .unwrap();
+        assert_eq!(vm.run_entry("f", &env), Ok(Value::Int(25)));
+
+        let renamed = vec![Entry::new("g", 0, params)];
+        let result = vm.swap_program(&Program::with_entries(square.clone(), renamed));
+        assert_eq!(result, Err(RuntimeError::IncompatibleProgram));
+
+        let reordered = vec![Entry::new("f", 0, vec!["y".to_string()])];
+        let result = vm.swap_program(&Program::with_entries(square, reordered));
+        assert_eq!(result, Err(RuntimeError::IncompatibleProgram));
+        assert_eq!(vm.run_entry("f", &env), Ok(Value::Int(25)));
+    }
+    #[test]
+    fn test_host_functions() {
+        let options = CompileOptions::new()
+            .params(&["x"])
+            .host_function("price", 1)
+            .host_function("fail", 0);
+        let program = compile_program("price(x + 1) * 2", &options).unwrap();
+        let mut vm = Vm::new(program, 10);
+        let args = [Value::Int(2)];
+        assert_eq!(
+            vm.run_with_args(&args),
+            Err(RuntimeError::UnknownHostFunction("price".to_string()))
+        );
+
+        vm.register_fn("price", 1, |args: &[Value]| match args {
+            [Value::Int(n)] => Ok(Value::Float(*n as f64 * 1.5)),
+            _ => Err("expected an integer"),
+        });
+        assert_eq!(vm.run_with_args(&args), Ok(Value::Float(9.0)));
+        // Through the dispatch loop, which a hook selects over the fused code
+        vm.set_hook(|_, _| {});
+        assert_eq!(vm.run_with_args(&args), Ok(Value::Float(9.0)));
+        assert_eq!(
+            vm.run_with_args(&[Value::from("a")]),
+            Err(RuntimeError::Host {
+                name: "price".to_string(),
+                message: "expected an integer".to_string()
+            })
+        );
+
+        vm.register_fn("price", 2, |_: &[Value]| Ok::<_, String>(Value::Int(0)));
+        assert_eq!(
+            vm.run_with_args(&args),
+            Err(RuntimeError::HostArity {
+                name: "price".to_string(),
+                expected: 2,
+                got: 1
+            })
+        );
+
+        // Registered functions stay across programs
+        vm.register_fn("fail", 0, |_: &[Value]| Err("no"));
+        vm.load(compile_program("fail()", &options.clone().params(&[])).unwrap());
+        vm.clear_hooks();
+        assert_eq!(
+            vm.run(),
+            Err(RuntimeError::Host {
+                name: "fail".to_string(),
+                message: "no".to_string()
+            })
+        );
+    }
+
+    #[rstest]
+    #[case("f(1) * (2 + g(2))")]
+    #[case("f(1) == (g(2) + 1) * 2")]
+    #[case("fn h(x) { g(x) } f(1) + (2 + h(2))")]
+    fn test_host_call_order(
+        #[case] input: &str,
+        #[values(OptLevel::Basic, OptLevel::Speed)] level: OptLevel,
+    ) {
+        let options = CompileOptions::new()
+            .opt_level(level)
+            .host_function("f", 1)
+            .host_function("g", 1);
+        let mut vm = Vm::new(compile_program(input, &options).unwrap(), 16);
+        let calls = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        for name in ["f", "g"] {
+            let calls = calls.clone();
+            vm.register_fn(name, 1, move |args: &[Value]| {
+                calls.lock().unwrap().push(name);
+                Ok::<_, String>(args[0].clone())
+            });
+        }
+        vm.run().unwrap();
+        assert_eq!(*calls.lock().unwrap(), ["f", "g"]);
+    }
+
+    #[test]
+    fn test_run_with() {
+        let options = CompileOptions::new().free_variables(true);
+        let program = compile_program("qty * price > limit", &options).unwrap();
+        let mut vm = Vm::new(program, 10);
+        let rows = [(2, 3.5, 5, true), (1, 3.5, 5, false), (4, 1.0, 4, false)];
+        for (qty, price, limit, expected) in rows {
+            let env = HashMap::from([
+                ("qty".to_string(), Value::Int(qty)),
+                ("price".to_string(), Value::Float(price)),
+                ("limit".to_string(), Value::Int(limit)),
+            ]);
+            assert_eq!(vm.run_with(&env), Ok(Value::Bool(expected)));
+        }
+        assert_eq!(
+            vm.run_with(&HashMap::new()),
+            Err(RuntimeError::MissingArgument("qty".to_string()))
+        );
+
+        let mut vm = Vm::new(compile("1 + 2").unwrap(), 10);
+        assert_eq!(vm.run_with(&HashMap::new()), Ok(Value::Int(3)));
+        let env = HashMap::from([("qty".to_string(), Value::Int(1))]);
+        assert_eq!(
+            vm.run_with(&env),
+            Err(RuntimeError::UnknownEntry("main".to_string()))
+        );
+    }
 }
@@ -0,0 +1,480 @@
+use std::collections::HashSet;
+
+use super::{apply_binary, apply_unary, host_call, FactorialOverflow, HostSlot, MAX_CALL_DEPTH};
+use crate::{
+    error::RuntimeError, instruction::Instruction, opcode::Opcode, stack::Stack, value::Value,
+};
+
+// Instructions decoded ahead of running, with jump and call targets resolved to indices
+// and the most common pairs merged into one operation
+#[derive(Debug, Clone, PartialEq)]
+enum Op {
+    Literal(Value),
+    LoadArg(usize),
+    Unary(Opcode),
+    Binary(Opcode),
+    Call { target: usize, argc: usize },
+    TailCall { target: usize, argc: usize },
+    CallHost { id: usize, argc: usize },
+    Jump(usize),
+    JumpIfFalse(usize),
+    Return,
+    // `literal` followed by a binary operation, like `x * 2`
+    LiteralBinary(Value, Opcode),
+    // `load_arg` followed by a binary operation, like `a + b`
+    LoadArgBinary(usize, Opcode),
+    // A comparison or other binary operation followed by `jump_if_false`, like `n < 2 ? ..`
+    BinaryJumpIfFalse(Opcode, usize),
+    // Bytes that do not decode, failing only once reached like they do undecoded
+    Invalid(RuntimeError),
+}
+
+// Operand types a binary operation has seen so far. This inline cache lets a site that only
+// ever sees integers, or only floats, skip the generic dispatch on `Value`. A site that
+// sees anything else falls back to the generic path for good.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shape {
+    Unseen,
+    Ints,
+    Floats,
+    Mixed,
+}
+
+impl Shape {
+    fn observe(self, lhs: &Value, rhs: &Value) -> Shape {
+        let seen = match (lhs, rhs) {
+            (Value::Int(_), Value::Int(_)) => Shape::Ints,
+            (Value::Float(_), Value::Float(_)) => Shape::Floats,
+            _ => Shape::Mixed,
+        };
+        match self {
+            Shape::Unseen => seen,
+            shape if shape == seen => shape,
+            _ => Shape::Mixed,
+        }
+    }
+}
+
+// Bytecode pre-decoded by `fuse`, run with `FusedCode::run`. Each operation keeps the
+// addresses of the first and last instruction it was decoded from, for fault reporting,
+// and the inline cache of its binary operation if it has one.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct FusedCode {
+    ops: Vec<Op>,
+    addresses: Vec<(usize, usize)>,
+    shapes: Vec<Shape>,
+}
+
+// Pre-decode `code` whose runs may start at any of `entries`. Pairs are only merged when
+// no jump, call or entry point lands on their second instruction. Returns `None` when a
+// jump lands inside an instruction or past malformed bytes, which only the byte at a time
+// dispatch can follow.
+pub(super) fn fuse(code: &[u8], entries: &[usize]) -> Option<FusedCode> {
+    let mut instructions = Vec::new();
+    let mut position = 0;
+    while position < code.len() {
+        match Instruction::try_decode(code, position) {
+            Ok((instruction, size)) => {
+                instructions.push((position, Ok(instruction)));
+                position += size;
+            }
+            Err(e) => {
+                instructions.push((position, Err(RuntimeError::from(e))));
+                break;
+            }
+        }
+    }
+
+    let mut targets: HashSet<usize> = entries.iter().copied().collect();
+    for (_, instruction) in &instructions {
+        match instruction {
+            Ok(Instruction::Jump(address))
+            | Ok(Instruction::JumpIfFalse(address))
+            | Ok(Instruction::Call { address, .. })
+            | Ok(Instruction::TailCall { address, .. }) => targets.insert(*address),
+            _ => continue,
+        };
+    }
+
+    let mut ops = Vec::with_capacity(instructions.len());
+    let mut addresses = Vec::with_capacity(instructions.len());
+    let mut i = 0;
+    while i < instructions.len() {
+        let (address, first) = &instructions[i];
+        let second = instructions
+            .get(i + 1)
+            .filter(|(next, _)| !targets.contains(next));
+        let (op, last) = match (first, second) {
+            (Ok(first), Some((next, Ok(second)))) => match fuse_pair(first, second) {
+                Some(op) => (op, *next),
+                None => (single(first), *address),
+            },
+            (Ok(first), _) => (single(first), *address),
+            (Err(e), _) => (Op::Invalid(e.clone()), *address),
+        };
+        i += if last == *address { 1 } else { 2 };
+        ops.push(op);
+        addresses.push((*address, last));
+    }
+
+    // Resolve targets to indices, jumps to the end of the code or beyond end the run
+    let end = code.len();
+    let index = |target: usize| match addresses.binary_search_by_key(&target, |&(a, _)| a) {
+        Ok(index) => Some(index),
+        Err(_) if target >= end => Some(ops.len()),
+        Err(_) => None,
+    };
+    let mut resolved = Vec::with_capacity(ops.len());
+    for op in &ops {
+        resolved.push(match *op {
+            Op::Jump(target) => Op::Jump(index(target)?),
+            Op::JumpIfFalse(target) => Op::JumpIfFalse(index(target)?),
+            Op::BinaryJumpIfFalse(op, target) => Op::BinaryJumpIfFalse(op, index(target)?),
+            Op::Call { target, argc } => Op::Call {
+                target: index(target)?,
+                argc,
+            },
+            Op::TailCall { target, argc } => Op::TailCall {
+                target: index(target)?,
+                argc,
+            },
+            ref op => op.clone(),
+        });
+    }
+    Some(FusedCode {
+        shapes: vec![Shape::Unseen; resolved.len()],
+        ops: resolved,
+        addresses,
+    })
+}
+
+fn single(instruction: &Instruction) -> Op {
+    match *instruction {
+        Instruction::Literal(ref value) => Op::Literal(value.clone()),
+        Instruction::LoadArg(index) => Op::LoadArg(index),
+        Instruction::Call { address, argc } => Op::Call {
+            target: address,
+            argc,
+        },
+        Instruction::TailCall { address, argc } => Op::TailCall {
+            target: address,
+            argc,
+        },
+        Instruction::CallHost { id, argc } => Op::CallHost { id, argc },
+        Instruction::Jump(address) => Op::Jump(address),
+        Instruction::JumpIfFalse(address) => Op::JumpIfFalse(address),
+        Instruction::Return => Op::Return,
+        ref instruction if instruction.stack_effect().0 == 1 => Op::Unary(instruction.opcode()),
+        ref instruction => Op::Binary(instruction.opcode()),
+    }
+}
+
+fn fuse_pair(first: &Instruction, second: &Instruction) -> Option<Op> {
+    let binary = |instruction: &Instruction| match instruction {
+        Instruction::Call { .. }
+        | Instruction::TailCall { .. }
+        | Instruction::CallHost { .. }
+        | Instruction::JumpIfFalse(_) => None,
+        instruction if instruction.stack_effect() == (2, 1) => Some(instruction.opcode()),
+        _ => None,
+    };
+    let op = match (first, second) {
+        (Instruction::Literal(value), second) => Op::LiteralBinary(value.clone(), binary(second)?),
+        (Instruction::LoadArg(index), second) => Op::LoadArgBinary(*index, binary(second)?),
+        (first, Instruction::JumpIfFalse(address)) => {
+            Op::BinaryJumpIfFalse(binary(first)?, *address)
+        }
+        _ => return None,
+    };
+    Some(op)
+}
+
+impl FusedCode {
+    // Index of the operation starting at `address`, `None` within a merged pair
+    pub(super) fn index_of(&self, address: usize) -> Option<usize> {
+        self.addresses
+            .binary_search_by_key(&address, |&(first, _)| first)
+            .ok()
+    }
+
+    // Run from the operation at `start` until the outermost return, failing with the
+    // address of the instruction at fault. Behaves exactly like the byte at a time
+    // dispatch, calls in progress are tracked on the side and dropped at the end.
+    pub(super) fn run(
+        &mut self,
+        start: usize,
+        stack: &mut Stack,
+        hosts: &[HostSlot],
+        checked: bool,
+        overflow: FactorialOverflow,
+    ) -> Result<Option<Value>, (RuntimeError, usize)> {
+        let mut frames: Vec<(usize, usize)> = Vec::new();
+        let mut pc = start;
+        while let Some(op) = self.ops.get(pc) {
+            let (first, last) = self.addresses[pc];
+            let shape = &mut self.shapes[pc];
+            pc += 1;
+            match op {
+                Op::Literal(value) => stack.push(value.clone()).map_err(at(first))?,
+                Op::LoadArg(index) => load_arg(stack, *index).map_err(at(first))?,
+                Op::Unary(op) => {
+                    let value = stack.pop().map_err(at(first))?;
+                    let result = apply_unary(*op, value, checked, overflow).map_err(at(first))?;
+                    stack.push(result).map_err(at(first))?;
+                }
+                Op::Binary(op) => binary(stack, *op, None, checked, shape).map_err(at(first))?,
+                Op::LiteralBinary(value, op) => {
+                    if stack.is_full() {
+                        return Err((RuntimeError::StackOverflow, first));
+                    }
+                    stack.record_depth(stack.len() + 1);
+                    binary(stack, *op, Some(value.clone()), checked, shape).map_err(at(last))?
+                }
+                Op::LoadArgBinary(index, op) => {
+                    load_arg(stack, *index).map_err(at(first))?;
+                    binary(stack, *op, None, checked, shape).map_err(at(last))?
+                }
+                Op::BinaryJumpIfFalse(op, target) => {
+                    binary(stack, *op, None, checked, shape).map_err(at(first))?;
+                    let value = stack.pop_unchecked();
+                    if !value.is_truthy(Opcode::JumpIfFalse).map_err(at(last))? {
+                        pc = *target;
+                    }
+                }
+                // Like the `tail_call` handler, a plain call from the main expression
+                Op::TailCall { target, argc } if !frames.is_empty() => {
+                    let base = stack.base();
+                    if base + argc > stack.len() {
+                        return Err((RuntimeError::StackUnderflow, first));
+                    }
+                    stack.squash(base, *argc);
+                    pc = *target;
+                }
+                Op::Call { target, argc } | Op::TailCall { target, argc } => {
+                    if frames.len() >= MAX_CALL_DEPTH {
+                        return Err((RuntimeError::CallStackOverflow, first));
+                    }
+                    let caller = stack.base();
+                    stack.push_frame(*argc).map_err(at(first))?;
+                    frames.push((pc, caller));
+                    pc = *target;
+                }
+                Op::CallHost { id, argc } => {
+                    host_call(hosts, *id, *argc, stack).map_err(at(first))?
+                }
+                Op::Jump(target) => pc = *target,
+                Op::JumpIfFalse(target) => {
+                    let value = stack.pop().map_err(at(first))?;
+                    if !value.is_truthy(Opcode::JumpIfFalse).map_err(at(first))? {
+                        pc = *target;
+                    }
+                }
+                Op::Return => {
+                    let value = stack.pop().map_err(at(first))?;
+                    match frames.pop() {
+                        Some((return_index, caller)) => {
+                            stack.pop_frame(caller);
+                            stack.push(value).map_err(at(first))?;
+                            pc = return_index;
+                        }
+                        None => return Ok(Some(value)),
+                    }
+                }
+                Op::Invalid(e) => return Err((e.clone(), first)),
+            }
+        }
+        Ok(None)
+    }
+}
+
+// Attach `address` to errors of the operation decoded from there
+fn at<E: Into<RuntimeError>>(address: usize) -> impl Fn(E) -> (RuntimeError, usize) {
+    move |e| (e.into(), address)
+}
+
+fn load_arg(stack: &mut Stack, index: usize) -> Result<(), RuntimeError> {
+    let value = stack.local(index)?;
+    Ok(stack.push(value)?)
+}
+
+// Apply `op` to the top two values of the stack, or to the top one and `rhs`, through the
+// specialized path `shape` has settled on when the operands still match it
+fn binary(
+    stack: &mut Stack,
+    op: Opcode,
+    rhs: Option<Value>,
+    checked: bool,
+    shape: &mut Shape,
+) -> Result<(), RuntimeError> {
+    let rhs = match rhs {
+        Some(rhs) => rhs,
+        None => stack.pop()?,
+    };
+    let lhs = stack.pop()?;
+    let specialized = match (*shape, &lhs, &rhs) {
+        (Shape::Ints, &Value::Int(a), &Value::Int(b)) => ints(op, a, b, checked),
+        (Shape::Floats, &Value::Float(a), &Value::Float(b)) => floats(op, a, b),
+        _ => {
+            *shape = shape.observe(&lhs, &rhs);
+            None
+        }
+    };
+    let value = match specialized {
+        Some(value) => value,
+        None => apply_binary(op, lhs, rhs, checked)?,
+    };
+    Ok(stack.push(value)?)
+}
+
+// Integer arithmetic and comparisons, `None` for the other operations and for checked
+// arithmetic that overflows, which the generic path reports
+#[inline]
+fn ints(op: Opcode, a: i64, b: i64, checked: bool) -> Option<Value> {
+    let value = match op {
+        Opcode::Addition if checked => Value::Int(a.checked_add(b)?),
+        Opcode::Subtract if checked => Value::Int(a.checked_sub(b)?),
+        Opcode::Multiply if checked => Value::Int(a.checked_mul(b)?),
+        Opcode::Addition => Value::Int(a.wrapping_add(b)),
+        Opcode::Subtract => Value::Int(a.wrapping_sub(b)),
+        Opcode::Multiply => Value::Int(a.wrapping_mul(b)),
+        Opcode::Less => Value::Bool(a < b),
+        Opcode::LessEqual => Value::Bool(a <= b),
+        Opcode::Greater => Value::Bool(a > b),
+        Opcode::GreaterEqual => Value::Bool(a >= b),
+        Opcode::Equal => Value::Bool(a == b),
+        Opcode::NotEqual => Value::Bool(a != b),
+        _ => return None,
+    };
+    Some(value)
+}
+
+// Float arithmetic and comparisons, where NaN compares false like in `Value::compare`
+#[inline]
+fn floats(op: Opcode, a: f64, b: f64) -> Option<Value> {
+    let value = match op {
+        Opcode::Addition => Value::Float(a + b),
+        Opcode::Subtract => Value::Float(a - b),
+        Opcode::Multiply => Value::Float(a * b),
+        Opcode::Less => Value::Bool(a < b),
+        Opcode::LessEqual => Value::Bool(a <= b),
+        Opcode::Greater => Value::Bool(a > b),
+        Opcode::GreaterEqual => Value::Bool(a >= b),
+        Opcode::Equal => Value::Bool(a == b),
+        Opcode::NotEqual => Value::Bool(a != b),
+        _ => return None,
+    };
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::compile, vm::Vm};
+    use rstest::rstest;
+
+    #[test]
+    fn test_fuse() {
+        let chunk = compile("fn f(n) { n < 2 ? 1 : n * f(n - 1) } f(5) + 1").unwrap();
+        let fused = fuse(chunk.code(), &[0]).unwrap();
+        assert_eq!(fused.ops.len(), chunk.instructions().count() - 3);
+        assert_eq!(
+            fused.ops[2],
+            Op::LiteralBinary(Value::Int(1), Opcode::Addition)
+        );
+        assert_eq!(fused.ops[5], Op::LiteralBinary(Value::Int(2), Opcode::Less));
+        assert_eq!(fused.ops[1], Op::Call { target: 4, argc: 1 });
+
+        let chunk = compile("fn f(a, b) { a * 2 < b * 3 ? 1 : 0 } f(1, 2)").unwrap();
+        let fused = fuse(chunk.code(), &[0]).unwrap();
+        assert!(fused
+            .ops
+            .iter()
+            .any(|op| matches!(op, Op::BinaryJumpIfFalse(Opcode::Less, _))));
+        // Addresses map back to the start of every operation
+        assert_eq!(fused.index_of(0), Some(0));
+        assert_eq!(fused.index_of(1), None);
+    }
+
+    #[rstest]
+    #[case("fn f(n) { n < 2 ? 1 : n * f(n - 1) } f(10)", 32)]
+    #[case("fn f(a, b) { a * 2 < b * 3 ? a / 0 : 0 } f(1, 2)", 32)]
+    #[case("1 + 2 * 3 % 0", 32)]
+    #[case("1 + 2 + 3", 1)]
+    #[case("fn f(x) { x + 1 } f(f(1))", 2)]
+    #[case("fn f(n) { f(n) + 1 } f(1)", 4096)]
+    #[case("fn f(n, acc) { n < 1 ? acc : f(n - 1, acc + n) } f(5000, 0)", 8)]
+    #[case("fn f(a, b) { a - b } fn g(n) { f(n * 2, 1) } g(3)", 4)]
+    #[case(r#"fn f(x) { x - 1 } f(2) + f("a")"#, 8)]
+    #[case("fn f(x) { x < 2 } f(1) < 3", 8)]
+    #[case(r#"fn f(x) { x ? 1 : 0 } f("a")"#, 8)]
+    fn test_matches_dispatch(#[case] input: &str, #[case] stack_size: usize) {
+        let chunk = compile(input).unwrap();
+        let mut fused = Vm::new(chunk.clone(), stack_size);
+        // A hook keeps the VM on the byte at a time dispatch
+        let mut dispatched = Vm::new(chunk, stack_size);
+        dispatched.set_hook(|_, _| {});
+        assert_eq!(fused.run(), dispatched.run());
+        assert_eq!(fused.fault_address(), dispatched.fault_address());
+    }
+
+    #[test]
+    fn test_inline_caches() {
+        let chunk = compile("fn f(x) { x * 2 < 10 } f(1) && f(2)").unwrap();
+        let mut fused = fuse(chunk.code(), &[0]).unwrap();
+        let mut stack = Stack::new(8);
+        let result = fused.run(0, &mut stack, &[], false, FactorialOverflow::Error);
+        assert_eq!(result, Ok(Some(Value::Bool(true))));
+        let seen: Vec<Shape> = fused
+            .shapes
+            .iter()
+            .copied()
+            .filter(|&shape| shape != Shape::Unseen)
+            .collect();
+        // Booleans are left to the generic path
+        assert_eq!(seen, [Shape::Mixed, Shape::Ints, Shape::Ints]);
+
+        // A site seeing another type gives up on specializing, with the same results
+        let mut stack = Stack::new(8);
+        let result = fused.run(0, &mut stack, &[], false, FactorialOverflow::Error);
+        assert_eq!(result, Ok(Some(Value::Bool(true))));
+        let chunk = compile("fn f(x) { x * 2 } f(1) + f(1.5) + f(2.5)").unwrap();
+        let mut fused = fuse(chunk.code(), &[0]).unwrap();
+        let result = fused.run(0, &mut stack, &[], false, FactorialOverflow::Error);
+        assert_eq!(result, Ok(Some(Value::Float(10.0))));
+        assert!(fused.shapes.contains(&Shape::Mixed));
+    }
+
+    #[rstest]
+    #[case(Shape::Unseen, Value::Int(1), Value::Int(2), Shape::Ints)]
+    #[case(Shape::Unseen, Value::Float(1.0), Value::Float(2.0), Shape::Floats)]
+    #[case(Shape::Unseen, Value::Int(1), Value::Float(2.0), Shape::Mixed)]
+    #[case(Shape::Ints, Value::Int(1), Value::Int(2), Shape::Ints)]
+    #[case(Shape::Ints, Value::Float(1.0), Value::Float(2.0), Shape::Mixed)]
+    #[case(Shape::Mixed, Value::Int(1), Value::Int(2), Shape::Mixed)]
+    fn test_observe(
+        #[case] shape: Shape,
+        #[case] lhs: Value,
+        #[case] rhs: Value,
+        #[case] expected: Shape,
+    ) {
+        assert_eq!(shape.observe(&lhs, &rhs), expected);
+    }
+
+    #[test]
+    fn test_targets_are_not_merged() {
+        // `literal 1; jump_if_false 25; literal 2; add` with the add jumped to
+        let mut code = vec![Opcode::Literal as u8];
+        code.extend(Value::Int(1).to_vec());
+        code.extend([Opcode::JumpIfFalse as u8, 0, 0, 0, 25]);
+        code.push(Opcode::Literal as u8);
+        code.extend(Value::Int(2).to_vec());
+        code.push(Opcode::Addition as u8);
+        let fused = fuse(&code, &[0]).unwrap();
+        assert_eq!(fused.ops[2], Op::Literal(Value::Int(2)));
+        assert_eq!(fused.ops[3], Op::Binary(Opcode::Addition));
+
+        // Jumping into the middle of an instruction cannot be followed
+        code.extend([Opcode::Jump as u8, 0, 0, 0, 3]);
+        assert_eq!(fuse(&code, &[0]), None);
+    }
+}
@@ -0,0 +1,530 @@
+use std::collections::{HashMap, HashSet};
+
+use super::{apply_binary, apply_unary, FactorialOverflow, MAX_CALL_DEPTH};
+use crate::{
+    error::RuntimeError,
+    instruction::Instruction,
+    opcode::Opcode,
+    program::{Entry, Program},
+    value::Value,
+};
+
+// Instruction set of the register machine. Registers are numbered from the base of the
+// frame of the running function, whose arguments are its first registers.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegInstruction {
+    Load {
+        dst: usize,
+        value: Value,
+    },
+    Move {
+        dst: usize,
+        src: usize,
+    },
+    Unary {
+        op: Opcode,
+        dst: usize,
+        src: usize,
+    },
+    Binary {
+        op: Opcode,
+        dst: usize,
+        lhs: usize,
+        rhs: usize,
+    },
+    Jump(usize),
+    JumpIfFalse {
+        cond: usize,
+        target: usize,
+    },
+    // Call the function at `target` with the `argc` registers from `base` as its arguments
+    // and its result replacing them, `frame` is the number of registers the callee uses
+    Call {
+        target: usize,
+        base: usize,
+        argc: usize,
+        frame: usize,
+    },
+    // Like `Call`, moving the arguments to the start of the running frame and returning
+    // straight to its caller. From the main expression it is a plain call.
+    TailCall {
+        target: usize,
+        base: usize,
+        argc: usize,
+        frame: usize,
+    },
+    Return(usize),
+}
+
+// Register code lowered from the bytecode reachable from an entry point
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lowered {
+    code: Vec<RegInstruction>,
+    // Index of the instruction the run starts at and the registers of its frame
+    start: usize,
+    frame: usize,
+}
+
+impl Lowered {
+    pub fn code(&self) -> &[RegInstruction] {
+        &self.code
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+}
+
+// Lower the stack bytecode run from `start` with `args` values already pushed. The depth of
+// the stack is known before every instruction, so stack slot `n` of a frame becomes its
+// register `n`. Code reaching an instruction at two different depths is rejected, as is code
+// popping more than it pushed and code calling host functions, which only `Vm` runs.
+pub fn lower(bytecode: &[u8], start: usize, args: usize) -> Result<Lowered, RuntimeError> {
+    let mut instructions = Vec::new();
+    let mut position = 0;
+    while position < bytecode.len() {
+        let (instruction, size) = Instruction::try_decode(bytecode, position)?;
+        instructions.push((position, instruction));
+        position += size;
+    }
+    let index: HashMap<usize, usize> = instructions
+        .iter()
+        .enumerate()
+        .map(|(i, (position, _))| (*position, i))
+        .collect();
+
+    // Depth before every reachable instruction and the frame size of every function
+    let mut depths: HashMap<usize, usize> = HashMap::new();
+    let mut frames: HashMap<usize, usize> = HashMap::new();
+    let mut functions = vec![(start, args)];
+    let mut seen = HashSet::new();
+    while let Some((function, argc)) = functions.pop() {
+        if !seen.insert(function) {
+            continue;
+        }
+        let mut frame = argc;
+        let mut pending = vec![(function, argc)];
+        while let Some((position, depth)) = pending.pop() {
+            let Some(&i) = index.get(&position) else {
+                continue;
+            };
+            if let Some(&known) = depths.get(&position) {
+                if known != depth {
+                    return Err(RuntimeError::UnbalancedStack(position));
+                }
+                continue;
+            }
+            depths.insert(position, depth);
+
+            let instruction = &instructions[i].1;
+            let (pops, pushes) = instruction.stack_effect();
+            if depth < pops {
+                return Err(RuntimeError::StackUnderflow);
+            }
+            let next = position + instruction.size();
+            match *instruction {
+                Instruction::Return => {}
+                Instruction::Jump(address) => pending.push((address, depth)),
+                Instruction::JumpIfFalse(address) => {
+                    pending.push((address, depth - 1));
+                    pending.push((next, depth - 1));
+                }
+                Instruction::Call { address, argc } | Instruction::TailCall { address, argc } => {
+                    functions.push((address, argc));
+                    pending.push((next, depth - argc + 1));
+                }
+                Instruction::LoadArg(slot) if slot >= depth => {
+                    return Err(RuntimeError::StackUnderflow)
+                }
+                Instruction::CallHost { .. } => {
+                    return Err(RuntimeError::InvalidOpcode(Opcode::CallHost as u8))
+                }
+                _ => {
+                    frame = frame.max(depth - pops + pushes);
+                    pending.push((next, depth - pops + pushes));
+                }
+            }
+        }
+        frames.insert(function, frame);
+    }
+
+    // Reachable instructions keep their order, so falling through to the next one still
+    // reaches the same code
+    let reachable: Vec<&(usize, Instruction)> = instructions
+        .iter()
+        .filter(|(position, _)| depths.contains_key(position))
+        .collect();
+    let targets: HashMap<usize, usize> = reachable
+        .iter()
+        .enumerate()
+        .map(|(i, (position, _))| (*position, i))
+        .collect();
+    // Jumps past the reachable code end the run like running off the end of the bytecode
+    let end = reachable.len();
+    let target = |address: usize| targets.get(&address).copied().unwrap_or(end);
+
+    let mut code = Vec::with_capacity(reachable.len());
+    for (position, instruction) in reachable {
+        let depth = depths[position];
+        let lowered = match *instruction {
+            Instruction::Literal(ref value) => RegInstruction::Load {
+                dst: depth,
+                value: value.clone(),
+            },
+            Instruction::LoadArg(slot) => RegInstruction::Move {
+                dst: depth,
+                src: slot,
+            },
+            Instruction::Jump(address) => RegInstruction::Jump(target(address)),
+            Instruction::JumpIfFalse(address) => RegInstruction::JumpIfFalse {
+                cond: depth - 1,
+                target: target(address),
+            },
+            Instruction::Call { address, argc } => RegInstruction::Call {
+                target: target(address),
+                base: depth - argc,
+                argc,
+                frame: frames[&address],
+            },
+            Instruction::TailCall { address, argc } => RegInstruction::TailCall {
+                target: target(address),
+                base: depth - argc,
+                argc,
+                frame: frames[&address],
+            },
+            Instruction::Return => RegInstruction::Return(depth - 1),
+            ref instruction => match instruction.stack_effect() {
+                (1, _) => RegInstruction::Unary {
+                    op: instruction.opcode(),
+                    dst: depth - 1,
+                    src: depth - 1,
+                },
+                _ => RegInstruction::Binary {
+                    op: instruction.opcode(),
+                    dst: depth - 2,
+                    lhs: depth - 2,
+                    rhs: depth - 1,
+                },
+            },
+        };
+        code.push(lowered);
+    }
+
+    Ok(Lowered {
+        code,
+        start: target(start),
+        frame: frames[&start],
+    })
+}
+
+// Alternative to `Vm` executing register code lowered from the stack bytecode, see `lower`.
+// Each entry point is lowered on its first run and the result kept for the next ones.
+pub struct RegisterVm {
+    bytecode: Vec<u8>,
+    entries: Vec<Entry>,
+    lowered: HashMap<(usize, usize), Lowered>,
+    registers: Vec<Value>,
+    checked: bool,
+    factorial_overflow: FactorialOverflow,
+}
+
+impl RegisterVm {
+    pub fn new<P>(program: P) -> RegisterVm
+    where
+        P: Into<Program>,
+    {
+        let (chunk, entries) = program.into().into_parts();
+        RegisterVm {
+            bytecode: chunk.into_code(),
+            entries,
+            lowered: HashMap::new(),
+            registers: Vec::new(),
+            checked: false,
+            factorial_overflow: FactorialOverflow::default(),
+        }
+    }
+
+    // See `Vm::checked_arithmetic`
+    pub fn checked_arithmetic(mut self, enabled: bool) -> RegisterVm {
+        self.checked = enabled;
+        self
+    }
+
+    pub fn factorial_overflow(mut self, overflow: FactorialOverflow) -> RegisterVm {
+        self.factorial_overflow = overflow;
+        self
+    }
+
+    pub fn run(&mut self) -> Result<Value, RuntimeError> {
+        self.execute(0, Vec::new())
+    }
+
+    pub fn run_with_args(&mut self, args: &[Value]) -> Result<Value, RuntimeError> {
+        self.execute(0, args.to_vec())
+    }
+
+    pub fn run_entry(
+        &mut self,
+        name: &str,
+        env: &HashMap<String, Value>,
+    ) -> Result<Value, RuntimeError> {
+        let entry = self
+            .entries
+            .iter()
+            .find(|entry| entry.name() == name)
+            .ok_or_else(|| RuntimeError::UnknownEntry(name.to_string()))?;
+        let args = entry
+            .params()
+            .iter()
+            .map(|param| {
+                env.get(param)
+                    .cloned()
+                    .ok_or_else(|| RuntimeError::MissingArgument(param.clone()))
+            })
+            .collect::<Result<Vec<Value>, RuntimeError>>()?;
+        self.execute(entry.address(), args)
+    }
+
+    fn execute(&mut self, start: usize, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        let key = (start, args.len());
+        if !self.lowered.contains_key(&key) {
+            let lowered = lower(&self.bytecode, start, args.len())?;
+            self.lowered.insert(key, lowered);
+        }
+        let lowered = &self.lowered[&key];
+        let registers = &mut self.registers;
+        *registers = args;
+        registers.resize(lowered.frame, Value::Int(0));
+
+        let mut frames: Vec<(usize, usize)> = Vec::new();
+        let mut base = 0;
+        let mut pc = lowered.start;
+        while let Some(instruction) = lowered.code.get(pc) {
+            pc += 1;
+            match instruction {
+                RegInstruction::Load { dst, value } => registers[base + dst] = value.clone(),
+                RegInstruction::Move { dst, src } => {
+                    registers[base + dst] = registers[base + src].clone()
+                }
+                RegInstruction::Unary { op, dst, src } => {
+                    let value = registers[base + src].clone();
+                    registers[base + dst] =
+                        apply_unary(*op, value, self.checked, self.factorial_overflow)?;
+                }
+                RegInstruction::Binary { op, dst, lhs, rhs } => {
+                    let lhs = registers[base + lhs].clone();
+                    let rhs = registers[base + rhs].clone();
+                    registers[base + dst] = apply_binary(*op, lhs, rhs, self.checked)?;
+                }
+                RegInstruction::Jump(target) => pc = *target,
+                RegInstruction::JumpIfFalse { cond, target } => {
+                    if !registers[base + cond].is_truthy(Opcode::JumpIfFalse)? {
+                        pc = *target;
+                    }
+                }
+                RegInstruction::Call {
+                    target,
+                    base: offset,
+                    frame,
+                    ..
+                } => {
+                    if frames.len() >= MAX_CALL_DEPTH {
+                        return Err(RuntimeError::CallStackOverflow);
+                    }
+                    frames.push((pc, base));
+                    base += offset;
+                    if registers.len() < base + frame {
+                        registers.resize(base + frame, Value::Int(0));
+                    }
+                    pc = *target;
+                }
+                RegInstruction::TailCall {
+                    target,
+                    base: offset,
+                    argc,
+                    frame,
+                } => {
+                    if frames.is_empty() {
+                        frames.push((pc, base));
+                        base += offset;
+                    } else {
+                        for i in 0..*argc {
+                            registers[base + i] = registers[base + offset + i].clone();
+                        }
+                    }
+                    if registers.len() < base + frame {
+                        registers.resize(base + frame, Value::Int(0));
+                    }
+                    pc = *target;
+                }
+                RegInstruction::Return(src) => {
+                    let value = registers[base + src].clone();
+                    match frames.pop() {
+                        Some((return_address, caller)) => {
+                            registers[base] = value;
+                            base = caller;
+                            pc = return_address;
+                        }
+                        None => return Ok(value),
+                    }
+                }
+            }
+        }
+        Err(RuntimeError::NoResult)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compiler::{compile, compile_unit},
+        vm::Vm,
+    };
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("2 * 3 + 4")]
+    #[case("1 / 0")]
+    #[case("5 / 0.0")]
+    #[case("pow(2, 3) + sqrt(16.0)")]
+    #[case("abs(-3) < max(1, 2) ? min(1, 2.5) : 0")]
+    #[case(r#""ab" + len("xyz") == "ab3""#)]
+    #[case("!(1 < 2) || 3 >= 3 && 1 != 2")]
+    #[case("fn f(x) { x * 2 } fn g(a, b) { f(a) - b } g(5, 3) + f(1)")]
+    #[case("fn fact(n) { n < 2 ? 1 : n * fact(n - 1) } fact(10)")]
+    #[case("fn f(n) { n > 0 ? f(n - 1) : 0 } f(2000)")]
+    #[case("fn f(a, b, n) { n < 1 ? a : f(b, a + b, n - 1) } fn g(n) { f(0, 1, n) } g(80)")]
+    #[case("21!")]
+    #[case("fn f(x) { x } f(1) + f(2.5)")]
+    #[case(r#""a" - 1"#)]
+    #[case("true + 1")]
+    #[case(r#"-"a""#)]
+    #[case(r#"!"a""#)]
+    #[case("1 < 2 < 3")]
+    fn test_matches_stack_vm(#[case] input: &str) {
+        let program = compile(input).unwrap();
+        let expected = Vm::new(program.clone(), 1 << 16).run();
+        assert_eq!(RegisterVm::new(program.clone()).run(), expected);
+
+        let checked = Vm::new(program.clone(), 1 << 16)
+            .checked_arithmetic(true)
+            .run();
+        let mut vm = RegisterVm::new(program).checked_arithmetic(true);
+        assert_eq!(vm.run(), checked);
+        // A second run reuses the lowered code
+        assert_eq!(vm.run(), checked);
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        let program = compile("9223372036854775807 * 2").unwrap();
+        let expected = Vm::new(program.clone(), 8).checked_arithmetic(true).run();
+        assert!(matches!(expected, Err(RuntimeError::Overflow { .. })));
+        let mut vm = RegisterVm::new(program).checked_arithmetic(true);
+        assert_eq!(vm.run(), expected);
+    }
+
+    #[test]
+    fn test_lower() {
+        let program = compile("fn f(x) { x * 2 } f(3) + 1").unwrap();
+        let lowered = lower(program.code(), 0, 0).unwrap();
+        assert_eq!(
+            lowered.code(),
+            &[
+                RegInstruction::Load {
+                    dst: 0,
+                    value: Value::Int(3)
+                },
+                RegInstruction::Call {
+                    target: 5,
+                    base: 0,
+                    argc: 1,
+                    frame: 3
+                },
+                RegInstruction::Load {
+                    dst: 1,
+                    value: Value::Int(1)
+                },
+                RegInstruction::Binary {
+                    op: Opcode::Addition,
+                    dst: 0,
+                    lhs: 0,
+                    rhs: 1
+                },
+                RegInstruction::Return(0),
+                RegInstruction::Move { dst: 1, src: 0 },
+                RegInstruction::Load {
+                    dst: 2,
+                    value: Value::Int(2)
+                },
+                RegInstruction::Binary {
+                    op: Opcode::Multiply,
+                    dst: 1,
+                    lhs: 1,
+                    rhs: 2
+                },
+                RegInstruction::Return(1),
+            ]
+        );
+        assert_eq!((lowered.start(), lowered.frame()), (0, 2));
+    }
+
+    #[test]
+    fn test_entries_and_args() {
+        let program = compile_unit(&[("area", "w * h"), ("double", "x * 2")]).unwrap();
+        let mut vm = RegisterVm::new(program);
+        let env = HashMap::from([
+            ("w".to_string(), Value::Int(3)),
+            ("h".to_string(), Value::Int(4)),
+            ("x".to_string(), Value::Float(1.5)),
+        ]);
+        assert_eq!(vm.run_entry("area", &env), Ok(Value::Int(12)));
+        assert_eq!(vm.run_entry("double", &env), Ok(Value::Float(3.0)));
+        assert_eq!(
+            vm.run_entry("volume", &env),
+            Err(RuntimeError::UnknownEntry("volume".to_string()))
+        );
+
+        let bytecode = vec![
+            Opcode::LoadArg as u8,
+            1,
+            Opcode::LoadArg as u8,
+            0,
+            0x02,
+            0x06,
+        ];
+        let args = [Value::Int(2), Value::Int(44)];
+        assert_eq!(
+            RegisterVm::new(bytecode).run_with_args(&args),
+            Ok(Value::Int(42))
+        );
+    }
+
+    #[rstest]
+    #[case(vec![Opcode::Addition as u8], RuntimeError::StackUnderflow)]
+    #[case(vec![Opcode::LoadArg as u8, 0], RuntimeError::StackUnderflow)]
+    #[case(vec![0x1B, 0, 0, 0, 0], RuntimeError::StackUnderflow)]
+    #[case(vec![0xF0], RuntimeError::InvalidOpcode(0xF0))]
+    #[case(vec![Opcode::Call as u8, 0, 0], RuntimeError::UnexpectedEnd)]
+    #[case(vec![Opcode::CallHost as u8, 0, 0, Opcode::Return as u8], RuntimeError::InvalidOpcode(0x1F))]
+    fn test_malformed(#[case] bytecode: Vec<u8>, #[case] expected: RuntimeError) {
+        assert_eq!(RegisterVm::new(bytecode).run(), Err(expected));
+    }
+
+    #[test]
+    fn test_unbalanced_stack() {
+        // Jumps back to the start with one more value on the stack each time
+        let mut bytecode = vec![Opcode::Literal as u8];
+        bytecode.extend(Value::Int(1).to_vec());
+        bytecode.extend([Opcode::Jump as u8, 0, 0, 0, 0]);
+        assert_eq!(
+            RegisterVm::new(bytecode).run(),
+            Err(RuntimeError::UnbalancedStack(0))
+        );
+    }
+}
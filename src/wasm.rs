@@ -0,0 +1,124 @@
+// JavaScript bindings, for the `wasm32-unknown-unknown` target with the `wasm` feature and
+// wasm-bindgen, so expressions can be compiled and run in the browser. Integers and floats
+// both become JS numbers, integers beyond 2^53 lose precision on the way out. Numbers
+// coming in are integers when they have no fraction and fit in that range, so `2.0` from
+// JS is the integer 2.
+use std::collections::HashMap;
+
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    compiler::compile_unit,
+    program::{Program, ENTRY},
+    value::Value,
+    verify::verify,
+    vm::Vm,
+};
+
+const STACK_SIZE: usize = 256;
+
+// Largest integer a JS number holds exactly
+const MAX_SAFE_INTEGER: f64 = 9007199254740991.0;
+
+// A compiled expression whose free variables are its parameters, in order of first use
+#[wasm_bindgen(js_name = Program)]
+pub struct WasmProgram {
+    program: Program,
+}
+
+#[wasm_bindgen(js_class = Program)]
+impl WasmProgram {
+    #[wasm_bindgen(getter)]
+    pub fn params(&self) -> Vec<String> {
+        self.program
+            .entry(ENTRY)
+            .map_or_else(Vec::new, |entry| entry.params().to_vec())
+    }
+
+    // The program in the format `rvm compile` writes, to store or send elsewhere
+    #[wasm_bindgen(js_name = toBytes)]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.program.to_bytes()
+    }
+
+    // Load bytes from `toBytes`, whose code is verified since they may come from anywhere
+    #[wasm_bindgen(js_name = fromBytes)]
+    pub fn from_bytes(bytes: &[u8]) -> Result<WasmProgram, JsError> {
+        let program = Program::from_bytes(bytes)?;
+        verify(&program)?;
+        if program.entry(ENTRY).is_none() {
+            return Err(JsError::new("program has no main entry point"));
+        }
+        Ok(WasmProgram { program })
+    }
+}
+
+#[wasm_bindgen]
+pub fn compile(source: &str) -> Result<WasmProgram, JsError> {
+    let program = compile_unit(&[(ENTRY, source)]).map_err(|e| JsError::new(&e.render(source)))?;
+    Ok(WasmProgram { program })
+}
+
+#[wasm_bindgen(js_name = Vm)]
+pub struct WasmVm {
+    vm: Vm,
+    params: Vec<String>,
+}
+
+#[wasm_bindgen(js_class = Vm)]
+impl WasmVm {
+    #[wasm_bindgen(constructor)]
+    pub fn new(program: &WasmProgram, stack_size: Option<usize>) -> WasmVm {
+        WasmVm {
+            vm: Vm::new(program.program.clone(), stack_size.unwrap_or(STACK_SIZE)),
+            params: program.params(),
+        }
+    }
+
+    // Run with `args` as the parameters of the program, in the order of `Program.params`
+    pub fn run(&mut self, args: Vec<JsValue>) -> Result<JsValue, JsError> {
+        if args.len() != self.params.len() {
+            return Err(JsError::new(&format!(
+                "expected {} arguments, got {}",
+                self.params.len(),
+                args.len()
+            )));
+        }
+        let env = self
+            .params
+            .iter()
+            .cloned()
+            .zip(args.iter().map(from_js))
+            .map(|(param, value)| Ok((param, value?)))
+            .collect::<Result<HashMap<String, Value>, JsError>>()?;
+        Ok(to_js(self.vm.run_entry(ENTRY, &env)?))
+    }
+}
+
+fn to_js(value: Value) -> JsValue {
+    match value {
+        Value::Int(value) => JsValue::from_f64(value as f64),
+        Value::Float(value) => JsValue::from_f64(value),
+        Value::Str(value) => JsValue::from_str(&value),
+        Value::Bool(value) => JsValue::from_bool(value),
+    }
+}
+
+fn from_js(value: &JsValue) -> Result<Value, JsError> {
+    if let Some(number) = value.as_f64() {
+        let integral = number.fract() == 0.0 && number.abs() <= MAX_SAFE_INTEGER;
+        return Ok(match integral {
+            true => Value::Int(number as i64),
+            false => Value::Float(number),
+        });
+    }
+    if let Some(string) = value.as_string() {
+        return Ok(Value::from(string));
+    }
+    match value.as_bool() {
+        Some(value) => Ok(Value::Bool(value)),
+        None => Err(JsError::new(
+            "arguments must be numbers, strings or booleans",
+        )),
+    }
+}